@@ -0,0 +1,76 @@
+#!/usr/bin/env python3
+"""A sample lod plugin, providing a fact for whether a flag file exists
+
+Add it to ~/.config/lod/config.toml with:
+
+    [[plugins]]
+    command = ["/path/to/flag_file.py", "/tmp/lod-flag"]
+
+and use the fact in rules, eg `when flag_present then desktop`. The "Toggle Flag" menu item
+creates or removes the file, and switching mode is logged by the `log_switch` action.
+"""
+
+import json
+import os
+import sys
+
+PROTOCOL_VERSION = 1
+
+
+class MethodNotFound(Exception):
+    pass
+
+
+def send(message):
+    print(json.dumps({"jsonrpc": "2.0", **message}), flush=True)
+
+
+def handle(flag, method, params):
+    if method == "initialize":
+        if params["protocol_version"] != PROTOCOL_VERSION:
+            raise ValueError(f"only protocol version {PROTOCOL_VERSION} is spoken")
+        return {
+            "protocol_version": PROTOCOL_VERSION,
+            "facts": ["flag_present"],
+            "actions": ["log_switch"],
+            "menu_items": [{"id": "toggle_flag", "title": "Toggle Flag"}],
+        }
+    if method == "detect":
+        return {"facts": {"flag_present": os.path.exists(flag)}}
+    if method == "run_action":
+        if params["action"] != "log_switch":
+            raise ValueError(f"unknown action {params['action']}")
+        message = f"switched from {params['previous_mode']} to {params['mode']}"
+        send({"method": "log", "params": {"message": message}})
+        return None
+    if method == "menu_item_clicked":
+        if os.path.exists(flag):
+            os.remove(flag)
+        else:
+            open(flag, "w").close()
+        present = os.path.exists(flag)
+        send({"method": "event", "params": {"name": "flag_changed", "data": {"present": present}}})
+        return None
+    raise MethodNotFound(method)
+
+
+def main():
+    flag = sys.argv[1] if len(sys.argv) > 1 else os.path.expanduser("~/.lod-flag")
+    for line in sys.stdin:
+        message = json.loads(line)
+        method = message.get("method")
+        if method == "shutdown":
+            break
+        try:
+            reply = {"result": handle(flag, method, message.get("params") or {})}
+        except MethodNotFound:
+            reply = {"error": {"code": -32601, "message": "Method not found"}}
+        except Exception as error:
+            reply = {"error": {"code": -32000, "message": str(error)}}
+        # Notifications, such as `menu_item_clicked`, are not replied to
+        if "id" in message:
+            send({"id": message["id"], **reply})
+
+
+if __name__ == "__main__":
+    main()
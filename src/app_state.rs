@@ -1,106 +1,590 @@
 use super::{
-    Config,
-    menu_item::Ext,
+    AutoSwitch, CaffeinateFor, Config, Event, Events, Hook, HookContext, IdleWatch,
+    KeepAwakeAction, KeepAwakePolicy, Mode, Plugin, Record, Registry, Reply, SavedState,
+    SharedStatus, StateChangeMessage, StateFile, Status, Supervisor, Webhooks,
+    detector::Facts,
+    duration,
+    hold::{self, Hold, Process},
+    menu,
     program::{Program, ProgramImpl},
+    ui::{Icon, MenuEntry, StatusUi},
     waiting_child::WaitingChild,
 };
-use std::{process::Command, sync::mpsc::Sender, thread};
-use system_status_bar_macos::{Image, Menu, MenuItem, StatusItem};
-
-#[derive(Debug)]
-pub enum Mode {
-    Laptop,
-    Desktop,
-}
-
-impl Mode {
-    const fn accessibility_description(&self) -> &'static str {
-        match self {
-            Self::Laptop => "Switch to Laptop mode",
-            Self::Desktop => "Switch to Desktop mode",
-        }
-    }
-
-    const fn description(&self) -> &'static str {
-        match self {
-            Self::Laptop => "Laptop Mode",
-            Self::Desktop => "Desktop Mode",
-        }
-    }
-
-    const fn sf_symbol(&self) -> &'static str {
-        match self {
-            Self::Laptop => "laptopcomputer",
-            Self::Desktop => "desktopcomputer",
-        }
-    }
+use serde_json::{Value, json};
+use std::{
+    process::{Child, Command},
+    sync::{Arc, mpsc::Sender},
+    thread,
+    time::{Duration, SystemTime},
+};
 
-    const fn toggle(&self) -> Self {
-        match self {
-            Self::Laptop => Self::Desktop,
-            Self::Desktop => Self::Laptop,
-        }
-    }
-}
+/// How often the time remaining for caffeination, and processes to keep awake for, are updated in
+/// the menu
+const TICK_INTERVAL: Duration = Duration::from_secs(30);
 
-pub struct AppState {
+/// The state machine behind whichever `StatusUi` shows it, changed by `StateChangeMessage`s
+pub struct AppState<U: StatusUi> {
     config: Config,
-    status_item: StatusItem,
+    ui: U,
     mode: Mode,
     caffeinate: Option<WaitingChild>,
+    caffeinate_expires_at: Option<SystemTime>,
+    holds: Vec<(Hold, WaitingChild)>,
+    caffeinate_by_mode: bool,
+    supervisor: Supervisor,
+    registry: Option<Registry>,
+    leftovers: Vec<Record>,
+    adopted: Vec<Record>,
+    automatic: AutoSwitch,
+    plugins: Vec<Arc<Plugin>>,
+    /// Counting each time plugins are started, so those started for an outdated config.toml are
+    /// dropped
+    plugins_generation: u64,
+    idle_watch: Option<IdleWatch>,
+    /// The latest listing of the user's processes, taken in the background
+    processes: Vec<Process>,
+    status: SharedStatus,
+    state_file: Option<StateFile>,
+    events: Events,
+    webhooks: Webhooks,
     sender: Sender<StateChangeMessage>,
 }
 
-impl AppState {
+impl<U: StatusUi> AppState<U> {
+    /// Start in `mode`, recording children in `registry` if given
     #[must_use]
-    pub fn new(config: Config, mode: Mode, sender: Sender<StateChangeMessage>) -> Self {
-        let mut status_item = StatusItem::new("", Menu::new(vec![]));
-        if let Some(image) =
-            Image::with_system_symbol_name(mode.sf_symbol(), Some(mode.accessibility_description()))
-        {
-            status_item.set_image(image);
+    pub fn new(
+        config: Config,
+        mode: Mode,
+        mut ui: U,
+        sender: Sender<StateChangeMessage>,
+        registry: Option<Registry>,
+    ) -> Self {
+        ui.set_icon(Self::icon(mode));
+
+        let tick_sender = sender.clone();
+        thread::spawn(move || {
+            while tick_sender.send(Self::list_processes()).is_ok() {
+                thread::sleep(TICK_INTERVAL);
+            }
+        });
+
+        // Find children left running by a previous instance, before we record our own
+        let leftovers = registry
+            .as_ref()
+            .map(Registry::leftovers)
+            .unwrap_or_default();
+        for leftover in &leftovers {
+            println!("Found {leftover} left running by a previous instance");
         }
 
+        let idle_watch = Self::spawn_idle_watch(&config, &sender);
+
+        let status = SharedStatus::new(Status::new(mode));
+        let events = Events::default();
+        let webhooks = Webhooks::spawn(&events, status.clone());
+        webhooks.set(config.webhooks());
+
         let mut app_state = Self {
             config,
-            status_item,
+            ui,
             mode,
             caffeinate: None,
+            caffeinate_expires_at: None,
+            holds: vec![],
+            caffeinate_by_mode: false,
+            supervisor: Supervisor::new(registry.clone(), sender.clone()),
+            registry,
+            leftovers,
+            adopted: vec![],
+            // Once the plugins have started, as rules can refer to their facts
+            automatic: AutoSwitch::disabled(),
+            plugins: vec![],
+            plugins_generation: 0,
+            idle_watch,
+            processes: vec![],
+            status,
+            state_file: None,
+            events,
+            webhooks,
             sender,
         };
+        app_state.start_plugins();
+        app_state.apply_keep_awake_policy();
+        app_state
+            .supervisor
+            .set_services(app_state.config.services(mode));
         app_state.configure_menu_items();
         app_state
     }
 
+    /// Save the state to `file` as it changes, first carrying on with any timed caffeination a
+    /// previous instance saved which has not yet expired
+    ///
+    /// Caffeination is only carried on when the mode has no keep awake policy to apply instead.
+    #[must_use]
+    pub fn with_state_file(mut self, file: StateFile) -> Self {
+        let remaining = file
+            .load()
+            .caffeinate_expires_at
+            .and_then(|expires_at| expires_at.duration_since(SystemTime::now()).ok());
+        self.state_file = Some(file);
+        if let Some(remaining) = remaining {
+            if self.caffeinate.is_none()
+                && self.config.keep_awake(self.mode) == KeepAwakePolicy::Unchanged
+            {
+                println!(
+                    "Carrying on caffeinating for the {} left",
+                    duration::format_remaining(remaining)
+                );
+                let _ = self.start_caffeinate(Some(remaining));
+            }
+        }
+        self.configure_menu_items();
+        self
+    }
+
+    /// Change the state as the message asks
+    pub fn handle(&mut self, message: StateChangeMessage) {
+        match message {
+            StateChangeMessage::Quit => (),
+            StateChangeMessage::ClearCaffeination => self.clear_caffeinate(),
+            StateChangeMessage::ToggleMode => self.toggle_mode(),
+            StateChangeMessage::SetMode(mode) => self.set_mode(mode),
+            StateChangeMessage::SwitchApproved(mode) => self.switch_approved(mode),
+            StateChangeMessage::ToggleAutomatic => self.toggle_automatic(),
+            StateChangeMessage::ToggleCaffeination => self.toggle_caffeination(),
+            StateChangeMessage::Caffeinate(caffeinate_for) => {
+                // Already reported should it fail
+                let _ = self.caffeinate(caffeinate_for);
+            }
+            StateChangeMessage::TerminateLeftover(pid) => self.terminate_leftover(pid),
+            StateChangeMessage::AdoptLeftover(pid) => self.adopt_leftover(pid),
+            StateChangeMessage::ServiceExited => self.service_exited(),
+            StateChangeMessage::RestartServices => self.restart_services(),
+            StateChangeMessage::ReloadConfig => {
+                let _ = self.reload_config();
+            }
+            StateChangeMessage::PluginMenuItem { plugin, item } => {
+                self.plugin_menu_item(plugin, item);
+            }
+            StateChangeMessage::PluginsStarted {
+                generation,
+                plugins,
+            } => self.plugins_started(generation, plugins),
+            StateChangeMessage::PluginExited => self.plugin_exited(),
+            StateChangeMessage::Tick => self.tick(),
+            StateChangeMessage::Processes(processes) => {
+                self.processes = processes;
+                self.forget_exited_leftovers();
+                self.tick();
+            }
+            StateChangeMessage::StopCaffeination => self.stop_caffeination(),
+            StateChangeMessage::KeepAwakeWhile(pid) => self.keep_awake_while(pid),
+            StateChangeMessage::ReleaseHold(caffeinate_pid) => self.release_hold(caffeinate_pid),
+            StateChangeMessage::Request(message, reply) => self.handle_request(*message, reply),
+        }
+    }
+
+    /// Change the state as asked over the control socket or HTTP, replying once the change has
+    /// been made, or with why it was not
+    fn handle_request(&mut self, message: StateChangeMessage, reply: Reply) {
+        let result = match message {
+            StateChangeMessage::ToggleMode => {
+                self.request_switch(self.mode.toggle(), Some(reply));
+                return;
+            }
+            StateChangeMessage::SetMode(mode) if mode != self.mode => {
+                self.request_switch(mode, Some(reply));
+                return;
+            }
+            StateChangeMessage::Caffeinate(caffeinate_for) => self.caffeinate(caffeinate_for),
+            StateChangeMessage::ReloadConfig => self.reload_config(),
+            message => {
+                self.handle(message);
+                Ok(())
+            }
+        };
+        reply.send(result);
+    }
+
+    #[must_use]
+    pub const fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// The facts detected for automatic switching on its latest poll
+    #[must_use]
+    pub fn facts(&self) -> Facts {
+        self.automatic.facts()
+    }
+
+    /// The status published for the control socket, kept up to date as the state changes
+    #[must_use]
+    pub fn status(&self) -> SharedStatus {
+        self.status.clone()
+    }
+
+    /// Events as the state changes, for subscribers to the control socket
+    #[must_use]
+    pub fn events(&self) -> Events {
+        self.events.clone()
+    }
+
+    const fn icon(mode: Mode) -> Icon {
+        Icon::new(mode.sf_symbol(), mode.accessibility_description())
+    }
+
+    fn spawn_auto_switch(
+        config: &Config,
+        plugins: &[Arc<Plugin>],
+        status: &SharedStatus,
+        sender: &Sender<StateChangeMessage>,
+    ) -> AutoSwitch {
+        AutoSwitch::spawn(config.automatic(), plugins, status.clone(), sender.clone())
+            .unwrap_or_else(|error| {
+                eprintln!("Automatic switching is unavailable: {error}");
+                AutoSwitch::disabled()
+            })
+    }
+
+    /// Start the configured plugins in the background, as each can take until its timeout to
+    /// reply, keeping those running whose configuration is unchanged unless they have exited
+    ///
+    /// Automatic switching is started again once they have, with `plugins_started`.
+    fn start_plugins(&mut self) {
+        self.plugins_generation += 1;
+        let generation = self.plugins_generation;
+        if self.config.plugins().is_empty() {
+            self.plugins_started(generation, vec![]);
+            return;
+        }
+
+        let configs = self.config.plugins().to_vec();
+        let running = self.plugins.clone();
+        let events = self.events.clone();
+        let sender = self.sender.clone();
+        let registry = self.registry.clone();
+        thread::spawn(move || {
+            let plugins = configs
+                .iter()
+                .filter_map(|plugin_config| {
+                    if let Some(plugin) = running
+                        .iter()
+                        .find(|plugin| plugin.config() == plugin_config && plugin.is_running())
+                    {
+                        return Some(plugin.clone());
+                    }
+                    Plugin::start(plugin_config, &events, sender.clone(), registry.as_ref())
+                        .inspect_err(|error| {
+                            eprintln!("{error}");
+                            events.emit(&Event::ActionFailed {
+                                action: "start_plugin",
+                                error: error.clone(),
+                            });
+                        })
+                        .ok()
+                        .map(Arc::new)
+                })
+                .collect();
+            let message = StateChangeMessage::PluginsStarted {
+                generation,
+                plugins,
+            };
+            if let Err(error) = sender.send(message) {
+                eprintln!("Failed to send StateChangeMessage::PluginsStarted message: {error}");
+            }
+        });
+    }
+
+    /// Replace the running plugins with those started, stopping any no longer configured, unless
+    /// they were started for an outdated config.toml
+    pub fn plugins_started(&mut self, generation: u64, plugins: Vec<Arc<Plugin>>) {
+        if generation != self.plugins_generation {
+            return;
+        }
+        self.plugins = plugins;
+        self.automatic =
+            Self::spawn_auto_switch(&self.config, &self.plugins, &self.status, &self.sender);
+        self.configure_menu_items();
+    }
+
+    fn spawn_idle_watch(config: &Config, sender: &Sender<StateChangeMessage>) -> Option<IdleWatch> {
+        config
+            .caffeinate_stop_after_idle()
+            .map(|threshold| IdleWatch::spawn(threshold, sender.clone()))
+    }
+
+    /// Load config.toml again, keeping the current configuration should it be invalid
+    ///
+    /// The keep awake policy is left to be applied on the next switch, as with a manual choice.
+    ///
+    /// # Errors
+    ///
+    /// If config.toml is invalid, which is also reported as an `ActionFailed` event
+    pub fn reload_config(&mut self) -> Result<(), String> {
+        let config = match Config::load() {
+            Ok(config) => config,
+            Err(error) => {
+                eprintln!("Failed to reload config.toml, keeping the current one: {error:?}");
+                self.events.emit(&Event::ActionFailed {
+                    action: "reload_config",
+                    error: error.to_string(),
+                });
+                return Err(format!("Failed to reload config.toml: {error}"));
+            }
+        };
+        println!("Reloaded config.toml");
+        let mut previous = std::mem::replace(&mut self.config, config);
+        previous.delete_apple_scripts();
+
+        self.start_plugins();
+        self.idle_watch = Self::spawn_idle_watch(&self.config, &self.sender);
+        self.set_idle_watch_caffeinating();
+        self.supervisor
+            .set_services(self.config.services(self.mode));
+        self.webhooks.set(self.config.webhooks());
+        self.configure_menu_items();
+        self.events.emit(&Event::ConfigReloaded);
+        Ok(())
+    }
+
     pub fn toggle_mode(&mut self) {
-        let new_mode = self.mode.toggle();
-        println!("Switching to {new_mode:#?} mode");
-        if let Some(image) = Image::with_system_symbol_name(
-            new_mode.sf_symbol(),
-            Some(new_mode.accessibility_description()),
-        ) {
-            self.status_item.set_image(image);
+        self.request_switch(self.mode.toggle(), None);
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        if self.mode == mode {
+            println!("Already in {mode:#?} mode");
+            return;
+        }
+        self.request_switch(mode, None);
+    }
+
+    /// Switch once the `before_switch` hooks, if any, have allowed it
+    ///
+    /// The hooks are run in the background, sending `SwitchApproved` should they all succeed. Any
+    /// `reply` is sent once switched, or should a hook fail.
+    fn request_switch(&mut self, mode: Mode, reply: Option<Reply>) {
+        let hooks = self.config.hooks();
+        if !hooks.has(Hook::BeforeSwitch) {
+            self.switch_to(mode);
+            if let Some(reply) = reply {
+                reply.send(Ok(()));
+            }
+            return;
         }
-        self.mode = new_mode;
 
-        self.run_apple_script();
+        let hooks = hooks.clone();
+        let context = self.hook_context(Some(mode), vec![]);
+        let sender = self.sender.clone();
+        let events = self.events.clone();
+        thread::spawn(move || match hooks.run(Hook::BeforeSwitch, &context) {
+            Ok(()) => {
+                let message = StateChangeMessage::SwitchApproved(mode);
+                let message = match reply {
+                    Some(reply) => StateChangeMessage::Request(Box::new(message), reply),
+                    None => message,
+                };
+                if let Err(error) = sender.send(message) {
+                    eprintln!("Failed to send StateChangeMessage::SwitchApproved message: {error}");
+                }
+            }
+            Err(error) => {
+                if let Some(reply) = reply {
+                    reply.send(Err(format!(
+                        "Not switching to {mode} mode, as a before_switch hook failed: {error}"
+                    )));
+                }
+                eprintln!(
+                    "Not switching to {mode:#?} mode, as a before_switch hook failed: {error}"
+                );
+                events.emit(&Event::ActionFailed {
+                    action: Hook::BeforeSwitch.name(),
+                    error: error.clone(),
+                });
+                let context = HookContext {
+                    actions: vec![
+                        json!({ "action": "before_switch", "ok": false, "error": error }),
+                    ],
+                    ..context
+                };
+                if let Err(error) = hooks.run(Hook::OnSwitchFailed, &context) {
+                    eprintln!("{error}");
+                }
+            }
+        });
+    }
+
+    /// The `before_switch` hooks have allowed switching to `mode`
+    pub fn switch_approved(&mut self, mode: Mode) {
+        if self.mode != mode {
+            self.switch_to(mode);
+        }
+    }
+
+    fn switch_to(&mut self, new_mode: Mode) {
+        println!("Switching to {new_mode:#?} mode");
+        self.ui.set_icon(Self::icon(new_mode));
+        let previous = std::mem::replace(&mut self.mode, new_mode);
+
+        self.apply_keep_awake_policy();
+        self.supervisor.set_services(self.config.services(new_mode));
+        // Once the keep awake policy is applied, so the hooks which follow it know the outcome
+        self.run_apple_script(previous);
+        self.run_plugin_actions(previous);
         self.configure_menu_items();
+        self.events.emit(&Event::ModeChanged {
+            mode: new_mode,
+            previous,
+        });
+    }
+
+    /// Caffeinate as the mode asks, overriding any manual choice made since the last switch
+    fn apply_keep_awake_policy(&mut self) {
+        let action = self.config.keep_awake(self.mode).action();
+        if action == KeepAwakeAction::Unchanged {
+            self.caffeinate_by_mode = false;
+            return;
+        }
+        println!("Applying keep awake policy for {:#?} mode", self.mode);
+        self.kill_caffeinate();
+        if let KeepAwakeAction::Start(caffeinate_for) = action {
+            let _ = self.start_caffeinate(caffeinate_for.duration());
+        }
+        self.caffeinate_by_mode = true;
+    }
+
+    /// Note for the Caffeinate menu on whether the mode or the user is in control
+    fn caffeinate_driver(&self) -> Option<&'static str> {
+        if self.config.keep_awake(self.mode) == KeepAwakePolicy::Unchanged {
+            None
+        } else if self.caffeinate_by_mode {
+            Some(self.mode.description())
+        } else {
+            Some("Manual")
+        }
+    }
+
+    /// List the user's processes, which is slow enough that it is done off the main thread
+    fn list_processes() -> StateChangeMessage {
+        match hold::processes() {
+            Ok(processes) => StateChangeMessage::Processes(processes),
+            Err(error) => {
+                eprintln!("Failed to list processes: {error:?}");
+                StateChangeMessage::Tick
+            }
+        }
+    }
+
+    /// List the user's processes again in the background, rather than waiting for the next tick
+    fn refresh_processes(&self) {
+        let sender = self.sender.clone();
+        thread::spawn(move || {
+            let _ = sender.send(Self::list_processes());
+        });
     }
 
     fn configure_menu_items(&mut self) {
         let opposite_mode = self.mode.toggle();
-        let menu_items = vec![
-            MenuItem::toggle_mode(
-                opposite_mode.description(),
-                opposite_mode.sf_symbol(),
-                opposite_mode.accessibility_description(),
-                self.sender.clone(),
+        let keep_awake = self.config.keep_awake_backend();
+        let holds: Vec<Hold> = self.holds.iter().map(|(hold, _)| hold.clone()).collect();
+        // Holds started elsewhere, eg by `lod caffeinate -- <command>`, are shown alongside ours
+        let other_holds: Vec<Hold> = hold::holds(&self.processes, keep_awake)
+            .into_iter()
+            .filter(|hold| {
+                !holds
+                    .iter()
+                    .any(|ours| ours.caffeinate_pid() == hold.caffeinate_pid())
+            })
+            .collect();
+
+        let mut menu_items = vec![
+            menu::toggle_mode_item(opposite_mode),
+            menu::caffeinate_item(
+                self.caffeinate.is_some(),
+                self.caffeinate_remaining(),
+                self.caffeinate_driver(),
+                self.config.caffeinate_until(),
+                &holds,
+                &other_holds,
+                &hold::candidates(&self.processes, keep_awake),
             ),
-            MenuItem::caffeinate_item(self.caffeinate.is_some(), self.sender.clone()),
-            MenuItem::separator(),
-            MenuItem::quit_item(self.sender.clone()),
         ];
-        self.status_item.set_menu(Menu::new(menu_items));
+        if !self.leftovers.is_empty() || !self.adopted.is_empty() {
+            menu_items.push(menu::leftovers_item(&self.leftovers, &self.adopted));
+        }
+        if self.supervisor.statuses().next().is_some() {
+            menu_items.push(menu::services_item(self.supervisor.statuses()));
+        }
+        if !self.plugins.is_empty() {
+            menu_items.push(menu::plugins_item(&self.plugins));
+        }
+        if self.automatic.is_available() {
+            menu_items.push(menu::automatic_item(self.automatic.is_enabled()));
+        }
+        let mut menu: Vec<MenuEntry> = menu_items.into_iter().map(MenuEntry::from).collect();
+        menu.push(MenuEntry::Separator);
+        menu.push(menu::quit_item().into());
+        self.ui.set_menu(menu);
+        self.publish_status(&holds);
+    }
+
+    /// Publish the state as shown in the menu, for the control socket
+    fn publish_status(&self, holds: &[Hold]) {
+        if let Some(state_file) = &self.state_file {
+            state_file.save(&SavedState {
+                caffeinate_expires_at: self.caffeinate_expires_at,
+                mode: Some(self.mode),
+            });
+        }
+        self.status.set(Status {
+            mode: self.mode,
+            automatic_available: self.automatic.is_available(),
+            automatic_enabled: self.automatic.is_enabled(),
+            caffeinating: self.caffeinate.is_some(),
+            caffeinate_expires_at: self.caffeinate_expires_at,
+            caffeinate_by_mode: (self.config.keep_awake(self.mode) != KeepAwakePolicy::Unchanged)
+                .then_some(self.caffeinate_by_mode),
+            keep_awake: self.config.keep_awake_backend().name(),
+            inhibits: self
+                .config
+                .keep_awake_backend()
+                .inhibits()
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            holds: holds
+                .iter()
+                .map(|hold| (hold.pid(), hold.name().to_owned()))
+                .collect(),
+            services: self
+                .supervisor
+                .statuses()
+                .map(|(name, status, restarts)| (name.to_owned(), status, restarts))
+                .collect(),
+        });
+    }
+
+    pub fn toggle_automatic(&mut self) {
+        self.automatic.toggle();
+        println!(
+            "Switching automatic mode selection to {}",
+            self.automatic.is_enabled()
+        );
+        self.configure_menu_items();
+    }
+
+    /// A service has exited, so restart it if its policy says to
+    pub fn service_exited(&mut self) {
+        self.supervisor.child_exited();
+        self.configure_menu_items();
+    }
+
+    /// Restart services whose backoff has passed
+    pub fn restart_services(&mut self) {
+        self.supervisor.restart_due();
+        self.configure_menu_items();
     }
 
     #[must_use]
@@ -108,44 +592,335 @@ impl AppState {
         self.caffeinate.is_some()
     }
 
+    /// When caffeination will stop, if it has been started for a limited time
+    #[must_use]
+    pub const fn caffeinate_expires_at(&self) -> Option<SystemTime> {
+        self.caffeinate_expires_at
+    }
+
+    fn caffeinate_remaining(&self) -> Option<Duration> {
+        self.caffeinate_expires_at.map(|expires_at| {
+            expires_at
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+        })
+    }
+
     #[allow(clippy::nonminimal_bool)]
     pub fn toggle_caffeination(&mut self) {
         // We want to show the state we are going to, thus the negation
         // and need for the clippy allow
         println!("Switching caffeination to {}", !self.caffeinate.is_some());
+        self.caffeinate_by_mode = false;
 
         if self.caffeinate.is_some() {
             self.kill_caffeinate();
         } else {
-            let caffeinate_app = self.config.caffeinate_app().unwrap_or("caffeinate");
-            let mut caffeinate = Command::new(caffeinate_app);
-            if let Some(arg) = self.config.caffeinate_options() {
-                caffeinate.arg(arg);
-            }
-            match caffeinate.spawn() {
-                Ok(child) => {
-                    let waiting_child = WaitingChild::new(child, self.sender.clone());
-                    self.caffeinate = Some(waiting_child);
+            // Only a plain toggle is limited to the configured timeout, as elsewhere the time
+            // is given
+            let _ = self.start_caffeinate(self.config.caffeinate_options().timeout());
+        }
+
+        self.configure_menu_items();
+    }
+
+    /// Start caffeinating for the given time, replacing any current caffeination
+    ///
+    /// # Errors
+    ///
+    /// If the keep awake command could not be started, which is also reported as an
+    /// `ActionFailed` event
+    pub fn caffeinate(&mut self, caffeinate_for: CaffeinateFor) -> Result<(), String> {
+        println!("Caffeinating for {caffeinate_for:?}");
+        self.caffeinate_by_mode = false;
+        self.kill_caffeinate();
+        let result = self.start_caffeinate(caffeinate_for.duration());
+        self.configure_menu_items();
+        result
+    }
+
+    /// Update the time remaining for caffeination and the processes shown in the menu
+    pub fn tick(&mut self) {
+        self.configure_menu_items();
+    }
+
+    /// Drop leftover and adopted children which are missing from the latest list of processes
+    fn forget_exited_leftovers(&mut self) {
+        for records in [&mut self.leftovers, &mut self.adopted] {
+            records.retain(|record| {
+                let running = record.is_in(&self.processes);
+                if !running {
+                    if let Some(registry) = &self.registry {
+                        registry.forget(record.pid());
+                    }
+                }
+                running
+            });
+        }
+    }
+
+    /// Stop a child left running by a previous instance, or one which was adopted
+    pub fn terminate_leftover(&mut self, pid: u32) {
+        for records in [&mut self.leftovers, &mut self.adopted] {
+            if let Some(index) = records.iter().position(|record| record.pid() == pid) {
+                let record = records.remove(index);
+                println!("Terminating {record}");
+                if let Err(error) = record.terminate() {
+                    eprintln!("Failed to terminate {record}: {error:?}");
+                    self.events.emit(&Event::ActionFailed {
+                        action: "terminate_leftover",
+                        error: error.to_string(),
+                    });
                 }
-                Err(error) => {
-                    eprintln!("Failed to start caffeinate: {error:?}");
+                if let Some(registry) = &self.registry {
+                    registry.forget(pid);
                 }
             }
         }
+        self.configure_menu_items();
+    }
 
+    /// Keep a child left running by a previous instance, stopping it when we quit
+    pub fn adopt_leftover(&mut self, pid: u32) {
+        if let Some(index) = self.leftovers.iter().position(|record| record.pid() == pid) {
+            let record = self.leftovers.remove(index);
+            println!("Adopting {record}");
+            self.adopted.push(record);
+        }
         self.configure_menu_items();
     }
 
-    fn run_apple_script(&self) {
+    /// Wait for a child in the background, recording it while it runs
+    fn watch(&self, child: Child, on_exit: StateChangeMessage) -> WaitingChild {
+        match &self.registry {
+            Some(registry) => WaitingChild::recorded(child, self.sender.clone(), on_exit, registry),
+            None => WaitingChild::new(child, self.sender.clone(), on_exit),
+        }
+    }
+
+    /// Keep the Mac awake until the process with the given `pid` exits
+    pub fn keep_awake_while(&mut self, pid: u32) {
+        let name = self
+            .processes
+            .iter()
+            .find(|process| process.pid() == pid)
+            .map_or_else(
+                || String::from("Process"),
+                |process| process.name().to_string(),
+            );
+        println!("Caffeinating while {name} ({pid}) is running");
+
+        let keep_awake = self.config.keep_awake_backend();
+        match keep_awake.command_while(pid).spawn() {
+            Ok(child) => {
+                let hold = Hold::new(child.id(), pid, name);
+                let waiting_child = self.watch(child, StateChangeMessage::ClearCaffeination);
+                self.holds.push((hold, waiting_child));
+            }
+            Err(error) => {
+                eprintln!("Failed to start {}: {error:?}", keep_awake.program());
+                self.events.emit(&Event::ActionFailed {
+                    action: "keep_awake_while",
+                    error: error.to_string(),
+                });
+            }
+        }
+        self.configure_menu_items();
+    }
+
+    /// Stop keeping the Mac awake for a process, before it has exited
+    ///
+    /// Only holds lod started are released, as the pid of any other could have been reused since
+    /// it was listed.
+    pub fn release_hold(&mut self, caffeinate_pid: u32) {
+        let Some(index) = self
+            .holds
+            .iter()
+            .position(|(hold, _)| hold.caffeinate_pid() == caffeinate_pid)
+        else {
+            eprintln!("Not releasing the hold kept by {caffeinate_pid}, as lod did not start it");
+            return;
+        };
+        println!("Releasing hold kept by caffeinate ({caffeinate_pid})");
+        let (_, child) = self.holds.remove(index);
+        if let Err(error) = child.kill() {
+            eprintln!("Failed to kill caffeinate: {error:?}");
+            self.events.emit(&Event::ActionFailed {
+                action: "release_hold",
+                error: error.to_string(),
+            });
+        }
+        self.refresh_processes();
+        self.configure_menu_items();
+    }
+
+    /// Start caffeinating for `duration`, or until stopped if `None`
+    fn start_caffeinate(&mut self, duration: Option<Duration>) -> Result<(), String> {
+        let expires_at = match duration {
+            Some(duration) => Some(SystemTime::now().checked_add(duration).ok_or_else(|| {
+                let error = format!("Caffeinating for {duration:?} would never end");
+                eprintln!("{error}");
+                self.events.emit(&Event::ActionFailed {
+                    action: "start_caffeinate",
+                    error: error.clone(),
+                });
+                error
+            })?),
+            None => None,
+        };
+        let keep_awake = self.config.keep_awake_backend();
+        // Let it exit by itself once the time is up, which will clear the menu state in the same
+        // way as if it had been killed
+        match keep_awake.command(duration).spawn() {
+            Ok(child) => {
+                let waiting_child = self.watch(child, StateChangeMessage::ClearCaffeination);
+                self.caffeinate = Some(waiting_child);
+                self.caffeinate_expires_at = expires_at;
+                self.set_idle_watch_caffeinating();
+                self.events
+                    .emit(&Event::CaffeinateStarted(self.caffeinate_expires_at));
+                self.run_hook(
+                    Hook::OnCaffeinateStart,
+                    vec![json!({ "action": "start_caffeinate", "ok": true })],
+                );
+                Ok(())
+            }
+            Err(error) => {
+                eprintln!("Failed to start {}: {error:?}", keep_awake.program());
+                self.events.emit(&Event::ActionFailed {
+                    action: "start_caffeinate",
+                    error: error.to_string(),
+                });
+                Err(format!("Failed to start {}: {error}", keep_awake.program()))
+            }
+        }
+    }
+
+    /// Run the mode's `AppleScript` in the background, followed by the `after_switch` hooks, or the
+    /// `on_switch_failed` ones should it fail
+    ///
+    /// Only macOS has `AppleScript`, elsewhere the `after_switch` hooks are run straight away.
+    fn run_apple_script(&self, previous: Mode) {
         let mut defaults = Command::new("osascript");
         defaults.arg(match self.mode {
             Mode::Laptop => self.config.laptop_applescript_path(),
             Mode::Desktop => self.config.desktop_applescript_path(),
         });
 
+        let events = self.events.clone();
+        let hooks = self.config.hooks().clone();
+        let mut context = self.hook_context(None, vec![]);
+        context.previous_mode = Some(previous);
+        thread::spawn(move || {
+            let result = cfg!(target_os = "macos").then(|| ProgramImpl::new(defaults, 0).execute());
+            let hook = match result {
+                None => Hook::AfterSwitch,
+                Some(Ok(_)) => {
+                    context
+                        .actions
+                        .push(json!({ "action": "run_applescript", "ok": true }));
+                    Hook::AfterSwitch
+                }
+                Some(Err(error)) => {
+                    eprintln!("{error:?}");
+                    events.emit(&Event::ActionFailed {
+                        action: "run_applescript",
+                        error: error.to_string(),
+                    });
+                    context.actions.push(json!({
+                        "action": "run_applescript",
+                        "ok": false,
+                        "error": error.to_string(),
+                    }));
+                    Hook::OnSwitchFailed
+                }
+            };
+            if let Err(error) = hooks.run(hook, &context) {
+                eprintln!("{error}");
+                events.emit(&Event::ActionFailed {
+                    action: hook.name(),
+                    error,
+                });
+            }
+        });
+    }
+
+    /// Run the actions plugins registered, in the background as plugins can take a while to reply
+    fn run_plugin_actions(&self, previous: Mode) {
+        for plugin in &self.plugins {
+            if plugin.registration().actions.is_empty() || !plugin.is_running() {
+                continue;
+            }
+            let plugin = plugin.clone();
+            let events = self.events.clone();
+            let mode = self.mode;
+            thread::spawn(move || {
+                for action in &plugin.registration().actions {
+                    if let Err(error) = plugin.run_action(action, mode, previous) {
+                        eprintln!("{error}");
+                        events.emit(&Event::ActionFailed {
+                            action: "run_plugin_action",
+                            error,
+                        });
+                    }
+                }
+            });
+        }
+    }
+
+    /// Tell a plugin its menu item was clicked
+    pub fn plugin_menu_item(&self, plugin: usize, item: usize) {
+        // The menu could have been built before config.toml was reloaded
+        let Some((plugin, menu_item)) = self.plugins.get(plugin).and_then(|plugin| {
+            plugin
+                .registration()
+                .menu_items
+                .get(item)
+                .map(|menu_item| (plugin, menu_item))
+        }) else {
+            return;
+        };
+        println!("Clicked {} of plugin {}", menu_item.title, plugin.name());
+        if let Err(error) = plugin.menu_item_clicked(&menu_item.id) {
+            eprintln!("{error}");
+            self.events.emit(&Event::ActionFailed {
+                action: "run_plugin",
+                error,
+            });
+        }
+    }
+
+    /// A plugin has exited, which has already been reported, so mark it in the menu
+    pub fn plugin_exited(&mut self) {
+        self.configure_menu_items();
+    }
+
+    /// The state given to hooks, for switching to `mode` if given, otherwise the current one
+    fn hook_context(&self, mode: Option<Mode>, actions: Vec<Value>) -> HookContext {
+        HookContext {
+            mode: mode.unwrap_or(self.mode),
+            previous_mode: mode.map(|_| self.mode),
+            caffeinating: self.caffeinate.is_some(),
+            actions,
+            facts: self.automatic.facts(),
+        }
+    }
+
+    /// Run a hook's actions in the background, as nothing waits for them
+    fn run_hook(&self, hook: Hook, actions: Vec<Value>) {
+        if !self.config.hooks().has(hook) {
+            return;
+        }
+        let hooks = self.config.hooks().clone();
+        let context = self.hook_context(None, actions);
+        let events = self.events.clone();
         thread::spawn(move || {
-            if let Err(error) = ProgramImpl::new(defaults, 0).execute() {
-                eprintln!("{error:?}");
+            if let Err(error) = hooks.run(hook, &context) {
+                eprintln!("{error}");
+                events.emit(&Event::ActionFailed {
+                    action: hook.name(),
+                    error,
+                });
             }
         });
     }
@@ -155,41 +930,425 @@ impl AppState {
             if let Err(error) = child.kill() {
                 eprintln!("Failed to kill caffeinate: {error:?}");
             }
+            self.events.emit(&Event::CaffeinateStopped);
+            self.run_hook(
+                Hook::OnCaffeinateStop,
+                vec![json!({ "action": "stop_caffeinate", "ok": true })],
+            );
+        }
+        self.caffeinate_expires_at = None;
+        self.set_idle_watch_caffeinating();
+    }
+
+    /// Stop caffeinating, eg because the user has been idle for too long
+    pub fn stop_caffeination(&mut self) {
+        if self.caffeinate.is_some() {
+            println!("Stopping caffeination");
+            self.caffeinate_by_mode = false;
+            self.kill_caffeinate();
+            self.configure_menu_items();
         }
     }
 
     pub fn clear_caffeinate(&mut self) {
+        let holds = self.holds.len();
+        self.holds.retain(|(hold, child)| {
+            if child.has_exited() {
+                println!("No longer caffeinating for {hold}");
+            }
+            !child.has_exited()
+        });
+        if self.holds.len() != holds {
+            self.configure_menu_items();
+        }
+
+        // The message could be for a hold, or a caffeinate which has since been replaced
+        if !self
+            .caffeinate
+            .as_ref()
+            .is_some_and(WaitingChild::has_exited)
+        {
+            return;
+        }
+
         println!(
             "Caffeinate has been killed, updating menu state. NOTE: This message could be delayed \
             from when the process was actually killed as waited until next event loop invocation."
         );
         self.caffeinate.take();
+        self.caffeinate_expires_at = None;
+        self.set_idle_watch_caffeinating();
         self.configure_menu_items();
+        self.events.emit(&Event::CaffeinateStopped);
+        self.run_hook(
+            Hook::OnCaffeinateStop,
+            vec![json!({ "action": "caffeinate_exited", "ok": true })],
+        );
+    }
+
+    fn set_idle_watch_caffeinating(&self) {
+        if let Some(idle_watch) = &self.idle_watch {
+            idle_watch.set_caffeinating(self.caffeinate.is_some());
+        }
     }
 }
 
-impl Drop for AppState {
+impl<U: StatusUi> Drop for AppState<U> {
     fn drop(&mut self) {
         println!("Deleting AppleScripts in AppState::drop()");
         self.config.delete_apple_scripts();
 
+        println!("Stopping services");
+        self.supervisor.stop_all();
+
+        println!("Stopping plugins");
+        let stopping: Vec<_> = self
+            .plugins
+            .drain(..)
+            .filter_map(|plugin| plugin.stop())
+            .collect();
+        for stop in stopping {
+            let _ = stop.join();
+        }
+
         println!("Killing caffeinate");
         self.kill_caffeinate();
+        for record in self.adopted.drain(..) {
+            if let Err(error) = record.terminate() {
+                eprintln!("Failed to terminate {record}: {error:?}");
+            }
+            if let Some(registry) = &self.registry {
+                registry.forget(record.pid());
+            }
+        }
+        for (_, child) in self.holds.drain(..) {
+            if let Err(error) = child.kill() {
+                eprintln!("Failed to kill caffeinate: {error:?}");
+            }
+        }
     }
 }
 
-/// Message sent to change the app's state
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
-pub enum StateChangeMessage {
-    /// Toggle the current mode
-    ToggleMode,
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ui::FakeUi;
+    use std::sync::mpsc::{self, Receiver};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// `sleep` stands in for `caffeinate`, with its argument as the only option
+    fn config(extra: &str) -> Config {
+        Config::parse(&format!(
+            r#"
+            desktop_applescript = ""
+            laptop_applescript = ""
+            caffeinate_app = "sleep"
+            {extra}
+
+            [caffeinate_options]
+            args = ["60"]
+            "#
+        ))
+        .unwrap()
+    }
+
+    fn app_state(config: Config) -> (AppState<FakeUi>, FakeUi, Receiver<StateChangeMessage>) {
+        let ui = FakeUi::default();
+        let (sender, receiver) = mpsc::channel();
+        let app_state = AppState::new(config, Mode::Laptop, ui.clone(), sender, None);
+        (app_state, ui, receiver)
+    }
+
+    /// Wait for the first message other than a `Tick` or `Processes`
+    fn next_message(receiver: &Receiver<StateChangeMessage>) -> StateChangeMessage {
+        loop {
+            match receiver.recv_timeout(TIMEOUT).unwrap() {
+                StateChangeMessage::Tick | StateChangeMessage::Processes(_) => (),
+                message => return message,
+            }
+        }
+    }
+
+    #[test]
+    fn it_shows_the_mode_and_menu_on_start() {
+        let (_app_state, ui, _receiver) = app_state(config(""));
+
+        assert_eq!(ui.icons(), [AppState::<FakeUi>::icon(Mode::Laptop)]);
+        let toggle = ui.item("Desktop Mode").unwrap();
+        assert_eq!(toggle.message, Some(StateChangeMessage::ToggleMode));
+        assert_eq!(ui.item("Caffeinate").unwrap().checked, Some(false));
+        assert!(ui.item("Automatic").is_none());
+        let menu = ui.menu();
+        assert_eq!(menu[menu.len() - 2], MenuEntry::Separator);
+        assert_eq!(
+            ui.item("Quit").unwrap().message,
+            Some(StateChangeMessage::Quit)
+        );
+    }
+
+    #[test]
+    fn it_switches_mode() {
+        let (mut sut, ui, _receiver) = app_state(config(""));
+        let events = sut.events().subscribe();
+
+        sut.handle(StateChangeMessage::ToggleMode);
+        assert_eq!(sut.mode(), Mode::Desktop);
+        assert_eq!(sut.status().get().mode, Mode::Desktop);
+        assert_eq!(
+            ui.icons().last(),
+            Some(&AppState::<FakeUi>::icon(Mode::Desktop))
+        );
+        assert!(ui.item("Laptop Mode").is_some());
+        assert!(events.try_iter().any(|event| event
+            == Event::ModeChanged {
+                mode: Mode::Desktop,
+                previous: Mode::Laptop
+            }));
+
+        sut.handle(StateChangeMessage::SetMode(Mode::Desktop));
+        assert_eq!(ui.icons().len(), 2);
+    }
+
+    #[test]
+    fn it_switches_once_before_switch_hooks_succeed() {
+        let (mut sut, _ui, receiver) = app_state(config("hooks = { before_switch = [\"true\"] }"));
+
+        sut.handle(StateChangeMessage::ToggleMode);
+        assert_eq!(sut.mode(), Mode::Laptop);
+
+        let message = next_message(&receiver);
+        assert_eq!(message, StateChangeMessage::SwitchApproved(Mode::Desktop));
+        sut.handle(message);
+        assert_eq!(sut.mode(), Mode::Desktop);
+    }
+
+    #[test]
+    fn it_does_not_switch_when_a_before_switch_hook_fails() {
+        let (mut sut, ui, _receiver) =
+            app_state(config("hooks = { before_switch = [\"exit 1\"] }"));
+        let events = sut.events().subscribe();
+
+        sut.handle(StateChangeMessage::ToggleMode);
+        let event = events.recv_timeout(TIMEOUT).unwrap();
+        assert!(
+            matches!(
+                event,
+                Event::ActionFailed {
+                    action: "before_switch",
+                    ..
+                }
+            ),
+            "{event:?}"
+        );
+        assert_eq!(sut.mode(), Mode::Laptop);
+        assert_eq!(ui.icons().len(), 1);
+    }
+
+    #[test]
+    fn it_starts_plugins_in_the_background() {
+        let (mut sut, ui, receiver) = app_state(config(
+            r#"
+            [[plugins]]
+            name = "slow"
+            command = ["sh", "-c", "read line; sleep 1; echo '{\"jsonrpc\": \"2.0\", \"id\": 1, \"result\": {\"protocol_version\": 1}}'; cat"]
+            "#,
+        ));
+        assert!(ui.item("Plugins").is_none());
 
-    /// Toggle caffeination
-    ToggleCaffeination,
+        let message = next_message(&receiver);
+        let StateChangeMessage::PluginsStarted {
+            generation,
+            plugins,
+        } = message.clone()
+        else {
+            panic!("Expected the plugins to have started, got {message:?}");
+        };
+        assert_eq!(plugins.len(), 1);
+        // Those started for an outdated config.toml are dropped
+        sut.handle(StateChangeMessage::PluginsStarted {
+            generation: generation - 1,
+            plugins: vec![],
+        });
+        assert!(ui.item("Plugins").is_none());
+        sut.handle(message);
+        assert!(ui.item("Plugins").is_some());
+    }
+
+    #[test]
+    fn it_replies_once_switched() {
+        let (mut sut, _ui, receiver) = app_state(config("hooks = { before_switch = [\"true\"] }"));
+        let (reply, replies) = Reply::new();
+
+        sut.handle(StateChangeMessage::Request(
+            Box::new(StateChangeMessage::SetMode(Mode::Desktop)),
+            reply.clone(),
+        ));
+        assert!(replies.try_recv().is_err());
+
+        let message = next_message(&receiver);
+        assert_eq!(
+            message,
+            StateChangeMessage::Request(
+                Box::new(StateChangeMessage::SwitchApproved(Mode::Desktop)),
+                reply
+            )
+        );
+        sut.handle(message);
+        assert_eq!(sut.mode(), Mode::Desktop);
+        assert_eq!(replies.recv_timeout(TIMEOUT), Ok(Ok(())));
+    }
+
+    #[test]
+    fn it_replies_when_a_before_switch_hook_fails() {
+        let (mut sut, _ui, _receiver) =
+            app_state(config("hooks = { before_switch = [\"exit 1\"] }"));
+        let (reply, replies) = Reply::new();
+
+        sut.handle(StateChangeMessage::Request(
+            Box::new(StateChangeMessage::ToggleMode),
+            reply,
+        ));
+        let error = replies.recv_timeout(TIMEOUT).unwrap().unwrap_err();
+        assert!(
+            error.starts_with("Not switching to desktop mode"),
+            "{error}"
+        );
+        assert_eq!(sut.mode(), Mode::Laptop);
+    }
+
+    #[test]
+    fn it_replies_when_caffeinate_fails_to_start() {
+        let config = Config::parse(
+            r#"
+            desktop_applescript = ""
+            laptop_applescript = ""
+            caffeinate_app = "/nonexistent/caffeinate"
+            "#,
+        )
+        .unwrap();
+        let (mut sut, _ui, _receiver) = app_state(config);
+        let (reply, replies) = Reply::new();
+
+        sut.handle(StateChangeMessage::Request(
+            Box::new(StateChangeMessage::Caffeinate(CaffeinateFor::Indefinitely)),
+            reply,
+        ));
+        assert!(replies.try_recv().unwrap().is_err());
+        assert!(!sut.caffeinating());
+    }
+
+    #[test]
+    fn it_rejects_caffeinating_for_longer_than_time_can_count() {
+        let (mut sut, _ui, _receiver) = app_state(config(""));
+        let (reply, replies) = Reply::new();
+
+        sut.handle(StateChangeMessage::Request(
+            Box::new(StateChangeMessage::Caffeinate(CaffeinateFor::Duration(
+                Duration::MAX,
+            ))),
+            reply,
+        ));
+        assert!(replies.try_recv().unwrap().is_err());
+        assert!(!sut.caffeinating());
+    }
+
+    #[test]
+    fn it_toggles_caffeination() {
+        let (mut sut, ui, _receiver) = app_state(config(""));
 
-    /// Clear the caffeination checkmark
-    ClearCaffeination,
+        sut.handle(StateChangeMessage::ToggleCaffeination);
+        assert!(sut.caffeinating());
+        assert!(sut.status().get().caffeinating);
+        let caffeinate = ui.item("Caffeinate").unwrap();
+        assert_eq!(caffeinate.checked, Some(true));
 
-    /// Quit the app
-    Quit,
+        sut.handle(StateChangeMessage::ToggleCaffeination);
+        assert!(!sut.caffeinating());
+        assert_eq!(ui.item("Caffeinate").unwrap().checked, Some(false));
+    }
+
+    #[test]
+    fn it_only_limits_toggled_caffeination_to_the_timeout() {
+        let config = Config::parse(
+            r#"
+            desktop_applescript = ""
+            laptop_applescript = ""
+            caffeinate_app = "sleep"
+            desktop_keep_awake = "on"
+
+            [caffeinate_options]
+            args = ["60"]
+            timeout = "1h"
+            "#,
+        )
+        .unwrap();
+        let (mut sut, _ui, _receiver) = app_state(config);
+
+        sut.handle(StateChangeMessage::ToggleCaffeination);
+        assert!(sut.caffeinate_remaining().is_some());
+
+        sut.handle(StateChangeMessage::Caffeinate(CaffeinateFor::Indefinitely));
+        assert!(sut.caffeinating());
+        assert_eq!(sut.caffeinate_remaining(), None);
+
+        sut.handle(StateChangeMessage::StopCaffeination);
+        sut.handle(StateChangeMessage::ToggleMode);
+        assert!(sut.caffeinating());
+        assert_eq!(sut.caffeinate_remaining(), None);
+    }
+
+    #[test]
+    fn it_applies_the_keep_awake_policy_on_switching() {
+        let (mut sut, ui, _receiver) = app_state(config("desktop_keep_awake = \"on\""));
+        assert!(!sut.caffeinating());
+
+        sut.handle(StateChangeMessage::ToggleMode);
+        assert!(sut.caffeinating());
+        assert_eq!(
+            ui.item("Caffeinate").unwrap().title,
+            "Caffeinate (Desktop Mode)"
+        );
+
+        sut.handle(StateChangeMessage::StopCaffeination);
+        assert!(!sut.caffeinating());
+        assert_eq!(ui.item("Caffeinate").unwrap().title, "Caffeinate (Manual)");
+    }
+
+    #[test]
+    fn it_clears_caffeination_once_caffeinate_exits() {
+        let (mut sut, ui, receiver) = app_state(config(""));
+
+        // `sleep 60 -t 900` exits straight away
+        sut.handle(StateChangeMessage::Caffeinate(CaffeinateFor::Duration(
+            Duration::from_secs(900),
+        )));
+        assert!(sut.caffeinating());
+        let message = next_message(&receiver);
+        assert_eq!(message, StateChangeMessage::ClearCaffeination);
+        sut.handle(message);
+        assert!(!sut.caffeinating());
+        assert_eq!(ui.item("Caffeinate").unwrap().checked, Some(false));
+    }
+
+    #[test]
+    fn it_saves_and_carries_on_timed_caffeination() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = StateFile::open(dir.path().join("state.json"));
+        let (sut, _ui, _receiver) = app_state(config(""));
+        let mut sut = sut.with_state_file(file.clone());
+        assert!(!sut.caffeinating());
+        sut.handle(StateChangeMessage::Caffeinate(CaffeinateFor::Duration(
+            Duration::from_secs(900),
+        )));
+        let expires_at = file.load().caffeinate_expires_at.unwrap();
+        drop(sut);
+
+        let (sut, _ui, _receiver) = app_state(config(""));
+        let sut = sut.with_state_file(file.clone());
+        assert!(sut.caffeinating());
+        let remaining = sut.caffeinate_remaining().unwrap();
+        assert!(remaining > Duration::from_secs(890) && remaining <= Duration::from_secs(900));
+        assert!(file.load().caffeinate_expires_at.unwrap() <= expires_at);
+    }
 }
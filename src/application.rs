@@ -7,7 +7,11 @@ use objc2::{
 use objc2_app_kit::{NSApplication, NSEvent, NSEventMask};
 use objc2_foundation::{MainThreadMarker, NSDate, NSString};
 
-use crate::app_state::StateChangeMessage;
+use crate::StateChangeMessage;
+
+/// How long to wait for an event before checking for messages sent from other threads, eg by
+/// automatic switching
+const MESSAGE_POLL_INTERVAL: f64 = 0.5;
 
 struct AutoReleasePoolContext(*mut c_void);
 unsafe impl Send for AutoReleasePoolContext {}
@@ -44,7 +48,7 @@ impl Application {
                 let event: Option<Retained<NSEvent>> = app
                     .nextEventMatchingMask_untilDate_inMode_dequeue(
                         NSEventMask::Any,
-                        Some(&NSDate::distantFuture()),
+                        Some(&NSDate::dateWithTimeIntervalSinceNow(MESSAGE_POLL_INTERVAL)),
                         &run_mode,
                         true,
                     );
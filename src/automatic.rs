@@ -0,0 +1,255 @@
+use super::{
+    Mode, Plugin, SharedStatus, StateChangeMessage,
+    clock::{Clock, SystemClock},
+    config::Automatic,
+    debounce::Debouncer,
+    detector::{self, Facts},
+    rules::RuleSet,
+};
+use std::{
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicBool, Ordering},
+        mpsc::Sender,
+    },
+    thread,
+    time::Duration,
+};
+
+/// Decides when to switch mode based on the facts detected on each poll
+///
+/// A switch is only asked for when the facts change and the outcome has settled, which means a
+/// manual switch by the user is left alone until something about the machine's surroundings
+/// changes.
+pub struct Engine<C: Clock> {
+    rules: RuleSet,
+    debouncer: Debouncer<Decision, C>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Decision {
+    mode: Mode,
+    facts: Facts,
+}
+
+impl<C: Clock> Engine<C> {
+    #[must_use]
+    pub const fn new(rules: RuleSet, settle: Duration, min_interval: Duration, clock: C) -> Self {
+        Self {
+            rules,
+            debouncer: Debouncer::new(settle, min_interval, clock),
+        }
+    }
+
+    /// Feed in the latest `facts`, returning the mode to switch to if it is time to do so
+    pub fn step(&mut self, facts: Facts) -> Option<Mode> {
+        let decision = self
+            .rules
+            .evaluate(&facts)
+            .map(|mode| Decision { mode, facts });
+        let decision = self.debouncer.observe(decision)?;
+        println!(
+            "Facts changed to {:?}, rules decided on {:?} mode",
+            decision.facts, decision.mode
+        );
+        Some(decision.mode)
+    }
+
+    /// Forget the facts seen so far, so the next step will re-evaluate the rules
+    pub fn reset(&mut self) {
+        self.debouncer.reset();
+    }
+}
+
+/// Handle to the background thread polling the configured detectors
+#[allow(clippy::module_name_repetitions)]
+pub struct AutoSwitch {
+    enabled: Option<Arc<AtomicBool>>,
+    facts: Arc<Mutex<Facts>>,
+}
+
+impl AutoSwitch {
+    /// Start polling in the background, unless there are no rules configured
+    ///
+    /// # Errors
+    ///
+    /// If the rules refer to a fact which there is no detector for, built-in or from `plugins`
+    pub fn spawn(
+        automatic: &Automatic,
+        plugins: &[Arc<Plugin>],
+        status: SharedStatus,
+        sender: Sender<StateChangeMessage>,
+    ) -> Result<Self, String> {
+        if automatic.rules().is_empty() {
+            return Ok(Self::disabled());
+        }
+
+        let detectors = detector::detectors_for(&automatic.rules().facts(), automatic, plugins)?;
+        let mut engine = Engine::new(
+            automatic.rules().clone().with_status(status),
+            automatic.settle(),
+            automatic.min_interval(),
+            SystemClock,
+        );
+        let interval = automatic.interval();
+        let enabled = Arc::new(AtomicBool::new(automatic.enabled()));
+        let thread_enabled = enabled.clone();
+        let facts = Arc::new(Mutex::new(Facts::new()));
+        let thread_facts = facts.clone();
+
+        thread::spawn(move || {
+            // Stop once the `AutoSwitch` has been dropped, eg when config.toml is reloaded
+            while Arc::strong_count(&thread_enabled) > 1 {
+                if thread_enabled.load(Ordering::Relaxed) {
+                    match detector::detect_all(&detectors) {
+                        Ok(facts) => {
+                            facts.clone_into(
+                                &mut thread_facts.lock().unwrap_or_else(PoisonError::into_inner),
+                            );
+                            if let Some(mode) = engine.step(facts) {
+                                if sender.send(StateChangeMessage::SetMode(mode)).is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                        Err(error) => eprintln!("Failed to detect facts: {error:?}"),
+                    }
+                } else {
+                    engine.reset();
+                }
+
+                thread::sleep(interval);
+            }
+        });
+
+        Ok(Self {
+            enabled: Some(enabled),
+            facts,
+        })
+    }
+
+    /// Handle for when automatic switching could not be started
+    #[must_use]
+    pub fn disabled() -> Self {
+        Self {
+            enabled: None,
+            facts: Arc::default(),
+        }
+    }
+
+    /// The facts detected on the latest poll, none until the first poll or without any rules
+    #[must_use]
+    pub fn facts(&self) -> Facts {
+        self.facts
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Whether there are any rules for automatic switching
+    #[must_use]
+    pub const fn is_available(&self) -> bool {
+        self.enabled.is_some()
+    }
+
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+            .as_ref()
+            .is_some_and(|enabled| enabled.load(Ordering::Relaxed))
+    }
+
+    pub fn toggle(&self) {
+        if let Some(enabled) = &self.enabled {
+            enabled.fetch_xor(true, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::FakeClock;
+
+    fn rules() -> RuleSet {
+        RuleSet::new(vec![
+            "when external_display && on_ac then desktop"
+                .parse()
+                .unwrap(),
+            "when !external_display then laptop".parse().unwrap(),
+        ])
+    }
+
+    fn engine() -> Engine<FakeClock> {
+        Engine::new(
+            rules(),
+            Duration::ZERO,
+            Duration::ZERO,
+            FakeClock::default(),
+        )
+    }
+
+    fn facts(external_display: bool, on_ac: bool) -> Facts {
+        Facts::from([
+            ("external_display".into(), external_display.into()),
+            ("on_ac".into(), on_ac.into()),
+        ])
+    }
+
+    #[test]
+    fn it_switches_on_first_step() {
+        let mut sut = engine();
+        assert_eq!(sut.step(facts(true, true)), Some(Mode::Desktop));
+    }
+
+    #[test]
+    fn it_does_not_repeat_itself_while_facts_are_unchanged() {
+        let mut sut = engine();
+        sut.step(facts(true, true));
+
+        // The user could have manually switched to laptop mode in the meantime
+        assert_eq!(sut.step(facts(true, true)), None);
+    }
+
+    #[test]
+    fn it_switches_when_facts_change() {
+        let mut sut = engine();
+        sut.step(facts(true, true));
+        assert_eq!(sut.step(facts(false, true)), Some(Mode::Laptop));
+    }
+
+    #[test]
+    fn it_has_no_outcome_when_no_rules_match() {
+        let mut sut = engine();
+        sut.step(facts(false, true));
+        assert_eq!(sut.step(facts(true, false)), None);
+    }
+
+    #[test]
+    fn it_waits_for_the_outcome_to_settle() {
+        let clock = FakeClock::default();
+        let mut sut = Engine::new(
+            rules(),
+            Duration::from_secs(15),
+            Duration::from_secs(60),
+            clock.clone(),
+        );
+
+        // Displays appearing one by one after docking
+        assert_eq!(sut.step(facts(true, false)), None);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(sut.step(facts(true, true)), None);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(sut.step(facts(true, true)), None);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(sut.step(facts(true, true)), Some(Mode::Desktop));
+    }
+
+    #[test]
+    fn it_re_evaluates_after_reset() {
+        let mut sut = engine();
+        sut.step(facts(true, true));
+        sut.reset();
+        assert_eq!(sut.step(facts(true, true)), Some(Mode::Desktop));
+    }
+}
@@ -0,0 +1,519 @@
+use super::{
+    control::{self, Client},
+    duration, runtime,
+};
+use serde_json::{Value, json};
+use std::{io, path::Path, time::Duration};
+
+/// The request failed, eg as the mode given was unknown
+pub const EXIT_FAILED: i32 = 1;
+
+/// The command line was not understood
+pub const EXIT_USAGE: i32 = 2;
+
+/// lod is not running, so there is nothing to control
+pub const EXIT_NOT_RUNNING: i32 = 3;
+
+const USAGE: &str = "Usage: lod [--json] status | switch <laptop|desktop> | toggle | \
+    caffeinate <on|off|for <duration>|until <time>> | reload | quit | watch";
+
+/// Run a subcommand which controls the running instance, eg `lod switch desktop`
+///
+/// Returns the exit code, or `None` when `args` is not such a subcommand and so is for lod itself.
+#[must_use]
+pub fn run(args: &[String]) -> Option<i32> {
+    let command = parse(args)?;
+    let socket = match runtime::dir() {
+        Ok(dir) => dir.join(control::SOCKET_NAME),
+        Err(error) => {
+            eprintln!("{error}");
+            return Some(EXIT_NOT_RUNNING);
+        }
+    };
+    Some(execute(command, &socket, &mut io::stdout()))
+}
+
+/// A parsed subcommand: the request to send, and whether to print the reply as JSON
+#[derive(Debug, PartialEq)]
+struct Command {
+    request: Result<Value, String>,
+    json: bool,
+}
+
+fn parse(args: &[String]) -> Option<Command> {
+    let json = args.iter().any(|arg| arg == "--json");
+    let args: Vec<&str> = args
+        .iter()
+        .map(String::as_str)
+        .filter(|arg| *arg != "--json")
+        .collect();
+
+    let request = match args.as_slice() {
+        ["status"] => Ok(json!({ "command": "get_status" })),
+        ["switch", mode] => Ok(json!({ "command": "set_mode", "mode": mode })),
+        ["toggle"] => Ok(json!({ "command": "toggle_mode" })),
+        ["caffeinate", "on"] => Ok(json!({ "command": "set_caffeinate", "enabled": true })),
+        ["caffeinate", "off"] => Ok(json!({ "command": "set_caffeinate", "enabled": false })),
+        ["caffeinate", "for", duration] => {
+            Ok(json!({ "command": "set_caffeinate", "for": duration }))
+        }
+        ["caffeinate", "until", time] => Ok(json!({ "command": "set_caffeinate", "until": time })),
+        ["reload"] => Ok(json!({ "command": "reload_config" })),
+        ["quit"] => Ok(json!({ "command": "quit" })),
+        ["watch"] => Ok(json!({ "command": "subscribe" })),
+        // `lod caffeinate -- <command>` keeps awake while the command runs, without the app
+        ["caffeinate", "--", ..] => return None,
+        [
+            "status" | "switch" | "toggle" | "caffeinate" | "reload" | "quit" | "watch",
+            ..,
+        ] => Err(USAGE.into()),
+        _ => return None,
+    };
+    Some(Command { request, json })
+}
+
+fn execute(command: Command, socket: &Path, out: &mut impl io::Write) -> i32 {
+    let request = match command.request {
+        Ok(request) => request,
+        Err(usage) => {
+            eprintln!("{usage}");
+            return EXIT_USAGE;
+        }
+    };
+
+    let mut client = match Client::connect(socket) {
+        Ok(client) => client,
+        Err(error) => {
+            eprintln!("lod is not running ({error})");
+            return EXIT_NOT_RUNNING;
+        }
+    };
+    let reply = match client.request(&request) {
+        Ok(reply) => reply,
+        Err(error) => {
+            eprintln!("Failed to talk to lod: {error}");
+            return EXIT_FAILED;
+        }
+    };
+
+    let ok = reply["ok"] == true;
+    let printed = if command.json {
+        writeln!(out, "{reply}")
+    } else if !ok {
+        eprintln!("{}", reply["error"].as_str().unwrap_or("Request failed"));
+        Ok(())
+    } else if let Some(status) = reply.get("status") {
+        write!(out, "{}", format_status(status))
+    } else {
+        Ok(())
+    };
+    if let Err(error) = printed {
+        eprintln!("Failed to print the reply: {error}");
+        return EXIT_FAILED;
+    }
+
+    if !ok {
+        return EXIT_FAILED;
+    }
+    if request["command"] == "subscribe" {
+        return watch(&mut client, command.json, out);
+    }
+    0
+}
+
+/// Print events as they arrive, until lod quits
+fn watch(client: &mut Client, json: bool, out: &mut impl io::Write) -> i32 {
+    loop {
+        let event = match client.read() {
+            Ok(Some(event)) => event,
+            Ok(None) => return 0,
+            Err(error) => {
+                eprintln!("Failed to talk to lod: {error}");
+                return EXIT_FAILED;
+            }
+        };
+        let printed = if json {
+            writeln!(out, "{event}")
+        } else {
+            writeln!(out, "{}", format_event(&event))
+        };
+        if let Err(error) = printed.and_then(|()| out.flush()) {
+            eprintln!("Failed to print the event: {error}");
+            return EXIT_FAILED;
+        }
+    }
+}
+
+/// Describe an event for people, eg `Mode changed to desktop`
+pub(crate) fn format_event(event: &Value) -> String {
+    match event["event"].as_str().unwrap_or_default() {
+        "mode_changed" => format!(
+            "Mode changed to {}",
+            event["mode"].as_str().unwrap_or("unknown")
+        ),
+        "caffeinate_started" => event["expires_in_secs"].as_u64().map_or_else(
+            || String::from("Caffeinate started"),
+            |secs| {
+                format!(
+                    "Caffeinate started for {}",
+                    duration::format_remaining(Duration::from_secs(secs))
+                )
+            },
+        ),
+        "caffeinate_stopped" => String::from("Caffeinate stopped"),
+        "action_failed" => format!(
+            "Failed to {}: {}",
+            event["action"].as_str().unwrap_or("act").replace('_', " "),
+            event["error"].as_str().unwrap_or_default()
+        ),
+        "config_reloaded" => String::from("Config reloaded"),
+        "plugin_event" => format!(
+            "Plugin {} sent {}",
+            event["plugin"].as_str().unwrap_or("unknown"),
+            event["name"].as_str().unwrap_or("an event")
+        ),
+        _ => event.to_string(),
+    }
+}
+
+/// Describe the status for people, eg
+///
+/// ```text
+/// Mode: desktop
+/// Automatic: on
+/// Caffeinate: on, 1h 05m left (mode)
+/// ```
+pub(crate) fn format_status(status: &Value) -> String {
+    let mut lines = vec![format!(
+        "Mode: {}",
+        status["mode"].as_str().unwrap_or("unknown")
+    )];
+
+    let automatic = &status["automatic"];
+    if automatic["available"] == true {
+        let enabled = if automatic["enabled"] == true {
+            "on"
+        } else {
+            "off"
+        };
+        lines.push(format!("Automatic: {enabled}"));
+    }
+
+    let caffeinate = &status["caffeinate"];
+    let state = match (
+        caffeinate["active"] == true,
+        caffeinate["expires_in_secs"].as_u64(),
+    ) {
+        (false, _) => String::from("off"),
+        (true, None) => String::from("on"),
+        (true, Some(secs)) => format!(
+            "on, {} left",
+            duration::format_remaining(Duration::from_secs(secs))
+        ),
+    };
+    let controlled_by = caffeinate["controlled_by"]
+        .as_str()
+        .map(|controlled_by| format!(" ({controlled_by})"))
+        .unwrap_or_default();
+    lines.push(format!("Caffeinate: {state}{controlled_by}"));
+
+    let holds = status["holds"]
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+    for hold in holds {
+        lines.push(format!(
+            "Keeping awake while: {} ({})",
+            hold["name"].as_str().unwrap_or_default(),
+            hold["pid"]
+        ));
+    }
+
+    let inhibits: Vec<_> = caffeinate["inhibits"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .collect();
+    if (caffeinate["active"] == true || !holds.is_empty()) && !inhibits.is_empty() {
+        lines.push(format!(
+            "Inhibiting: {} ({})",
+            inhibits.join(", "),
+            caffeinate["backend"].as_str().unwrap_or_default()
+        ));
+    }
+
+    for service in status["services"].as_array().into_iter().flatten() {
+        let restarts = service["restarts"].as_u64().unwrap_or_default();
+        let restarts = match restarts {
+            0 => String::new(),
+            1 => String::from(", restarted once"),
+            restarts => format!(", restarted {restarts} times"),
+        };
+        lines.push(format!(
+            "Service {}: {}{restarts}",
+            service["name"].as_str().unwrap_or_default(),
+            service["status"].as_str().unwrap_or_default()
+        ));
+    }
+
+    lines.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        Events, Mode, SharedStatus, StateChangeMessage, Status,
+        control::{ControlServer, Handler},
+        supervisor::ServiceStatus,
+    };
+    use std::sync::mpsc::{self, Receiver};
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    fn request(line: &str) -> Option<Result<Value, String>> {
+        parse(&args(line)).map(|command| command.request)
+    }
+
+    fn server() -> (
+        ControlServer,
+        Receiver<StateChangeMessage>,
+        tempfile::TempDir,
+    ) {
+        server_answering(Ok(()))
+    }
+
+    /// A running instance which answers each change with `result`
+    fn server_answering(
+        result: Result<(), String>,
+    ) -> (
+        ControlServer,
+        Receiver<StateChangeMessage>,
+        tempfile::TempDir,
+    ) {
+        let dir = tempfile::tempdir().unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let mut status = Status::new(Mode::Desktop);
+        status.caffeinating = true;
+        status.caffeinate_by_mode = Some(true);
+        status
+            .services
+            .push(("syncthing".into(), ServiceStatus::Restarting, 2));
+        let server = ControlServer::spawn(
+            dir.path().join(control::SOCKET_NAME),
+            Handler::new(sender, SharedStatus::new(status), Events::default()),
+        )
+        .unwrap();
+        (server, control::answer_requests(receiver, result), dir)
+    }
+
+    fn execute_line(line: &str, socket: &Path) -> (i32, String) {
+        let mut out = vec![];
+        let code = execute(parse(&args(line)).unwrap(), socket, &mut out);
+        (code, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn it_parses_subcommands() {
+        assert_eq!(
+            request("status"),
+            Some(Ok(json!({ "command": "get_status" })))
+        );
+        assert_eq!(
+            request("switch laptop"),
+            Some(Ok(json!({ "command": "set_mode", "mode": "laptop" })))
+        );
+        assert_eq!(
+            request("--json toggle"),
+            Some(Ok(json!({ "command": "toggle_mode" })))
+        );
+        assert_eq!(
+            request("caffeinate off"),
+            Some(Ok(json!({ "command": "set_caffeinate", "enabled": false })))
+        );
+        assert_eq!(
+            request("caffeinate for 2h"),
+            Some(Ok(json!({ "command": "set_caffeinate", "for": "2h" })))
+        );
+        assert_eq!(
+            request("reload"),
+            Some(Ok(json!({ "command": "reload_config" })))
+        );
+        assert_eq!(request("quit"), Some(Ok(json!({ "command": "quit" }))));
+        assert_eq!(
+            request("watch"),
+            Some(Ok(json!({ "command": "subscribe" })))
+        );
+        assert!(parse(&args("status --json")).unwrap().json);
+    }
+
+    #[test]
+    fn it_leaves_other_arguments_for_lod() {
+        assert_eq!(request(""), None);
+        assert_eq!(request("caffeinate -- rsync -a a b"), None);
+        assert_eq!(request("help"), None);
+    }
+
+    #[test]
+    fn it_rejects_malformed_subcommands() {
+        assert!(matches!(request("switch"), Some(Err(_))));
+        assert!(matches!(request("caffeinate"), Some(Err(_))));
+        assert!(matches!(request("caffeinate for"), Some(Err(_))));
+        assert!(matches!(request("quit now"), Some(Err(_))));
+
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(execute_line("switch", dir.path()).0, EXIT_USAGE);
+    }
+
+    #[test]
+    fn it_says_when_lod_is_not_running() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join(control::SOCKET_NAME);
+        assert_eq!(
+            execute_line("status", &socket),
+            (EXIT_NOT_RUNNING, String::new())
+        );
+    }
+
+    #[test]
+    fn it_controls_the_running_instance() {
+        let (server, receiver, _dir) = server();
+        assert_eq!(
+            execute_line("switch laptop", server.path()),
+            (0, String::new())
+        );
+        assert_eq!(
+            receiver.try_recv(),
+            Ok(StateChangeMessage::SetMode(Mode::Laptop))
+        );
+
+        let (code, out) = execute_line("--json quit", server.path());
+        assert_eq!((code, out.as_str()), (0, "{\"ok\":true}\n"));
+    }
+
+    #[test]
+    fn it_fails_when_the_change_does() {
+        let (server, _receiver, _dir) = server_answering(Err("A before_switch hook failed".into()));
+        let (code, out) = execute_line("--json switch laptop", server.path());
+        assert_eq!(code, EXIT_FAILED);
+        assert_eq!(
+            serde_json::from_str::<Value>(&out).unwrap()["error"],
+            "A before_switch hook failed"
+        );
+    }
+
+    #[test]
+    fn it_fails_when_the_request_does() {
+        let (server, receiver, _dir) = server();
+        assert_eq!(
+            execute_line("switch tablet", server.path()),
+            (EXIT_FAILED, String::new())
+        );
+        assert!(receiver.try_recv().is_err());
+
+        let (code, out) = execute_line("caffeinate for soon --json", server.path());
+        assert_eq!(code, EXIT_FAILED);
+        assert_eq!(serde_json::from_str::<Value>(&out).unwrap()["ok"], false);
+    }
+
+    #[test]
+    fn it_prints_the_status() {
+        let (server, _receiver, _dir) = server();
+        assert_eq!(
+            execute_line("status", server.path()),
+            (
+                0,
+                "Mode: desktop\nCaffeinate: on (mode)\nService syncthing: restarting, restarted 2 times\n"
+                    .into()
+            )
+        );
+
+        let (code, out) = execute_line("status --json", server.path());
+        assert_eq!(code, 0);
+        let reply: Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(reply["status"]["services"][0]["restarts"], 2);
+    }
+
+    #[test]
+    fn it_formats_holds_and_time_left() {
+        let mut status = Status::new(Mode::Laptop);
+        status.automatic_available = true;
+        status.caffeinating = true;
+        status.caffeinate_expires_at =
+            Some(std::time::SystemTime::now() + Duration::from_secs(3900));
+        status.holds.push((42, "rsync".into()));
+        assert_eq!(
+            format_status(&status.to_json()),
+            "Mode: laptop\nAutomatic: off\nCaffeinate: on, 1h 05m left\nKeeping awake while: rsync (42)\n"
+        );
+    }
+
+    #[test]
+    fn it_formats_what_is_inhibited_while_caffeinating() {
+        let mut status = Status::new(Mode::Laptop);
+        status.keep_awake = "systemd-inhibit";
+        status.inhibits = vec!["idle".into(), "sleep".into()];
+        assert_eq!(
+            format_status(&status.to_json()),
+            "Mode: laptop\nCaffeinate: off\n"
+        );
+        status.caffeinating = true;
+        assert_eq!(
+            format_status(&status.to_json()),
+            "Mode: laptop\nCaffeinate: on\nInhibiting: idle, sleep (systemd-inhibit)\n"
+        );
+    }
+
+    #[test]
+    fn it_formats_events() {
+        let events = [
+            json!({ "event": "mode_changed", "mode": "laptop" }),
+            json!({ "event": "caffeinate_started", "expires_in_secs": 1800 }),
+            json!({ "event": "caffeinate_started", "expires_in_secs": null }),
+            json!({ "event": "caffeinate_stopped" }),
+            json!({ "event": "action_failed", "action": "run_applescript", "error": "not found" }),
+            json!({ "event": "config_reloaded" }),
+            json!({ "event": "plugin_event", "plugin": "vpn", "name": "connected" }),
+        ];
+        assert_eq!(
+            events.iter().map(format_event).collect::<Vec<_>>(),
+            [
+                "Mode changed to laptop",
+                "Caffeinate started for 30m",
+                "Caffeinate started",
+                "Caffeinate stopped",
+                "Failed to run applescript: not found",
+                "Config reloaded",
+                "Plugin vpn sent connected",
+            ]
+        );
+    }
+
+    #[test]
+    fn it_prints_events_until_lod_quits() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join(control::SOCKET_NAME);
+        let listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        // Stands in for lod, which accepts the subscription, sends an event and quits
+        let lod = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut line = String::new();
+            io::BufRead::read_line(&mut io::BufReader::new(&stream), &mut line).unwrap();
+            assert_eq!(line, "{\"command\":\"subscribe\"}\n");
+            io::Write::write_all(
+                &mut stream,
+                b"{\"ok\":true}\n{\"event\":\"mode_changed\",\"mode\":\"desktop\"}\n",
+            )
+            .unwrap();
+        });
+
+        assert_eq!(
+            execute_line("watch", &socket),
+            (0, "Mode changed to desktop\n".into())
+        );
+        lod.join().unwrap();
+    }
+}
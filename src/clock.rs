@@ -0,0 +1,52 @@
+use std::time::Instant;
+
+/// Source of the current time, so that time dependent logic can be tested
+pub trait Clock: Send {
+    fn now(&self) -> Instant;
+}
+
+#[allow(clippy::module_name_repetitions)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+#[cfg(test)]
+pub use fake::FakeClock;
+
+#[cfg(test)]
+mod fake {
+    use super::Clock;
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    /// Clock which only moves when told to, clones share the same time
+    #[derive(Clone)]
+    pub struct FakeClock(Arc<Mutex<Instant>>);
+
+    impl Default for FakeClock {
+        fn default() -> Self {
+            Self(Arc::new(Mutex::new(Instant::now())))
+        }
+    }
+
+    impl FakeClock {
+        /// # Panics
+        ///
+        /// If another thread panicked while holding the lock
+        pub fn advance(&self, duration: Duration) {
+            *self.0.lock().unwrap() += duration;
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+}
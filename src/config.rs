@@ -1,9 +1,20 @@
+use super::{
+    Hook, HookAction, Hooks, KeepAwake, KeepAwakePolicy, Mode, PluginConfig, RestartPolicy, Script,
+    ServiceConfig, StopSignal, WebhookConfig,
+    duration::{self, TimeOfDay},
+    hooks, keep_awake, plugin,
+    rules::{Rule, RuleSet},
+    webhooks,
+};
 use std::{
+    collections::BTreeMap,
+    env,
     error::Error,
     fs,
     fs::File,
     io::Write,
     path::{Path, PathBuf},
+    time::Duration,
 };
 use tempfile::TempDir;
 use toml::Table;
@@ -12,8 +23,380 @@ pub struct Config {
     temp_dir: Option<TempDir>,
     desktop_applescript_path: PathBuf,
     laptop_applescript_path: PathBuf,
-    caffeinate_app: Option<String>,
-    caffeinate_options: Option<String>,
+    keep_awake_backend: KeepAwake,
+    caffeinate_options: CaffeinateOptions,
+    caffeinate_until: TimeOfDay,
+    caffeinate_stop_after_idle: Option<Duration>,
+    desktop_keep_awake: KeepAwakePolicy,
+    laptop_keep_awake: KeepAwakePolicy,
+    desktop_services: Vec<ServiceConfig>,
+    laptop_services: Vec<ServiceConfig>,
+    automatic: Automatic,
+    http: Option<Http>,
+    webhooks: Vec<WebhookConfig>,
+    hooks: Hooks,
+    plugins: Vec<PluginConfig>,
+}
+
+/// Settings for the HTTP API, from the `[http]` table, which is off unless given
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Http {
+    port: u16,
+    token: String,
+}
+
+/// Settings for automatically switching modes, from the `[automatic]` table
+#[derive(Clone, Debug)]
+pub struct Automatic {
+    enabled: bool,
+    interval: Duration,
+    settle: Duration,
+    min_interval: Duration,
+    wifi_interface: String,
+    rules: RuleSet,
+}
+
+impl Default for Automatic {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval: Duration::from_secs(10),
+            settle: Duration::from_secs(15),
+            min_interval: Duration::from_secs(60),
+            wifi_interface: String::from("en0"),
+            rules: RuleSet::default(),
+        }
+    }
+}
+
+impl Automatic {
+    fn from_toml(toml: &Table) -> Result<Self, Box<dyn Error>> {
+        let Some(automatic) = toml.get("automatic") else {
+            return Ok(Self::default());
+        };
+        let automatic = automatic
+            .as_table()
+            .ok_or("`automatic` in config.toml should be a table")?;
+
+        let mut result = Self::default();
+        if let Some(enabled) = automatic.get("enabled") {
+            result.enabled = enabled
+                .as_bool()
+                .ok_or("`automatic.enabled` in config.toml should be true or false")?;
+        }
+        for (key, value) in [
+            ("interval", &mut result.interval),
+            ("settle", &mut result.settle),
+            ("min_interval", &mut result.min_interval),
+        ] {
+            if let Some(duration) = automatic.get(key) {
+                let duration = duration.as_str().ok_or_else(|| {
+                    format!("`automatic.{key}` in config.toml should be a string, eg \"10s\"")
+                })?;
+                *value = duration::parse(duration)?;
+            }
+        }
+        if let Some(wifi_interface) = automatic.get("wifi_interface") {
+            result.wifi_interface = wifi_interface
+                .as_str()
+                .ok_or("`automatic.wifi_interface` in config.toml should be a string, eg \"en0\"")?
+                .into();
+        }
+
+        let mut rules: Vec<Rule> = vec![];
+        if let Some(rule_strings) = automatic.get("rules") {
+            for rule in rule_strings
+                .as_array()
+                .ok_or("`automatic.rules` in config.toml should be an array of strings")?
+            {
+                rules.push(
+                    rule.as_str()
+                        .ok_or("Each rule should be a string")?
+                        .parse()?,
+                );
+            }
+        }
+        if let Some(ssids) = automatic.get("ssids") {
+            let ssids = ssids.as_table().ok_or(
+                "`automatic.ssids` in config.toml should be a table of Wi-Fi network names to modes",
+            )?;
+            let mut otherwise = None;
+            for (ssid, mode) in ssids {
+                let mode = mode
+                    .as_str()
+                    .ok_or_else(|| format!("Mode for Wi-Fi network `{ssid}` should be a string"))?
+                    .parse()?;
+                if ssid == "*" {
+                    otherwise = Some(Rule::always(mode));
+                } else {
+                    rules.push(Rule::fact_equals("wifi_ssid", ssid, mode));
+                }
+            }
+            rules.extend(otherwise);
+        }
+        result.rules = RuleSet::new(rules);
+
+        Ok(result)
+    }
+
+    /// Whether automatic switching is on when lod starts
+    #[must_use]
+    pub const fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// How often to poll the detectors
+    #[must_use]
+    pub const fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// How long the outcome of the rules must be stable for before switching
+    #[must_use]
+    pub const fn settle(&self) -> Duration {
+        self.settle
+    }
+
+    /// Minimum time between automatic switches
+    #[must_use]
+    pub const fn min_interval(&self) -> Duration {
+        self.min_interval
+    }
+
+    /// Name of the Wi-Fi interface, used to detect `wifi_ssid` and tell it apart from wired ones
+    #[must_use]
+    pub fn wifi_interface(&self) -> &str {
+        &self.wifi_interface
+    }
+
+    /// Rules from `automatic.rules`, followed by those from `automatic.ssids`
+    #[must_use]
+    pub const fn rules(&self) -> &RuleSet {
+        &self.rules
+    }
+}
+
+/// Options passed to `caffeinate`, from `caffeinate_options`
+///
+/// This is either a table of typed options, or a string of flags which is split like a shell
+/// would for compatibility with older configurations.
+// Each bool maps to an independent `caffeinate` flag
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CaffeinateOptions {
+    prevent_display_sleep: bool,
+    prevent_idle_sleep: bool,
+    prevent_disk_sleep: bool,
+    prevent_system_sleep: bool,
+    declare_user_active: bool,
+    timeout: Option<Duration>,
+    args: Vec<String>,
+}
+
+impl CaffeinateOptions {
+    fn from_toml(toml: &Table) -> Result<Self, Box<dyn Error>> {
+        let Some(options) = toml.get("caffeinate_options") else {
+            return Ok(Self::default());
+        };
+        if let Some(options) = options.as_str() {
+            return Ok(Self {
+                args: split_shell(options)
+                    .map_err(|error| format!("`caffeinate_options` in config.toml: {error}"))?,
+                ..Self::default()
+            });
+        }
+        let options = options
+            .as_table()
+            .ok_or("`caffeinate_options` in config.toml should be a table or a string")?;
+
+        let mut result = Self::default();
+        for (key, value) in options {
+            let flag = match key.as_str() {
+                "prevent_display_sleep" => &mut result.prevent_display_sleep,
+                "prevent_idle_sleep" => &mut result.prevent_idle_sleep,
+                "prevent_disk_sleep" => &mut result.prevent_disk_sleep,
+                "prevent_system_sleep" => &mut result.prevent_system_sleep,
+                "declare_user_active" => &mut result.declare_user_active,
+                "timeout" => {
+                    let timeout = value.as_str().ok_or(
+                        "`caffeinate_options.timeout` in config.toml should be a string, eg \"1h\"",
+                    )?;
+                    result.timeout = Some(duration::parse(timeout)?);
+                    continue;
+                }
+                "args" => {
+                    for arg in value.as_array().ok_or(
+                        "`caffeinate_options.args` in config.toml should be an array of strings",
+                    )? {
+                        result.args.push(
+                            arg.as_str()
+                                .ok_or("Each of `caffeinate_options.args` should be a string")?
+                                .into(),
+                        );
+                    }
+                    continue;
+                }
+                _ => {
+                    return Err(format!("Unknown `caffeinate_options.{key}` in config.toml").into());
+                }
+            };
+            *flag = value.as_bool().ok_or_else(|| {
+                format!("`caffeinate_options.{key}` in config.toml should be true or false")
+            })?;
+        }
+
+        Ok(result)
+    }
+
+    /// How long to caffeinate for when no time is given, eg when toggled from the menu
+    #[must_use]
+    pub const fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// The first option given which only `caffeinate` understands, which is any but `timeout`
+    fn caffeinate_only(&self) -> Option<&'static str> {
+        [
+            (self.prevent_display_sleep, "prevent_display_sleep"),
+            (self.prevent_idle_sleep, "prevent_idle_sleep"),
+            (self.prevent_disk_sleep, "prevent_disk_sleep"),
+            (self.prevent_system_sleep, "prevent_system_sleep"),
+            (self.declare_user_active, "declare_user_active"),
+            (!self.args.is_empty(), "args"),
+        ]
+        .into_iter()
+        .find_map(|(given, key)| given.then_some(key))
+    }
+
+    /// Arguments for `caffeinate`, other than the timeout
+    #[must_use]
+    pub fn args(&self) -> Vec<String> {
+        let mut args: Vec<String> = [
+            (self.prevent_display_sleep, "-d"),
+            (self.prevent_idle_sleep, "-i"),
+            (self.prevent_disk_sleep, "-m"),
+            (self.prevent_system_sleep, "-s"),
+            (self.declare_user_active, "-u"),
+        ]
+        .into_iter()
+        .filter(|(enabled, _)| *enabled)
+        .map(|(_, flag)| flag.into())
+        .collect();
+        args.extend(self.args.iter().cloned());
+        args
+    }
+}
+
+/// Split `s` into arguments as a shell would, honouring quotes and backslash escapes
+fn split_shell(s: &str) -> Result<Vec<String>, String> {
+    let mut args = vec![];
+    let mut arg: Option<String> = None;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => arg.push(c),
+                        None => return Err(format!("Missing closing `'` in `{s}`")),
+                    }
+                }
+            }
+            '"' => {
+                let arg = arg.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\')) => arg.push(c),
+                            Some(c) => {
+                                arg.push('\\');
+                                arg.push(c);
+                            }
+                            None => return Err(format!("Missing closing `\"` in `{s}`")),
+                        },
+                        Some(c) => arg.push(c),
+                        None => return Err(format!("Missing closing `\"` in `{s}`")),
+                    }
+                }
+            }
+            '\\' => {
+                let c = chars
+                    .next()
+                    .ok_or_else(|| format!("Nothing to escape at the end of `{s}`"))?;
+                arg.get_or_insert_with(String::new).push(c);
+            }
+            c if c.is_whitespace() => args.extend(arg.take()),
+            c => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    args.extend(arg);
+
+    Ok(args)
+}
+
+impl Http {
+    #[cfg(test)]
+    pub(crate) fn new(port: u16, token: &str) -> Self {
+        Self {
+            port,
+            token: token.into(),
+        }
+    }
+
+    fn from_toml(toml: &Table) -> Result<Option<Self>, Box<dyn Error>> {
+        let Some(http) = toml.get("http") else {
+            return Ok(None);
+        };
+        let http = http
+            .as_table()
+            .ok_or("`http` in config.toml should be a table")?;
+
+        let mut port = None;
+        let mut token = None;
+        for (key, value) in http {
+            match key.as_str() {
+                "port" => {
+                    port = Some(
+                        value
+                            .as_integer()
+                            .and_then(|port| u16::try_from(port).ok())
+                            .filter(|port| *port != 0)
+                            .ok_or("`http.port` in config.toml should be a port number, eg 8765")?,
+                    );
+                }
+                "token" => {
+                    token = Some(
+                        value
+                            .as_str()
+                            .filter(|token| !token.is_empty())
+                            .ok_or("`http.token` in config.toml should be a non-empty string")?
+                            .to_string(),
+                    );
+                }
+                _ => return Err(format!("Unknown `http.{key}` in config.toml").into()),
+            }
+        }
+
+        Ok(Some(Self {
+            port: port.ok_or("`http.port` in config.toml is required")?,
+            token: token.ok_or("`http.token` in config.toml is required")?,
+        }))
+    }
+
+    /// Port to listen on, on 127.0.0.1
+    #[must_use]
+    pub const fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Bearer token which requests must give in their `Authorization` header
+    #[must_use]
+    pub fn token(&self) -> &str {
+        &self.token
+    }
 }
 
 impl Config {
@@ -45,7 +428,8 @@ impl Config {
         Ok(config_file_path)
     }
 
-    /// Attempt to load `Config` from storage
+    /// Attempt to load `Config` from storage, `$LOD_CONFIG` if set or otherwise
+    /// `~/.config/lod/config.toml`
     ///
     /// # Errors
     ///
@@ -53,8 +437,20 @@ impl Config {
     /// - Unable to parse TOML
     /// - Creation of temp directory failed
     pub fn load() -> Result<Self, Box<dyn Error>> {
-        let config_file_path = Self::check_exists_or_default()?;
-        let toml = fs::read_to_string(config_file_path)?;
+        let config_file_path = match env::var_os("LOD_CONFIG") {
+            Some(path) => PathBuf::from(path),
+            None => Self::check_exists_or_default()?,
+        };
+        Self::parse(&fs::read_to_string(config_file_path)?)
+    }
+
+    /// Parse `Config` from the contents of a config.toml
+    ///
+    /// # Errors
+    ///
+    /// - Unable to parse TOML
+    /// - Creation of temp directory failed
+    pub fn parse(toml: &str) -> Result<Self, Box<dyn Error>> {
         let toml = toml.parse::<Table>()?;
 
         let temp_dir = tempfile::tempdir()?;
@@ -67,37 +463,489 @@ impl Config {
             .get("caffeinate_app")
             .and_then(|x| x.as_str())
             .map(String::from);
-        let caffeinate_options = toml
-            .get("caffeinate_options")
-            .and_then(|x| x.as_str())
-            .map(String::from);
+        let caffeinate_options = CaffeinateOptions::from_toml(&toml)?;
+        let keep_awake_backend =
+            Self::keep_awake_backend_from_toml(&toml, caffeinate_app, &caffeinate_options)?;
+        let caffeinate_until = toml
+            .get("caffeinate_until")
+            .map(|x| {
+                x.as_str()
+                    .ok_or("`caffeinate_until` in config.toml should be a string, eg \"18:00\"")
+                    .map(str::parse)
+            })
+            .transpose()?
+            .transpose()?
+            .unwrap_or(TimeOfDay::EVENING);
+        let caffeinate_stop_after_idle = toml
+            .get("caffeinate_stop_after_idle")
+            .map(|x| {
+                x.as_str()
+                    .ok_or("`caffeinate_stop_after_idle` in config.toml should be a string, eg \"30m\"")
+                    .map(duration::parse)
+            })
+            .transpose()?
+            .transpose()?;
+        let desktop_keep_awake = Self::keep_awake_policy(&toml, Mode::Desktop)?;
+        let laptop_keep_awake = Self::keep_awake_policy(&toml, Mode::Laptop)?;
+        let desktop_services = Self::services_from_toml(&toml, Mode::Desktop)?;
+        let laptop_services = Self::services_from_toml(&toml, Mode::Laptop)?;
+        let automatic = Automatic::from_toml(&toml)?;
+        let http = Http::from_toml(&toml)?;
+        let webhooks = Self::webhooks_from_toml(&toml)?;
+        let hooks = Self::hooks_from_toml(&toml)?;
+        let plugins = Self::plugins_from_toml(&toml)?;
 
         Ok(Self {
             temp_dir: Some(temp_dir),
             desktop_applescript_path,
             laptop_applescript_path,
-            caffeinate_app,
+            keep_awake_backend,
             caffeinate_options,
+            caffeinate_until,
+            caffeinate_stop_after_idle,
+            desktop_keep_awake,
+            laptop_keep_awake,
+            desktop_services,
+            laptop_services,
+            automatic,
+            http,
+            webhooks,
+            hooks,
+            plugins,
         })
     }
 
+    /// Each action of a hook is either a command line run by `sh -c`, an array of a program and
+    /// its arguments, or a table with a `script`, alongside a `timeout` for each command
+    fn hooks_from_toml(toml: &Table) -> Result<Hooks, Box<dyn Error>> {
+        let Some(hooks) = toml.get("hooks") else {
+            return Ok(Hooks::default());
+        };
+        let hooks = hooks
+            .as_table()
+            .ok_or("`hooks` in config.toml should be a table")?;
+
+        let mut actions = vec![];
+        let mut timeout = hooks::DEFAULT_TIMEOUT;
+        for (key, hook_actions) in hooks {
+            if key == "timeout" {
+                timeout = duration::parse(
+                    hook_actions
+                        .as_str()
+                        .ok_or("`hooks.timeout` in config.toml should be a string, eg \"30s\"")?,
+                )?;
+                if timeout.is_zero() {
+                    return Err("`hooks.timeout` in config.toml should be more than zero".into());
+                }
+                continue;
+            }
+            let hook: Hook = key
+                .parse()
+                .map_err(|error| format!("`hooks.{key}` in config.toml: {error}"))?;
+            let hook_actions = hook_actions.as_array().ok_or_else(|| {
+                format!("`hooks.{key}` in config.toml should be an array of commands")
+            })?;
+            for action in hook_actions {
+                let action = if let Some(command_line) = action.as_str() {
+                    HookAction::Command(vec!["sh".into(), "-c".into(), command_line.into()])
+                } else if let Some(table) = action.as_table() {
+                    let mut script = None;
+                    for (field, value) in table {
+                        match field.as_str() {
+                            "script" => {
+                                let source = value.as_str().ok_or_else(|| {
+                                    format!(
+                                        "`hooks.{key}.script` in config.toml should be a string"
+                                    )
+                                })?;
+                                script = Some(Script::compile(source).map_err(|error| {
+                                    format!("`hooks.{key}.script` in config.toml: {error}")
+                                })?);
+                            }
+                            _ => {
+                                return Err(format!(
+                                    "Unknown key `hooks.{key}.{field}` in config.toml"
+                                )
+                                .into());
+                            }
+                        }
+                    }
+                    HookAction::Script(script.ok_or_else(|| {
+                        format!("`hooks.{key}` tables in config.toml need a `script`")
+                    })?)
+                } else {
+                    action
+                        .as_array()
+                        .filter(|argv| !argv.is_empty())
+                        .and_then(|argv| {
+                            argv.iter()
+                                .map(|arg| arg.as_str().map(String::from))
+                                .collect::<Option<Vec<_>>>()
+                        })
+                        .ok_or_else(|| {
+                            format!(
+                                "Each of `hooks.{key}` in config.toml should be a string, an \
+                                array of strings or a table"
+                            )
+                        })
+                        .map(HookAction::Command)?
+                };
+                actions.push((hook, action));
+            }
+        }
+
+        Ok(Hooks::new(actions).with_timeout(timeout))
+    }
+
+    /// From the `[keep_awake]` table, otherwise `caffeinate` on macOS or when `caffeinate_app` is
+    /// given, and `systemd-inhibit` elsewhere
+    fn keep_awake_backend_from_toml(
+        toml: &Table,
+        caffeinate_app: Option<String>,
+        caffeinate_options: &CaffeinateOptions,
+    ) -> Result<KeepAwake, Box<dyn Error>> {
+        let strings = |key: &str, value: &toml::Value| -> Result<Vec<String>, String> {
+            value
+                .as_array()
+                .ok_or_else(|| {
+                    format!("`keep_awake.{key}` in config.toml should be an array of strings")
+                })?
+                .iter()
+                .map(|value| {
+                    value
+                        .as_str()
+                        .map(String::from)
+                        .ok_or_else(|| format!("Each of `keep_awake.{key}` should be a string"))
+                })
+                .collect()
+        };
+
+        let mut backend = None;
+        let mut program = None;
+        let mut what = None;
+        let mut command = None;
+        let mut inhibits = None;
+        if let Some(keep_awake) = toml.get("keep_awake") {
+            for (key, value) in keep_awake
+                .as_table()
+                .ok_or("`keep_awake` in config.toml should be a table")?
+            {
+                let as_str = || {
+                    value.as_str().ok_or_else(|| {
+                        format!("`keep_awake.{key}` in config.toml should be a string")
+                    })
+                };
+                match key.as_str() {
+                    "backend" => backend = Some(as_str()?),
+                    "program" => program = Some(as_str()?.to_string()),
+                    "what" => what = Some(strings(key, value)?),
+                    "command" => command = Some(strings(key, value)?),
+                    "inhibits" => inhibits = Some(strings(key, value)?),
+                    _ => return Err(format!("Unknown `keep_awake.{key}` in config.toml").into()),
+                }
+            }
+        }
+
+        let default = if cfg!(target_os = "macos") || caffeinate_app.is_some() {
+            "caffeinate"
+        } else {
+            "systemd-inhibit"
+        };
+        let backend = backend.unwrap_or(default);
+        if let Some(key) = caffeinate_options
+            .caffeinate_only()
+            .filter(|_| matches!(backend, "systemd-inhibit" | "command"))
+        {
+            return Err(format!(
+                "`caffeinate_options.{key}` in config.toml does not apply to the {backend} backend"
+            )
+            .into());
+        }
+        let unexpected = |key: &str, given: bool| {
+            if given {
+                Err(format!(
+                    "`keep_awake.{key}` in config.toml does not apply to the {backend} backend"
+                ))
+            } else {
+                Ok(())
+            }
+        };
+        match backend {
+            "caffeinate" => {
+                unexpected("what", what.is_some())?;
+                unexpected("command", command.is_some())?;
+                unexpected("inhibits", inhibits.is_some())?;
+                let program = program
+                    .or(caffeinate_app)
+                    .unwrap_or_else(|| String::from("caffeinate"));
+                Ok(KeepAwake::caffeinate(program, caffeinate_options.args()))
+            }
+            "systemd-inhibit" => {
+                unexpected("command", command.is_some())?;
+                unexpected("inhibits", inhibits.is_some())?;
+                Ok(KeepAwake::SystemdInhibit {
+                    program: program.unwrap_or_else(|| String::from("systemd-inhibit")),
+                    what: Self::systemd_what(what)?,
+                })
+            }
+            "command" => {
+                unexpected("program", program.is_some())?;
+                unexpected("what", what.is_some())?;
+                let argv = command.filter(|argv| !argv.is_empty()).ok_or(
+                    "`keep_awake.command` in config.toml is needed for the command backend",
+                )?;
+                Ok(KeepAwake::Command {
+                    argv,
+                    inhibits: inhibits.unwrap_or_default(),
+                })
+            }
+            _ => Err(format!(
+                "Unknown `keep_awake.backend` \"{backend}\" in config.toml, expected caffeinate, \
+                 systemd-inhibit or command"
+            )
+            .into()),
+        }
+    }
+
+    /// `keep_awake.what`, checked against what `systemd-inhibit` knows of
+    fn systemd_what(what: Option<Vec<String>>) -> Result<Vec<String>, Box<dyn Error>> {
+        let Some(what) = what else {
+            return Ok(keep_awake::SYSTEMD_DEFAULT_WHAT.map(String::from).to_vec());
+        };
+        if what.is_empty() {
+            return Err("`keep_awake.what` in config.toml should not be empty".into());
+        }
+        if let Some(unknown) = what
+            .iter()
+            .find(|what| !keep_awake::SYSTEMD_WHAT.contains(&what.as_str()))
+        {
+            return Err(format!(
+                "Unknown `keep_awake.what` \"{unknown}\" in config.toml, expected any of {}",
+                keep_awake::SYSTEMD_WHAT.join(", ")
+            )
+            .into());
+        }
+        Ok(what)
+    }
+
+    fn webhooks_from_toml(toml: &Table) -> Result<Vec<WebhookConfig>, Box<dyn Error>> {
+        let Some(webhooks) = toml.get("webhooks") else {
+            return Ok(vec![]);
+        };
+        let webhooks = webhooks
+            .as_array()
+            .ok_or("`webhooks` in config.toml should be an array of tables")?;
+
+        let mut result = vec![];
+        for webhook in webhooks {
+            let webhook = webhook
+                .as_table()
+                .ok_or("Each of `webhooks` in config.toml should be a table")?;
+            let mut url = None;
+            let mut events = vec![];
+            let mut timeout = webhooks::DEFAULT_TIMEOUT;
+            let mut retries = webhooks::DEFAULT_RETRIES;
+            let mut secret = None;
+            for (field, value) in webhook {
+                let as_str = || {
+                    value.as_str().ok_or_else(|| {
+                        format!("`webhooks.{field}` in config.toml should be a string")
+                    })
+                };
+                match field.as_str() {
+                    "url" => url = Some(as_str()?.to_string()),
+                    "events" => {
+                        for event in value.as_array().ok_or(
+                            "`webhooks.events` in config.toml should be an array of strings",
+                        )? {
+                            events.push(
+                                event
+                                    .as_str()
+                                    .ok_or("Each of `webhooks.events` should be a string")?
+                                    .to_string(),
+                            );
+                        }
+                    }
+                    "timeout" => timeout = duration::parse(as_str()?)?,
+                    "retries" => {
+                        retries = value
+                            .as_integer()
+                            .and_then(|retries| u32::try_from(retries).ok())
+                            .ok_or("`webhooks.retries` in config.toml should be a number, eg 2")?;
+                    }
+                    "secret" => secret = Some(as_str()?.to_string()),
+                    _ => return Err(format!("Unknown `webhooks.{field}` in config.toml").into()),
+                }
+            }
+            let url = url.ok_or("`webhooks.url` in config.toml is required")?;
+            result.push(
+                WebhookConfig::new(url, events, timeout, retries, secret)
+                    .map_err(|error| format!("`webhooks` in config.toml: {error}"))?,
+            );
+        }
+
+        Ok(result)
+    }
+
+    fn plugins_from_toml(toml: &Table) -> Result<Vec<PluginConfig>, Box<dyn Error>> {
+        let Some(plugins) = toml.get("plugins") else {
+            return Ok(vec![]);
+        };
+        let plugins = plugins
+            .as_array()
+            .ok_or("`plugins` in config.toml should be an array of tables")?;
+
+        let mut result = vec![];
+        for plugin in plugins {
+            let plugin = plugin
+                .as_table()
+                .ok_or("Each of `plugins` in config.toml should be a table")?;
+            let mut name = None;
+            let mut argv = vec![];
+            let mut env = BTreeMap::new();
+            let mut timeout = plugin::DEFAULT_TIMEOUT;
+            for (field, value) in plugin {
+                let as_str = || {
+                    value.as_str().ok_or_else(|| {
+                        format!("`plugins.{field}` in config.toml should be a string")
+                    })
+                };
+                match field.as_str() {
+                    "name" => name = Some(as_str()?.to_string()),
+                    "command" => {
+                        for arg in value.as_array().ok_or(
+                            "`plugins.command` in config.toml should be an array of strings",
+                        )? {
+                            argv.push(
+                                arg.as_str()
+                                    .ok_or("Each of `plugins.command` should be a string")?
+                                    .to_string(),
+                            );
+                        }
+                    }
+                    "env" => {
+                        for (variable, value) in value
+                            .as_table()
+                            .ok_or("`plugins.env` in config.toml should be a table of strings")?
+                        {
+                            let value = value.as_str().ok_or_else(|| {
+                                format!(
+                                    "`plugins.env.{variable}` in config.toml should be a string"
+                                )
+                            })?;
+                            env.insert(variable.clone(), value.to_string());
+                        }
+                    }
+                    "timeout" => timeout = duration::parse(as_str()?)?,
+                    _ => return Err(format!("Unknown `plugins.{field}` in config.toml").into()),
+                }
+            }
+            result.push(
+                PluginConfig::new(name, argv, env, timeout)
+                    .map_err(|error| format!("`plugins` in config.toml: {error}"))?,
+            );
+        }
+
+        Ok(result)
+    }
+
+    fn services_from_toml(toml: &Table, mode: Mode) -> Result<Vec<ServiceConfig>, Box<dyn Error>> {
+        let key = format!("{mode}_services");
+        let Some(services) = toml.get(&key) else {
+            return Ok(vec![]);
+        };
+        let services = services
+            .as_array()
+            .ok_or_else(|| format!("`{key}` in config.toml should be an array of tables"))?;
+
+        let mut result = vec![];
+        for service in services {
+            let service = service
+                .as_table()
+                .ok_or_else(|| format!("Each of `{key}` in config.toml should be a table"))?;
+            let mut name = None;
+            let mut argv = vec![];
+            let mut env = BTreeMap::new();
+            let mut restart = RestartPolicy::default();
+            let mut stop_signal = StopSignal::default();
+            for (field, value) in service {
+                let as_str = || {
+                    value
+                        .as_str()
+                        .ok_or_else(|| format!("`{key}.{field}` in config.toml should be a string"))
+                };
+                match field.as_str() {
+                    "name" => name = Some(as_str()?.to_string()),
+                    "command" => {
+                        for arg in value.as_array().ok_or_else(|| {
+                            format!("`{key}.command` in config.toml should be an array of strings")
+                        })? {
+                            argv.push(
+                                arg.as_str()
+                                    .ok_or_else(|| {
+                                        format!("Each of `{key}.command` should be a string")
+                                    })?
+                                    .to_string(),
+                            );
+                        }
+                    }
+                    "env" => {
+                        for (variable, value) in value.as_table().ok_or_else(|| {
+                            format!("`{key}.env` in config.toml should be a table of strings")
+                        })? {
+                            let value = value.as_str().ok_or_else(|| {
+                                format!("`{key}.env.{variable}` in config.toml should be a string")
+                            })?;
+                            env.insert(variable.clone(), value.to_string());
+                        }
+                    }
+                    "restart" => restart = as_str()?.parse()?,
+                    "stop_signal" => stop_signal = as_str()?.parse()?,
+                    _ => return Err(format!("Unknown `{key}.{field}` in config.toml").into()),
+                }
+            }
+            result.push(
+                ServiceConfig::new(name, argv, env, restart, stop_signal)
+                    .map_err(|error| format!("`{key}` in config.toml: {error}"))?,
+            );
+        }
+
+        Ok(result)
+    }
+
+    fn keep_awake_policy(toml: &Table, mode: Mode) -> Result<KeepAwakePolicy, Box<dyn Error>> {
+        let key = format!("{mode}_keep_awake");
+        let Some(policy) = toml.get(&key) else {
+            return Ok(KeepAwakePolicy::default());
+        };
+        let policy = policy
+            .as_str()
+            .ok_or_else(|| format!("`{key}` in config.toml should be a string, eg \"on\""))?;
+
+        Ok(policy.parse()?)
+    }
+
     fn create_apple_script(
         toml: &Table,
         temp_dir: &TempDir,
         key: &str,
     ) -> Result<PathBuf, Box<dyn Error>> {
-        let applescript = toml
-            .get(key)
-            .ok_or_else(|| {
-                format!("`{key}` is missing from config.toml. Please add, or revert to defaults.")
-            })?
-            .as_str()
-            .ok_or_else(|| {
+        let applescript = match toml.get(key) {
+            Some(applescript) => applescript.as_str().ok_or_else(|| {
                 format!(
                     "`{key}` is malformed in config.toml. Please ensure it is valid \
                 AppleScript as a TOML string (see https://quickref.me/toml)."
                 )
-            })?;
+            })?,
+            // Only macOS has AppleScript, elsewhere hooks are left to apply the mode
+            None if !cfg!(target_os = "macos") => "",
+            None => {
+                return Err(format!(
+                    "`{key}` is missing from config.toml. Please add, or revert to defaults."
+                )
+                .into());
+            }
+        };
         let path = temp_dir.path().join(format!("{key}.scpt"));
         let mut temp_file = File::create(&path)?;
         write!(temp_file, "{applescript}")?;
@@ -121,13 +969,637 @@ impl Config {
         &self.laptop_applescript_path
     }
 
+    /// How to keep the computer awake, eg with `caffeinate` or `systemd-inhibit`
+    #[must_use]
+    pub const fn keep_awake_backend(&self) -> &KeepAwake {
+        &self.keep_awake_backend
+    }
+
+    #[must_use]
+    pub const fn caffeinate_options(&self) -> &CaffeinateOptions {
+        &self.caffeinate_options
+    }
+
+    /// Time of day offered in the Caffeinate menu, eg `Until 18:00`
+    #[must_use]
+    pub const fn caffeinate_until(&self) -> TimeOfDay {
+        self.caffeinate_until
+    }
+
+    /// How long the user can be idle for before caffeination is stopped
+    #[must_use]
+    pub const fn caffeinate_stop_after_idle(&self) -> Option<Duration> {
+        self.caffeinate_stop_after_idle
+    }
+
+    /// What to do with caffeination when switching into `mode`
+    #[must_use]
+    pub const fn keep_awake(&self, mode: Mode) -> KeepAwakePolicy {
+        match mode {
+            Mode::Desktop => self.desktop_keep_awake,
+            Mode::Laptop => self.laptop_keep_awake,
+        }
+    }
+
+    /// Services to keep running while in `mode`
+    #[must_use]
+    pub fn services(&self, mode: Mode) -> &[ServiceConfig] {
+        match mode {
+            Mode::Desktop => &self.desktop_services,
+            Mode::Laptop => &self.laptop_services,
+        }
+    }
+
+    #[must_use]
+    pub const fn automatic(&self) -> &Automatic {
+        &self.automatic
+    }
+
+    /// Settings for the HTTP API, if it is to be served
+    #[must_use]
+    pub const fn http(&self) -> Option<&Http> {
+        self.http.as_ref()
+    }
+
+    /// Webhooks to send events to
     #[must_use]
-    pub fn caffeinate_app(&self) -> Option<&str> {
-        self.caffeinate_app.as_deref()
+    pub fn webhooks(&self) -> &[WebhookConfig] {
+        &self.webhooks
     }
 
     #[must_use]
-    pub fn caffeinate_options(&self) -> Option<&str> {
-        self.caffeinate_options.as_deref()
+    pub const fn hooks(&self) -> &Hooks {
+        &self.hooks
+    }
+
+    /// Plugins to start, extending lod with facts, actions and menu items
+    #[must_use]
+    pub fn plugins(&self) -> &[PluginConfig] {
+        &self.plugins
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_defaults_caffeinate_options_to_none() {
+        let options = CaffeinateOptions::from_toml(&Table::new()).unwrap();
+        assert!(options.args().is_empty());
+        assert_eq!(options.timeout(), None);
+    }
+
+    #[test]
+    fn it_parses_caffeinate_options() {
+        let toml = r#"
+            [caffeinate_options]
+            prevent_display_sleep = true
+            prevent_idle_sleep = true
+            prevent_disk_sleep = false
+            declare_user_active = true
+            timeout = "2h"
+            args = ["-x", "value"]
+        "#
+        .parse::<Table>()
+        .unwrap();
+
+        let options = CaffeinateOptions::from_toml(&toml).unwrap();
+        assert_eq!(options.args(), ["-d", "-i", "-u", "-x", "value"]);
+        assert_eq!(options.timeout(), Some(Duration::from_secs(2 * 60 * 60)));
+    }
+
+    #[test]
+    fn it_splits_legacy_caffeinate_options() {
+        let toml = r#"caffeinate_options = "-d -i""#.parse::<Table>().unwrap();
+        let options = CaffeinateOptions::from_toml(&toml).unwrap();
+        assert_eq!(options.args(), ["-d", "-i"]);
+    }
+
+    #[test]
+    fn it_rejects_malformed_caffeinate_options() {
+        for toml in [
+            "caffeinate_options = 1",
+            "caffeinate_options = \"-d 'unterminated\"",
+            "[caffeinate_options]\nprevent_display_sleep = \"yes\"",
+            "[caffeinate_options]\nprevent_sleep = true",
+            "[caffeinate_options]\ntimeout = \"forever\"",
+            "[caffeinate_options]\nargs = \"-d\"",
+        ] {
+            let toml = toml.parse::<Table>().unwrap();
+            assert!(CaffeinateOptions::from_toml(&toml).is_err(), "{toml}");
+        }
+    }
+
+    #[test]
+    fn it_splits_like_a_shell() {
+        assert_eq!(
+            split_shell("  -d   -i "),
+            Ok(vec!["-d".into(), "-i".into()])
+        );
+        assert_eq!(
+            split_shell(r#"-a 'one two' "three \"four\"" five\ six ''"#),
+            Ok(vec![
+                "-a".into(),
+                "one two".into(),
+                "three \"four\"".into(),
+                "five six".into(),
+                String::new(),
+            ])
+        );
+        assert!(split_shell("\"unterminated").is_err());
+        assert!(split_shell("trailing\\").is_err());
+    }
+
+    #[test]
+    fn it_rejects_malformed_keep_awake_policies() {
+        for toml in ["desktop_keep_awake = true", "desktop_keep_awake = \"0\""] {
+            let toml = toml.parse::<Table>().unwrap();
+            assert!(Config::keep_awake_policy(&toml, Mode::Desktop).is_err());
+        }
+        assert_eq!(
+            Config::keep_awake_policy(&Table::new(), Mode::Desktop).unwrap(),
+            KeepAwakePolicy::Unchanged
+        );
+    }
+
+    fn keep_awake_backend(toml: &str) -> Result<KeepAwake, Box<dyn Error>> {
+        let toml = toml.parse::<Table>().unwrap();
+        let options = CaffeinateOptions::from_toml(&toml)?;
+        Config::keep_awake_backend_from_toml(&toml, None, &options)
+    }
+
+    #[test]
+    fn it_picks_a_keep_awake_backend_for_the_platform() {
+        assert_eq!(keep_awake_backend("").unwrap(), KeepAwake::default());
+        let toml = Table::new();
+        assert_eq!(
+            Config::keep_awake_backend_from_toml(
+                &toml,
+                Some("/opt/bin/caffeinate".into()),
+                &CaffeinateOptions::default()
+            )
+            .unwrap(),
+            KeepAwake::caffeinate("/opt/bin/caffeinate", vec![])
+        );
+    }
+
+    #[test]
+    fn it_parses_keep_awake_backends() {
+        assert_eq!(
+            keep_awake_backend(
+                r#"
+                [caffeinate_options]
+                prevent_display_sleep = true
+
+                [keep_awake]
+                backend = "caffeinate"
+                "#
+            )
+            .unwrap(),
+            KeepAwake::caffeinate("caffeinate", vec!["-d".into()])
+        );
+        assert_eq!(
+            keep_awake_backend(
+                r#"
+                [keep_awake]
+                backend = "systemd-inhibit"
+                program = "/usr/local/bin/systemd-inhibit"
+                what = ["idle", "handle-lid-switch"]
+                "#
+            )
+            .unwrap(),
+            KeepAwake::SystemdInhibit {
+                program: "/usr/local/bin/systemd-inhibit".into(),
+                what: vec!["idle".into(), "handle-lid-switch".into()],
+            }
+        );
+        assert_eq!(
+            keep_awake_backend(
+                r#"
+                [keep_awake]
+                backend = "command"
+                command = ["gnome-session-inhibit", "--inhibit", "idle"]
+                inhibits = ["idle"]
+                "#
+            )
+            .unwrap(),
+            KeepAwake::Command {
+                argv: vec![
+                    "gnome-session-inhibit".into(),
+                    "--inhibit".into(),
+                    "idle".into()
+                ],
+                inhibits: vec!["idle".into()],
+            }
+        );
+    }
+
+    #[test]
+    fn it_rejects_malformed_keep_awake_backends() {
+        for toml in [
+            "keep_awake = \"caffeinate\"",
+            "[keep_awake]\nbackend = \"coffee\"",
+            "[keep_awake]\nbackend = 1",
+            "[keep_awake]\nbackend = \"caffeinate\"\nwhat = [\"idle\"]",
+            "[keep_awake]\nbackend = \"systemd-inhibit\"\nwhat = [\"naps\"]",
+            "[keep_awake]\nbackend = \"systemd-inhibit\"\nwhat = []",
+            "[keep_awake]\nbackend = \"systemd-inhibit\"\ncommand = [\"true\"]",
+            "[keep_awake]\nbackend = \"command\"",
+            "[keep_awake]\nbackend = \"command\"\ncommand = []",
+            "[keep_awake]\nbackend = \"command\"\ncommand = [\"true\"]\nprogram = \"true\"",
+            "[keep_awake]\nwhy = \"because\"",
+        ] {
+            assert!(keep_awake_backend(toml).is_err(), "{toml}");
+        }
+    }
+
+    #[test]
+    fn it_rejects_caffeinate_options_for_other_backends() {
+        for (toml, key) in [
+            (
+                "[keep_awake]\nbackend = \"systemd-inhibit\"\n\
+                 [caffeinate_options]\nprevent_display_sleep = true",
+                "prevent_display_sleep",
+            ),
+            (
+                "caffeinate_options = \"-d\"\n[keep_awake]\nbackend = \"command\"\n\
+                 command = [\"true\"]",
+                "args",
+            ),
+        ] {
+            let error = keep_awake_backend(toml).unwrap_err().to_string();
+            assert!(
+                error.starts_with(&format!("`caffeinate_options.{key}`"))
+                    && error.contains("does not apply"),
+                "{error}"
+            );
+        }
+        // Unlike the timeout
+        assert!(
+            keep_awake_backend(
+                "[keep_awake]\nbackend = \"systemd-inhibit\"\n[caffeinate_options]\ntimeout = \"1h\""
+            )
+            .is_ok()
+        );
+    }
+
+    #[test]
+    fn it_parses_services() {
+        let toml = r#"
+            [[desktop_services]]
+            name = "kvm"
+            command = ["/usr/local/bin/synergyc", "-f", "desk.local"]
+            env = { SYNERGY_LOG = "info" }
+            restart = "always"
+            stop_signal = "SIGINT"
+
+            [[desktop_services]]
+            command = ["/usr/local/bin/proxy"]
+        "#
+        .parse::<Table>()
+        .unwrap();
+
+        let services = Config::services_from_toml(&toml, Mode::Desktop).unwrap();
+        assert_eq!(
+            services,
+            [
+                ServiceConfig::new(
+                    Some("kvm".into()),
+                    vec![
+                        "/usr/local/bin/synergyc".into(),
+                        "-f".into(),
+                        "desk.local".into()
+                    ],
+                    BTreeMap::from([("SYNERGY_LOG".into(), "info".into())]),
+                    RestartPolicy::Always,
+                    "SIGINT".parse().unwrap(),
+                )
+                .unwrap(),
+                ServiceConfig::new(
+                    None,
+                    vec!["/usr/local/bin/proxy".into()],
+                    BTreeMap::new(),
+                    RestartPolicy::OnFailure,
+                    StopSignal::default(),
+                )
+                .unwrap(),
+            ]
+        );
+        assert!(
+            Config::services_from_toml(&toml, Mode::Laptop)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn it_rejects_malformed_services() {
+        for toml in [
+            "desktop_services = \"proxy\"",
+            "[[desktop_services]]\nname = \"no command\"",
+            "[[desktop_services]]\ncommand = [\"proxy\"]\nrestart = \"sometimes\"",
+            "[[desktop_services]]\ncommand = [\"proxy\"]\nstop_signal = \"SIGNOPE\"",
+            "[[desktop_services]]\ncommand = [\"proxy\"]\nenv = { PORT = 8080 }",
+            "[[desktop_services]]\ncommand = [\"proxy\"]\nrestrat = \"always\"",
+        ] {
+            let toml = toml.parse::<Table>().unwrap();
+            assert!(
+                Config::services_from_toml(&toml, Mode::Desktop).is_err(),
+                "{toml}"
+            );
+        }
+    }
+
+    #[test]
+    fn it_defaults_http_to_off() {
+        assert_eq!(Http::from_toml(&Table::new()).unwrap(), None);
+    }
+
+    #[test]
+    fn it_parses_http() {
+        let toml = "[http]\nport = 8765\ntoken = \"secret\""
+            .parse::<Table>()
+            .unwrap();
+        assert_eq!(
+            Http::from_toml(&toml).unwrap(),
+            Some(Http::new(8765, "secret"))
+        );
+    }
+
+    #[test]
+    fn it_rejects_malformed_http() {
+        for toml in [
+            "http = true",
+            "[http]\ntoken = \"secret\"",
+            "[http]\nport = 8765",
+            "[http]\nport = 0\ntoken = \"secret\"",
+            "[http]\nport = 70000\ntoken = \"secret\"",
+            "[http]\nport = 8765\ntoken = \"\"",
+            "[http]\nport = 8765\ntoken = \"secret\"\nhost = \"0.0.0.0\"",
+        ] {
+            let toml = toml.parse::<Table>().unwrap();
+            assert!(Http::from_toml(&toml).is_err(), "{toml}");
+        }
+    }
+
+    #[test]
+    fn it_parses_webhooks() {
+        let toml = r#"
+            [[webhooks]]
+            url = "https://example.com/lod"
+
+            [[webhooks]]
+            url = "http://localhost:8123/api/webhook/lod"
+            events = ["mode_changed"]
+            timeout = "2s"
+            retries = 0
+            secret = "shh"
+        "#
+        .parse::<Table>()
+        .unwrap();
+
+        assert_eq!(
+            Config::webhooks_from_toml(&toml).unwrap(),
+            [
+                WebhookConfig::new(
+                    "https://example.com/lod".into(),
+                    vec![],
+                    webhooks::DEFAULT_TIMEOUT,
+                    webhooks::DEFAULT_RETRIES,
+                    None
+                )
+                .unwrap(),
+                WebhookConfig::new(
+                    "http://localhost:8123/api/webhook/lod".into(),
+                    vec!["mode_changed".into()],
+                    Duration::from_secs(2),
+                    0,
+                    Some("shh".into())
+                )
+                .unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_rejects_malformed_webhooks() {
+        for toml in [
+            "webhooks = \"https://example.com\"",
+            "[[webhooks]]\nevents = []",
+            "[[webhooks]]\nurl = \"example.com\"",
+            "[[webhooks]]\nurl = \"https://example.com\"\nevents = [\"moved\"]",
+            "[[webhooks]]\nurl = \"https://example.com\"\ntimeout = \"0s\"",
+            "[[webhooks]]\nurl = \"https://example.com\"\nretries = -1",
+            "[[webhooks]]\nurl = \"https://example.com\"\nmethod = \"PUT\"",
+        ] {
+            let toml = toml.parse::<Table>().unwrap();
+            assert!(Config::webhooks_from_toml(&toml).is_err(), "{toml}");
+        }
+    }
+
+    #[test]
+    fn it_parses_hooks() {
+        let toml = r#"
+            [hooks]
+            before_switch = ["pgrep -q zoom.us && exit 1 || exit 0"]
+            on_caffeinate_start = [["say", "Staying awake"], "echo started"]
+            after_switch = [{ script = 'notify("lod", mode())' }]
+        "#
+        .parse::<Table>()
+        .unwrap();
+
+        assert_eq!(
+            Config::hooks_from_toml(&toml).unwrap(),
+            Hooks::new(vec![
+                (
+                    Hook::AfterSwitch,
+                    HookAction::Script(Script::compile("notify(\"lod\", mode())").unwrap())
+                ),
+                (
+                    Hook::BeforeSwitch,
+                    HookAction::Command(vec![
+                        "sh".into(),
+                        "-c".into(),
+                        "pgrep -q zoom.us && exit 1 || exit 0".into()
+                    ])
+                ),
+                (
+                    Hook::OnCaffeinateStart,
+                    HookAction::Command(vec!["say".into(), "Staying awake".into()])
+                ),
+                (
+                    Hook::OnCaffeinateStart,
+                    HookAction::Command(vec!["sh".into(), "-c".into(), "echo started".into()])
+                ),
+            ])
+        );
+        assert_eq!(
+            Config::hooks_from_toml(&Table::new()).unwrap(),
+            Hooks::default()
+        );
+    }
+
+    #[test]
+    fn it_parses_the_hook_timeout() {
+        let toml = "[hooks]\ntimeout = \"2s\"\nafter_switch = [\"true\"]"
+            .parse::<Table>()
+            .unwrap();
+
+        assert_eq!(
+            Config::hooks_from_toml(&toml).unwrap(),
+            Hooks::new(vec![(
+                Hook::AfterSwitch,
+                HookAction::Command(vec!["sh".into(), "-c".into(), "true".into()])
+            )])
+            .with_timeout(Duration::from_secs(2))
+        );
+    }
+
+    #[test]
+    fn it_parses_plugins() {
+        let toml = r#"
+            [[plugins]]
+            command = ["/usr/local/bin/lod-vpn"]
+
+            [[plugins]]
+            name = "flag"
+            command = ["python3", "flag_file.py", "/tmp/flag"]
+            env = { PYTHONUNBUFFERED = "1" }
+            timeout = "10s"
+        "#
+        .parse::<Table>()
+        .unwrap();
+
+        assert_eq!(
+            Config::plugins_from_toml(&toml).unwrap(),
+            [
+                PluginConfig::new(
+                    None,
+                    vec!["/usr/local/bin/lod-vpn".into()],
+                    BTreeMap::new(),
+                    plugin::DEFAULT_TIMEOUT
+                )
+                .unwrap(),
+                PluginConfig::new(
+                    Some("flag".into()),
+                    vec!["python3".into(), "flag_file.py".into(), "/tmp/flag".into()],
+                    BTreeMap::from([("PYTHONUNBUFFERED".into(), "1".into())]),
+                    Duration::from_secs(10)
+                )
+                .unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn it_rejects_malformed_plugins() {
+        for toml in [
+            "plugins = \"lod-vpn\"",
+            "[[plugins]]\nname = \"no command\"",
+            "[[plugins]]\ncommand = \"lod-vpn\"",
+            "[[plugins]]\ncommand = [\"lod-vpn\"]\ntimeout = \"0s\"",
+            "[[plugins]]\ncommand = [\"lod-vpn\"]\nrestart = \"always\"",
+        ] {
+            let toml = toml.parse::<Table>().unwrap();
+            assert!(Config::plugins_from_toml(&toml).is_err(), "{toml}");
+        }
+    }
+
+    #[test]
+    fn it_rejects_malformed_hooks() {
+        for toml in [
+            "hooks = []",
+            "[hooks]\nbefore_sleep = [\"true\"]",
+            "[hooks]\nafter_switch = \"true\"",
+            "[hooks]\nafter_switch = [[]]",
+            "[hooks]\nafter_switch = [[\"say\", 1]]",
+            "[hooks]\nafter_switch = [{}]",
+            "[hooks]\nafter_switch = [{ script = 1 }]",
+            "[hooks]\nafter_switch = [{ script = \"mode(\" }]",
+            "[hooks]\nafter_switch = [{ script = \"mode()\", timeout = \"1s\" }]",
+            "[hooks]\ntimeout = 30",
+            "[hooks]\ntimeout = \"0s\"",
+        ] {
+            let toml = toml.parse::<Table>().unwrap();
+            assert!(Config::hooks_from_toml(&toml).is_err(), "{toml}");
+        }
+    }
+
+    #[test]
+    fn it_defaults_automatic_to_no_rules() {
+        let automatic = Automatic::from_toml(&Table::new()).unwrap();
+        assert!(automatic.rules().is_empty());
+    }
+
+    #[test]
+    fn it_parses_automatic() {
+        let toml = r#"
+            [automatic]
+            enabled = false
+            interval = "1m"
+            settle = "30s"
+            min_interval = "5m"
+            rules = ["when external_display && on_ac then desktop"]
+        "#
+        .parse::<Table>()
+        .unwrap();
+
+        let automatic = Automatic::from_toml(&toml).unwrap();
+        assert!(!automatic.enabled());
+        assert_eq!(automatic.interval(), Duration::from_secs(60));
+        assert_eq!(automatic.settle(), Duration::from_secs(30));
+        assert_eq!(automatic.min_interval(), Duration::from_secs(5 * 60));
+        assert!(!automatic.rules().is_empty());
+    }
+
+    #[test]
+    fn it_maps_ssids_to_modes() {
+        use crate::{
+            Mode,
+            detector::{Fact, Facts},
+        };
+
+        let toml = r#"
+            [automatic]
+            rules = ["when wired_network then desktop"]
+
+            [automatic.ssids]
+            "*" = "laptop"
+            "Office WiFi" = "desktop"
+        "#
+        .parse::<Table>()
+        .unwrap();
+        let automatic = Automatic::from_toml(&toml).unwrap();
+        let ssid = |ssid: &str| Facts::from([("wifi_ssid".into(), Fact::Text(ssid.into()))]);
+
+        assert_eq!(
+            automatic.rules().evaluate(&ssid("Office WiFi")),
+            Some(Mode::Desktop)
+        );
+        assert_eq!(
+            automatic.rules().evaluate(&ssid("Coffee Shop")),
+            Some(Mode::Laptop)
+        );
+        assert_eq!(
+            automatic.rules().evaluate(&Facts::new()),
+            Some(Mode::Laptop)
+        );
+        assert_eq!(
+            automatic.rules().facts().into_iter().collect::<Vec<_>>(),
+            vec!["wifi_ssid", "wired_network"]
+        );
+    }
+
+    #[test]
+    fn it_rejects_malformed_rules() {
+        let toml = r#"
+            [automatic]
+            rules = ["if on_ac then desktop"]
+        "#
+        .parse::<Table>()
+        .unwrap();
+
+        assert!(Automatic::from_toml(&toml).is_err());
     }
 }
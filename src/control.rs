@@ -0,0 +1,579 @@
+use super::{CaffeinateFor, Event, Events, Reply, SharedStatus, StateChangeMessage, duration};
+use serde_json::{Map, Value, json};
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{Receiver, RecvTimeoutError, Sender},
+    },
+    thread,
+    time::Duration,
+};
+
+/// Name of the control socket in the runtime directory
+pub const SOCKET_NAME: &str = "lod.sock";
+
+/// The error given once the main thread has stopped handling requests
+pub(crate) const SHUTTING_DOWN: &str = "lod is shutting down";
+
+/// How long to wait for a change to be made, which includes running any `before_switch` hooks
+const REPLY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A request received on the control socket
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Request {
+    /// Reply with the current `Status`
+    GetStatus,
+
+    /// Push each `Event` from now on, until the connection is closed
+    Subscribe,
+
+    /// Pass the message on to the main thread
+    Send(StateChangeMessage),
+}
+
+impl Request {
+    /// Parse a request such as `{"command": "set_mode", "mode": "desktop"}`
+    ///
+    /// # Errors
+    ///
+    /// If the command is unknown, or its arguments are missing or invalid
+    pub fn from_json(request: &Map<String, Value>) -> Result<Self, String> {
+        let command = request
+            .get("command")
+            .and_then(Value::as_str)
+            .ok_or("Request should have a `command` string")?;
+
+        let message = match command {
+            "get_status" => return Ok(Self::GetStatus),
+            "subscribe" => return Ok(Self::Subscribe),
+            "set_mode" => StateChangeMessage::SetMode(string(request, "mode")?.parse()?),
+            "toggle_mode" => StateChangeMessage::ToggleMode,
+            "set_caffeinate" => set_caffeinate(request)?,
+            "reload_config" => StateChangeMessage::ReloadConfig,
+            "quit" => StateChangeMessage::Quit,
+            _ => return Err(format!("Unknown command `{command}`")),
+        };
+        Ok(Self::Send(message))
+    }
+}
+
+/// `set_caffeinate` takes `enabled`, which defaults to true, and either `for` a duration such as
+/// `"1h30m"` or `until` a time such as `"18:00"`, otherwise caffeinating indefinitely
+fn set_caffeinate(request: &Map<String, Value>) -> Result<StateChangeMessage, String> {
+    let enabled = match request.get("enabled") {
+        None => true,
+        Some(enabled) => enabled.as_bool().ok_or("`enabled` should be a boolean")?,
+    };
+    let duration = request.get("for").map(|_| string(request, "for"));
+    let until = request.get("until").map(|_| string(request, "until"));
+
+    if !enabled {
+        if duration.is_some() || until.is_some() {
+            return Err("`for` and `until` only apply when enabling caffeination".into());
+        }
+        return Ok(StateChangeMessage::StopCaffeination);
+    }
+    let caffeinate_for = match (duration, until) {
+        (Some(_), Some(_)) => return Err("Give either `for` or `until`, not both".into()),
+        (Some(duration), None) => CaffeinateFor::Duration(duration::parse(duration?)?),
+        (None, Some(until)) => CaffeinateFor::Until(until?.parse()?),
+        (None, None) => CaffeinateFor::Indefinitely,
+    };
+    Ok(StateChangeMessage::Caffeinate(caffeinate_for))
+}
+
+fn string<'a>(request: &'a Map<String, Value>, key: &str) -> Result<&'a str, String> {
+    request
+        .get(key)
+        .and_then(Value::as_str)
+        .ok_or_else(|| format!("Request should have a `{key}` string"))
+}
+
+/// Handles requests, from any connection, passing changes on to the main thread
+#[derive(Clone, Debug)]
+pub struct Handler {
+    sender: Sender<StateChangeMessage>,
+    status: SharedStatus,
+    events: Events,
+}
+
+impl Handler {
+    #[must_use]
+    pub const fn new(
+        sender: Sender<StateChangeMessage>,
+        status: SharedStatus,
+        events: Events,
+    ) -> Self {
+        Self {
+            sender,
+            status,
+            events,
+        }
+    }
+
+    /// Handle a line of the protocol, returning the reply, along with the events to push for a
+    /// `subscribe` request
+    ///
+    /// Replies are `{"ok": true}` once a change has been made, with a `status` for `get_status`,
+    /// or `{"ok": false, "error": "..."}` should it have failed or been vetoed by a hook. Any `id`
+    /// given in the request is included in the reply.
+    #[must_use]
+    pub fn handle(&self, line: &str) -> (Value, Option<Receiver<Event>>) {
+        match serde_json::from_str::<Value>(line) {
+            Ok(Value::Object(request)) => self.handle_request(&request),
+            Ok(_) => (error(None, "Request should be a JSON object"), None),
+            Err(parse_error) => (error(None, &format!("Invalid JSON: {parse_error}")), None),
+        }
+    }
+
+    /// As `handle`, for a request which has already been parsed
+    #[must_use]
+    pub fn handle_request(&self, request: &Map<String, Value>) -> (Value, Option<Receiver<Event>>) {
+        let id = request.get("id").cloned();
+
+        let (mut reply, events) = match Request::from_json(request) {
+            Ok(Request::GetStatus) => (
+                json!({ "ok": true, "status": self.status.get().to_json() }),
+                None,
+            ),
+            Ok(Request::Subscribe) => (json!({ "ok": true }), Some(self.events.subscribe())),
+            Ok(Request::Send(message)) => match self.send(message) {
+                Ok(()) => (json!({ "ok": true }), None),
+                Err(message) => return (error(id, &message), None),
+            },
+            Err(request_error) => return (error(id, &request_error), None),
+        };
+        if let Some(id) = id {
+            reply["id"] = id;
+        }
+        (reply, events)
+    }
+
+    /// Pass the message on to the main thread, waiting for it to be handled
+    fn send(&self, message: StateChangeMessage) -> Result<(), String> {
+        // Nothing is left to reply once quitting
+        if message == StateChangeMessage::Quit {
+            return self.sender.send(message).map_err(|_| SHUTTING_DOWN.into());
+        }
+
+        let (reply, receiver) = Reply::new();
+        self.sender
+            .send(StateChangeMessage::Request(Box::new(message), reply))
+            .map_err(|_| SHUTTING_DOWN)?;
+        match receiver.recv_timeout(REPLY_TIMEOUT) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Err("Timed out waiting for lod".into()),
+            Err(RecvTimeoutError::Disconnected) => Err(SHUTTING_DOWN.into()),
+        }
+    }
+}
+
+fn error(id: Option<Value>, message: &str) -> Value {
+    let mut reply = json!({ "ok": false, "error": message });
+    if let Some(id) = id {
+        reply["id"] = id;
+    }
+    reply
+}
+
+/// Listens on a Unix socket for requests to control lod, eg from scripts, passing them on to the
+/// main thread as `StateChangeMessage`s
+///
+/// The socket is only accessible to the user, as it is in the runtime directory.
+pub struct ControlServer {
+    path: PathBuf,
+    stopped: Arc<AtomicBool>,
+}
+
+impl ControlServer {
+    /// Listen on `path`, replacing any socket left behind by a previous instance
+    ///
+    /// # Errors
+    ///
+    /// If the socket could not be created
+    pub fn spawn(path: PathBuf, handler: Handler) -> io::Result<Self> {
+        match fs::remove_file(&path) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+        let listener = UnixListener::bind(&path)?;
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let stopping = stopped.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopping.load(Ordering::SeqCst) {
+                    break;
+                }
+                match stream {
+                    Ok(stream) => {
+                        let handler = handler.clone();
+                        thread::spawn(move || {
+                            if let Err(error) = serve(stream, &handler) {
+                                eprintln!("Control connection failed: {error:?}");
+                            }
+                        });
+                    }
+                    Err(error) => eprintln!("Failed to accept control connection: {error:?}"),
+                }
+            }
+        });
+
+        Ok(Self { path, stopped })
+    }
+
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wake the listening thread so it sees it has been stopped
+        let _ = UnixStream::connect(&self.path);
+        if let Err(error) = fs::remove_file(&self.path) {
+            eprintln!("Failed to remove {}: {error:?}", self.path.display());
+        }
+    }
+}
+
+/// Connection to the control socket of a running instance
+pub struct Client {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl Client {
+    /// # Errors
+    ///
+    /// If lod is not running, or the socket could not be connected to
+    pub fn connect(path: &Path) -> io::Result<Self> {
+        let writer = UnixStream::connect(path)?;
+        Ok(Self {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+        })
+    }
+
+    /// Send a request, waiting for the reply
+    ///
+    /// # Errors
+    ///
+    /// If the connection failed, or the reply was not JSON
+    pub fn request(&mut self, request: &Value) -> io::Result<Value> {
+        writeln!(self.writer, "{request}")?;
+        self.read()?
+            .ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+    }
+
+    /// Wait for the next line, eg an event once subscribed, or `None` once lod has gone
+    ///
+    /// # Errors
+    ///
+    /// If the connection failed, or the line was not JSON
+    pub fn read(&mut self) -> io::Result<Option<Value>> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_str(&line)?))
+    }
+}
+
+fn serve(stream: UnixStream, handler: &Handler) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (reply, events) = handler.handle(&line);
+        writeln!(writer, "{reply}")?;
+
+        // The connection only carries events once subscribed
+        if let Some(events) = events {
+            for event in events {
+                writeln!(writer, "{}", event.to_json())?;
+            }
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Stands in for the main thread, passing on each message it is sent and answering requests with
+/// `result`
+#[cfg(test)]
+pub(crate) fn answer_requests(
+    receiver: Receiver<StateChangeMessage>,
+    result: Result<(), String>,
+) -> Receiver<StateChangeMessage> {
+    let (sender, answered) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        for message in receiver {
+            let reply = match message {
+                StateChangeMessage::Request(message, reply) => {
+                    let _ = sender.send(*message);
+                    reply
+                }
+                message => {
+                    let _ = sender.send(message);
+                    continue;
+                }
+            };
+            reply.send(result.clone());
+        }
+    });
+    answered
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Mode, Status};
+    use std::{
+        sync::mpsc::{self, Receiver},
+        time::Duration,
+    };
+
+    fn sut() -> (Handler, Receiver<StateChangeMessage>, SharedStatus, Events) {
+        let (sender, receiver) = mpsc::channel();
+        let status = SharedStatus::new(Status::new(Mode::Laptop));
+        let events = Events::default();
+        (
+            Handler::new(sender, status.clone(), events.clone()),
+            receiver,
+            status,
+            events,
+        )
+    }
+
+    fn request(line: &str) -> Result<Request, String> {
+        match serde_json::from_str(line).unwrap() {
+            Value::Object(request) => Request::from_json(&request),
+            _ => unreachable!(),
+        }
+    }
+
+    fn connect(server: &ControlServer) -> (BufReader<UnixStream>, UnixStream) {
+        let stream = UnixStream::connect(server.path()).unwrap();
+        (BufReader::new(stream.try_clone().unwrap()), stream)
+    }
+
+    fn read_reply(reader: &mut BufReader<UnixStream>) -> Value {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn it_parses_requests() {
+        assert_eq!(
+            request(r#"{"command": "get_status"}"#),
+            Ok(Request::GetStatus)
+        );
+        assert_eq!(
+            request(r#"{"command": "subscribe"}"#),
+            Ok(Request::Subscribe)
+        );
+        assert_eq!(
+            request(r#"{"command": "set_mode", "mode": "desktop"}"#),
+            Ok(Request::Send(StateChangeMessage::SetMode(Mode::Desktop)))
+        );
+        assert_eq!(
+            request(r#"{"command": "toggle_mode"}"#),
+            Ok(Request::Send(StateChangeMessage::ToggleMode))
+        );
+        assert_eq!(
+            request(r#"{"command": "reload_config"}"#),
+            Ok(Request::Send(StateChangeMessage::ReloadConfig))
+        );
+        assert_eq!(
+            request(r#"{"command": "quit"}"#),
+            Ok(Request::Send(StateChangeMessage::Quit))
+        );
+    }
+
+    #[test]
+    fn it_parses_set_caffeinate() {
+        assert_eq!(
+            request(r#"{"command": "set_caffeinate"}"#),
+            Ok(Request::Send(StateChangeMessage::Caffeinate(
+                CaffeinateFor::Indefinitely
+            )))
+        );
+        assert_eq!(
+            request(r#"{"command": "set_caffeinate", "enabled": true, "for": "1h30m"}"#),
+            Ok(Request::Send(StateChangeMessage::Caffeinate(
+                CaffeinateFor::Duration(Duration::from_secs(5400))
+            )))
+        );
+        assert_eq!(
+            request(r#"{"command": "set_caffeinate", "until": "18:00"}"#),
+            Ok(Request::Send(StateChangeMessage::Caffeinate(
+                CaffeinateFor::Until("18:00".parse().unwrap())
+            )))
+        );
+        assert_eq!(
+            request(r#"{"command": "set_caffeinate", "enabled": false}"#),
+            Ok(Request::Send(StateChangeMessage::StopCaffeination))
+        );
+    }
+
+    #[test]
+    fn it_rejects_invalid_requests() {
+        assert!(request(r"{}").is_err());
+        assert!(request(r#"{"command": "explode"}"#).is_err());
+        assert!(request(r#"{"command": "set_mode"}"#).is_err());
+        assert!(request(r#"{"command": "set_mode", "mode": "tablet"}"#).is_err());
+        assert!(request(r#"{"command": "set_caffeinate", "for": "soon"}"#).is_err());
+        assert!(
+            request(r#"{"command": "set_caffeinate", "for": "1h", "until": "18:00"}"#).is_err()
+        );
+        assert!(
+            request(r#"{"command": "set_caffeinate", "enabled": false, "for": "1h"}"#).is_err()
+        );
+        assert!(request(r#"{"command": "set_caffeinate", "enabled": "yes"}"#).is_err());
+    }
+
+    #[test]
+    fn it_passes_changes_to_the_main_thread() {
+        let (sut, receiver, _status, _events) = sut();
+        let receiver = answer_requests(receiver, Ok(()));
+        let (reply, events) = sut.handle(r#"{"command": "toggle_mode", "id": 7}"#);
+        assert_eq!(reply, json!({ "ok": true, "id": 7 }));
+        assert!(events.is_none());
+        assert_eq!(receiver.try_recv(), Ok(StateChangeMessage::ToggleMode));
+    }
+
+    #[test]
+    fn it_replies_once_the_change_has_failed() {
+        let (sut, receiver, _status, _events) = sut();
+        let _receiver = answer_requests(receiver, Err("A before_switch hook failed".into()));
+        let (reply, _) = sut.handle(r#"{"command": "set_mode", "mode": "desktop", "id": 7}"#);
+        assert_eq!(
+            reply,
+            json!({ "ok": false, "error": "A before_switch hook failed", "id": 7 })
+        );
+    }
+
+    #[test]
+    fn it_replies_with_the_status() {
+        let (sut, receiver, status, _events) = sut();
+        let mut current = Status::new(Mode::Desktop);
+        current.caffeinating = true;
+        status.set(current);
+
+        let (reply, _) = sut.handle(r#"{"command": "get_status"}"#);
+        assert_eq!(reply["ok"], true);
+        assert_eq!(reply["status"]["mode"], "desktop");
+        assert_eq!(reply["status"]["caffeinate"]["active"], true);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn it_replies_with_errors() {
+        let (sut, _receiver, _status, _events) = sut();
+        let (reply, _) = sut.handle("not json");
+        assert_eq!(reply["ok"], false);
+        assert!(reply["error"].as_str().unwrap().starts_with("Invalid JSON"));
+
+        let (reply, _) = sut.handle(r#"{"command": "explode", "id": "a"}"#);
+        assert_eq!(
+            reply,
+            json!({ "ok": false, "error": "Unknown command `explode`", "id": "a" })
+        );
+    }
+
+    #[test]
+    fn it_replies_with_an_error_for_caffeinating_too_long() {
+        let (sut, receiver, _status, _events) = sut();
+        let (reply, _) =
+            sut.handle(r#"{"command": "set_caffeinate", "for": "18446744073709551615"}"#);
+        assert_eq!(reply["ok"], false);
+        assert!(
+            reply["error"].as_str().unwrap().contains("at most a year"),
+            "{reply}"
+        );
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn it_replies_once_shutting_down() {
+        let (sut, receiver, _status, _events) = sut();
+        drop(receiver);
+        let (reply, _) = sut.handle(r#"{"command": "quit"}"#);
+        assert_eq!(
+            reply,
+            json!({ "ok": false, "error": "lod is shutting down" })
+        );
+    }
+
+    #[test]
+    fn it_subscribes_to_events() {
+        let (sut, _receiver, _status, events) = sut();
+        let (reply, subscription) = sut.handle(r#"{"command": "subscribe", "id": 1}"#);
+        assert_eq!(reply, json!({ "ok": true, "id": 1 }));
+
+        events.emit(&Event::ConfigReloaded);
+        assert_eq!(subscription.unwrap().try_recv(), Ok(Event::ConfigReloaded));
+    }
+
+    #[test]
+    fn it_serves_requests_on_the_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(SOCKET_NAME);
+        // A socket left behind by a previous instance is replaced
+        fs::write(&path, "").unwrap();
+
+        let (handler, receiver, _status, _events) = sut();
+        let sut = ControlServer::spawn(path.clone(), handler).unwrap();
+
+        let (mut reader, mut writer) = connect(&sut);
+        for request in [r#"{"command": "get_status"}"#, "", r#"{"command": "quit"}"#] {
+            writeln!(writer, "{request}").unwrap();
+        }
+
+        assert_eq!(read_reply(&mut reader)["status"]["mode"], "laptop");
+        assert_eq!(read_reply(&mut reader), json!({ "ok": true }));
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(10)),
+            Ok(StateChangeMessage::Quit)
+        );
+
+        drop(sut);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn it_pushes_events_to_subscribers() {
+        let dir = tempfile::tempdir().unwrap();
+        let (handler, _receiver, _status, events) = sut();
+        let sut = ControlServer::spawn(dir.path().join(SOCKET_NAME), handler).unwrap();
+
+        let (mut reader, mut writer) = connect(&sut);
+        writeln!(writer, r#"{{"command": "subscribe"}}"#).unwrap();
+        assert_eq!(read_reply(&mut reader), json!({ "ok": true }));
+
+        events.emit(&Event::ModeChanged {
+            mode: Mode::Desktop,
+            previous: Mode::Laptop,
+        });
+        events.emit(&Event::CaffeinateStopped);
+        assert_eq!(
+            read_reply(&mut reader),
+            json!({ "event": "mode_changed", "mode": "desktop", "previous": "laptop" })
+        );
+        assert_eq!(
+            read_reply(&mut reader),
+            json!({ "event": "caffeinate_stopped" })
+        );
+    }
+}
@@ -0,0 +1,219 @@
+use super::clock::Clock;
+use std::{
+    fmt::Debug,
+    time::{Duration, Instant},
+};
+
+/// Holds back changes in a value until it has settled, to stop automatic switching from flapping
+/// while eg displays and USB hubs appear one by one after docking
+///
+/// A value is only emitted once it has been observed unchanged for the settle period, differs
+/// from the last value emitted and at least the minimum interval has passed since then.
+pub struct Debouncer<T, C: Clock> {
+    settle: Duration,
+    min_interval: Duration,
+    clock: C,
+    candidate: Option<Candidate<T>>,
+    last_emitted: Option<(T, Instant)>,
+}
+
+struct Candidate<T> {
+    value: T,
+    since: Instant,
+    held_back: bool,
+}
+
+impl<T, C> Debouncer<T, C>
+where
+    T: Clone + Debug + PartialEq,
+    C: Clock,
+{
+    pub const fn new(settle: Duration, min_interval: Duration, clock: C) -> Self {
+        Self {
+            settle,
+            min_interval,
+            clock,
+            candidate: None,
+            last_emitted: None,
+        }
+    }
+
+    /// Observe the latest `value`, where `None` means there is nothing to switch to, returning
+    /// the value if it should be acted upon now
+    pub fn observe(&mut self, value: Option<T>) -> Option<T> {
+        let now = self.clock.now();
+
+        let is_same_candidate = matches!(
+            (&self.candidate, &value),
+            (Some(candidate), Some(value)) if candidate.value == *value
+        );
+        if !is_same_candidate {
+            if let Some(candidate) = self.candidate.take() {
+                if !self.was_last_emitted(&candidate.value) {
+                    println!(
+                        "Suppressed switch to {:?} as it changed again within {:?}",
+                        candidate.value,
+                        now - candidate.since
+                    );
+                }
+            }
+            self.candidate = value.map(|value| Candidate {
+                value,
+                since: now,
+                held_back: false,
+            });
+        }
+
+        let candidate = self.candidate.as_mut()?;
+        if now - candidate.since < self.settle {
+            return None;
+        }
+        if let Some((last_value, last_time)) = &self.last_emitted {
+            if *last_value == candidate.value {
+                return None;
+            }
+            let elapsed = now - *last_time;
+            if elapsed < self.min_interval {
+                if !candidate.held_back {
+                    println!(
+                        "Suppressed switch to {:?} as the last automatic switch was only {:?} ago",
+                        candidate.value, elapsed
+                    );
+                    candidate.held_back = true;
+                }
+                return None;
+            }
+        }
+
+        self.last_emitted = Some((candidate.value.clone(), now));
+        Some(candidate.value.clone())
+    }
+
+    /// Forget everything observed so far, so the next settled value is emitted regardless
+    pub fn reset(&mut self) {
+        self.candidate = None;
+        self.last_emitted = None;
+    }
+
+    fn was_last_emitted(&self, value: &T) -> bool {
+        self.last_emitted
+            .as_ref()
+            .is_some_and(|(last_value, _)| last_value == value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::FakeClock;
+
+    const SETTLE: Duration = Duration::from_secs(15);
+    const MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+    fn debouncer() -> (Debouncer<u8, FakeClock>, FakeClock) {
+        let clock = FakeClock::default();
+        (Debouncer::new(SETTLE, MIN_INTERVAL, clock.clone()), clock)
+    }
+
+    #[test]
+    fn it_waits_for_a_value_to_settle() {
+        let (mut sut, clock) = debouncer();
+        assert_eq!(sut.observe(Some(1)), None);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(sut.observe(Some(1)), None);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(sut.observe(Some(1)), Some(1));
+    }
+
+    #[test]
+    fn it_emits_immediately_without_a_settle_period() {
+        let clock = FakeClock::default();
+        let mut sut = Debouncer::new(Duration::ZERO, Duration::ZERO, clock);
+        assert_eq!(sut.observe(Some(1)), Some(1));
+        assert_eq!(sut.observe(Some(2)), Some(2));
+    }
+
+    #[test]
+    fn it_only_emits_once() {
+        let (mut sut, clock) = debouncer();
+        sut.observe(Some(1));
+        clock.advance(SETTLE);
+        assert_eq!(sut.observe(Some(1)), Some(1));
+        clock.advance(MIN_INTERVAL * 2);
+        assert_eq!(sut.observe(Some(1)), None);
+    }
+
+    #[test]
+    fn it_restarts_settling_when_the_value_flaps() {
+        let (mut sut, clock) = debouncer();
+        sut.observe(Some(1));
+        clock.advance(Duration::from_secs(10));
+        sut.observe(Some(2));
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(sut.observe(Some(1)), None);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(sut.observe(Some(1)), None);
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(sut.observe(Some(1)), Some(1));
+    }
+
+    #[test]
+    fn it_restarts_settling_after_nothing_to_switch_to() {
+        let (mut sut, clock) = debouncer();
+        sut.observe(Some(1));
+        clock.advance(Duration::from_secs(10));
+        sut.observe(None);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(sut.observe(Some(1)), None);
+    }
+
+    #[test]
+    fn it_never_emits_nothing() {
+        let (mut sut, clock) = debouncer();
+        sut.observe(None);
+        clock.advance(SETTLE);
+        assert_eq!(sut.observe(None), None);
+    }
+
+    #[test]
+    fn it_enforces_a_minimum_interval_between_switches() {
+        let (mut sut, clock) = debouncer();
+        sut.observe(Some(1));
+        clock.advance(SETTLE);
+        assert_eq!(sut.observe(Some(1)), Some(1));
+
+        sut.observe(Some(2));
+        clock.advance(SETTLE);
+        assert_eq!(sut.observe(Some(2)), None);
+        clock.advance(Duration::from_secs(45));
+        assert_eq!(sut.observe(Some(2)), Some(2));
+    }
+
+    #[test]
+    fn it_does_not_switch_back_to_the_last_value() {
+        let (mut sut, clock) = debouncer();
+        sut.observe(Some(1));
+        clock.advance(SETTLE);
+        sut.observe(Some(1));
+
+        // A blip which never settles should not cause a switch back
+        sut.observe(Some(2));
+        clock.advance(Duration::from_secs(1));
+        sut.observe(Some(1));
+        clock.advance(MIN_INTERVAL);
+        assert_eq!(sut.observe(Some(1)), None);
+    }
+
+    #[test]
+    fn it_emits_again_after_reset() {
+        let (mut sut, clock) = debouncer();
+        sut.observe(Some(1));
+        clock.advance(SETTLE);
+        sut.observe(Some(1));
+
+        sut.reset();
+        sut.observe(Some(1));
+        clock.advance(SETTLE);
+        assert_eq!(sut.observe(Some(1)), Some(1));
+    }
+}
@@ -0,0 +1,433 @@
+use super::{
+    Plugin,
+    config::Automatic,
+    program::{Program, ProgramImpl},
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    process::Command,
+    sync::{Arc, Weak},
+};
+
+/// Named facts about the machine's surroundings, eg `on_ac`, which rules are evaluated against
+pub type Facts = BTreeMap<String, Fact>;
+
+/// The value of a fact, either true or false such as `on_ac`, or text such as `wifi_ssid`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fact {
+    Bool(bool),
+    Text(String),
+}
+
+impl Fact {
+    /// Whether the fact holds when used on its own in a rule, text is true when not empty
+    #[must_use]
+    pub fn is_true(&self) -> bool {
+        match self {
+            Self::Bool(value) => *value,
+            Self::Text(text) => !text.is_empty(),
+        }
+    }
+}
+
+impl From<bool> for Fact {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+
+impl From<String> for Fact {
+    fn from(text: String) -> Self {
+        Self::Text(text)
+    }
+}
+
+/// Something which can find out facts about the machine, usually by running a command line tool
+pub trait Detector: Send {
+    /// Names of the facts this detector provides
+    fn facts(&self) -> Vec<&str>;
+
+    /// Detect the current value of the facts, inserting them into `facts`
+    ///
+    /// # Errors
+    ///
+    /// If the underlying tool failed or gave output that could not be parsed
+    fn detect(&self, facts: &mut Facts) -> Result<(), Box<dyn Error>>;
+}
+
+/// Find the detectors required to provide every fact in `facts`, built-in ones first and then
+/// those registered by `plugins`
+///
+/// # Errors
+///
+/// If a fact is not provided by any of the detectors
+pub fn detectors_for(
+    facts: &BTreeSet<&str>,
+    automatic: &Automatic,
+    plugins: &[Arc<Plugin>],
+) -> Result<Vec<Box<dyn Detector>>, String> {
+    let mut detectors: Vec<Box<dyn Detector>> = vec![];
+    for fact in facts {
+        if detectors
+            .iter()
+            .any(|detector| detector.facts().contains(fact))
+        {
+            continue;
+        }
+        let detector: Box<dyn Detector> = match *fact {
+            "dock_autohide" => Box::new(DockAutohide),
+            "on_ac" => Box::new(PowerSource),
+            "external_display" => Box::new(Displays),
+            "wifi_ssid" => Box::new(WifiNetwork {
+                interface: automatic.wifi_interface().into(),
+            }),
+            "wired_network" => Box::new(NetworkInterfaces {
+                wifi_interface: automatic.wifi_interface().into(),
+            }),
+            _ => {
+                let plugin = plugins
+                    .iter()
+                    .find(|plugin| plugin.registration().facts.iter().any(|name| name == fact))
+                    .ok_or_else(|| format!("Unknown fact `{fact}` used in rules"))?;
+                Box::new(PluginFacts {
+                    facts: plugin.registration().facts.clone(),
+                    plugin: Arc::downgrade(plugin),
+                })
+            }
+        };
+        detectors.push(detector);
+    }
+
+    Ok(detectors)
+}
+
+/// Run all `detectors`, giving up on the first one to fail
+///
+/// # Errors
+///
+/// The error from the first detector which failed
+pub fn detect_all(detectors: &[Box<dyn Detector>]) -> Result<Facts, Box<dyn Error>> {
+    let mut facts = Facts::new();
+    for detector in detectors {
+        detector.detect(&mut facts)?;
+    }
+
+    Ok(facts)
+}
+
+/// Facts registered by a plugin, which is asked for them all at once
+///
+/// The plugin is not kept running by this, so it stops as soon as it is no longer configured.
+struct PluginFacts {
+    facts: Vec<String>,
+    plugin: Weak<Plugin>,
+}
+
+impl Detector for PluginFacts {
+    fn facts(&self) -> Vec<&str> {
+        self.facts.iter().map(String::as_str).collect()
+    }
+
+    fn detect(&self, facts: &mut Facts) -> Result<(), Box<dyn Error>> {
+        let plugin = self.plugin.upgrade().ok_or("The plugin has been stopped")?;
+        facts.extend(plugin.detect()?);
+        Ok(())
+    }
+}
+
+fn stdout_of(command: Command) -> Result<String, Box<dyn Error>> {
+    let output = ProgramImpl::new(command, 0).execute()?;
+    Ok(String::from_utf8_lossy(output.stdout()).into_owned())
+}
+
+struct DockAutohide;
+
+impl Detector for DockAutohide {
+    fn facts(&self) -> Vec<&str> {
+        vec!["dock_autohide"]
+    }
+
+    fn detect(&self, facts: &mut Facts) -> Result<(), Box<dyn Error>> {
+        facts.insert("dock_autohide".into(), super::dock_autohide()?.into());
+        Ok(())
+    }
+}
+
+struct PowerSource;
+
+impl Detector for PowerSource {
+    fn facts(&self) -> Vec<&str> {
+        vec!["on_ac"]
+    }
+
+    fn detect(&self, facts: &mut Facts) -> Result<(), Box<dyn Error>> {
+        let mut pmset = Command::new("pmset");
+        pmset.args(["-g", "batt"]);
+        facts.insert(
+            "on_ac".into(),
+            parse_pmset_on_ac(&stdout_of(pmset)?)?.into(),
+        );
+        Ok(())
+    }
+}
+
+/// `pmset -g batt` starts with a line such as `Now drawing from 'AC Power'`
+fn parse_pmset_on_ac(output: &str) -> Result<bool, String> {
+    let first_line = output.lines().next().unwrap_or_default();
+    if first_line.contains("'AC Power'") {
+        Ok(true)
+    } else if first_line.contains("'Battery Power'") || first_line.contains("'UPS Power'") {
+        Ok(false)
+    } else {
+        Err(format!("Unexpected output from `pmset`: {first_line:?}"))
+    }
+}
+
+struct Displays;
+
+impl Detector for Displays {
+    fn facts(&self) -> Vec<&str> {
+        vec!["external_display"]
+    }
+
+    fn detect(&self, facts: &mut Facts) -> Result<(), Box<dyn Error>> {
+        let mut system_profiler = Command::new("system_profiler");
+        system_profiler.arg("SPDisplaysDataType");
+        let external = parse_external_display(&stdout_of(system_profiler)?);
+        facts.insert("external_display".into(), external.into());
+        Ok(())
+    }
+}
+
+/// Looks through each display listed under `Displays:` in the output of
+/// `system_profiler SPDisplaysDataType` for one which is not built-in
+fn parse_external_display(output: &str) -> bool {
+    let indent_of = |line: &str| line.len() - line.trim_start().len();
+
+    // (indent of `Displays:`, indent of each display's name)
+    let mut section: Option<(usize, Option<usize>)> = None;
+    // Whether each display found so far is built-in
+    let mut displays: Vec<bool> = vec![];
+    for line in output.lines().filter(|line| !line.trim().is_empty()) {
+        let indent = indent_of(line);
+        let line = line.trim();
+        match section {
+            Some((section_indent, _)) if indent <= section_indent => section = None,
+            Some((_, ref mut name_indent)) => {
+                let name_indent = *name_indent.get_or_insert(indent);
+                if indent == name_indent {
+                    displays.push(false);
+                } else if let Some(built_in) = displays.last_mut() {
+                    let lowercase = line.to_ascii_lowercase();
+                    *built_in |= lowercase == "connection type: internal"
+                        || (lowercase.starts_with("display type:")
+                            && lowercase.contains("built-in"));
+                }
+                continue;
+            }
+            None => (),
+        }
+        if line == "Displays:" {
+            section = Some((indent, None));
+        }
+    }
+
+    displays.iter().any(|built_in| !built_in)
+}
+
+struct WifiNetwork {
+    interface: String,
+}
+
+impl Detector for WifiNetwork {
+    fn facts(&self) -> Vec<&str> {
+        vec!["wifi_ssid"]
+    }
+
+    /// `wifi_ssid` is left out of `facts` when not associated with a network
+    fn detect(&self, facts: &mut Facts) -> Result<(), Box<dyn Error>> {
+        let mut networksetup = Command::new("networksetup");
+        networksetup.args(["-getairportnetwork", &self.interface]);
+        let mut ssid = parse_networksetup_ssid(&stdout_of(networksetup)?)?;
+
+        // Recent versions of macOS claim not to be associated with any network in
+        // `networksetup`, for privacy reasons, so also check with `ipconfig`
+        if ssid.is_none() {
+            let mut ipconfig = Command::new("ipconfig");
+            ipconfig.args(["getsummary", &self.interface]);
+            ssid = parse_ipconfig_ssid(&stdout_of(ipconfig)?);
+        }
+
+        if let Some(ssid) = ssid {
+            facts.insert("wifi_ssid".into(), ssid.into());
+        }
+        Ok(())
+    }
+}
+
+/// `networksetup -getairportnetwork` prints a single line such as `Current Wi-Fi Network: Home`,
+/// which is localised, or a sentence without a colon when not associated with a network
+fn parse_networksetup_ssid(output: &str) -> Result<Option<String>, String> {
+    let first_line = output.lines().next().unwrap_or_default().trim();
+    if output.lines().any(|line| line.starts_with("** Error")) {
+        return Err(format!(
+            "Unexpected output from `networksetup`: {first_line:?}"
+        ));
+    }
+
+    Ok(first_line
+        .split_once(':')
+        .map(|(_, ssid)| ssid.trim())
+        .filter(|ssid| !ssid.is_empty())
+        .map(String::from))
+}
+
+/// `ipconfig getsummary` prints a dictionary with a `SSID : <name>` line when associated
+fn parse_ipconfig_ssid(output: &str) -> Option<String> {
+    output
+        .lines()
+        .filter_map(|line| line.split_once(" : "))
+        .find(|(key, _)| key.trim() == "SSID")
+        .map(|(_, ssid)| ssid.trim().to_string())
+        .filter(|ssid| !ssid.is_empty())
+}
+
+struct NetworkInterfaces {
+    wifi_interface: String,
+}
+
+impl Detector for NetworkInterfaces {
+    fn facts(&self) -> Vec<&str> {
+        vec!["wired_network"]
+    }
+
+    fn detect(&self, facts: &mut Facts) -> Result<(), Box<dyn Error>> {
+        let output = stdout_of(Command::new("ifconfig"))?;
+        let wired = parse_ifconfig_active(&output)
+            .iter()
+            .any(|interface| interface.starts_with("en") && *interface != self.wifi_interface);
+        facts.insert("wired_network".into(), wired.into());
+        Ok(())
+    }
+}
+
+/// Names of the interfaces which `ifconfig` reports as `status: active`
+fn parse_ifconfig_active(output: &str) -> Vec<&str> {
+    let mut active = vec![];
+    let mut interface = None;
+    for line in output.lines() {
+        if !line.starts_with(char::is_whitespace) {
+            interface = line.split_once(':').map(|(name, _)| name);
+        } else if line.trim() == "status: active" {
+            active.extend(interface);
+        }
+    }
+
+    active
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_detects_ac_power() {
+        let output = include_str!("../tests/fixtures/pmset_ac.txt");
+        assert_eq!(parse_pmset_on_ac(output), Ok(true));
+    }
+
+    #[test]
+    fn it_detects_battery_power() {
+        let output = include_str!("../tests/fixtures/pmset_battery.txt");
+        assert_eq!(parse_pmset_on_ac(output), Ok(false));
+    }
+
+    #[test]
+    fn it_rejects_unexpected_pmset_output() {
+        assert!(parse_pmset_on_ac("").is_err());
+    }
+
+    #[test]
+    fn it_detects_an_external_display() {
+        let output = include_str!("../tests/fixtures/system_profiler_displays_external.txt");
+        assert!(parse_external_display(output));
+    }
+
+    #[test]
+    fn it_detects_an_external_display_in_clamshell_mode() {
+        let output = include_str!("../tests/fixtures/system_profiler_displays_clamshell.txt");
+        assert!(parse_external_display(output));
+    }
+
+    #[test]
+    fn it_ignores_the_built_in_display() {
+        let output = include_str!("../tests/fixtures/system_profiler_displays_internal.txt");
+        assert!(!parse_external_display(output));
+    }
+
+    #[test]
+    fn it_finds_the_ssid() {
+        let output = include_str!("../tests/fixtures/networksetup_associated.txt");
+        assert_eq!(
+            parse_networksetup_ssid(output),
+            Ok(Some("Office WiFi".into()))
+        );
+    }
+
+    #[test]
+    fn it_finds_the_ssid_when_localised() {
+        let output = include_str!("../tests/fixtures/networksetup_associated_fr.txt");
+        assert_eq!(
+            parse_networksetup_ssid(output),
+            Ok(Some("Café du Coin".into()))
+        );
+    }
+
+    #[test]
+    fn it_finds_no_ssid_when_not_associated() {
+        let output = include_str!("../tests/fixtures/networksetup_not_associated.txt");
+        assert_eq!(parse_networksetup_ssid(output), Ok(None));
+        let output = include_str!("../tests/fixtures/networksetup_power_off.txt");
+        assert_eq!(parse_networksetup_ssid(output), Ok(None));
+    }
+
+    #[test]
+    fn it_rejects_an_interface_which_is_not_wifi() {
+        let output = include_str!("../tests/fixtures/networksetup_not_wifi.txt");
+        assert!(parse_networksetup_ssid(output).is_err());
+    }
+
+    #[test]
+    fn it_finds_the_ssid_from_ipconfig() {
+        let output = include_str!("../tests/fixtures/ipconfig_getsummary_associated.txt");
+        assert_eq!(parse_ipconfig_ssid(output), Some("Office WiFi".into()));
+    }
+
+    #[test]
+    fn it_finds_no_ssid_from_ipconfig_when_not_associated() {
+        let output = include_str!("../tests/fixtures/ipconfig_getsummary_not_associated.txt");
+        assert_eq!(parse_ipconfig_ssid(output), None);
+    }
+
+    #[test]
+    fn it_finds_active_interfaces() {
+        let output = include_str!("../tests/fixtures/ifconfig_wired.txt");
+        assert_eq!(parse_ifconfig_active(output), vec!["en0", "en7"]);
+        let output = include_str!("../tests/fixtures/ifconfig_wifi_only.txt");
+        assert_eq!(parse_ifconfig_active(output), vec!["en0"]);
+    }
+
+    #[test]
+    fn it_finds_detectors_for_facts() {
+        let facts = BTreeSet::from(["on_ac", "external_display", "wifi_ssid"]);
+        let detectors = detectors_for(&facts, &Automatic::default(), &[]).unwrap();
+        assert_eq!(detectors.len(), 3);
+    }
+
+    #[test]
+    fn it_rejects_unknown_facts() {
+        let facts = BTreeSet::from(["on_the_moon"]);
+        assert!(detectors_for(&facts, &Automatic::default(), &[]).is_err());
+    }
+}
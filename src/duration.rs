@@ -0,0 +1,228 @@
+use std::{
+    fmt::{Display, Formatter},
+    str::FromStr,
+    time::Duration,
+};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Longest duration parsed, well within what can be added to the current time
+pub const MAX: Duration = Duration::from_secs(366 * SECONDS_PER_DAY);
+
+/// Parse a human friendly duration as used in config.toml, eg `"30s"`, `"15m"`, `"1h30m"`
+///
+/// A bare number is taken to be seconds.
+///
+/// # Errors
+///
+/// If the string is empty, has an unknown unit, a number is malformed or it is longer than `MAX`
+pub fn parse(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let duration = parse_unbounded(s)?;
+    if duration > MAX {
+        return Err(format!("Duration `{s}` should be at most a year"));
+    }
+    Ok(duration)
+}
+
+fn parse_unbounded(s: &str) -> Result<Duration, String> {
+    if s.is_empty() {
+        return Err("Duration should not be empty".into());
+    }
+    if let Ok(seconds) = s.parse::<u64>() {
+        return Ok(Duration::from_secs(seconds));
+    }
+
+    let mut total = Duration::ZERO;
+    let mut digits = String::new();
+    for c in s.chars() {
+        if c.is_ascii_digit() {
+            digits.push(c);
+            continue;
+        }
+
+        let multiplier = match c {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => return Err(format!("Unknown unit `{c}` in duration `{s}`")),
+        };
+        let value = digits
+            .parse::<u64>()
+            .map_err(|_| format!("Missing number before `{c}` in duration `{s}`"))?;
+        total = value
+            .checked_mul(multiplier)
+            .and_then(|seconds| total.checked_add(Duration::from_secs(seconds)))
+            .ok_or_else(|| format!("Duration `{s}` is too long"))?;
+        digits.clear();
+    }
+    if !digits.is_empty() {
+        return Err(format!("Missing unit after `{digits}` in duration `{s}`"));
+    }
+
+    Ok(total)
+}
+
+/// Format the time remaining compactly for display in the menu, eg `1h 05m` or `14m`
+#[must_use]
+pub fn format_remaining(duration: Duration) -> String {
+    let minutes = duration.as_secs().div_ceil(60);
+    let (hours, minutes) = (minutes / 60, minutes % 60);
+    if hours > 0 {
+        format!("{hours}h {minutes:02}m")
+    } else {
+        format!("{minutes}m")
+    }
+}
+
+/// A wall clock time in the local time zone, eg `18:00`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct TimeOfDay {
+    hour: u8,
+    minute: u8,
+}
+
+impl TimeOfDay {
+    /// End of the working day
+    pub const EVENING: Self = Self {
+        hour: 18,
+        minute: 0,
+    };
+
+    /// How long from now until this time next occurs
+    #[must_use]
+    pub fn from_now(self) -> Duration {
+        self.from(seconds_since_local_midnight())
+    }
+
+    /// How long from `now`, in seconds since midnight, until this time next occurs
+    ///
+    /// At exactly this time it next occurs tomorrow, rather than straight away.
+    const fn from(self, now: u64) -> Duration {
+        let target = (self.hour as u64 * 60 + self.minute as u64) * 60;
+        let seconds = (target + SECONDS_PER_DAY - now % SECONDS_PER_DAY) % SECONDS_PER_DAY;
+        Duration::from_secs(if seconds == 0 {
+            SECONDS_PER_DAY
+        } else {
+            seconds
+        })
+    }
+}
+
+impl Display for TimeOfDay {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:02}:{:02}", self.hour, self.minute)
+    }
+}
+
+impl FromStr for TimeOfDay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("Time `{s}` should be in 24 hour `HH:MM` format");
+        let (hour, minute) = s.trim().split_once(':').ok_or_else(error)?;
+        let hour = hour.parse().map_err(|_| error())?;
+        let minute = minute.parse().map_err(|_| error())?;
+        if hour > 23 || minute > 59 {
+            return Err(error());
+        }
+
+        Ok(Self { hour, minute })
+    }
+}
+
+fn seconds_since_local_midnight() -> u64 {
+    // SAFETY: `time` accepts a null pointer and `localtime_r` only writes to the `tm` given
+    let tm = unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm = std::mem::zeroed::<libc::tm>();
+        libc::localtime_r(&raw const now, &raw mut tm);
+        tm
+    };
+    let seconds = (tm.tm_hour * 60 + tm.tm_min) * 60 + tm.tm_sec;
+
+    u64::try_from(seconds).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_parses_bare_seconds() {
+        assert_eq!(parse("90"), Ok(Duration::from_secs(90)));
+    }
+
+    #[test]
+    fn it_parses_units() {
+        assert_eq!(parse("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse("15m"), Ok(Duration::from_secs(15 * 60)));
+        assert_eq!(parse("2h"), Ok(Duration::from_secs(2 * 60 * 60)));
+        assert_eq!(parse("1d"), Ok(Duration::from_secs(24 * 60 * 60)));
+    }
+
+    #[test]
+    fn it_parses_compound_durations() {
+        assert_eq!(parse(" 1h30m "), Ok(Duration::from_secs(90 * 60)));
+    }
+
+    #[test]
+    fn it_rejects_malformed_durations() {
+        assert!(parse("").is_err());
+        assert!(parse("m").is_err());
+        assert!(parse("10x").is_err());
+        assert!(parse("1h30").is_err());
+    }
+
+    #[test]
+    fn it_rejects_durations_which_overflow() {
+        assert!(parse("999999999999999999d").is_err());
+        assert!(parse("18446744073709551615s1s").is_err());
+    }
+
+    #[test]
+    fn it_rejects_durations_longer_than_a_year() {
+        assert_eq!(parse("366d"), Ok(MAX));
+        assert!(parse("367d").is_err());
+        assert!(parse("18446744073709551615").is_err());
+    }
+
+    #[test]
+    fn it_formats_remaining_time() {
+        assert_eq!(format_remaining(Duration::from_secs(42)), "1m");
+        assert_eq!(format_remaining(Duration::from_secs(14 * 60)), "14m");
+        assert_eq!(format_remaining(Duration::from_secs(3900)), "1h 05m");
+    }
+
+    #[test]
+    fn it_parses_time_of_day() {
+        let time: TimeOfDay = "18:00".parse().unwrap();
+        assert_eq!(time.to_string(), "18:00");
+        assert_eq!("7:05".parse::<TimeOfDay>().unwrap().to_string(), "07:05");
+        assert!("24:00".parse::<TimeOfDay>().is_err());
+        assert!("18".parse::<TimeOfDay>().is_err());
+        assert!("six:00".parse::<TimeOfDay>().is_err());
+    }
+
+    #[test]
+    fn it_finds_time_until_later_today() {
+        let time: TimeOfDay = "18:00".parse().unwrap();
+        let now = 17 * 60 * 60 + 30 * 60;
+        assert_eq!(time.from(now), Duration::from_secs(30 * 60));
+    }
+
+    #[test]
+    fn it_finds_time_until_tomorrow() {
+        let time: TimeOfDay = "09:00".parse().unwrap();
+        let now = 18 * 60 * 60;
+        assert_eq!(time.from(now), Duration::from_secs(15 * 60 * 60));
+    }
+
+    #[test]
+    fn it_finds_time_until_tomorrow_at_exactly_the_time() {
+        let time: TimeOfDay = "18:00".parse().unwrap();
+        let now = 18 * 60 * 60;
+        assert_eq!(time.from(now), Duration::from_secs(24 * 60 * 60));
+    }
+}
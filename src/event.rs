@@ -0,0 +1,219 @@
+use super::Mode;
+use serde_json::{Value, json};
+use std::{
+    sync::{
+        Arc, Mutex, PoisonError,
+        mpsc::{self, Receiver, Sender},
+    },
+    time::SystemTime,
+};
+
+/// Something which has happened to the app, pushed to subscribers such as `lod watch`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// Switched into `mode` from `previous`
+    ModeChanged { mode: Mode, previous: Mode },
+
+    /// Started caffeinating, until the given time if it will stop by itself
+    CaffeinateStarted(Option<SystemTime>),
+
+    /// Stopped caffeinating, whether asked to or as its time was up
+    CaffeinateStopped,
+
+    /// Something lod tried to do failed, eg running the mode's `AppleScript`
+    ActionFailed { action: &'static str, error: String },
+
+    /// config.toml was loaded again
+    ConfigReloaded,
+
+    /// Sent by a plugin, with whatever data it chose
+    PluginEvent {
+        plugin: String,
+        name: String,
+        data: Value,
+    },
+}
+
+impl Event {
+    /// Names of the events, as given in `event` of their JSON
+    pub const NAMES: [&str; 6] = [
+        "mode_changed",
+        "caffeinate_started",
+        "caffeinate_stopped",
+        "action_failed",
+        "config_reloaded",
+        "plugin_event",
+    ];
+
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::ModeChanged { .. } => "mode_changed",
+            Self::CaffeinateStarted(_) => "caffeinate_started",
+            Self::CaffeinateStopped => "caffeinate_stopped",
+            Self::ActionFailed { .. } => "action_failed",
+            Self::ConfigReloaded => "config_reloaded",
+            Self::PluginEvent { .. } => "plugin_event",
+        }
+    }
+
+    #[must_use]
+    pub fn to_json(&self) -> Value {
+        match self {
+            Self::ModeChanged { mode, previous } => json!({
+                "event": "mode_changed",
+                "mode": mode.name(),
+                "previous": previous.name(),
+            }),
+            Self::CaffeinateStarted(expires_at) => {
+                let expires_in = expires_at.map(|expires_at| {
+                    expires_at
+                        .duration_since(SystemTime::now())
+                        .unwrap_or_default()
+                        .as_secs()
+                });
+                json!({ "event": "caffeinate_started", "expires_in_secs": expires_in })
+            }
+            Self::CaffeinateStopped => json!({ "event": "caffeinate_stopped" }),
+            Self::ActionFailed { action, error } => {
+                json!({ "event": "action_failed", "action": action, "error": error })
+            }
+            Self::ConfigReloaded => json!({ "event": "config_reloaded" }),
+            Self::PluginEvent { plugin, name, data } => json!({
+                "event": "plugin_event",
+                "plugin": plugin,
+                "name": name,
+                "data": data,
+            }),
+        }
+    }
+}
+
+/// Sends each `Event` to every subscriber, shared between the main thread and the control socket
+#[derive(Clone, Debug, Default)]
+pub struct Events {
+    subscribers: Arc<Mutex<Vec<Sender<Event>>>>,
+}
+
+impl Events {
+    #[must_use]
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(sender);
+        receiver
+    }
+
+    /// Send the event to the subscribers, forgetting those which have gone
+    pub fn emit(&self, event: &Event) {
+        self.subscribers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn it_sends_events_to_each_subscriber() {
+        let sut = Events::default();
+        let first = sut.subscribe();
+        let second = sut.subscribe();
+
+        let event = Event::ModeChanged {
+            mode: Mode::Laptop,
+            previous: Mode::Desktop,
+        };
+        sut.emit(&event);
+        assert_eq!(first.try_recv(), Ok(event.clone()));
+        assert_eq!(second.try_recv(), Ok(event));
+    }
+
+    #[test]
+    fn it_forgets_subscribers_which_have_gone() {
+        let sut = Events::default();
+        drop(sut.subscribe());
+        let remaining = sut.subscribe();
+
+        sut.emit(&Event::ConfigReloaded);
+        assert_eq!(sut.subscribers.lock().unwrap().len(), 1);
+        assert_eq!(remaining.try_recv(), Ok(Event::ConfigReloaded));
+    }
+
+    #[test]
+    fn it_names_every_event() {
+        let events = [
+            Event::ModeChanged {
+                mode: Mode::Desktop,
+                previous: Mode::Laptop,
+            },
+            Event::CaffeinateStarted(None),
+            Event::CaffeinateStopped,
+            Event::ActionFailed {
+                action: "run_applescript",
+                error: String::new(),
+            },
+            Event::ConfigReloaded,
+            Event::PluginEvent {
+                plugin: "vpn".into(),
+                name: "connected".into(),
+                data: Value::Null,
+            },
+        ];
+        for event in &events {
+            assert_eq!(event.to_json()["event"], event.name());
+        }
+        assert_eq!(events.map(|event| event.name()), Event::NAMES);
+    }
+
+    #[test]
+    fn it_serialises_to_json() {
+        assert_eq!(
+            Event::ModeChanged {
+                mode: Mode::Desktop,
+                previous: Mode::Laptop
+            }
+            .to_json(),
+            json!({ "event": "mode_changed", "mode": "desktop", "previous": "laptop" })
+        );
+        assert_eq!(
+            Event::CaffeinateStarted(None).to_json(),
+            json!({ "event": "caffeinate_started", "expires_in_secs": null })
+        );
+        let expires_at = SystemTime::now() + Duration::from_secs(60);
+        assert!(
+            Event::CaffeinateStarted(Some(expires_at)).to_json()["expires_in_secs"]
+                .as_u64()
+                .unwrap()
+                <= 60
+        );
+        assert_eq!(
+            Event::ActionFailed {
+                action: "run_applescript",
+                error: "not found".into()
+            }
+            .to_json(),
+            json!({ "event": "action_failed", "action": "run_applescript", "error": "not found" })
+        );
+        assert_eq!(
+            Event::PluginEvent {
+                plugin: "vpn".into(),
+                name: "connected".into(),
+                data: json!({ "server": "office" }),
+            }
+            .to_json(),
+            json!({
+                "event": "plugin_event",
+                "plugin": "vpn",
+                "name": "connected",
+                "data": { "server": "office" },
+            })
+        );
+    }
+}
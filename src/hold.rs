@@ -0,0 +1,351 @@
+use super::{
+    KeepAwake,
+    program::{Program, ProgramImpl},
+    waiting_child,
+};
+use std::{
+    error::Error,
+    fmt::{Display, Formatter},
+    path::Path,
+    process::{self, Command, ExitStatus, Stdio},
+};
+
+/// A process as listed by `ps`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Process {
+    pid: u32,
+    tty: Option<String>,
+    started: String,
+    command: String,
+}
+
+impl Process {
+    #[must_use]
+    pub const fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Name of the executable, without its path or arguments
+    ///
+    /// This is taken from the command up to the first space, as `ps` does not quote it, so the
+    /// name of an executable whose path has a space in it is cut short, eg `/Applications/My`.
+    #[must_use]
+    pub fn name(&self) -> &str {
+        let program = self.command.split_whitespace().next().unwrap_or_default();
+        Path::new(program)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(program)
+    }
+
+    /// When the process started, as given by `ps -o lstart=`
+    #[must_use]
+    pub fn started(&self) -> &str {
+        &self.started
+    }
+
+    /// The command line, with arguments separated by spaces
+    #[must_use]
+    pub fn command(&self) -> &str {
+        &self.command
+    }
+
+    /// Whether the process was started from a terminal, eg a build or `rsync`
+    #[must_use]
+    pub const fn has_terminal(&self) -> bool {
+        self.tty.is_some()
+    }
+
+    /// The pid this process is keeping the computer awake for, if it was started by
+    /// `KeepAwake::command_while`, eg `caffeinate -w <pid>`
+    ///
+    /// The command is matched against the program as configured, so that a path with a space in
+    /// it is recognised, otherwise against its name.
+    fn waiting_for(&self, keep_awake: &KeepAwake) -> Option<u32> {
+        let program = keep_awake.program();
+        let args = match self.command.strip_prefix(program) {
+            Some(args) if args.starts_with(' ') => args,
+            _ if self.name() == executable_name(program) => {
+                self.command.split_once(' ').map_or("", |(_, args)| args)
+            }
+            _ => return None,
+        };
+        let mut args = args.split_whitespace();
+        args.find(|arg| *arg == keep_awake.hold_marker())?;
+        args.next()?.parse().ok()
+    }
+}
+
+/// Caffeination which lasts only as long as another process is running
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hold {
+    caffeinate_pid: u32,
+    pid: u32,
+    name: String,
+}
+
+impl Hold {
+    #[must_use]
+    pub const fn new(caffeinate_pid: u32, pid: u32, name: String) -> Self {
+        Self {
+            caffeinate_pid,
+            pid,
+            name,
+        }
+    }
+
+    /// Pid of the `caffeinate` keeping the Mac awake, which is what to kill to release the hold
+    #[must_use]
+    pub const fn caffeinate_pid(&self) -> u32 {
+        self.caffeinate_pid
+    }
+
+    /// Pid of the process being waited for
+    #[must_use]
+    pub const fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Name of the process being waited for
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Display for Hold {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.name, self.pid)
+    }
+}
+
+/// List the current user's processes
+///
+/// # Errors
+///
+/// If `ps` could not be run
+pub fn processes() -> Result<Vec<Process>, Box<dyn Error>> {
+    let mut ps = Command::new("ps");
+    ps.args(["-x", "-o", "pid=,tty=,lstart=,command="]);
+    let output = ProgramImpl::new(ps, 0).execute()?;
+
+    Ok(parse_ps(&String::from_utf8_lossy(output.stdout())))
+}
+
+/// Processes which could be picked to keep the computer awake for, ie those started from a
+/// terminal other than whatever keeps it awake and this process
+#[must_use]
+pub fn candidates<'a>(processes: &'a [Process], keep_awake: &KeepAwake) -> Vec<&'a Process> {
+    let keep_awake_name = executable_name(keep_awake.program());
+    processes
+        .iter()
+        .filter(|process| {
+            process.has_terminal()
+                && process.name() != keep_awake_name
+                && process.pid != process::id()
+        })
+        .collect()
+}
+
+/// Holds found in the list of processes, eg those started by `lod caffeinate -- <command>`
+#[must_use]
+pub fn holds(processes: &[Process], keep_awake: &KeepAwake) -> Vec<Hold> {
+    processes
+        .iter()
+        .filter_map(|caffeinate| {
+            let pid = caffeinate.waiting_for(keep_awake)?;
+            let name = processes
+                .iter()
+                .find(|process| process.pid == pid)
+                .map_or_else(|| String::from("Process"), |process| process.name().into());
+            Some(Hold::new(caffeinate.pid, pid, name))
+        })
+        .collect()
+}
+
+/// Run `command`, keeping the computer awake until it exits
+///
+/// The keep awake command waits for the pid, eg `caffeinate -w <pid>`, so that it also stops by
+/// itself should we be killed.
+///
+/// # Errors
+///
+/// If `command` is empty or either it or the keep awake command could not be started
+pub fn keep_awake_while_running(
+    keep_awake: &KeepAwake,
+    command: &[String],
+) -> Result<ExitStatus, Box<dyn Error>> {
+    let (program, args) = command
+        .split_first()
+        .ok_or("Expected a command to run, eg `lod caffeinate -- make`")?;
+    let mut child = Command::new(program)
+        .args(args)
+        .spawn()
+        .map_err(|error| format!("Failed to run `{program}`: {error}"))?;
+
+    let mut caffeinate = keep_awake.command_while(child.id());
+    caffeinate.stdout(Stdio::null()).stderr(Stdio::null());
+    let mut caffeinate = match caffeinate.spawn() {
+        Ok(caffeinate) => Some(caffeinate),
+        Err(error) => {
+            eprintln!(
+                "Failed to start {}, running without it: {error:?}",
+                keep_awake.program()
+            );
+            None
+        }
+    };
+
+    let status = child.wait()?;
+    if let Some(caffeinate) = caffeinate.as_mut() {
+        // It should already be on its way out, but do not leave it, or what it runs, behind if not
+        let _ = waiting_child::send_signal(caffeinate.id(), libc::SIGKILL);
+        let _ = caffeinate.wait();
+    }
+
+    Ok(status)
+}
+
+fn executable_name(path: &str) -> &str {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(path)
+}
+
+/// `ps -o pid=,tty=,lstart=,command=` gives lines such as
+/// `  981 ttys000  Mon Oct 19 09:01:40 2026     cargo build`, where processes without a terminal
+/// have `??` as their tty and the start time is always five fields
+fn parse_ps(output: &str) -> Vec<Process> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut rest = line.trim_start();
+            let mut fields = Vec::with_capacity(7);
+            while fields.len() < 7 {
+                let (field, remainder) = rest.split_once(char::is_whitespace)?;
+                fields.push(field);
+                rest = remainder.trim_start();
+            }
+            let command = rest.trim();
+            if command.is_empty() {
+                return None;
+            }
+            let tty = fields[1];
+            Some(Process {
+                pid: fields[0].parse().ok()?,
+                tty: (tty != "??" && tty != "?").then(|| tty.into()),
+                started: fields[2..].join(" "),
+                command: command.into(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn fixture() -> Vec<Process> {
+        parse_ps(include_str!("../tests/fixtures/ps.txt"))
+    }
+
+    #[test]
+    fn it_parses_ps() {
+        let processes = fixture();
+        assert_eq!(processes.len(), 7);
+        assert_eq!(
+            processes[2],
+            Process {
+                pid: 1204,
+                tty: Some("ttys000".into()),
+                started: "Mon Oct 19 09:14:03 2026".into(),
+                command: "cargo build --release".into(),
+            }
+        );
+        assert_eq!(processes[0].name(), "Finder");
+        assert!(!processes[0].has_terminal());
+        assert_eq!(processes[6].started(), "Mon Oct 19 9:30:00 2026");
+        assert!(parse_ps(" 412 ??       Mon Oct 19 08:58:12 2026\n").is_empty());
+    }
+
+    #[test]
+    fn it_lists_terminal_processes_as_candidates() {
+        let processes = fixture();
+        let names: Vec<_> = candidates(&processes, &KeepAwake::caffeinate("caffeinate", vec![]))
+            .into_iter()
+            .map(Process::name)
+            .collect();
+        assert_eq!(names, ["-zsh", "cargo", "rsync"]);
+    }
+
+    #[test]
+    fn it_finds_holds() {
+        let processes = fixture();
+        assert_eq!(
+            holds(
+                &processes,
+                &KeepAwake::caffeinate("/usr/bin/caffeinate", vec![])
+            ),
+            [
+                Hold::new(1302, 1210, "rsync".into()),
+                Hold::new(1305, 1204, "cargo".into()),
+                Hold::new(1311, 4242, "Process".into()),
+            ]
+        );
+        assert_eq!(
+            holds(&processes, &KeepAwake::caffeinate("caffeinate", vec![]))[0].to_string(),
+            "rsync (1210)"
+        );
+    }
+
+    #[test]
+    fn it_finds_holds_kept_by_systemd_inhibit() {
+        let processes = parse_ps(
+            "  2001 ?        Mon Oct 19 09:20:56 2026 systemd-inhibit --what=idle:sleep --who=lod --why=Keeping awake while \
+             a process runs sh -c while kill -0 \"$1\" 2>/dev/null; do sleep 1; done lod-hold 1210\n\
+             2002 ?        Mon Oct 19 09:20:56 2026 sh -c while kill -0 \"$1\" 2>/dev/null; do sleep 1; done lod-hold 1210\n\
+             1210 pts/1    Mon Oct 19 09:20:55 2026 rsync -a src/ backup:/src/\n",
+        );
+        assert_eq!(
+            holds(&processes, &KeepAwake::systemd_inhibit("systemd-inhibit")),
+            [Hold::new(2001, 1210, "rsync".into())]
+        );
+    }
+
+    #[test]
+    fn it_cuts_names_short_at_a_space() {
+        let processes = parse_ps(
+            " 2001 ??       Mon Oct 19 09:20:56 2026 /Applications/Keep Awake.app/Contents/MacOS/caffeinate -w 1210\n",
+        );
+        assert_eq!(processes[0].name(), "Keep");
+    }
+
+    #[test]
+    fn it_finds_holds_kept_by_a_program_with_a_space_in_its_path() {
+        let processes = parse_ps(
+            " 2001 ??       Mon Oct 19 09:20:56 2026 /Applications/Keep Awake.app/Contents/MacOS/caffeinate -w 1210\n",
+        );
+        let keep_awake = KeepAwake::caffeinate(
+            "/Applications/Keep Awake.app/Contents/MacOS/caffeinate",
+            vec![],
+        );
+        assert_eq!(
+            holds(&processes, &keep_awake),
+            [Hold::new(2001, 1210, "Process".into())]
+        );
+    }
+
+    #[test]
+    fn it_returns_the_exit_status_of_the_command() {
+        let command = ["sh", "-c", "exit 3"].map(String::from);
+        let status =
+            keep_awake_while_running(&KeepAwake::caffeinate("true", vec![]), &command).unwrap();
+        assert_eq!(status.code(), Some(3));
+    }
+
+    #[test]
+    fn it_requires_a_command() {
+        assert!(keep_awake_while_running(&KeepAwake::caffeinate("true", vec![]), &[]).is_err());
+    }
+}
@@ -0,0 +1,520 @@
+use super::{
+    Commands, Mode, Script, ScriptContext,
+    detector::Facts,
+    program::{self, Program, ProgramImpl},
+};
+use serde_json::{Value, json};
+use std::{
+    io::{self, Read, Write},
+    os::unix::process::CommandExt,
+    path::Path,
+    process::{Command, Output, Stdio},
+    str::FromStr,
+    sync::{
+        Arc,
+        mpsc::{self, Receiver},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long each hook command can run for before it is killed, unless configured otherwise
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often a running hook command is checked on
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// When a hook's actions are run
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Hook {
+    /// Before switching mode, where an action exiting non-zero stops the switch
+    BeforeSwitch,
+
+    /// Once the mode's `AppleScript` has run successfully
+    AfterSwitch,
+
+    /// When a `before_switch` action or the mode's `AppleScript` failed
+    OnSwitchFailed,
+
+    /// Once caffeination has started, however it was asked for
+    OnCaffeinateStart,
+
+    /// Once caffeination has stopped, whether it was stopped or its time was up
+    OnCaffeinateStop,
+}
+
+impl Hook {
+    /// Name used in the `[hooks]` table of config.toml
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::BeforeSwitch => "before_switch",
+            Self::AfterSwitch => "after_switch",
+            Self::OnSwitchFailed => "on_switch_failed",
+            Self::OnCaffeinateStart => "on_caffeinate_start",
+            Self::OnCaffeinateStop => "on_caffeinate_stop",
+        }
+    }
+}
+
+impl FromStr for Hook {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            Self::BeforeSwitch,
+            Self::AfterSwitch,
+            Self::OnSwitchFailed,
+            Self::OnCaffeinateStart,
+            Self::OnCaffeinateStop,
+        ]
+        .into_iter()
+        .find(|hook| hook.name() == s)
+        .ok_or_else(|| format!("Unknown hook `{s}`"))
+    }
+}
+
+/// What has happened, given to each action in `LOD_*` environment variables and a JSON report
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HookContext {
+    pub mode: Mode,
+    pub previous_mode: Option<Mode>,
+    pub caffeinating: bool,
+    /// What lod did, eg `{"action": "run_applescript", "ok": true}`
+    pub actions: Vec<Value>,
+    /// Detected on the latest poll for automatic switching, for scripts
+    pub facts: Facts,
+}
+
+impl HookContext {
+    fn report(&self, hook: Hook) -> Value {
+        json!({
+            "hook": hook.name(),
+            "mode": self.mode.name(),
+            "previous_mode": self.previous_mode.map(|mode| mode.name()),
+            "caffeinating": self.caffeinating,
+            "actions": self.actions,
+        })
+    }
+}
+
+/// Something to do when a hook runs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HookAction {
+    /// A program and its arguments
+    Command(Vec<String>),
+
+    /// A script, where throwing an error fails the action
+    Script(Script),
+}
+
+impl HookAction {
+    fn run(
+        &self,
+        hook: Hook,
+        context: &HookContext,
+        report: &Path,
+        timeout: Duration,
+    ) -> Result<(), String> {
+        match self {
+            Self::Command(argv) => {
+                let Some((program, arguments)) = argv.split_first() else {
+                    return Ok(());
+                };
+                let mut command = Command::new(program);
+                command
+                    .args(arguments)
+                    .env("LOD_HOOK", hook.name())
+                    .env("LOD_MODE", context.mode.name())
+                    .env(
+                        "LOD_PREVIOUS_MODE",
+                        context.previous_mode.map_or("", |mode| mode.name()),
+                    )
+                    .env(
+                        "LOD_CAFFEINATING",
+                        if context.caffeinating { "1" } else { "0" },
+                    )
+                    .env("LOD_REPORT", report);
+                println!("Running {} hook `{}`", hook.name(), argv.join(" "));
+                ProgramImpl::new(TimedCommand { command, timeout }, 0)
+                    .execute()
+                    .map_err(|error| match error {
+                        program::Error::Io(error) if error.kind() == io::ErrorKind::TimedOut => {
+                            format!("`{}` timed out after {timeout:?}", argv.join(" "))
+                        }
+                        error => format!("`{}` failed: {error:?}", argv.join(" ")),
+                    })
+                    .map(drop)
+            }
+            Self::Script(script) => {
+                // Rather than taking a fact which is not detected to be false, as rules do
+                if let Some(name) = script
+                    .facts()
+                    .iter()
+                    .find(|name| !context.facts.contains_key(*name))
+                {
+                    return Err(format!(
+                        "{} hook script refers to `{name}`, which has not been detected for \
+                         automatic switching",
+                        hook.name()
+                    ));
+                }
+                let context = ScriptContext {
+                    mode: Some(context.mode),
+                    previous_mode: context.previous_mode,
+                    caffeinating: context.caffeinating,
+                    facts: context.facts.clone(),
+                };
+                println!("Running {} hook script", hook.name());
+                // On a thread of its own, so it can be given up on once out of time, by when any
+                // command it is running has been killed
+                let commands: Arc<dyn Commands> = Arc::new(TimedCommands {
+                    deadline: Instant::now() + timeout,
+                });
+                let script = script.clone();
+                let (sender, receiver) = mpsc::channel();
+                thread::spawn(move || {
+                    let _ = sender.send(script.run(&context, &commands).map(drop));
+                });
+                receiver.recv_timeout(timeout).unwrap_or_else(|_| {
+                    Err(format!(
+                        "{} hook script timed out after {timeout:?}",
+                        hook.name()
+                    ))
+                })
+            }
+        }
+    }
+}
+
+/// A command which is killed, along with anything it started, should it run for longer than
+/// `timeout`
+struct TimedCommand {
+    command: Command,
+    timeout: Duration,
+}
+
+impl program::Command for TimedCommand {
+    fn output(&mut self) -> io::Result<Output> {
+        let mut child = self
+            .command
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            // In a group of its own, so that what a command line started can be killed with it
+            .process_group(0)
+            .spawn()?;
+        let stdout = read_to_end(child.stdout.take());
+        let stderr = read_to_end(child.stderr.take());
+
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                let group = libc::pid_t::try_from(child.id()).map_err(io::Error::other)?;
+                // SAFETY: `kill` has no memory safety requirements, and the child is yet to be
+                // waited for so its pid cannot have been reused
+                unsafe { libc::kill(-group, libc::SIGKILL) };
+                child.wait()?;
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            thread::sleep(POLL_INTERVAL);
+        };
+
+        // Something the command started in the background could keep its output open, so that
+        // is only waited for until the time is up, and is left running
+        let output = |pipe: Receiver<Vec<u8>>| {
+            pipe.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .unwrap_or_default()
+        };
+        Ok(Output {
+            status,
+            stdout: output(stdout),
+            stderr: output(stderr),
+        })
+    }
+}
+
+/// Read a pipe in the background, so a command writing a lot to it is not blocked
+fn read_to_end(pipe: Option<impl Read + Send + 'static>) -> Receiver<Vec<u8>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut contents = vec![];
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut contents);
+        }
+        let _ = sender.send(contents);
+    });
+    receiver
+}
+
+/// Runs the commands of a hook script, killing any still running at the hook's `deadline`
+struct TimedCommands {
+    deadline: Instant,
+}
+
+impl Commands for TimedCommands {
+    fn command(&self, argv: &[String]) -> Box<dyn program::Command + Send> {
+        let mut command = Command::new(&argv[0]);
+        command.args(&argv[1..]);
+        Box::new(TimedCommand {
+            command,
+            timeout: self.deadline.saturating_duration_since(Instant::now()),
+        })
+    }
+}
+
+/// Actions to run at points in lod's lifecycle, from the `[hooks]` table in config.toml
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hooks {
+    actions: Vec<(Hook, HookAction)>,
+    timeout: Duration,
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Self::new(vec![])
+    }
+}
+
+impl Hooks {
+    /// Each hook's actions are run in the order given
+    #[must_use]
+    pub const fn new(actions: Vec<(Hook, HookAction)>) -> Self {
+        Self {
+            actions,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Kill each command which runs for longer than `timeout`, failing its hook
+    #[must_use]
+    pub const fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    #[must_use]
+    pub fn has(&self, hook: Hook) -> bool {
+        self.actions
+            .iter()
+            .any(|(action_hook, _)| *action_hook == hook)
+    }
+
+    /// Run the hook's actions in turn, stopping at the first which fails or runs out of time
+    ///
+    /// This waits for the actions, so should be called off the main thread.
+    ///
+    /// # Errors
+    ///
+    /// Describing the action which failed
+    pub fn run(&self, hook: Hook, context: &HookContext) -> Result<(), String> {
+        if !self.has(hook) {
+            return Ok(());
+        }
+        let mut report = tempfile::Builder::new()
+            .prefix("lod-report-")
+            .suffix(".json")
+            .tempfile()
+            .map_err(|error| format!("Failed to create the action report: {error}"))?;
+        writeln!(report, "{}", context.report(hook))
+            .map_err(|error| format!("Failed to write the action report: {error}"))?;
+
+        for (_, action) in self
+            .actions
+            .iter()
+            .filter(|(action_hook, _)| *action_hook == hook)
+        {
+            action.run(hook, context, report.path(), self.timeout)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn context() -> HookContext {
+        HookContext {
+            mode: Mode::Desktop,
+            previous_mode: Some(Mode::Laptop),
+            caffeinating: true,
+            actions: vec![json!({ "action": "run_applescript", "ok": true })],
+            facts: Facts::from([("on_ac".into(), true.into())]),
+        }
+    }
+
+    fn sh(script: &str) -> HookAction {
+        command(&["sh", "-c", script])
+    }
+
+    fn command(argv: &[&str]) -> HookAction {
+        HookAction::Command(argv.iter().map(|arg| (*arg).into()).collect())
+    }
+
+    #[test]
+    fn it_parses_hook_names() {
+        assert_eq!("before_switch".parse(), Ok(Hook::BeforeSwitch));
+        assert_eq!("on_caffeinate_stop".parse(), Ok(Hook::OnCaffeinateStop));
+        assert!("after_caffeinate".parse::<Hook>().is_err());
+    }
+
+    #[test]
+    fn it_runs_actions_with_the_context() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let sut = Hooks::new(vec![(
+            Hook::AfterSwitch,
+            sh(&format!(
+                "echo \"$LOD_HOOK $LOD_MODE $LOD_PREVIOUS_MODE $LOD_CAFFEINATING\" > {0} && \
+                cat \"$LOD_REPORT\" >> {0}",
+                out.display()
+            )),
+        )]);
+
+        sut.run(Hook::AfterSwitch, &context()).unwrap();
+        let out = std::fs::read_to_string(out).unwrap();
+        let (variables, report) = out.split_once('\n').unwrap();
+        assert_eq!(variables, "after_switch desktop laptop 1");
+        assert_eq!(
+            serde_json::from_str::<Value>(report).unwrap(),
+            json!({
+                "hook": "after_switch",
+                "mode": "desktop",
+                "previous_mode": "laptop",
+                "caffeinating": true,
+                "actions": [{ "action": "run_applescript", "ok": true }],
+            })
+        );
+    }
+
+    #[test]
+    fn it_only_runs_the_given_hook() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let sut = Hooks::new(vec![(
+            Hook::OnCaffeinateStart,
+            command(&["touch", &out.display().to_string()]),
+        )]);
+        assert!(!sut.has(Hook::BeforeSwitch));
+
+        sut.run(Hook::BeforeSwitch, &context()).unwrap();
+        assert!(!out.exists());
+        sut.run(Hook::OnCaffeinateStart, &context()).unwrap();
+        assert!(out.exists());
+    }
+
+    #[test]
+    fn it_stops_at_the_first_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let sut = Hooks::new(vec![
+            (Hook::BeforeSwitch, sh("exit 3")),
+            (
+                Hook::BeforeSwitch,
+                command(&["touch", &out.display().to_string()]),
+            ),
+        ]);
+
+        let error = sut.run(Hook::BeforeSwitch, &context()).unwrap_err();
+        assert!(error.starts_with("`sh -c exit 3` failed"), "{error}");
+        assert!(!out.exists());
+    }
+
+    #[test]
+    fn it_runs_scripts_with_the_context() {
+        let script = |source: &str| HookAction::Script(Script::compile(source).unwrap());
+        let sut = Hooks::new(vec![
+            (
+                Hook::BeforeSwitch,
+                script(
+                    r#"if mode() != "desktop" || previous_mode() != "laptop" || !caffeinating() {
+                        throw "wrong context";
+                    }"#,
+                ),
+            ),
+            (
+                Hook::AfterSwitch,
+                script(r#"if mode() == "desktop" { throw "not today"; }"#),
+            ),
+        ]);
+
+        sut.run(Hook::BeforeSwitch, &context()).unwrap();
+        let error = sut.run(Hook::AfterSwitch, &context()).unwrap_err();
+        assert!(error.contains("not today"), "{error}");
+    }
+
+    #[test]
+    fn it_gives_scripts_the_detected_facts() {
+        let script = |source: &str| HookAction::Script(Script::compile(source).unwrap());
+        let sut = Hooks::new(vec![
+            (
+                Hook::BeforeSwitch,
+                script(r#"if !on_ac { throw "on battery"; }"#),
+            ),
+            (Hook::AfterSwitch, script("if on_mars { 1 }")),
+        ]);
+
+        sut.run(Hook::BeforeSwitch, &context()).unwrap();
+        let error = sut.run(Hook::AfterSwitch, &context()).unwrap_err();
+        assert!(
+            error.contains("`on_mars`, which has not been detected"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn it_kills_commands_which_run_out_of_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let sut = Hooks::new(vec![(
+            Hook::BeforeSwitch,
+            sh(&format!("sleep 1 && touch {}", out.display())),
+        )])
+        .with_timeout(Duration::from_millis(100));
+
+        let started = Instant::now();
+        let error = sut.run(Hook::BeforeSwitch, &context()).unwrap_err();
+        assert!(error.contains("timed out"), "{error}");
+        assert!(started.elapsed() < Duration::from_secs(1));
+        // The command line's children are killed with it
+        thread::sleep(Duration::from_millis(1500));
+        assert!(!out.exists());
+    }
+
+    #[test]
+    fn it_does_not_wait_for_what_commands_leave_running() {
+        let sut = Hooks::new(vec![(Hook::BeforeSwitch, sh("sleep 5 &"))])
+            .with_timeout(Duration::from_millis(300));
+
+        let started = Instant::now();
+        sut.run(Hook::BeforeSwitch, &context()).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn it_times_out_scripts() {
+        let script = |source: &str| HookAction::Script(Script::compile(source).unwrap());
+        let sut = Hooks::new(vec![
+            (Hook::BeforeSwitch, script(r#"run(["sleep", "5"])"#)),
+            (Hook::AfterSwitch, script("loop {}")),
+        ])
+        .with_timeout(Duration::from_millis(200));
+
+        for hook in [Hook::BeforeSwitch, Hook::AfterSwitch] {
+            let started = Instant::now();
+            assert!(sut.run(hook, &context()).is_err());
+            assert!(started.elapsed() < Duration::from_secs(2));
+        }
+    }
+
+    #[test]
+    fn it_fails_for_missing_programs() {
+        let sut = Hooks::new(vec![(Hook::BeforeSwitch, command(&["/no/such/program"]))]);
+        assert!(sut.run(Hook::BeforeSwitch, &context()).is_err());
+    }
+}
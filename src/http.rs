@@ -0,0 +1,580 @@
+use super::{
+    Event,
+    config::Http,
+    control::{self, Handler},
+};
+use serde_json::{Map, Value, json};
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::Receiver,
+    },
+    thread,
+    time::Duration,
+};
+
+/// Longest the request line and headers can be, so a client cannot use up memory
+const MAX_HEAD_LENGTH: usize = 8 * 1024;
+
+/// Longest a request body can be, which only ever needs to be a small JSON object
+const MAX_BODY_LENGTH: usize = 64 * 1024;
+
+/// How long a client has to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most connections served at once, as each has its own thread and event streams stay open
+const MAX_CONNECTIONS: usize = 32;
+
+/// Serves the control requests over HTTP/1.1 on 127.0.0.1, for tools which only speak HTTP
+///
+/// - `GET /status` replies with the status, as given by `get_status`
+/// - `POST /mode/{name}` switches mode
+/// - `POST /caffeinate` takes the arguments of `set_caffeinate` as a JSON body, if any
+/// - `GET /events` is a stream of server-sent events, one for each `Event`
+///
+/// Every request must have the configured bearer token.
+pub struct HttpServer {
+    address: SocketAddr,
+    stopped: Arc<AtomicBool>,
+}
+
+impl HttpServer {
+    /// # Errors
+    ///
+    /// If the port could not be listened on, eg as it is in use
+    pub fn spawn(http: &Http, handler: Handler) -> io::Result<Self> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, http.port()))?;
+        let address = listener.local_addr()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let stopping = stopped.clone();
+        let token = http.token().to_string();
+        let connections = Arc::new(AtomicUsize::new(0));
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stopping.load(Ordering::SeqCst) {
+                    break;
+                }
+                match stream {
+                    Ok(mut stream) => {
+                        let Some(connection) = Connection::open(&connections) else {
+                            let body = json!({ "ok": false, "error": "Too many connections" });
+                            if let Err(error) = write_json(&mut stream, 503, &body) {
+                                eprintln!("HTTP connection failed: {error:?}");
+                            }
+                            continue;
+                        };
+                        let handler = handler.clone();
+                        let token = token.clone();
+                        thread::spawn(move || {
+                            if let Err(error) = serve(stream, &token, &handler) {
+                                eprintln!("HTTP connection failed: {error:?}");
+                            }
+                            drop(connection);
+                        });
+                    }
+                    Err(error) => eprintln!("Failed to accept HTTP connection: {error:?}"),
+                }
+            }
+        });
+
+        Ok(Self { address, stopped })
+    }
+
+    #[must_use]
+    pub const fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl Drop for HttpServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wake the listening thread so it sees it has been stopped
+        let _ = TcpStream::connect(self.address);
+    }
+}
+
+/// A connection being served, counted towards `MAX_CONNECTIONS` until dropped
+struct Connection(Arc<AtomicUsize>);
+
+impl Connection {
+    /// Count another connection, unless there are already too many
+    fn open(connections: &Arc<AtomicUsize>) -> Option<Self> {
+        connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                (count < MAX_CONNECTIONS).then_some(count + 1)
+            })
+            .ok()?;
+        Some(Self(connections.clone()))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Request {
+    method: String,
+    path: String,
+    authorization: Option<String>,
+    body: Vec<u8>,
+}
+
+enum Response {
+    Json(u16, Value),
+    Events(Receiver<Event>),
+}
+
+fn serve(stream: TcpStream, token: &str, handler: &Handler) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    let response = match read_request(&mut BufReader::new(stream)) {
+        Ok(request) => route(&request, token, handler),
+        Err((status, message)) => error(status, &message),
+    };
+
+    match response {
+        Response::Json(status, body) => write_json(&mut writer, status, &body),
+        Response::Events(events) => {
+            write!(
+                writer,
+                "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\n\
+                Cache-Control: no-cache\r\nConnection: close\r\n\r\n"
+            )?;
+            writer.flush()?;
+            for event in events {
+                let event = event.to_json();
+                let name = event["event"].as_str().unwrap_or_default();
+                write!(writer, "event: {name}\ndata: {event}\n\n")?;
+                writer.flush()?;
+            }
+            Ok(())
+        }
+    }
+}
+
+fn write_json(writer: &mut impl Write, status: u16, body: &Value) -> io::Result<()> {
+    let body = body.to_string();
+    write!(
+        writer,
+        "HTTP/1.1 {status} {}\r\nContent-Type: application/json\r\n\
+        Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        reason(status),
+        body.len()
+    )
+}
+
+/// Read the request, or give the status and error to reply with should it not be understood
+fn read_request(reader: &mut impl BufRead) -> Result<Request, (u16, String)> {
+    let mut head = reader.take(MAX_HEAD_LENGTH as u64);
+    let mut line = String::new();
+    let mut read_line = |line: &mut String| {
+        line.clear();
+        match head.read_line(line) {
+            Ok(0) => Err(String::from("Incomplete request")),
+            Ok(_) if !line.ends_with('\n') => Err(String::from("Request head is too long")),
+            Ok(_) => Ok(()),
+            Err(error) => Err(format!("Failed to read request: {error}")),
+        }
+    };
+    let bad_request = |message: String| (400, message);
+
+    read_line(&mut line).map_err(bad_request)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target), Some(_version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(bad_request(format!(
+            "Malformed request line `{}`",
+            line.trim()
+        )));
+    };
+    let mut request = Request {
+        method: method.into(),
+        path: target.split('?').next().unwrap_or_default().into(),
+        ..Request::default()
+    };
+
+    let mut content_length = 0;
+    loop {
+        read_line(&mut line).map_err(bad_request)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| bad_request(format!("Malformed header `{header}`")))?;
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("authorization") {
+            request.authorization = Some(value.into());
+        } else if name.eq_ignore_ascii_case("content-length") {
+            content_length = value
+                .parse()
+                .map_err(|_| bad_request(format!("Invalid Content-Length `{value}`")))?;
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            // Bodies are only ever small, so chunked ones are not supported
+            return Err((501, format!("Transfer-Encoding `{value}` is not supported")));
+        }
+    }
+
+    if content_length > MAX_BODY_LENGTH {
+        return Err(bad_request(String::from("Request body is too long")));
+    }
+    request.body = vec![0; content_length];
+    reader
+        .read_exact(&mut request.body)
+        .map_err(|error| bad_request(format!("Failed to read request body: {error}")))?;
+
+    Ok(request)
+}
+
+fn route(request: &Request, token: &str, handler: &Handler) -> Response {
+    if !authorised(request.authorization.as_deref(), token) {
+        return error(401, "Missing or incorrect bearer token");
+    }
+
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    let command = match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["status"]) => command("get_status", Map::new()),
+        ("GET", ["events"]) => command("subscribe", Map::new()),
+        ("POST", ["mode", mode]) => {
+            command("set_mode", Map::from_iter([("mode".into(), json!(mode))]))
+        }
+        ("POST", ["caffeinate"]) => match caffeinate_arguments(&request.body) {
+            Ok(arguments) => command("set_caffeinate", arguments),
+            Err(message) => return error(400, &message),
+        },
+        (_, ["status" | "events" | "caffeinate"] | ["mode", _]) => {
+            return error(405, "Method not allowed");
+        }
+        _ => return error(404, "Not found"),
+    };
+
+    match handler.handle_request(&command) {
+        (_, Some(events)) => Response::Events(events),
+        (reply, None) if reply["error"] == control::SHUTTING_DOWN => Response::Json(503, reply),
+        (reply, None) if reply["ok"] != true => Response::Json(400, reply),
+        (mut reply, None) => {
+            // `GET /status` replies with just the status
+            let status = reply.get_mut("status").map(Value::take);
+            Response::Json(200, status.unwrap_or(reply))
+        }
+    }
+}
+
+/// A control request, with the given arguments
+fn command(name: &str, mut arguments: Map<String, Value>) -> Map<String, Value> {
+    arguments.insert("command".into(), json!(name));
+    arguments
+}
+
+/// The body of `POST /caffeinate`, which is optional and otherwise caffeinates indefinitely
+fn caffeinate_arguments(body: &[u8]) -> Result<Map<String, Value>, String> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(Map::new());
+    }
+    match serde_json::from_slice(body) {
+        Ok(Value::Object(arguments)) => Ok(arguments),
+        Ok(_) => Err(String::from("Body should be a JSON object")),
+        Err(parse_error) => Err(format!("Invalid JSON: {parse_error}")),
+    }
+}
+
+/// Compare the token without stopping at the first difference, so its value cannot be found by
+/// timing requests
+fn authorised(authorization: Option<&str>, token: &str) -> bool {
+    let Some(given) = authorization.and_then(|header| header.strip_prefix("Bearer ")) else {
+        return false;
+    };
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+fn error(status: u16, message: &str) -> Response {
+    Response::Json(status, json!({ "ok": false, "error": message }))
+}
+
+const fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CaffeinateFor, Events, Mode, SharedStatus, StateChangeMessage, Status};
+    use std::sync::mpsc::{self, Receiver};
+
+    const TOKEN: &str = "secret";
+
+    fn handler() -> (Handler, Receiver<StateChangeMessage>, Events) {
+        let (sender, receiver) = mpsc::channel();
+        let events = Events::default();
+        let handler = Handler::new(
+            sender,
+            SharedStatus::new(Status::new(Mode::Laptop)),
+            events.clone(),
+        );
+        (
+            handler,
+            crate::control::answer_requests(receiver, Ok(())),
+            events,
+        )
+    }
+
+    fn request(method: &str, path: &str, body: &str) -> Request {
+        Request {
+            method: method.into(),
+            path: path.into(),
+            authorization: Some(format!("Bearer {TOKEN}")),
+            body: body.into(),
+        }
+    }
+
+    fn json_response(response: Response) -> (u16, Value) {
+        match response {
+            Response::Json(status, body) => (status, body),
+            Response::Events(_) => panic!("Expected a JSON response"),
+        }
+    }
+
+    /// Make a request as a client would, returning the whole response
+    fn send(server: &HttpServer, request: &str) -> String {
+        let mut stream = TcpStream::connect(server.address()).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn it_reads_requests() {
+        let request = "POST /caffeinate?x=1 HTTP/1.1\r\nHost: localhost\r\n\
+            authorization: Bearer abc\r\nContent-Length: 13\r\n\r\n{\"for\":\"1h\"}\n";
+        assert_eq!(
+            read_request(&mut request.as_bytes()),
+            Ok(Request {
+                method: "POST".into(),
+                path: "/caffeinate".into(),
+                authorization: Some("Bearer abc".into()),
+                body: b"{\"for\":\"1h\"}\n".to_vec(),
+            })
+        );
+    }
+
+    #[test]
+    fn it_rejects_malformed_requests() {
+        for request in [
+            String::new(),
+            String::from("GET /status\r\n\r\n"),
+            String::from("GET /status HTTP/1.1\r\nHost localhost\r\n\r\n"),
+            String::from("GET /status HTTP/1.1\r\nContent-Length: lots\r\n\r\n"),
+            String::from("POST /caffeinate HTTP/1.1\r\nContent-Length: 10\r\n\r\n{}"),
+            format!("GET /status HTTP/1.1\r\nContent-Length: {MAX_BODY_LENGTH}1\r\n\r\n"),
+            format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_HEAD_LENGTH)),
+        ] {
+            assert_eq!(
+                read_request(&mut request.as_bytes()).map_err(|(status, _)| status),
+                Err(400),
+                "{request}"
+            );
+        }
+    }
+
+    #[test]
+    fn it_does_not_implement_transfer_encodings() {
+        let request = "POST /caffeinate HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            2\r\n{}\r\n0\r\n\r\n";
+        assert_eq!(
+            read_request(&mut request.as_bytes()).map_err(|(status, _)| status),
+            Err(501)
+        );
+    }
+
+    #[test]
+    fn it_is_unavailable_while_shutting_down() {
+        let (sender, receiver) = mpsc::channel();
+        let handler = Handler::new(
+            sender,
+            SharedStatus::new(Status::new(Mode::Laptop)),
+            Events::default(),
+        );
+        drop(receiver);
+        let response = route(&request("POST", "/mode/desktop", ""), TOKEN, &handler);
+        assert_eq!(json_response(response).0, 503);
+    }
+
+    #[test]
+    fn it_limits_the_connections_served_at_once() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let mut open: Vec<_> = (0..MAX_CONNECTIONS)
+            .map(|_| Connection::open(&connections).unwrap())
+            .collect();
+        assert!(Connection::open(&connections).is_none());
+
+        drop(open.pop());
+        assert_eq!(open.len(), MAX_CONNECTIONS - 1);
+        assert!(Connection::open(&connections).is_some());
+    }
+
+    #[test]
+    fn it_requires_the_token() {
+        assert!(authorised(Some("Bearer secret"), TOKEN));
+        assert!(!authorised(None, TOKEN));
+        assert!(!authorised(Some("secret"), TOKEN));
+        assert!(!authorised(Some("Bearer secreT"), TOKEN));
+        assert!(!authorised(Some("Bearer secret2"), TOKEN));
+
+        let (handler, receiver, _events) = handler();
+        let mut request = request("POST", "/mode/desktop", "");
+        request.authorization = None;
+        assert_eq!(json_response(route(&request, TOKEN, &handler)).0, 401);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn it_replies_with_the_status() {
+        let (handler, _receiver, _events) = handler();
+        let (status, body) = json_response(route(&request("GET", "/status", ""), TOKEN, &handler));
+        assert_eq!(status, 200);
+        assert_eq!(body["mode"], "laptop");
+    }
+
+    #[test]
+    fn it_switches_mode() {
+        let (handler, receiver, _events) = handler();
+        let response = route(&request("POST", "/mode/desktop", ""), TOKEN, &handler);
+        assert_eq!(json_response(response), (200, json!({ "ok": true })));
+        assert_eq!(
+            receiver.try_recv(),
+            Ok(StateChangeMessage::SetMode(Mode::Desktop))
+        );
+
+        let (status, body) =
+            json_response(route(&request("POST", "/mode/tablet", ""), TOKEN, &handler));
+        assert_eq!((status, &body["ok"]), (400, &json!(false)));
+    }
+
+    #[test]
+    fn it_caffeinates() {
+        let (handler, receiver, _events) = handler();
+        for (body, message) in [
+            (
+                "",
+                StateChangeMessage::Caffeinate(CaffeinateFor::Indefinitely),
+            ),
+            (
+                r#"{"for": "2m"}"#,
+                StateChangeMessage::Caffeinate(CaffeinateFor::Duration(Duration::from_secs(120))),
+            ),
+            (
+                r#"{"enabled": false}"#,
+                StateChangeMessage::StopCaffeination,
+            ),
+            // The command cannot be changed through the body
+            (
+                r#"{"command": "quit"}"#,
+                StateChangeMessage::Caffeinate(CaffeinateFor::Indefinitely),
+            ),
+        ] {
+            let response = route(&request("POST", "/caffeinate", body), TOKEN, &handler);
+            assert_eq!(json_response(response).0, 200, "{body}");
+            assert_eq!(receiver.try_recv(), Ok(message));
+        }
+
+        for body in ["[]", "{", r#"{"for": "soon"}"#] {
+            let response = route(&request("POST", "/caffeinate", body), TOKEN, &handler);
+            assert_eq!(json_response(response).0, 400, "{body}");
+        }
+    }
+
+    #[test]
+    fn it_rejects_unknown_routes() {
+        let (handler, _receiver, _events) = handler();
+        for (method, path, expected) in [
+            ("GET", "/", 404),
+            ("GET", "/mode", 404),
+            ("POST", "/status", 405),
+            ("GET", "/mode/desktop", 405),
+            ("DELETE", "/caffeinate", 405),
+        ] {
+            let response = route(&request(method, path, ""), TOKEN, &handler);
+            assert_eq!(json_response(response).0, expected, "{method} {path}");
+        }
+    }
+
+    #[test]
+    fn it_serves_http() {
+        let (handler, receiver, _events) = handler();
+        let sut = HttpServer::spawn(&Http::new(0, TOKEN), handler).unwrap();
+        assert!(sut.address().ip().is_loopback());
+
+        let response = send(
+            &sut,
+            "POST /mode/desktop HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n",
+        );
+        assert_eq!(
+            response,
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 11\r\n\
+            Connection: close\r\n\r\n{\"ok\":true}"
+        );
+        assert_eq!(
+            receiver.recv_timeout(READ_TIMEOUT),
+            Ok(StateChangeMessage::SetMode(Mode::Desktop))
+        );
+
+        let response = send(&sut, "GET /status HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+    }
+
+    #[test]
+    fn it_streams_events() {
+        let (handler, _receiver, events) = handler();
+        let sut = HttpServer::spawn(&Http::new(0, TOKEN), handler).unwrap();
+
+        let mut stream = TcpStream::connect(sut.address()).unwrap();
+        stream
+            .write_all(b"GET /events HTTP/1.1\r\nAuthorization: Bearer secret\r\n\r\n")
+            .unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            reader.read_line(&mut head).unwrap();
+        }
+        assert!(head.contains("Content-Type: text/event-stream\r\n"));
+
+        events.emit(&Event::ModeChanged {
+            mode: Mode::Desktop,
+            previous: Mode::Laptop,
+        });
+        let mut event = String::new();
+        for _ in 0..3 {
+            reader.read_line(&mut event).unwrap();
+        }
+        assert_eq!(
+            event,
+            "event: mode_changed\ndata: \
+            {\"event\":\"mode_changed\",\"mode\":\"desktop\",\"previous\":\"laptop\"}\n\n"
+        );
+    }
+}
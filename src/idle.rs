@@ -0,0 +1,185 @@
+use super::{
+    StateChangeMessage,
+    clock::{Clock, SystemClock},
+    program::{Program, ProgramImpl},
+};
+use std::{
+    error::Error,
+    process::Command,
+    sync::{Arc, Mutex, mpsc::Sender},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Decides when caffeination should stop because the user has walked away
+pub struct IdleMonitor<C: Clock> {
+    threshold: Duration,
+    clock: C,
+}
+
+impl<C: Clock> IdleMonitor<C> {
+    pub const fn new(threshold: Duration, clock: C) -> Self {
+        Self { threshold, clock }
+    }
+
+    /// Whether caffeination, started at `caffeinating_since`, should be stopped given the user
+    /// has been `idle` for this long
+    ///
+    /// Only idle time since caffeination started counts, so that caffeinating while already
+    /// away (eg over SSH) is not immediately undone.
+    pub fn should_stop(&self, idle: Duration, caffeinating_since: Option<Instant>) -> bool {
+        let Some(since) = caffeinating_since else {
+            return false;
+        };
+        let caffeinated_for = self.clock.now().saturating_duration_since(since);
+        idle.min(caffeinated_for) >= self.threshold
+    }
+}
+
+/// Handle to the background thread which polls how long the user has been idle for
+pub struct IdleWatch {
+    caffeinating_since: Arc<Mutex<Option<Instant>>>,
+}
+
+impl IdleWatch {
+    /// Poll the idle time in the background, sending `StateChangeMessage::StopCaffeination`
+    /// once it has crossed `threshold` while caffeinating
+    #[must_use]
+    pub fn spawn(threshold: Duration, sender: Sender<StateChangeMessage>) -> Self {
+        let monitor = IdleMonitor::new(threshold, SystemClock);
+        let caffeinating_since = Arc::new(Mutex::new(None));
+        let thread_caffeinating_since = caffeinating_since.clone();
+        let interval = (threshold / 4).clamp(Duration::from_secs(1), Duration::from_secs(60));
+
+        thread::spawn(move || {
+            // Stop once the `IdleWatch` has been dropped, eg when config.toml is reloaded
+            while Arc::strong_count(&thread_caffeinating_since) > 1 {
+                thread::sleep(interval);
+
+                let Some(since) = *thread_caffeinating_since
+                    .lock()
+                    .unwrap_or_else(std::sync::PoisonError::into_inner)
+                else {
+                    continue;
+                };
+                let idle = match hid_idle_time() {
+                    Ok(idle) => idle,
+                    Err(error) => {
+                        eprintln!("Failed to get idle time: {error:?}");
+                        continue;
+                    }
+                };
+                if monitor.should_stop(idle, Some(since)) {
+                    println!("Idle for {idle:?}, stopping caffeination");
+                    if sender.send(StateChangeMessage::StopCaffeination).is_err() {
+                        break;
+                    }
+                    // Do not ask again for the same caffeination
+                    let mut caffeinating_since = thread_caffeinating_since
+                        .lock()
+                        .unwrap_or_else(std::sync::PoisonError::into_inner);
+                    if *caffeinating_since == Some(since) {
+                        *caffeinating_since = None;
+                    }
+                }
+            }
+        });
+
+        Self { caffeinating_since }
+    }
+
+    /// Let the watch know whether caffeination is now running
+    pub fn set_caffeinating(&self, caffeinating: bool) {
+        let mut caffeinating_since = self
+            .caffeinating_since
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        *caffeinating_since = caffeinating.then(Instant::now);
+    }
+}
+
+fn hid_idle_time() -> Result<Duration, Box<dyn Error>> {
+    let mut ioreg = Command::new("ioreg");
+    ioreg.args(["-c", "IOHIDSystem"]);
+    let output = ProgramImpl::new(ioreg, 0).execute()?;
+    Ok(parse_hid_idle_time(&String::from_utf8_lossy(
+        output.stdout(),
+    ))?)
+}
+
+/// `ioreg -c IOHIDSystem` includes a line such as `"HIDIdleTime" = 1925432101583`, in nanoseconds
+fn parse_hid_idle_time(output: &str) -> Result<Duration, String> {
+    let value = output
+        .lines()
+        .find_map(|line| line.split_once("\"HIDIdleTime\" = "))
+        .map(|(_, value)| value.trim())
+        .ok_or("Could not find `HIDIdleTime` in output from `ioreg`")?;
+    let nanoseconds = value
+        .parse()
+        .map_err(|_| format!("Unexpected `HIDIdleTime` from `ioreg`: {value:?}"))?;
+
+    Ok(Duration::from_nanos(nanoseconds))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::FakeClock;
+
+    const THRESHOLD: Duration = Duration::from_secs(30 * 60);
+
+    #[test]
+    fn it_parses_idle_time() {
+        let output = include_str!("../tests/fixtures/ioreg_hid_idle.txt");
+        assert_eq!(
+            parse_hid_idle_time(output),
+            Ok(Duration::from_nanos(1_925_432_101_583))
+        );
+    }
+
+    #[test]
+    fn it_parses_idle_time_when_active() {
+        let output = include_str!("../tests/fixtures/ioreg_hid_active.txt");
+        assert_eq!(
+            parse_hid_idle_time(output),
+            Ok(Duration::from_nanos(83_125))
+        );
+    }
+
+    #[test]
+    fn it_rejects_missing_idle_time() {
+        assert!(parse_hid_idle_time("+-o Root  <class IORegistryEntry>").is_err());
+        assert!(parse_hid_idle_time("\"HIDIdleTime\" = <00>").is_err());
+    }
+
+    #[test]
+    fn it_does_nothing_when_not_caffeinating() {
+        let sut = IdleMonitor::new(THRESHOLD, FakeClock::default());
+        assert!(!sut.should_stop(THRESHOLD * 2, None));
+    }
+
+    #[test]
+    fn it_stops_once_idle_for_the_threshold() {
+        let clock = FakeClock::default();
+        let sut = IdleMonitor::new(THRESHOLD, clock.clone());
+        let since = clock.now();
+        clock.advance(Duration::from_secs(2 * 60 * 60));
+
+        assert!(!sut.should_stop(Duration::from_secs(29 * 60), Some(since)));
+        assert!(sut.should_stop(THRESHOLD, Some(since)));
+    }
+
+    #[test]
+    fn it_only_counts_idle_time_since_caffeinating() {
+        let clock = FakeClock::default();
+        let sut = IdleMonitor::new(THRESHOLD, clock.clone());
+        let since = clock.now();
+
+        // Already idle for an hour when caffeination was started remotely
+        clock.advance(Duration::from_secs(10 * 60));
+        assert!(!sut.should_stop(Duration::from_secs(70 * 60), Some(since)));
+
+        clock.advance(Duration::from_secs(20 * 60));
+        assert!(sut.should_stop(Duration::from_secs(90 * 60), Some(since)));
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, Write},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    process,
+};
+
+/// Whether this is the only instance of lod, found by taking an exclusive lock on a file in the
+/// runtime directory
+#[derive(Debug)]
+pub enum Instance {
+    /// This is the only instance, for as long as the lock is held
+    Primary(Lock),
+
+    /// Another instance is already running, with the given pid unless it is still starting up
+    Running(Option<u32>),
+}
+
+/// Exclusive lock on the lock file, released when dropped or should lod exit for any reason
+#[derive(Debug)]
+pub struct Lock {
+    _file: File,
+}
+
+/// Try to become the only instance, using `lod.lock` in `dir`
+///
+/// # Errors
+///
+/// If the lock file could not be opened or written
+pub fn acquire(dir: &Path) -> io::Result<Instance> {
+    acquire_lock(&dir.join("lod.lock"))
+}
+
+fn acquire_lock(path: &PathBuf) -> io::Result<Instance> {
+    let mut file = open(path)?;
+    // A held lock always means another instance is running, as the lock is released when the
+    // process holding it exits however it does so. The pid is missing should it still be
+    // starting up.
+    if !try_lock(&file)? {
+        return Ok(Instance::Running(read_pid(&mut file)));
+    }
+
+    file.set_len(0)?;
+    file.rewind()?;
+    writeln!(file, "{}", process::id())?;
+
+    Ok(Instance::Primary(Lock { _file: file }))
+}
+
+fn open(path: &PathBuf) -> io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+}
+
+/// Take the lock without waiting, returning whether it was taken
+fn try_lock(file: &File) -> io::Result<bool> {
+    // SAFETY: `flock` has no memory safety requirements, and the file outlives the call
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let error = io::Error::last_os_error();
+    if error.kind() == io::ErrorKind::WouldBlock {
+        return Ok(false);
+    }
+    Err(error)
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut contents = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    contents.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    fn lock_path() -> (PathBuf, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        (dir.path().join("lod.lock"), dir)
+    }
+
+    #[test]
+    fn it_becomes_the_primary_instance() {
+        let (path, _dir) = lock_path();
+        let instance = acquire_lock(&path).unwrap();
+        assert!(matches!(instance, Instance::Primary(_)));
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", process::id())
+        );
+    }
+
+    #[test]
+    fn it_finds_the_running_instance() {
+        let (path, _dir) = lock_path();
+        let _primary = acquire_lock(&path).unwrap();
+
+        let instance = acquire(path.parent().unwrap()).unwrap();
+        assert!(matches!(instance, Instance::Running(Some(pid)) if pid == process::id()));
+    }
+
+    #[test]
+    fn it_takes_over_once_the_instance_has_gone() {
+        let (path, _dir) = lock_path();
+        let primary = acquire_lock(&path).unwrap();
+        drop(primary);
+
+        let instance = acquire_lock(&path).unwrap();
+        assert!(matches!(instance, Instance::Primary(_)));
+    }
+
+    #[test]
+    fn it_never_replaces_a_held_lock() {
+        let (path, _dir) = lock_path();
+        let held = open(&path).unwrap();
+        assert!(try_lock(&held).unwrap());
+        fs::write(&path, "999999\n").unwrap();
+
+        let instance = acquire_lock(&path).unwrap();
+        assert!(matches!(instance, Instance::Running(Some(999_999))));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "999999\n");
+    }
+
+    #[test]
+    fn it_waits_for_an_instance_starting_up() {
+        let (path, _dir) = lock_path();
+        let starting = open(&path).unwrap();
+        assert!(try_lock(&starting).unwrap());
+
+        let instance = acquire_lock(&path).unwrap();
+        assert!(matches!(instance, Instance::Running(None)));
+    }
+}
@@ -0,0 +1,279 @@
+use std::{os::unix::process::CommandExt, process::Command, time::Duration};
+
+/// What systemd can be asked to inhibit, see `systemd-inhibit --help`
+pub const SYSTEMD_WHAT: [&str; 7] = [
+    "shutdown",
+    "sleep",
+    "idle",
+    "handle-power-key",
+    "handle-suspend-key",
+    "handle-hibernate-key",
+    "handle-lid-switch",
+];
+
+/// What `systemd-inhibit` blocks unless told otherwise, as `caffeinate` does without flags
+pub const SYSTEMD_DEFAULT_WHAT: [&str; 2] = ["idle", "sleep"];
+
+/// Passed as `$0` to the script waiting for a process, so holds can be found with `ps`
+const HOLD_MARKER: &str = "lod-hold";
+
+/// Waits for the process given as `$1` to exit
+const WAIT_SCRIPT: &str = r#"while kill -0 "$1" 2>/dev/null; do sleep 1; done"#;
+
+/// How the computer is kept awake, chosen per platform unless set by `[keep_awake]`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeepAwake {
+    /// macOS `caffeinate`, with its flags
+    Caffeinate { program: String, args: Vec<String> },
+    /// `systemd-inhibit`, holding a lock on `what` while a command runs
+    SystemdInhibit { program: String, what: Vec<String> },
+    /// Any command which keeps the computer awake while the command given after it runs, eg
+    /// `gnome-session-inhibit --inhibit idle`, along with what it is said to inhibit
+    Command {
+        argv: Vec<String>,
+        inhibits: Vec<String>,
+    },
+}
+
+impl Default for KeepAwake {
+    #[cfg(target_os = "macos")]
+    fn default() -> Self {
+        Self::caffeinate("caffeinate", vec![])
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    fn default() -> Self {
+        Self::systemd_inhibit("systemd-inhibit")
+    }
+}
+
+impl KeepAwake {
+    #[must_use]
+    pub fn caffeinate(program: impl Into<String>, args: Vec<String>) -> Self {
+        Self::Caffeinate {
+            program: program.into(),
+            args,
+        }
+    }
+
+    /// `systemd-inhibit` blocking `SYSTEMD_DEFAULT_WHAT`
+    #[must_use]
+    pub fn systemd_inhibit(program: impl Into<String>) -> Self {
+        Self::SystemdInhibit {
+            program: program.into(),
+            what: SYSTEMD_DEFAULT_WHAT.map(String::from).to_vec(),
+        }
+    }
+
+    /// Name of the backend, as given for `keep_awake.backend`
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Caffeinate { .. } => "caffeinate",
+            Self::SystemdInhibit { .. } => "systemd-inhibit",
+            Self::Command { .. } => "command",
+        }
+    }
+
+    /// The program run, which is how its processes are recognised
+    #[must_use]
+    pub fn program(&self) -> &str {
+        match self {
+            Self::Caffeinate { program, .. } | Self::SystemdInhibit { program, .. } => program,
+            Self::Command { argv, .. } => argv.first().map_or("", String::as_str),
+        }
+    }
+
+    /// What is kept from happening, eg `idle` and `sleep`
+    #[must_use]
+    pub fn inhibits(&self) -> Vec<&str> {
+        match self {
+            Self::Caffeinate { args, .. } => {
+                let mut inhibits = vec![];
+                for flag in args
+                    .iter()
+                    .filter_map(|arg| arg.strip_prefix('-'))
+                    .flat_map(str::chars)
+                {
+                    let inhibit = match flag {
+                        'd' => "display",
+                        'i' => "idle",
+                        'm' => "disk",
+                        's' => "sleep",
+                        'u' => "user-idle",
+                        _ => continue,
+                    };
+                    if !inhibits.contains(&inhibit) {
+                        inhibits.push(inhibit);
+                    }
+                }
+                // Without flags `caffeinate` prevents idle sleep
+                if inhibits.is_empty() {
+                    inhibits.push("idle");
+                }
+                inhibits
+            }
+            Self::SystemdInhibit { what, .. } => what.iter().map(String::as_str).collect(),
+            Self::Command { inhibits, .. } => inhibits.iter().map(String::as_str).collect(),
+        }
+    }
+
+    /// Command keeping the computer awake until it is killed, or for `duration`
+    #[must_use]
+    pub fn command(&self, duration: Option<Duration>) -> Command {
+        let secs = duration.map(|duration| duration.as_secs().to_string());
+        if let Self::Caffeinate { .. } = self {
+            let mut caffeinate = self.prefix("Caffeinating");
+            if let Some(secs) = secs {
+                caffeinate.args(["-t", &secs]);
+            }
+            return caffeinate;
+        }
+        let mut command = self.prefix("Caffeinating");
+        command.args(["sleep", secs.as_deref().unwrap_or("infinity")]);
+        command
+    }
+
+    /// Command keeping the computer awake until the process with the given `pid` exits
+    #[must_use]
+    pub fn command_while(&self, pid: u32) -> Command {
+        let pid = pid.to_string();
+        let mut command = self.prefix("Keeping awake while a process runs");
+        if let Self::Caffeinate { .. } = self {
+            command.args(["-w", &pid]);
+        } else {
+            command.args(["sh", "-c", WAIT_SCRIPT, HOLD_MARKER, &pid]);
+        }
+        command
+    }
+
+    /// What comes before the pid in a command from `command_while`, to find holds with `ps`
+    pub(crate) const fn hold_marker(&self) -> &'static str {
+        match self {
+            Self::Caffeinate { .. } => "-w",
+            Self::SystemdInhibit { .. } | Self::Command { .. } => HOLD_MARKER,
+        }
+    }
+
+    /// In a process group of its own, so that stopping it also stops what it runs, eg the `sleep`
+    /// run by `systemd-inhibit`
+    fn prefix(&self, why: &str) -> Command {
+        let mut command = self.program_and_options(why);
+        command.process_group(0);
+        command
+    }
+
+    fn program_and_options(&self, why: &str) -> Command {
+        match self {
+            Self::Caffeinate { program, args } => {
+                let mut caffeinate = Command::new(program);
+                caffeinate.args(args);
+                caffeinate
+            }
+            Self::SystemdInhibit { program, what } => {
+                let mut inhibit = Command::new(program);
+                inhibit.args([
+                    format!("--what={}", what.join(":")),
+                    String::from("--who=lod"),
+                    format!("--why={why}"),
+                    String::from("--mode=block"),
+                ]);
+                inhibit
+            }
+            Self::Command { argv, .. } => {
+                let (program, args) = argv
+                    .split_first()
+                    .map_or(("", &[][..]), |(program, args)| (program.as_str(), args));
+                let mut command = Command::new(program);
+                command.args(args);
+                command
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn argv(command: &Command) -> Vec<String> {
+        std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn it_builds_caffeinate_commands() {
+        let caffeinate = KeepAwake::caffeinate("caffeinate", vec![String::from("-d")]);
+        assert_eq!(argv(&caffeinate.command(None)), ["caffeinate", "-d"]);
+        assert_eq!(
+            argv(&caffeinate.command(Some(Duration::from_secs(60)))),
+            ["caffeinate", "-d", "-t", "60"]
+        );
+        assert_eq!(
+            argv(&caffeinate.command_while(42)),
+            ["caffeinate", "-d", "-w", "42"]
+        );
+    }
+
+    #[test]
+    fn it_builds_systemd_inhibit_commands() {
+        let inhibit = KeepAwake::systemd_inhibit("systemd-inhibit");
+        assert_eq!(
+            argv(&inhibit.command(None)),
+            [
+                "systemd-inhibit",
+                "--what=idle:sleep",
+                "--who=lod",
+                "--why=Caffeinating",
+                "--mode=block",
+                "sleep",
+                "infinity"
+            ]
+        );
+        assert_eq!(
+            argv(&inhibit.command(Some(Duration::from_secs(60))))[5..],
+            ["sleep", "60"]
+        );
+        assert_eq!(
+            argv(&inhibit.command_while(42))[5..],
+            ["sh", "-c", WAIT_SCRIPT, "lod-hold", "42"]
+        );
+    }
+
+    #[test]
+    fn it_builds_custom_commands() {
+        let command = KeepAwake::Command {
+            argv: vec![String::from("inhibit"), String::from("--idle")],
+            inhibits: vec![],
+        };
+        assert_eq!(command.program(), "inhibit");
+        assert_eq!(
+            argv(&command.command(None)),
+            ["inhibit", "--idle", "sleep", "infinity"]
+        );
+    }
+
+    #[test]
+    fn it_reports_what_is_inhibited() {
+        assert_eq!(
+            KeepAwake::caffeinate("caffeinate", vec![]).inhibits(),
+            ["idle"]
+        );
+        let args = ["-di", "-s", "-t", "60"].map(String::from).to_vec();
+        assert_eq!(
+            KeepAwake::caffeinate("caffeinate", args).inhibits(),
+            ["display", "idle", "sleep"]
+        );
+        assert_eq!(
+            KeepAwake::systemd_inhibit("systemd-inhibit").inhibits(),
+            ["idle", "sleep"]
+        );
+        let command = KeepAwake::Command {
+            argv: vec![String::from("inhibit")],
+            inhibits: vec![String::from("screensaver")],
+        };
+        assert_eq!(command.inhibits(), ["screensaver"]);
+    }
+}
@@ -2,21 +2,67 @@
 #![warn(clippy::pedantic)]
 #![warn(clippy::nursery)]
 
-#[cfg(target_os = "macos")]
 mod app_state;
-#[cfg(target_os = "macos")]
-pub use app_state::{AppState, Mode, StateChangeMessage};
+pub use app_state::AppState;
+mod automatic;
+pub use automatic::AutoSwitch;
+pub mod cli;
+mod clock;
 mod config;
+pub mod control;
 pub use config::Config;
 #[cfg(target_os = "macos")]
 mod application;
 #[cfg(target_os = "macos")]
 pub use application::Application;
+mod debounce;
+mod detector;
+pub use detector::{Fact, Facts};
+mod event;
+pub use event::{Event, Events};
+pub mod duration;
+pub mod hold;
+mod hooks;
+pub use hooks::{Hook, HookAction, HookContext, Hooks};
+mod http;
+pub use http::HttpServer;
+mod idle;
+pub use idle::IdleWatch;
+pub mod instance;
+mod keep_awake;
+pub use keep_awake::KeepAwake;
+mod menu;
 #[cfg(target_os = "macos")]
-mod menu_item;
-pub mod program;
+mod menu_bar;
 #[cfg(target_os = "macos")]
+pub use menu_bar::MenuBar;
+mod message;
+pub use message::{CaffeinateFor, Reply, StateChangeMessage};
+mod mode;
+pub use mode::{KeepAwakeAction, KeepAwakePolicy, Mode};
+mod orphans;
+pub use orphans::{Record, Registry};
+mod plugin;
+pub use plugin::{Plugin, PluginConfig, PluginMenuItem, Registration};
+pub mod program;
+mod rules;
+pub mod runtime;
+mod saved_state;
+pub use saved_state::{SavedState, StateFile};
+mod script;
+pub use script::{Commands, Script, ScriptContext, SystemCommands};
+mod signals;
+pub use signals::SignalBridge;
+mod status;
+pub use status::{SharedStatus, Status};
+mod supervisor;
+pub use supervisor::{RestartPolicy, ServiceConfig, ServiceStatus, StopSignal, Supervisor};
+pub mod tui;
+pub mod ui;
 mod waiting_child;
+pub use waiting_child::WaitingChild;
+mod webhooks;
+pub use webhooks::{WebhookConfig, Webhooks};
 
 use program::{Program, ProgramImpl};
 use std::error::Error;
@@ -2,40 +2,203 @@
 #![warn(clippy::pedantic)]
 #![warn(clippy::nursery)]
 
+use lod::{
+    AppState, Config, HttpServer, Mode, Registry, SignalBridge, StateChangeMessage, StateFile,
+    control::{self, ControlServer, Handler},
+    instance::{self, Instance},
+    runtime,
+    ui::{Headless, StatusUi},
+};
 #[cfg(target_os = "macos")]
-use lod::{AppState, Application, Config, Mode, StateChangeMessage};
-#[cfg(target_os = "macos")]
-use std::{error::Error, sync::mpsc};
+use lod::{Application, MenuBar};
+use std::{
+    env,
+    error::Error,
+    path::Path,
+    process,
+    sync::mpsc::{self, Receiver, Sender},
+};
+
+/// How lod shows its state
+enum Frontend {
+    /// The status item in the macOS menu bar
+    #[cfg(target_os = "macos")]
+    MenuBar,
+
+    /// Nothing but the control socket, `lod daemon`
+    Daemon,
+
+    /// A full screen terminal interface, `lod tui`
+    Tui,
+}
 
-#[cfg(target_os = "macos")]
 fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    // Subcommands such as `lod switch desktop` control the running instance
+    if let Some(code) = lod::cli::run(&args) {
+        process::exit(code);
+    }
+
     let config = Config::load()?;
 
-    // For me, when I hide my Dock I am in 'laptop' mode
-    let mode = if lod::dock_autohide()? {
-        Mode::Laptop
-    } else {
-        Mode::Desktop
+    let frontend = match args
+        .split_first()
+        .map(|(subcommand, rest)| (subcommand.as_str(), rest))
+    {
+        Some(("caffeinate", command)) => {
+            let command = command
+                .strip_prefix(&[String::from("--")])
+                .ok_or("Usage: lod caffeinate -- <command> [args...]")?;
+            let status = lod::hold::keep_awake_while_running(config.keep_awake_backend(), command)?;
+            process::exit(status.code().unwrap_or(1));
+        }
+        Some(("daemon", [])) => Frontend::Daemon,
+        Some(("tui", [])) => Frontend::Tui,
+        #[cfg(target_os = "macos")]
+        None => Frontend::MenuBar,
+        // There is no menu bar to be in elsewhere
+        #[cfg(not(target_os = "macos"))]
+        None => Frontend::Daemon,
+        Some(_) => {
+            return Err("Usage: lod [daemon | tui | caffeinate -- <command> [args...]]".into());
+        }
+    };
+
+    // Only one instance should be running, managing caffeinate
+    let runtime_dir = runtime::dir()?;
+    let _lock = match instance::acquire(&runtime_dir)? {
+        Instance::Primary(lock) => lock,
+        Instance::Running(pid) => {
+            let pid = pid
+                .map(|pid| format!(" with pid {pid}"))
+                .unwrap_or_default();
+            return Err(format!(
+                "lod is already running{pid}, use its menu or `lod quit` to quit it first"
+            )
+            .into());
+        }
     };
+
+    // Alongside the runtime files should there be nowhere for state to outlast a reboot
+    let state_dir = runtime::state_dir().unwrap_or_else(|error| {
+        eprintln!("Failed to create the state directory, so using the runtime one: {error:?}");
+        runtime_dir.clone()
+    });
+    let state_file = StateFile::open(state_dir.join("state.json"));
+    let mode = initial_mode(&state_file);
     println!("Starting in {mode:#?} mode");
 
     let (sender, receiver) = mpsc::channel();
-    let mut app_state = AppState::new(config, mode, sender);
-    Application::run(&receiver, move |message| match message {
-        StateChangeMessage::Quit => (),
-        StateChangeMessage::ClearCaffeination => {
-            app_state.clear_caffeinate();
+    // Quit cleanly on Ctrl-C or `launchctl stop`, so that `Drop for AppState` tidies up
+    let _signal_bridge = SignalBridge::spawn(sender.clone())
+        .inspect_err(|error| eprintln!("Failed to handle signals: {error:?}"))
+        .ok();
+    let registry = Registry::open(runtime_dir.join("children"));
+
+    match frontend {
+        #[cfg(target_os = "macos")]
+        Frontend::MenuBar => {
+            let ui = MenuBar::new(sender.clone());
+            let (mut app_state, _servers) = start(
+                config,
+                mode,
+                ui,
+                &sender,
+                &runtime_dir,
+                registry,
+                state_file,
+            );
+            Application::run(&receiver, move |message| app_state.handle(message));
         }
-        StateChangeMessage::ToggleMode => {
-            app_state.toggle_mode();
+        Frontend::Daemon => {
+            let (mut app_state, _servers) = start(
+                config,
+                mode,
+                Headless,
+                &sender,
+                &runtime_dir,
+                registry,
+                state_file,
+            );
+            run_headless(&receiver, |message| app_state.handle(message));
         }
-        StateChangeMessage::ToggleCaffeination => {
-            app_state.toggle_caffeination();
+        Frontend::Tui => {
+            let (mut app_state, _servers) = start(
+                config,
+                mode,
+                Headless,
+                &sender,
+                &runtime_dir,
+                registry,
+                state_file,
+            );
+            lod::tui::run(&mut app_state, &receiver)?;
         }
-    });
+    }
 
     Ok(())
 }
 
-#[cfg(target_os = "linux")]
-const fn main() {}
+/// For me, when I hide my Dock I am in 'laptop' mode
+///
+/// Elsewhere there is no Dock, so the mode lod was last in is carried on with, if saved.
+fn initial_mode(state_file: &StateFile) -> Mode {
+    if !cfg!(target_os = "macos") {
+        return state_file.load().mode.unwrap_or(Mode::Laptop);
+    }
+    match lod::dock_autohide() {
+        Ok(true) => Mode::Laptop,
+        Ok(false) => Mode::Desktop,
+        Err(error) => {
+            eprintln!("Failed to read whether the Dock hides, so assuming laptop mode: {error}");
+            Mode::Laptop
+        }
+    }
+}
+
+/// Servers for controlling lod, which stop listening when dropped
+type Servers = (Option<ControlServer>, Option<HttpServer>);
+
+/// Start the state machine behind `ui`, then listen for control requests
+fn start<U: StatusUi>(
+    config: Config,
+    mode: Mode,
+    ui: U,
+    sender: &Sender<StateChangeMessage>,
+    runtime_dir: &Path,
+    registry: Registry,
+    state_file: StateFile,
+) -> (AppState<U>, Servers) {
+    // Changes to `[http]` only take effect on restart, as the server is started once here
+    let http = config.http().cloned();
+    let app_state =
+        AppState::new(config, mode, ui, sender.clone(), Some(registry)).with_state_file(state_file);
+    let handler = Handler::new(sender.clone(), app_state.status(), app_state.events());
+    // Listening only once the lock is held, so that the socket of a running instance is never
+    // replaced
+    let control_server =
+        ControlServer::spawn(runtime_dir.join(control::SOCKET_NAME), handler.clone())
+            .inspect_err(|error| eprintln!("Failed to listen for control requests: {error:?}"))
+            .ok();
+    let http_server = http.and_then(|http| {
+        HttpServer::spawn(&http, handler)
+            .inspect(|server| println!("Serving the HTTP API on {}", server.address()))
+            .inspect_err(|error| eprintln!("Failed to serve the HTTP API: {error:?}"))
+            .ok()
+    });
+
+    (app_state, (control_server, http_server))
+}
+
+/// Handle messages until asked to quit
+fn run_headless(
+    receiver: &Receiver<StateChangeMessage>,
+    mut callback: impl FnMut(StateChangeMessage),
+) {
+    for message in receiver {
+        match message {
+            StateChangeMessage::Quit => break,
+            _ => callback(message),
+        }
+    }
+}
@@ -0,0 +1,320 @@
+use super::{
+    CaffeinateFor, Mode, Plugin, Record, ServiceStatus, StateChangeMessage,
+    duration::{self, TimeOfDay},
+    hold::{Hold, Process},
+    ui::{MenuEntry, MenuItem},
+};
+use std::{sync::Arc, time::Duration};
+
+/// Item switching to `mode`, ie the opposite of the current one
+#[must_use]
+pub fn toggle_mode_item(mode: Mode) -> MenuItem {
+    MenuItem::new(mode.description())
+        .with_icon(mode.sf_symbol(), mode.accessibility_description())
+        .with_message(StateChangeMessage::ToggleMode)
+}
+
+#[must_use]
+pub fn caffeinate_item(
+    caffeinating: bool,
+    remaining: Option<Duration>,
+    driver: Option<&str>,
+    until: TimeOfDay,
+    holds: &[Hold],
+    other_holds: &[Hold],
+    candidates: &[&Process],
+) -> MenuItem {
+    let durations = [
+        ("15 Minutes", Duration::from_secs(15 * 60)),
+        ("1 Hour", Duration::from_secs(60 * 60)),
+        ("2 Hours", Duration::from_secs(2 * 60 * 60)),
+    ];
+    let mut items: Vec<MenuEntry> = durations
+        .into_iter()
+        .map(|(title, duration)| {
+            let message = StateChangeMessage::Caffeinate(CaffeinateFor::Duration(duration));
+            MenuItem::new(title).with_message(message).into()
+        })
+        .collect();
+    items.push(
+        MenuItem::new(format!("Until {until}"))
+            .with_message(StateChangeMessage::Caffeinate(CaffeinateFor::Until(until)))
+            .into(),
+    );
+    items.push(
+        MenuItem::new("Indefinitely")
+            .with_message(StateChangeMessage::Caffeinate(CaffeinateFor::Indefinitely))
+            .into(),
+    );
+
+    let mut processes: Vec<MenuEntry> = candidates
+        .iter()
+        .map(|process| {
+            MenuItem::new(format!("{} ({})", process.name(), process.pid()))
+                .with_message(StateChangeMessage::KeepAwakeWhile(process.pid()))
+                .into()
+        })
+        .collect();
+    if processes.is_empty() {
+        processes.push(MenuItem::new("No Processes Running in a Terminal").into());
+    }
+    items.push(
+        MenuItem::new("Keep Awake While…")
+            .with_submenu(processes)
+            .into(),
+    );
+    if !holds.is_empty() || !other_holds.is_empty() {
+        items.push(MenuEntry::Separator);
+    }
+    for hold in holds {
+        items.push(
+            MenuItem::new(format!("While {hold}"))
+                .with_message(StateChangeMessage::ReleaseHold(hold.caffeinate_pid()))
+                .with_checked(true)
+                .into(),
+        );
+    }
+    // Only shown, as lod cannot be sure a process it did not start is still the one listed
+    for hold in other_holds {
+        items.push(
+            MenuItem::new(format!("While {hold}"))
+                .with_checked(true)
+                .into(),
+        );
+    }
+
+    if caffeinating {
+        items.push(MenuEntry::Separator);
+        items.push(
+            MenuItem::new("Stop")
+                .with_message(StateChangeMessage::StopCaffeination)
+                .into(),
+        );
+    }
+
+    // Say whether the mode or the user is in control, and how long is left
+    let notes: Vec<String> = driver
+        .map(String::from)
+        .into_iter()
+        .chain(remaining.map(|remaining| format!("{} left", duration::format_remaining(remaining))))
+        .collect();
+    let title = if notes.is_empty() {
+        String::from("Caffeinate")
+    } else {
+        format!("Caffeinate ({})", notes.join(", "))
+    };
+
+    MenuItem::new(title)
+        .with_icon("mug.fill", "Toggle caffeination of your Mac")
+        .with_checked(caffeinating || !holds.is_empty() || !other_holds.is_empty())
+        .with_submenu(items)
+}
+
+#[must_use]
+pub fn services_item<'a>(
+    statuses: impl Iterator<Item = (&'a str, ServiceStatus, u32)>,
+) -> MenuItem {
+    let items = statuses
+        .map(|(name, status, restarts)| {
+            let title = match restarts {
+                0 => format!("{name}: {status}"),
+                1 => format!("{name}: {status}, restarted once"),
+                restarts => format!("{name}: {status}, restarted {restarts} times"),
+            };
+            let item = MenuItem::new(title);
+            if matches!(status, ServiceStatus::Running(_)) {
+                item.with_checked(true).into()
+            } else {
+                item.into()
+            }
+        })
+        .collect();
+
+    MenuItem::new("Services")
+        .with_icon("gearshape.2", "Services for this mode")
+        .with_submenu(items)
+}
+
+#[must_use]
+pub fn leftovers_item(leftovers: &[Record], adopted: &[Record]) -> MenuItem {
+    let terminate = |record: &Record| -> MenuEntry {
+        MenuItem::new("Terminate")
+            .with_message(StateChangeMessage::TerminateLeftover(record.pid()))
+            .into()
+    };
+    let mut items: Vec<MenuEntry> = leftovers
+        .iter()
+        .map(|record| {
+            let adopt = MenuItem::new("Adopt")
+                .with_message(StateChangeMessage::AdoptLeftover(record.pid()))
+                .into();
+            MenuItem::new(record.to_string())
+                .with_submenu(vec![terminate(record), adopt])
+                .into()
+        })
+        .collect();
+    items.extend(adopted.iter().map(|record| {
+        MenuItem::new(format!("{record} (Adopted)"))
+            .with_submenu(vec![terminate(record)])
+            .with_checked(true)
+            .into()
+    }));
+
+    let title = if leftovers.is_empty() {
+        "Adopted Processes"
+    } else {
+        "Left Running by Previous lod"
+    };
+    MenuItem::new(title)
+        .with_icon(
+            "exclamationmark.triangle",
+            "Processes left running by a previous lod",
+        )
+        .with_submenu(items)
+}
+
+#[must_use]
+pub fn plugins_item(plugins: &[Arc<Plugin>]) -> MenuItem {
+    let items = plugins
+        .iter()
+        .enumerate()
+        .map(|(plugin_index, plugin)| {
+            if !plugin.is_running() {
+                return MenuItem::new(format!("{} (Exited)", plugin.name())).into();
+            }
+            let menu_items = &plugin.registration().menu_items;
+            let item = MenuItem::new(plugin.name()).with_checked(true);
+            if menu_items.is_empty() {
+                return item.into();
+            }
+            item.with_submenu(
+                menu_items
+                    .iter()
+                    .enumerate()
+                    .map(|(item_index, menu_item)| {
+                        MenuItem::new(&menu_item.title)
+                            .with_message(StateChangeMessage::PluginMenuItem {
+                                plugin: plugin_index,
+                                item: item_index,
+                            })
+                            .into()
+                    })
+                    .collect(),
+            )
+            .into()
+        })
+        .collect();
+
+    MenuItem::new("Plugins")
+        .with_icon("puzzlepiece.extension", "Plugins")
+        .with_submenu(items)
+}
+
+#[must_use]
+pub fn automatic_item(enabled: bool) -> MenuItem {
+    MenuItem::new("Automatic")
+        .with_icon("wand.and.stars", "Toggle switching mode automatically")
+        .with_checked(enabled)
+        .with_message(StateChangeMessage::ToggleAutomatic)
+}
+
+#[must_use]
+pub fn quit_item() -> MenuItem {
+    MenuItem::new("Quit").with_message(StateChangeMessage::Quit)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn titles(item: &MenuItem) -> Vec<&str> {
+        item.submenu
+            .iter()
+            .flatten()
+            .map(|entry| match entry {
+                MenuEntry::Item(item) => item.title.as_str(),
+                MenuEntry::Separator => "-",
+            })
+            .collect()
+    }
+
+    #[test]
+    fn it_offers_times_to_caffeinate_for() {
+        let item = caffeinate_item(false, None, None, TimeOfDay::EVENING, &[], &[], &[]);
+        assert_eq!(item.title, "Caffeinate");
+        assert_eq!(item.checked, Some(false));
+        assert_eq!(
+            titles(&item),
+            [
+                "15 Minutes",
+                "1 Hour",
+                "2 Hours",
+                "Until 18:00",
+                "Indefinitely",
+                "Keep Awake While…"
+            ]
+        );
+    }
+
+    #[test]
+    fn it_notes_who_is_caffeinating_and_for_how_long() {
+        let item = caffeinate_item(
+            true,
+            Some(Duration::from_secs(90 * 60)),
+            Some("Desktop"),
+            TimeOfDay::EVENING,
+            &[],
+            &[],
+            &[],
+        );
+        assert_eq!(item.title, "Caffeinate (Desktop, 1h 30m left)");
+        assert_eq!(item.checked, Some(true));
+        assert_eq!(titles(&item).last(), Some(&"Stop"));
+        let Some(MenuEntry::Item(stop)) = item.submenu.unwrap().pop() else {
+            panic!("Expected a Stop item");
+        };
+        assert_eq!(stop.message, Some(StateChangeMessage::StopCaffeination));
+    }
+
+    #[test]
+    fn it_only_releases_holds_lod_started() {
+        let ours = Hold::new(1302, 1210, "rsync".into());
+        let other = Hold::new(1305, 1204, "cargo".into());
+        let item = caffeinate_item(
+            false,
+            None,
+            None,
+            TimeOfDay::EVENING,
+            &[ours],
+            &[other],
+            &[],
+        );
+        assert_eq!(item.checked, Some(true));
+        let holds: Vec<_> = item
+            .submenu
+            .unwrap()
+            .into_iter()
+            .filter_map(|entry| match entry {
+                MenuEntry::Item(item) if item.title.starts_with("While") => Some(item),
+                MenuEntry::Item(_) | MenuEntry::Separator => None,
+            })
+            .collect();
+        assert_eq!(holds[0].title, "While rsync (1210)");
+        assert_eq!(
+            holds[0].message,
+            Some(StateChangeMessage::ReleaseHold(1302))
+        );
+        assert_eq!(holds[1].title, "While cargo (1204)");
+        assert_eq!(holds[1].message, None);
+    }
+
+    #[test]
+    fn it_checks_automatic_when_enabled() {
+        assert_eq!(automatic_item(true).checked, Some(true));
+        assert_eq!(automatic_item(false).checked, Some(false));
+        assert_eq!(
+            automatic_item(false).message,
+            Some(StateChangeMessage::ToggleAutomatic)
+        );
+    }
+}
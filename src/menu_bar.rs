@@ -0,0 +1,77 @@
+use super::{
+    StateChangeMessage,
+    ui::{self, Icon, MenuEntry, StatusUi},
+};
+use std::sync::mpsc::Sender;
+use system_status_bar_macos::{ControlState, Image, Menu, MenuItem, StatusItem};
+
+/// The status item in the macOS menu bar, sending the message of each menu item clicked
+pub struct MenuBar {
+    status_item: StatusItem,
+    sender: Sender<StateChangeMessage>,
+}
+
+impl MenuBar {
+    #[must_use]
+    pub fn new(sender: Sender<StateChangeMessage>) -> Self {
+        Self {
+            status_item: StatusItem::new("", Menu::new(vec![])),
+            sender,
+        }
+    }
+
+    fn menu(&self, entries: Vec<MenuEntry>) -> Menu {
+        Menu::new(
+            entries
+                .into_iter()
+                .map(|entry| match entry {
+                    MenuEntry::Item(item) => self.menu_item(item),
+                    MenuEntry::Separator => MenuItem::separator(),
+                })
+                .collect(),
+        )
+    }
+
+    fn menu_item(&self, item: ui::MenuItem) -> MenuItem {
+        let callback = item.message.map(|message| {
+            let sender = self.sender.clone();
+            Box::new(move || {
+                if let Err(error) = sender.send(message.clone()) {
+                    eprintln!(
+                        "Failed to send StateChangeMessage::{:?} message. Error: {error}",
+                        error.0
+                    );
+                }
+            }) as Box<dyn Fn()>
+        });
+        let submenu = item.submenu.map(|entries| self.menu(entries));
+        let mut menu_item = MenuItem::new(item.title, callback, submenu);
+        if let Some(image) = item.icon.and_then(image) {
+            menu_item.set_image(image);
+        }
+        match item.checked {
+            Some(true) => menu_item.set_control_state(ControlState::On),
+            Some(false) => menu_item.set_control_state(ControlState::Off),
+            None => (),
+        }
+
+        menu_item
+    }
+}
+
+impl StatusUi for MenuBar {
+    fn set_icon(&mut self, icon: Icon) {
+        if let Some(image) = image(icon) {
+            self.status_item.set_image(image);
+        }
+    }
+
+    fn set_menu(&mut self, menu: Vec<MenuEntry>) {
+        let menu = self.menu(menu);
+        self.status_item.set_menu(menu);
+    }
+}
+
+fn image(icon: Icon) -> Option<Image> {
+    Image::with_system_symbol_name(icon.symbol, Some(icon.description))
+}
@@ -0,0 +1,131 @@
+use super::{Mode, Plugin, duration::TimeOfDay, hold::Process};
+use std::{
+    sync::{
+        Arc,
+        mpsc::{self, Receiver, Sender},
+    },
+    time::Duration,
+};
+
+/// Message sent to change the app's state
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StateChangeMessage {
+    /// Toggle the current mode
+    ToggleMode,
+
+    /// Switch to the given mode, if not already in it
+    SetMode(Mode),
+
+    /// The `before_switch` hooks have allowed switching to the given mode
+    SwitchApproved(Mode),
+
+    /// Toggle automatic switching of modes based on the configured rules
+    ToggleAutomatic,
+
+    /// Toggle caffeination
+    ToggleCaffeination,
+
+    /// Start caffeinating for the given time, replacing any current caffeination
+    Caffeinate(CaffeinateFor),
+
+    /// Stop caffeinating, if currently doing so
+    StopCaffeination,
+
+    /// Keep the Mac awake until the process with the given pid exits
+    KeepAwakeWhile(u32),
+
+    /// Stop the hold kept by the `caffeinate` with the given pid, if lod started it
+    ReleaseHold(u32),
+
+    /// Clear the caffeination checkmark, or a hold, once its `caffeinate` has exited
+    ClearCaffeination,
+
+    /// Terminate the child with the given pid left running by a previous instance
+    TerminateLeftover(u32),
+
+    /// Look after the child with the given pid left running by a previous instance
+    AdoptLeftover(u32),
+
+    /// A service has exited, so may need restarting
+    ServiceExited,
+
+    /// The backoff before restarting a service has passed
+    RestartServices,
+
+    /// Load config.toml again, eg on SIGHUP
+    ReloadConfig,
+
+    /// A plugin's menu item was clicked, by index of the plugin and then the item
+    PluginMenuItem { plugin: usize, item: usize },
+
+    /// The plugins configured have been started in the background, replacing those running unless
+    /// config.toml was reloaded again since
+    PluginsStarted {
+        generation: u64,
+        plugins: Vec<Arc<Plugin>>,
+    },
+
+    /// A plugin has exited, so its menu items should go
+    PluginExited,
+
+    /// Sent periodically, so the time remaining in the menu can be updated
+    Tick,
+
+    /// The user's processes, listed in the background for the menu as `ps` can take a while, and
+    /// otherwise as `Tick`
+    Processes(Vec<Process>),
+
+    /// Make the change asked for over the control socket or HTTP, replying once it has been
+    /// made or has failed
+    Request(Box<Self>, Reply),
+
+    /// Quit the app
+    Quit,
+}
+
+/// Where to send whether a `StateChangeMessage::Request` succeeded, with the error if not
+#[derive(Clone, Debug)]
+pub struct Reply(Arc<Sender<Result<(), String>>>);
+
+impl Reply {
+    /// A reply, along with where it will be received
+    #[must_use]
+    pub fn new() -> (Self, Receiver<Result<(), String>>) {
+        let (sender, receiver) = mpsc::channel();
+        (Self(Arc::new(sender)), receiver)
+    }
+
+    pub fn send(&self, result: Result<(), String>) {
+        // The requester could have given up waiting
+        let _ = self.0.send(result);
+    }
+}
+
+/// Each reply is only equal to itself and its clones
+impl PartialEq for Reply {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Reply {}
+
+/// How long to caffeinate for
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CaffeinateFor {
+    Duration(Duration),
+    Until(TimeOfDay),
+    Indefinitely,
+}
+
+impl CaffeinateFor {
+    /// Time from now until caffeination should stop, if it should stop at all
+    #[must_use]
+    pub fn duration(self) -> Option<Duration> {
+        match self {
+            Self::Duration(duration) => Some(duration),
+            Self::Until(time) => Some(time.from_now()),
+            Self::Indefinitely => None,
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use super::{CaffeinateFor, duration};
+use std::{
+    fmt::{Display, Formatter},
+    str::FromStr,
+};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Laptop,
+    Desktop,
+}
+
+impl Mode {
+    #[must_use]
+    pub const fn accessibility_description(&self) -> &'static str {
+        match self {
+            Self::Laptop => "Switch to Laptop mode",
+            Self::Desktop => "Switch to Desktop mode",
+        }
+    }
+
+    #[must_use]
+    pub const fn description(&self) -> &'static str {
+        match self {
+            Self::Laptop => "Laptop Mode",
+            Self::Desktop => "Desktop Mode",
+        }
+    }
+
+    #[must_use]
+    pub const fn sf_symbol(&self) -> &'static str {
+        match self {
+            Self::Laptop => "laptopcomputer",
+            Self::Desktop => "desktopcomputer",
+        }
+    }
+
+    /// Name used to refer to the mode in config.toml
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Laptop => "laptop",
+            Self::Desktop => "desktop",
+        }
+    }
+
+    #[must_use]
+    pub const fn toggle(&self) -> Self {
+        match self {
+            Self::Laptop => Self::Desktop,
+            Self::Desktop => Self::Laptop,
+        }
+    }
+}
+
+impl Display for Mode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "laptop" => Ok(Self::Laptop),
+            "desktop" => Ok(Self::Desktop),
+            _ => Err(format!(
+                "Unknown mode `{s}`, expected `laptop` or `desktop`"
+            )),
+        }
+    }
+}
+
+/// What to do with caffeination when switching into a mode
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum KeepAwakePolicy {
+    /// Caffeinate until switching out of the mode
+    On,
+
+    /// Stop caffeinating
+    Off,
+
+    /// Leave caffeination as it is
+    #[default]
+    Unchanged,
+
+    /// Caffeinate for the given time
+    For(std::time::Duration),
+}
+
+/// What a `KeepAwakePolicy` does to caffeination on switching
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum KeepAwakeAction {
+    /// Leave caffeination as it is
+    Unchanged,
+
+    /// Stop caffeinating
+    Stop,
+
+    /// Replace any caffeination with caffeinating for the given time
+    Start(CaffeinateFor),
+}
+
+impl KeepAwakePolicy {
+    #[must_use]
+    pub const fn action(self) -> KeepAwakeAction {
+        match self {
+            Self::On => KeepAwakeAction::Start(CaffeinateFor::Indefinitely),
+            Self::Off => KeepAwakeAction::Stop,
+            Self::Unchanged => KeepAwakeAction::Unchanged,
+            Self::For(duration) => KeepAwakeAction::Start(CaffeinateFor::Duration(duration)),
+        }
+    }
+}
+
+impl FromStr for KeepAwakePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "on" => Ok(Self::On),
+            "off" => Ok(Self::Off),
+            "unchanged" => Ok(Self::Unchanged),
+            duration => match duration::parse(duration) {
+                Ok(duration) if duration.is_zero() => Err(format!(
+                    "Keep awake policy `{s}` should be longer than no time, or `off`"
+                )),
+                Ok(duration) => Ok(Self::For(duration)),
+                Err(_) => Err(format!(
+                    "Unknown keep awake policy `{s}`, expected `on`, `off`, `unchanged` or a \
+                    duration, eg \"2h\""
+                )),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_parses_names() {
+        assert_eq!("laptop".parse::<Mode>(), Ok(Mode::Laptop));
+        assert_eq!(" Desktop ".parse::<Mode>(), Ok(Mode::Desktop));
+        assert!("tablet".parse::<Mode>().is_err());
+    }
+
+    #[test]
+    fn it_round_trips_through_display() {
+        for mode in [Mode::Laptop, Mode::Desktop] {
+            assert_eq!(mode.to_string().parse::<Mode>(), Ok(mode));
+        }
+    }
+
+    #[test]
+    fn it_parses_keep_awake_policies() {
+        assert_eq!("on".parse(), Ok(KeepAwakePolicy::On));
+        assert_eq!("off".parse(), Ok(KeepAwakePolicy::Off));
+        assert_eq!("unchanged".parse(), Ok(KeepAwakePolicy::Unchanged));
+        assert_eq!(
+            "2h".parse(),
+            Ok(KeepAwakePolicy::For(std::time::Duration::from_secs(
+                2 * 60 * 60
+            )))
+        );
+        assert!("sometimes".parse::<KeepAwakePolicy>().is_err());
+        assert!("0".parse::<KeepAwakePolicy>().is_err());
+        assert!("0m".parse::<KeepAwakePolicy>().is_err());
+    }
+
+    #[test]
+    fn it_turns_keep_awake_policies_into_actions() {
+        assert_eq!(
+            KeepAwakePolicy::On.action(),
+            KeepAwakeAction::Start(CaffeinateFor::Indefinitely)
+        );
+        assert_eq!(KeepAwakePolicy::Off.action(), KeepAwakeAction::Stop);
+        assert_eq!(
+            KeepAwakePolicy::Unchanged.action(),
+            KeepAwakeAction::Unchanged
+        );
+    }
+}
@@ -0,0 +1,302 @@
+use super::{
+    hold::Process,
+    program::{Program, ProgramImpl},
+    waiting_child,
+};
+use std::{
+    error::Error,
+    fmt::{Display, Formatter},
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+/// A child process spawned by lod, as recorded in the runtime file
+///
+/// The start time and command are kept alongside the pid, so that a process which has since
+/// reused the pid is never mistaken for the child.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    pid: u32,
+    started: String,
+    command: String,
+}
+
+impl Record {
+    /// Look up the start time and command of a running process
+    ///
+    /// # Errors
+    ///
+    /// If the process is not running, or `ps` could not be run
+    pub fn of(pid: u32) -> Result<Self, Box<dyn Error>> {
+        let mut ps = Command::new("ps");
+        ps.args(["-o", "lstart=,command=", "-p", &pid.to_string()]);
+        let output = ProgramImpl::new(ps, 0).execute()?;
+        let (started, command) = parse_ps(&String::from_utf8_lossy(output.stdout()))
+            .ok_or_else(|| format!("Unexpected output from `ps` for {pid}"))?;
+
+        Ok(Self {
+            pid,
+            started,
+            command,
+        })
+    }
+
+    #[must_use]
+    pub const fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    /// Whether the process is still running and is the one recorded
+    #[must_use]
+    pub fn is_running(&self) -> bool {
+        Self::of(self.pid).is_ok_and(|current| current == *self)
+    }
+
+    /// Whether the process is among those listed, and is the one recorded, which saves running
+    /// `ps` for each record
+    #[must_use]
+    pub fn is_in(&self, processes: &[Process]) -> bool {
+        processes.iter().any(|process| {
+            process.pid() == self.pid
+                && process.started() == self.started
+                && process
+                    .command()
+                    .split_whitespace()
+                    .eq(self.command.split_whitespace())
+        })
+    }
+
+    /// Ask the process to exit, having checked it is still the one recorded
+    ///
+    /// # Errors
+    ///
+    /// If the pid now belongs to another process, or it could not be signalled
+    pub fn terminate(&self) -> Result<(), Box<dyn Error>> {
+        if !self.is_running() {
+            return Err(format!("{self} is no longer running").into());
+        }
+        Ok(waiting_child::send_signal(self.pid, libc::SIGTERM)?)
+    }
+
+    fn to_line(&self) -> String {
+        format!("{}\t{}\t{}", self.pid, self.started, self.command)
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(3, '\t');
+        Some(Self {
+            pid: fields.next()?.parse().ok()?,
+            started: fields.next()?.into(),
+            command: fields.next()?.into(),
+        })
+    }
+}
+
+impl Display for Record {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let program = self.command.split_whitespace().next().unwrap_or_default();
+        let name = Path::new(program)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(program);
+        write!(f, "{name} ({})", self.pid)
+    }
+}
+
+/// The runtime file listing the children which are running, shared between threads
+#[derive(Clone, Debug)]
+pub struct Registry {
+    path: Arc<Mutex<PathBuf>>,
+}
+
+impl Registry {
+    #[must_use]
+    pub fn open(path: PathBuf) -> Self {
+        Self {
+            path: Arc::new(Mutex::new(path)),
+        }
+    }
+
+    /// Record a newly spawned child
+    pub fn record(&self, pid: u32) {
+        let record = match Record::of(pid) {
+            Ok(record) => record,
+            Err(error) => {
+                eprintln!("Failed to record child {pid}: {error:?}");
+                return;
+            }
+        };
+        self.update(|records| records.push(record));
+    }
+
+    /// Forget a child, once it has exited
+    pub fn forget(&self, pid: u32) {
+        self.update(|records| records.retain(|record| record.pid != pid));
+    }
+
+    /// Children left behind by a previous instance which are still running
+    ///
+    /// Those which have exited, or whose pid has been reused, are forgotten.
+    #[must_use]
+    pub fn leftovers(&self) -> Vec<Record> {
+        let mut leftovers = vec![];
+        self.update(|records| {
+            records.retain(Record::is_running);
+            leftovers.clone_from(records);
+        });
+        leftovers
+    }
+
+    fn update(&self, change: impl FnOnce(&mut Vec<Record>)) {
+        let path = self.path.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut records = match fs::read_to_string(&*path) {
+            Ok(contents) => contents.lines().filter_map(Record::from_line).collect(),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => vec![],
+            Err(error) => {
+                eprintln!("Failed to read {}: {error:?}", path.display());
+                vec![]
+            }
+        };
+        change(&mut records);
+
+        let contents: String = records
+            .iter()
+            .map(|record| record.to_line() + "\n")
+            .collect();
+        if let Err(error) = fs::write(&*path, contents) {
+            eprintln!("Failed to write {}: {error:?}", path.display());
+        }
+        // Only released now, so that other threads cannot interleave their changes
+        drop(path);
+    }
+}
+
+/// `ps -o lstart=,command=` gives lines such as `Mon Oct 19 10:00:00 2026 caffeinate -i`, where
+/// the start time is always five fields
+fn parse_ps(output: &str) -> Option<(String, String)> {
+    let mut fields = output.split_whitespace();
+    let started: Vec<_> = fields.by_ref().take(5).collect();
+    if started.len() != 5 {
+        return None;
+    }
+    let command: Vec<_> = fields.collect();
+    if command.is_empty() {
+        return None;
+    }
+
+    Some((started.join(" "), command.join(" ")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::process::Child;
+
+    struct Sleep(Child);
+
+    impl Sleep {
+        fn spawn() -> Self {
+            Self(Command::new("sleep").arg("60").spawn().unwrap())
+        }
+
+        fn pid(&self) -> u32 {
+            self.0.id()
+        }
+    }
+
+    impl Drop for Sleep {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn registry() -> (Registry, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        (Registry::open(dir.path().join("children")), dir)
+    }
+
+    #[test]
+    fn it_parses_ps() {
+        assert_eq!(
+            parse_ps("Mon Oct  9 10:00:00 2026 /usr/bin/caffeinate  -i\n"),
+            Some((
+                "Mon Oct 9 10:00:00 2026".into(),
+                "/usr/bin/caffeinate -i".into()
+            ))
+        );
+        assert_eq!(parse_ps(""), None);
+        assert_eq!(parse_ps("Mon Oct 9 10:00:00 2026"), None);
+    }
+
+    #[test]
+    fn it_round_trips_records() {
+        let record = Record {
+            pid: 42,
+            started: "Mon Oct 9 10:00:00 2026".into(),
+            command: "caffeinate -w 7".into(),
+        };
+        assert_eq!(Record::from_line(&record.to_line()), Some(record.clone()));
+        assert_eq!(record.to_string(), "caffeinate (42)");
+    }
+
+    #[test]
+    fn it_finds_children_still_running() {
+        let (sut, _dir) = registry();
+        let sleep = Sleep::spawn();
+        sut.record(sleep.pid());
+
+        let leftovers = Registry::open(sut.path.lock().unwrap().clone()).leftovers();
+        assert_eq!(leftovers.len(), 1);
+        assert_eq!(leftovers[0].pid(), sleep.pid());
+        assert!(leftovers[0].to_string().starts_with("sleep"));
+
+        sut.forget(sleep.pid());
+        assert!(sut.leftovers().is_empty());
+    }
+
+    #[test]
+    fn it_forgets_children_which_exited() {
+        let (sut, _dir) = registry();
+        let sleep = Sleep::spawn();
+        let pid = sleep.pid();
+        sut.record(pid);
+        drop(sleep);
+
+        assert!(sut.leftovers().is_empty());
+    }
+
+    #[test]
+    fn it_does_not_mistake_another_process_for_a_child() {
+        let sleep = Sleep::spawn();
+        let mut record = Record::of(sleep.pid()).unwrap();
+        assert!(record.is_running());
+
+        record.command = String::from("caffeinate -i");
+        assert!(!record.is_running());
+        assert!(record.terminate().is_err());
+    }
+
+    #[test]
+    fn it_finds_a_child_among_the_processes() {
+        let sleep = Sleep::spawn();
+        let mut record = Record::of(sleep.pid()).unwrap();
+        let processes = crate::hold::processes().unwrap();
+        assert!(record.is_in(&processes));
+
+        record.started = String::from("Mon Oct 9 10:00:00 2026");
+        assert!(!record.is_in(&processes));
+    }
+
+    #[test]
+    fn it_terminates_a_leftover() {
+        let mut sleep = Sleep::spawn();
+        let record = Record::of(sleep.pid()).unwrap();
+        record.terminate().unwrap();
+
+        let status = sleep.0.wait().unwrap();
+        assert!(!status.success());
+    }
+}
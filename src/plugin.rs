@@ -0,0 +1,841 @@
+use super::{
+    Event, Events, Mode, Registry, StateChangeMessage,
+    detector::{Fact, Facts},
+};
+use serde_json::{Value, json};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    io::{BufRead, BufReader, Read, Write},
+    process::{Child, ChildStdin, ChildStdout, Command, Stdio},
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender, SyncSender, TrySendError},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// Version of the protocol spoken with plugins, sent with `initialize` and checked in its reply
+pub const PROTOCOL_VERSION: u64 = 1;
+
+/// How long a plugin has to reply to each request, unless configured otherwise
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest line read from a plugin, so one which misbehaves cannot use up all the memory
+const MAX_LINE_LENGTH: u64 = 1024 * 1024;
+
+/// How long a plugin has to exit by itself once asked to, before it is killed
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(1);
+
+/// Most messages waiting to be written to a plugin, beyond which it is not keeping up
+const MAX_QUEUED_MESSAGES: usize = 64;
+
+/// A program extending lod, from `[[plugins]]` in config.toml
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PluginConfig {
+    name: String,
+    argv: Vec<String>,
+    env: BTreeMap<String, String>,
+    timeout: Duration,
+}
+
+impl PluginConfig {
+    /// # Errors
+    ///
+    /// If `argv` is empty or `timeout` is zero
+    pub fn new(
+        name: Option<String>,
+        argv: Vec<String>,
+        env: BTreeMap<String, String>,
+        timeout: Duration,
+    ) -> Result<Self, String> {
+        let program = argv.first().ok_or("A plugin needs a command to run")?;
+        if timeout.is_zero() {
+            return Err("A plugin's `timeout` should be more than zero".into());
+        }
+        let name = name.unwrap_or_else(|| {
+            program
+                .rsplit('/')
+                .next()
+                .unwrap_or(program.as_str())
+                .into()
+        });
+
+        Ok(Self {
+            name,
+            argv,
+            env,
+            timeout,
+        })
+    }
+
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.argv[0]);
+        command
+            .args(&self.argv[1..])
+            .envs(&self.env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped());
+        command
+    }
+}
+
+/// An item a plugin asked for in the menu, which it is told about when clicked
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PluginMenuItem {
+    pub id: String,
+    pub title: String,
+}
+
+/// What a plugin provides, from its reply to `initialize`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Registration {
+    /// Facts for automatic switching rules, asked for with `detect`
+    pub facts: Vec<String>,
+
+    /// Actions run with `run_action` on each switch of mode
+    pub actions: Vec<String>,
+
+    pub menu_items: Vec<PluginMenuItem>,
+}
+
+impl Registration {
+    fn from_json(result: &Value) -> Result<Self, String> {
+        let version = result["protocol_version"]
+            .as_u64()
+            .ok_or("did not say which `protocol_version` it speaks")?;
+        if version != PROTOCOL_VERSION {
+            return Err(format!(
+                "speaks protocol version {version}, but lod speaks {PROTOCOL_VERSION}"
+            ));
+        }
+
+        let strings = |key: &str| match &result[key] {
+            Value::Null => Ok(vec![]),
+            Value::Array(items) => items
+                .iter()
+                .map(|item| item.as_str().map(String::from))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| format!("registered `{key}` which are not all strings")),
+            _ => Err(format!("registered `{key}` which are not an array")),
+        };
+        let menu_items = match &result["menu_items"] {
+            Value::Null => vec![],
+            Value::Array(items) => items
+                .iter()
+                .map(|item| {
+                    Some(PluginMenuItem {
+                        id: item["id"].as_str()?.into(),
+                        title: item["title"].as_str()?.into(),
+                    })
+                })
+                .collect::<Option<Vec<_>>>()
+                .ok_or("registered `menu_items` without an `id` and `title`")?,
+            _ => return Err("registered `menu_items` which are not an array".into()),
+        };
+
+        Ok(Self {
+            facts: strings("facts")?,
+            actions: strings("actions")?,
+            menu_items,
+        })
+    }
+}
+
+type Reply = Result<Value, String>;
+
+/// Messages queued for the plugin's stdin, along with the requests waiting on a reply
+struct Connection {
+    /// Closing the plugin's stdin once dropped and what was queued has been written
+    writer: Option<SyncSender<Value>>,
+    next_id: u64,
+    pending: HashMap<u64, (&'static str, Sender<Reply>)>,
+    /// Why the plugin can no longer be talked to, once its output has closed
+    gone: Option<String>,
+}
+
+impl Connection {
+    /// Queue the message to be written, without waiting for a plugin which is slow to read it
+    fn send(&self, message: Value) -> Result<(), String> {
+        let writer = self.writer.as_ref().ok_or("is not running")?;
+        writer.try_send(message).map_err(|error| match error {
+            TrySendError::Full(_) => {
+                format!(
+                    "has {MAX_QUEUED_MESSAGES} messages waiting to be read, so is not keeping up"
+                )
+            }
+            TrySendError::Disconnected(_) => "could not be written to".into(),
+        })
+    }
+}
+
+/// Write each message to the plugin's stdin as a line, until it fails or there are no more
+fn write(name: &str, mut stdin: ChildStdin, messages: &Receiver<Value>) {
+    for message in messages {
+        if let Err(error) = writeln!(stdin, "{message}").and_then(|()| stdin.flush()) {
+            eprintln!("Plugin `{name}` could not be written to: {error}");
+            return;
+        }
+    }
+}
+
+/// A running plugin, spoken to with JSON-RPC 2.0, one message per line over its stdin and stdout
+///
+/// Messages are written, and replies and notifications read, on threads of its own, so a plugin
+/// which crashes or hangs only fails its own requests. Once initialized, its exit is reported as
+/// an `ActionFailed` event and `StateChangeMessage::PluginExited`.
+pub struct Plugin {
+    config: PluginConfig,
+    registration: Registration,
+    connection: Arc<Mutex<Connection>>,
+    child: Arc<Mutex<Child>>,
+    report_exit: Arc<AtomicBool>,
+    stopping: AtomicBool,
+    registry: Option<Registry>,
+}
+
+impl fmt::Debug for Plugin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Plugin")
+            .field("config", &self.config)
+            .field("registration", &self.registration)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Each plugin is only equal to itself, as two started with the same config are separate programs
+impl PartialEq for Plugin {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Eq for Plugin {}
+
+impl Plugin {
+    /// Start the plugin and wait for it to reply to `initialize` with what it provides
+    ///
+    /// # Errors
+    ///
+    /// If the plugin could not be started, did not reply in time or speaks another version of the
+    /// protocol
+    pub fn start(
+        config: &PluginConfig,
+        events: &Events,
+        sender: Sender<StateChangeMessage>,
+        registry: Option<&Registry>,
+    ) -> Result<Self, String> {
+        let mut child = config
+            .command()
+            .spawn()
+            .map_err(|error| format!("Plugin `{}` failed to start: {error}", config.name))?;
+        if let Some(registry) = registry {
+            registry.record(child.id());
+        }
+        let stdout = child.stdout.take();
+        let (writer, messages) = mpsc::sync_channel(MAX_QUEUED_MESSAGES);
+        if let Some(stdin) = child.stdin.take() {
+            let name = config.name.clone();
+            thread::spawn(move || write(&name, stdin, &messages));
+        }
+        let connection = Arc::new(Mutex::new(Connection {
+            writer: Some(writer),
+            next_id: 1,
+            pending: HashMap::new(),
+            gone: None,
+        }));
+        let mut plugin = Self {
+            config: config.clone(),
+            registration: Registration::default(),
+            connection,
+            child: Arc::new(Mutex::new(child)),
+            report_exit: Arc::new(AtomicBool::new(false)),
+            stopping: AtomicBool::new(false),
+            registry: registry.cloned(),
+        };
+
+        let reader = Reader {
+            name: config.name.clone(),
+            connection: plugin.connection.clone(),
+            child: plugin.child.clone(),
+            report_exit: plugin.report_exit.clone(),
+            events: events.clone(),
+            sender,
+        };
+        if let Some(stdout) = stdout {
+            thread::spawn(move || reader.run(stdout));
+        }
+
+        let result = plugin.request(
+            "initialize",
+            &json!({
+                "protocol_version": PROTOCOL_VERSION,
+                "lod_version": env!("CARGO_PKG_VERSION"),
+            }),
+        )?;
+        plugin.registration = Registration::from_json(&result)
+            .map_err(|error| format!("Plugin `{}` {error}", config.name))?;
+        plugin.report_exit.store(true, Ordering::Relaxed);
+        println!(
+            "Started plugin {}, providing {:?}",
+            config.name, plugin.registration
+        );
+
+        Ok(plugin)
+    }
+
+    #[must_use]
+    pub const fn config(&self) -> &PluginConfig {
+        &self.config
+    }
+
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    #[must_use]
+    pub const fn registration(&self) -> &Registration {
+        &self.registration
+    }
+
+    /// Whether the plugin can still be talked to, rather than having exited
+    #[must_use]
+    pub fn is_running(&self) -> bool {
+        self.lock_connection().gone.is_none()
+    }
+
+    /// Ask the plugin for the current value of the facts it registered
+    ///
+    /// # Errors
+    ///
+    /// If the plugin failed, or replied with facts it did not register or which are neither
+    /// true, false nor text
+    pub fn detect(&self) -> Result<Facts, String> {
+        let result = self.request("detect", &json!({ "facts": self.registration.facts }))?;
+        let values = result["facts"].as_object().ok_or_else(|| {
+            format!(
+                "Plugin `{}` replied to `detect` without `facts`",
+                self.name()
+            )
+        })?;
+
+        let mut facts = Facts::new();
+        for (name, value) in values {
+            if !self.registration.facts.contains(name) {
+                return Err(format!(
+                    "Plugin `{}` detected `{name}`, which it did not register",
+                    self.name()
+                ));
+            }
+            let fact = match value {
+                Value::Bool(value) => Fact::Bool(*value),
+                Value::String(text) => Fact::Text(text.clone()),
+                _ => {
+                    return Err(format!(
+                        "Plugin `{}` detected `{name}` as {value}, rather than true, false or text",
+                        self.name()
+                    ));
+                }
+            };
+            facts.insert(name.clone(), fact);
+        }
+
+        Ok(facts)
+    }
+
+    /// Run one of the plugin's actions, having switched into `mode`
+    ///
+    /// # Errors
+    ///
+    /// If the plugin failed to run the action
+    pub fn run_action(&self, action: &str, mode: Mode, previous: Mode) -> Result<(), String> {
+        self.request(
+            "run_action",
+            &json!({
+                "action": action,
+                "mode": mode.name(),
+                "previous_mode": previous.name(),
+            }),
+        )
+        .map(drop)
+    }
+
+    /// Tell the plugin one of its menu items was clicked, without waiting for it to be told
+    ///
+    /// # Errors
+    ///
+    /// If the plugin is no longer running, or not keeping up with what it is sent
+    pub fn menu_item_clicked(&self, id: &str) -> Result<(), String> {
+        self.lock_connection()
+            .send(json!({
+                "jsonrpc": "2.0",
+                "method": "menu_item_clicked",
+                "params": { "id": id },
+            }))
+            .map_err(|error| format!("Plugin `{}` {error}", self.name()))
+    }
+
+    /// Ask the plugin to shut down, killing it should it not exit in time, in the background
+    ///
+    /// Gives where it is being stopped, to wait on, unless it was already asked to stop.
+    pub fn stop(&self) -> Option<JoinHandle<()>> {
+        if self.stopping.swap(true, Ordering::Relaxed) {
+            return None;
+        }
+        self.report_exit.store(false, Ordering::Relaxed);
+        // Closing stdin as well, for plugins which stop once there is nothing left to read
+        let writer = self.lock_connection().writer.take();
+        if let Some(writer) = writer {
+            let _ = writer.try_send(json!({ "jsonrpc": "2.0", "method": "shutdown" }));
+        }
+
+        let name = self.config.name.clone();
+        let child = self.child.clone();
+        let registry = self.registry.clone();
+        Some(thread::spawn(move || {
+            let mut child = child.lock().unwrap_or_else(PoisonError::into_inner);
+            let deadline = Instant::now() + SHUTDOWN_GRACE;
+            while matches!(child.try_wait(), Ok(None)) && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+            if matches!(child.try_wait(), Ok(None)) {
+                println!("Killing plugin {name}, as it did not exit");
+                if let Err(error) = child.kill().and_then(|()| child.wait().map(drop)) {
+                    eprintln!("Failed to kill plugin {name}: {error:?}");
+                }
+            }
+            if let Some(registry) = registry {
+                registry.forget(child.id());
+            }
+        }))
+    }
+
+    fn request(&self, method: &'static str, params: &Value) -> Reply {
+        let (sender, receiver) = mpsc::channel();
+        let id = {
+            let mut connection = self.lock_connection();
+            if let Some(gone) = &connection.gone {
+                return Err(gone.clone());
+            }
+            let id = connection.next_id;
+            connection.next_id += 1;
+            connection.pending.insert(id, (method, sender));
+            let request = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+            let sent = connection.send(request);
+            if sent.is_err() {
+                connection.pending.remove(&id);
+            }
+            drop(connection);
+            sent.map_err(|error| format!("Plugin `{}` {error}", self.name()))?;
+            id
+        };
+
+        receiver
+            .recv_timeout(self.config.timeout)
+            .unwrap_or_else(|_| {
+                self.lock_connection().pending.remove(&id);
+                Err(format!(
+                    "Plugin `{}` did not reply to `{method}` within {:?}",
+                    self.name(),
+                    self.config.timeout
+                ))
+            })
+    }
+
+    fn lock_connection(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Drop for Plugin {
+    fn drop(&mut self) {
+        // Not waiting, as a plugin can be dropped on the main thread when config.toml is reloaded
+        drop(self.stop());
+    }
+}
+
+/// Reads replies and notifications from a plugin until its output closes
+struct Reader {
+    name: String,
+    connection: Arc<Mutex<Connection>>,
+    child: Arc<Mutex<Child>>,
+    report_exit: Arc<AtomicBool>,
+    events: Events,
+    sender: Sender<StateChangeMessage>,
+}
+
+impl Reader {
+    fn run(self, stdout: ChildStdout) {
+        let mut stdout = BufReader::new(stdout);
+        let mut line = vec![];
+        let reason = loop {
+            line.clear();
+            match stdout
+                .by_ref()
+                .take(MAX_LINE_LENGTH)
+                .read_until(b'\n', &mut line)
+            {
+                Ok(0) => break self.exit_status(),
+                Ok(length) if length as u64 == MAX_LINE_LENGTH && !line.ends_with(b"\n") => {
+                    let _ = self.lock_child().kill();
+                    break format!(
+                        "sent a line longer than {MAX_LINE_LENGTH} bytes, so was killed"
+                    );
+                }
+                Ok(_) => self.handle(&line),
+                Err(error) => break format!("could not be read from: {error}"),
+            }
+        };
+
+        let error = format!("Plugin `{}` {reason}", self.name);
+        let pending: Vec<_> = {
+            let mut connection = self
+                .connection
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            connection.gone = Some(error.clone());
+            connection.writer = None;
+            connection.pending.drain().collect()
+        };
+        for (_, (_, sender)) in pending {
+            let _ = sender.send(Err(error.clone()));
+        }
+
+        if self.report_exit.load(Ordering::Relaxed) {
+            eprintln!("{error}");
+            self.events.emit(&Event::ActionFailed {
+                action: "run_plugin",
+                error,
+            });
+            if let Err(error) = self.sender.send(StateChangeMessage::PluginExited) {
+                eprintln!("Failed to send StateChangeMessage::PluginExited message: {error}");
+            }
+        }
+    }
+
+    fn handle(&self, line: &[u8]) {
+        let message: Value = match serde_json::from_slice(line) {
+            Ok(message) => message,
+            Err(error) => {
+                eprintln!(
+                    "Plugin `{}` sent something other than JSON, ignoring it: {error}",
+                    self.name
+                );
+                return;
+            }
+        };
+
+        let Some(method) = message.get("method") else {
+            self.handle_reply(&message);
+            return;
+        };
+        let params = &message["params"];
+        match method.as_str() {
+            Some("event") => {
+                let Some(name) = params["name"].as_str().filter(|name| !name.is_empty()) else {
+                    eprintln!("Plugin `{}` sent an event without a `name`", self.name);
+                    return;
+                };
+                self.events.emit(&Event::PluginEvent {
+                    plugin: self.name.clone(),
+                    name: name.into(),
+                    data: params["data"].clone(),
+                });
+            }
+            Some("log") => println!(
+                "Plugin {}: {}",
+                self.name,
+                params["message"].as_str().unwrap_or_default()
+            ),
+            _ => {
+                eprintln!("Plugin `{}` sent unknown method {method}", self.name);
+                if let Some(id) = message.get("id") {
+                    let reply = json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": { "code": -32601, "message": "Method not found" },
+                    });
+                    let _ = self
+                        .connection
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .send(reply);
+                }
+            }
+        }
+    }
+
+    fn handle_reply(&self, message: &Value) {
+        let Some(id) = message["id"].as_u64() else {
+            eprintln!(
+                "Plugin `{}` sent a message which is neither a reply nor a notification",
+                self.name
+            );
+            return;
+        };
+        let Some((method, sender)) = self
+            .connection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .pending
+            .remove(&id)
+        else {
+            // The request may have timed out already
+            return;
+        };
+
+        let reply = message.get("error").map_or_else(
+            || Ok(message.get("result").cloned().unwrap_or_default()),
+            |error| {
+                Err(format!(
+                    "Plugin `{}` failed to {method}: {}",
+                    self.name,
+                    error["message"].as_str().unwrap_or("no reason given")
+                ))
+            },
+        );
+        let _ = sender.send(reply);
+    }
+
+    /// Why the output closed, giving the plugin a moment to exit should it be about to
+    fn exit_status(&self) -> String {
+        let deadline = Instant::now() + SHUTDOWN_GRACE;
+        loop {
+            let status = self.lock_child().try_wait();
+            match status {
+                Ok(Some(status)) => return format!("exited with {status}"),
+                Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+                Ok(None) => return "closed its output".into(),
+                Err(error) => return format!("could not be waited on: {error}"),
+            }
+        }
+    }
+
+    fn lock_child(&self) -> std::sync::MutexGuard<'_, Child> {
+        self.child.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{path::Path, sync::mpsc::Receiver};
+
+    const SAMPLE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/examples/plugins/flag_file.py");
+
+    const REGISTERED: &str =
+        r#"{"jsonrpc": "2.0", "id": 1, "result": {"protocol_version": 1, "facts": ["docked"]}}"#;
+
+    fn sample(flag: &Path) -> PluginConfig {
+        PluginConfig::new(
+            Some("flag_file".into()),
+            vec!["python3".into(), SAMPLE.into(), flag.display().to_string()],
+            BTreeMap::new(),
+            DEFAULT_TIMEOUT,
+        )
+        .unwrap()
+    }
+
+    /// A plugin acted out by a shell script
+    fn scripted(script: &str) -> PluginConfig {
+        PluginConfig::new(
+            Some("scripted".into()),
+            vec!["sh".into(), "-c".into(), script.into()],
+            BTreeMap::new(),
+            Duration::from_millis(500),
+        )
+        .unwrap()
+    }
+
+    fn start(config: &PluginConfig) -> (Result<Plugin, String>, Receiver<StateChangeMessage>) {
+        let (sender, receiver) = mpsc::channel();
+        (
+            Plugin::start(config, &Events::default(), sender, None),
+            receiver,
+        )
+    }
+
+    #[test]
+    fn it_registers_what_the_sample_plugin_provides() {
+        let flag = tempfile::tempdir().unwrap().path().join("flag");
+        let (sut, _) = start(&sample(&flag));
+        let sut = sut.unwrap();
+
+        assert_eq!(
+            *sut.registration(),
+            Registration {
+                facts: vec!["flag_present".into()],
+                actions: vec!["log_switch".into()],
+                menu_items: vec![PluginMenuItem {
+                    id: "toggle_flag".into(),
+                    title: "Toggle Flag".into(),
+                }],
+            }
+        );
+        assert!(sut.is_running());
+    }
+
+    #[test]
+    fn it_detects_facts_with_the_sample_plugin() {
+        let dir = tempfile::tempdir().unwrap();
+        let flag = dir.path().join("flag");
+        let (sut, _) = start(&sample(&flag));
+        let sut = sut.unwrap();
+
+        assert_eq!(
+            sut.detect(),
+            Ok(Facts::from([("flag_present".into(), false.into())]))
+        );
+        std::fs::write(&flag, "").unwrap();
+        assert_eq!(
+            sut.detect(),
+            Ok(Facts::from([("flag_present".into(), true.into())]))
+        );
+    }
+
+    #[test]
+    fn it_runs_actions_of_the_sample_plugin() {
+        let flag = tempfile::tempdir().unwrap().path().join("flag");
+        let (sut, _) = start(&sample(&flag));
+        let sut = sut.unwrap();
+
+        assert_eq!(
+            sut.run_action("log_switch", Mode::Desktop, Mode::Laptop),
+            Ok(())
+        );
+        let error = sut
+            .run_action("launch_rockets", Mode::Desktop, Mode::Laptop)
+            .unwrap_err();
+        assert!(error.contains("failed to run_action"), "{error}");
+    }
+
+    #[test]
+    fn it_pushes_events_from_plugins() {
+        let dir = tempfile::tempdir().unwrap();
+        let flag = dir.path().join("flag");
+        let events = Events::default();
+        let subscription = events.subscribe();
+        let (sender, _receiver) = mpsc::channel();
+        let sut = Plugin::start(&sample(&flag), &events, sender, None).unwrap();
+
+        sut.menu_item_clicked("toggle_flag").unwrap();
+        assert_eq!(
+            subscription.recv_timeout(DEFAULT_TIMEOUT),
+            Ok(Event::PluginEvent {
+                plugin: "flag_file".into(),
+                name: "flag_changed".into(),
+                data: json!({ "present": true }),
+            })
+        );
+        assert!(flag.exists());
+    }
+
+    #[test]
+    fn it_rejects_plugins_speaking_another_protocol_version() {
+        let (sut, _) = start(&scripted(
+            r#"read line; echo '{"jsonrpc": "2.0", "id": 1, "result": {"protocol_version": 2}}'; cat"#,
+        ));
+        let error = sut.err().unwrap();
+        assert!(error.contains("protocol version 2"), "{error}");
+    }
+
+    #[test]
+    fn it_fails_to_start_plugins_which_do_not_exist() {
+        let config = PluginConfig::new(
+            None,
+            vec!["/does/not/exist".into()],
+            BTreeMap::new(),
+            DEFAULT_TIMEOUT,
+        )
+        .unwrap();
+        assert_eq!(config.name(), "exist");
+        assert!(start(&config).0.is_err());
+    }
+
+    #[test]
+    fn it_ignores_lines_which_are_not_json() {
+        let (sut, _) = start(&scripted(&format!(
+            "read line; echo 'Starting up...'; echo '{REGISTERED}'; cat"
+        )));
+        assert_eq!(sut.unwrap().registration().facts, ["docked"]);
+    }
+
+    #[test]
+    fn it_reports_plugins_which_crash() {
+        let events = Events::default();
+        let subscription = events.subscribe();
+        let (sender, receiver) = mpsc::channel();
+        let sut = Plugin::start(
+            &scripted(&format!(
+                "read line; echo '{REGISTERED}'; read line; exit 3"
+            )),
+            &events,
+            sender,
+            None,
+        )
+        .unwrap();
+
+        let error = sut.detect().unwrap_err();
+        assert!(error.contains("exited"), "{error}");
+        assert_eq!(
+            receiver.recv_timeout(DEFAULT_TIMEOUT),
+            Ok(StateChangeMessage::PluginExited)
+        );
+        assert!(matches!(
+            subscription.try_recv(),
+            Ok(Event::ActionFailed {
+                action: "run_plugin",
+                ..
+            })
+        ));
+        assert!(!sut.is_running());
+        assert_eq!(sut.detect().unwrap_err(), error);
+    }
+
+    #[test]
+    fn it_gives_up_on_plugins_which_do_not_reply() {
+        let (sut, _) = start(&scripted(&format!(
+            "read line; echo '{REGISTERED}'; exec sleep 10"
+        )));
+        let sut = sut.unwrap();
+
+        let error = sut.detect().unwrap_err();
+        assert!(error.contains("did not reply to `detect`"), "{error}");
+        // Still running, so is killed once dropped
+        assert!(sut.is_running());
+    }
+
+    #[test]
+    fn it_does_not_wait_for_plugins_which_stop_reading() {
+        let (sut, _) = start(&scripted(&format!(
+            "read line; echo '{REGISTERED}'; exec sleep 10"
+        )));
+        let sut = sut.unwrap();
+
+        let started = Instant::now();
+        let error = (0..10_000)
+            .find_map(|_| sut.menu_item_clicked("toggle_flag").err())
+            .unwrap();
+        assert!(error.contains("not keeping up"), "{error}");
+        assert!(started.elapsed() < DEFAULT_TIMEOUT);
+    }
+
+    #[test]
+    fn it_rejects_facts_which_were_not_registered() {
+        let (sut, _) = start(&scripted(&format!(
+            r#"read line; echo '{REGISTERED}'; read line; echo '{{"jsonrpc": "2.0", "id": 2, "result": {{"facts": {{"on_mars": true}}}}}}'; cat"#
+        )));
+        let error = sut.unwrap().detect().unwrap_err();
+        assert!(error.contains("did not register"), "{error}");
+    }
+}
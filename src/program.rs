@@ -125,6 +125,12 @@ impl Command for std::process::Command {
     }
 }
 
+impl<T: Command + ?Sized> Command for Box<T> {
+    fn output(&mut self) -> io::Result<std::process::Output> {
+        (**self).output()
+    }
+}
+
 #[derive(Debug)]
 pub struct Output {
     status_code: i32,
@@ -0,0 +1,461 @@
+use super::{
+    Mode, Script, ScriptContext, SharedStatus, Status, SystemCommands,
+    detector::{Fact, Facts},
+};
+use std::{collections::BTreeSet, iter::Peekable, str::FromStr, vec::IntoIter};
+
+/// A single rule from config.toml, eg `when external_display && on_ac then desktop`
+///
+/// Conditions can also be a script between backticks, eg ``when `on_ac && hour() > 8` then
+/// desktop``.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    condition: Expr,
+    mode: Mode,
+}
+
+impl Rule {
+    /// Rule matching when the text fact `fact` is exactly `value`
+    #[must_use]
+    pub fn fact_equals(fact: &str, value: &str, mode: Mode) -> Self {
+        Self {
+            condition: Expr::Equals(fact.into(), value.into()),
+            mode,
+        }
+    }
+
+    /// Rule which always matches
+    #[must_use]
+    pub const fn always(mode: Mode) -> Self {
+        Self {
+            condition: Expr::Constant(true),
+            mode,
+        }
+    }
+
+    #[must_use]
+    pub fn matches(&self, facts: &Facts) -> bool {
+        self.condition.evaluate(facts, None)
+    }
+
+    #[must_use]
+    pub const fn mode(&self) -> Mode {
+        self.mode
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rule = s.trim();
+        let condition = rule
+            .strip_prefix("when ")
+            .ok_or_else(|| format!("Rule `{rule}` should start with `when`"))?;
+        let (condition, mode) = condition
+            .rsplit_once(" then ")
+            .ok_or_else(|| format!("Rule `{rule}` should end with `then <mode>`"))?;
+        let mode = mode.parse()?;
+
+        let mut tokens = tokenize(condition)?.into_iter().peekable();
+        let condition = parse_or(&mut tokens)?;
+        if let Some(token) = tokens.next() {
+            return Err(format!("Unexpected {token:?} in rule `{rule}`"));
+        }
+
+        Ok(Self { condition, mode })
+    }
+}
+
+/// An ordered list of rules, where the first matching rule wins
+#[derive(Clone, Debug, Default)]
+pub struct RuleSet {
+    rules: Vec<Rule>,
+    status: Option<SharedStatus>,
+}
+
+impl RuleSet {
+    #[must_use]
+    pub const fn new(rules: Vec<Rule>) -> Self {
+        Self {
+            rules,
+            status: None,
+        }
+    }
+
+    /// Give scripts in the rules the mode and caffeination from `status`
+    #[must_use]
+    pub fn with_status(self, status: SharedStatus) -> Self {
+        Self {
+            status: Some(status),
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Mode of the first rule to match `facts`, if any
+    #[must_use]
+    pub fn evaluate(&self, facts: &Facts) -> Option<Mode> {
+        let status = self.status.as_ref().map(SharedStatus::get);
+        self.rules
+            .iter()
+            .find(|rule| rule.condition.evaluate(facts, status.as_ref()))
+            .map(Rule::mode)
+    }
+
+    /// Names of all facts referred to by the rules, used to decide which detectors to run
+    #[must_use]
+    pub fn facts(&self) -> BTreeSet<&str> {
+        let mut facts = BTreeSet::new();
+        for rule in &self.rules {
+            rule.condition.collect_facts(&mut facts);
+        }
+        facts
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Expr {
+    Constant(bool),
+    Fact(String),
+    Equals(String, String),
+    Not(Box<Self>),
+    And(Box<Self>, Box<Self>),
+    Or(Box<Self>, Box<Self>),
+    Script(Script),
+}
+
+impl Expr {
+    fn evaluate(&self, facts: &Facts, status: Option<&Status>) -> bool {
+        match self {
+            Self::Constant(value) => *value,
+            // Facts which could not be detected are treated as false
+            Self::Fact(name) => facts.get(name).is_some_and(Fact::is_true),
+            Self::Equals(name, value) => {
+                matches!(facts.get(name), Some(Fact::Text(text)) if text == value)
+            }
+            Self::Not(expr) => !expr.evaluate(facts, status),
+            Self::And(lhs, rhs) => lhs.evaluate(facts, status) && rhs.evaluate(facts, status),
+            Self::Or(lhs, rhs) => lhs.evaluate(facts, status) || rhs.evaluate(facts, status),
+            Self::Script(script) => {
+                let context = ScriptContext {
+                    mode: status.map(|status| status.mode),
+                    previous_mode: None,
+                    caffeinating: status.is_some_and(|status| status.caffeinating),
+                    facts: facts.clone(),
+                };
+                script
+                    .is_true(&context, &SystemCommands::shared())
+                    .unwrap_or_else(|error| {
+                        eprintln!("{error}, so the rule does not match");
+                        false
+                    })
+            }
+        }
+    }
+
+    fn collect_facts<'a>(&'a self, facts: &mut BTreeSet<&'a str>) {
+        match self {
+            Self::Constant(_) => (),
+            Self::Fact(name) | Self::Equals(name, _) => {
+                facts.insert(name);
+            }
+            Self::Not(expr) => expr.collect_facts(facts),
+            Self::Script(script) => facts.extend(script.facts().iter().map(String::as_str)),
+            Self::And(lhs, rhs) | Self::Or(lhs, rhs) => {
+                lhs.collect_facts(facts);
+                rhs.collect_facts(facts);
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token {
+    Identifier(String),
+    Text(String),
+    Equals,
+    NotEquals,
+    And,
+    Or,
+    Not,
+    OpenParen,
+    CloseParen,
+    Script(String),
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => (),
+            '(' => tokens.push(Token::OpenParen),
+            ')' => tokens.push(Token::CloseParen),
+            '!' if chars.next_if_eq(&'=').is_some() => tokens.push(Token::NotEquals),
+            '!' => tokens.push(Token::Not),
+            '=' if chars.next_if_eq(&'=').is_some() => tokens.push(Token::Equals),
+            '"' => {
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => text.extend(chars.next()),
+                        Some(c) => text.push(c),
+                        None => return Err(format!("Missing closing `\"` in `{s}`")),
+                    }
+                }
+                tokens.push(Token::Text(text));
+            }
+            '`' => {
+                let mut script = String::new();
+                loop {
+                    match chars.next() {
+                        Some('`') => break,
+                        Some(c) => script.push(c),
+                        None => return Err(format!("Missing closing `` ` `` in `{s}`")),
+                    }
+                }
+                tokens.push(Token::Script(script));
+            }
+            '&' if chars.next_if_eq(&'&').is_some() => tokens.push(Token::And),
+            '|' if chars.next_if_eq(&'|').is_some() => tokens.push(Token::Or),
+            c if c.is_ascii_alphanumeric() || c == '_' => {
+                let mut identifier = String::from(c);
+                while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                    identifier.push(c);
+                }
+                tokens.push(Token::Identifier(identifier));
+            }
+            _ => return Err(format!("Unexpected character `{c}` in `{s}`")),
+        }
+    }
+
+    Ok(tokens)
+}
+
+type Tokens = Peekable<IntoIter<Token>>;
+
+fn parse_or(tokens: &mut Tokens) -> Result<Expr, String> {
+    let mut expr = parse_and(tokens)?;
+    while tokens.next_if_eq(&Token::Or).is_some() {
+        expr = Expr::Or(Box::new(expr), Box::new(parse_and(tokens)?));
+    }
+
+    Ok(expr)
+}
+
+fn parse_and(tokens: &mut Tokens) -> Result<Expr, String> {
+    let mut expr = parse_unary(tokens)?;
+    while tokens.next_if_eq(&Token::And).is_some() {
+        expr = Expr::And(Box::new(expr), Box::new(parse_unary(tokens)?));
+    }
+
+    Ok(expr)
+}
+
+fn parse_unary(tokens: &mut Tokens) -> Result<Expr, String> {
+    match tokens.next() {
+        Some(Token::Not) => Ok(Expr::Not(Box::new(parse_unary(tokens)?))),
+        Some(Token::OpenParen) => {
+            let expr = parse_or(tokens)?;
+            match tokens.next() {
+                Some(Token::CloseParen) => Ok(expr),
+                _ => Err("Missing `)`".into()),
+            }
+        }
+        Some(Token::Identifier(identifier)) => {
+            let negate =
+                match tokens.next_if(|token| matches!(token, Token::Equals | Token::NotEquals)) {
+                    Some(Token::Equals) => false,
+                    Some(_) => true,
+                    None => {
+                        return Ok(match identifier.as_str() {
+                            "true" => Expr::Constant(true),
+                            "false" => Expr::Constant(false),
+                            _ => Expr::Fact(identifier),
+                        });
+                    }
+                };
+            let Some(Token::Text(text)) = tokens.next() else {
+                return Err(format!(
+                    "Expected a \"string\" to compare `{identifier}` with"
+                ));
+            };
+            let expr = Expr::Equals(identifier, text);
+            Ok(if negate {
+                Expr::Not(Box::new(expr))
+            } else {
+                expr
+            })
+        }
+        Some(Token::Script(source)) => Ok(Expr::Script(Script::compile(&source)?)),
+        Some(token) => Err(format!("Unexpected {token:?}")),
+        None => Err("Unexpected end of condition".into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn facts(facts: &[(&str, bool)]) -> Facts {
+        facts
+            .iter()
+            .map(|(name, value)| ((*name).to_string(), (*value).into()))
+            .collect()
+    }
+
+    fn ssid(ssid: &str) -> Facts {
+        Facts::from([("wifi_ssid".into(), ssid.to_string().into())])
+    }
+
+    #[test]
+    fn it_parses_a_simple_rule() {
+        let rule: Rule = "when on_ac then desktop".parse().unwrap();
+        assert_eq!(rule.mode(), Mode::Desktop);
+        assert!(rule.matches(&facts(&[("on_ac", true)])));
+        assert!(!rule.matches(&facts(&[("on_ac", false)])));
+    }
+
+    #[test]
+    fn it_treats_missing_facts_as_false() {
+        let rule: Rule = "when !external_display then laptop".parse().unwrap();
+        assert!(rule.matches(&facts(&[])));
+    }
+
+    #[test]
+    fn it_respects_precedence() {
+        // `&&` binds tighter than `||`
+        let rule: Rule = "when a || b && c then desktop".parse().unwrap();
+        assert!(rule.matches(&facts(&[("a", true)])));
+        assert!(!rule.matches(&facts(&[("b", true)])));
+
+        let rule: Rule = "when (a || b) && c then desktop".parse().unwrap();
+        assert!(!rule.matches(&facts(&[("a", true)])));
+        assert!(rule.matches(&facts(&[("b", true), ("c", true)])));
+    }
+
+    #[test]
+    fn it_supports_constants() {
+        let rule: Rule = "when true then laptop".parse().unwrap();
+        assert!(rule.matches(&facts(&[])));
+    }
+
+    #[test]
+    fn it_compares_text_facts() {
+        let rule: Rule = r#"when wifi_ssid == "Office \"5G\"" then desktop"#.parse().unwrap();
+        assert!(rule.matches(&ssid("Office \"5G\"")));
+        assert!(!rule.matches(&ssid("Home")));
+        assert!(!rule.matches(&facts(&[])));
+
+        let rule: Rule = r#"when wifi_ssid != "Office" then laptop"#.parse().unwrap();
+        assert!(rule.matches(&ssid("Home")));
+        assert!(rule.matches(&facts(&[])));
+    }
+
+    #[test]
+    fn it_treats_text_facts_as_true_when_present() {
+        let rule: Rule = "when wifi_ssid then laptop".parse().unwrap();
+        assert!(rule.matches(&ssid("Home")));
+        assert!(!rule.matches(&facts(&[])));
+    }
+
+    #[test]
+    fn it_runs_scripts_as_conditions() {
+        let rule: Rule = r#"when `on_ac && wifi_ssid != "Cafe"` && !docked then desktop"#
+            .parse()
+            .unwrap();
+        assert!(rule.matches(&facts(&[("on_ac", true)])));
+        assert!(!rule.matches(&facts(&[("on_ac", true), ("docked", true)])));
+        assert!(!rule.matches(&ssid("Cafe")));
+
+        let rules = RuleSet::new(vec![rule]);
+        assert_eq!(
+            rules.facts().into_iter().collect::<Vec<_>>(),
+            vec!["docked", "on_ac", "wifi_ssid"]
+        );
+    }
+
+    #[test]
+    fn it_gives_scripts_in_rules_the_status() {
+        let status = SharedStatus::new(Status::new(Mode::Laptop));
+        let rules = RuleSet::new(vec![
+            r#"when `mode() == "laptop"` then desktop"#.parse().unwrap(),
+        ]);
+        assert_eq!(rules.evaluate(&facts(&[])), None);
+        assert_eq!(
+            rules.with_status(status).evaluate(&facts(&[])),
+            Some(Mode::Desktop)
+        );
+    }
+
+    #[test]
+    fn it_does_not_match_when_scripts_fail() {
+        let rule: Rule = r#"when `throw "no"` then desktop"#.parse().unwrap();
+        assert!(!rule.matches(&facts(&[])));
+        let rule: Rule = "when `42` then desktop".parse().unwrap();
+        assert!(!rule.matches(&facts(&[])));
+    }
+
+    #[test]
+    fn it_rejects_malformed_rules() {
+        assert!("external_display then desktop".parse::<Rule>().is_err());
+        assert!("when external_display".parse::<Rule>().is_err());
+        assert!("when external_display then tablet".parse::<Rule>().is_err());
+        assert!(
+            "when external_display && then desktop"
+                .parse::<Rule>()
+                .is_err()
+        );
+        assert!("when (a || b then desktop".parse::<Rule>().is_err());
+        assert!("when a b then desktop".parse::<Rule>().is_err());
+        assert!("when a & b then desktop".parse::<Rule>().is_err());
+        assert!("when a == b then desktop".parse::<Rule>().is_err());
+        assert!(r#"when a == "b then desktop"#.parse::<Rule>().is_err());
+        assert!("when `a && then desktop".parse::<Rule>().is_err());
+        assert!("when `a &&` then desktop".parse::<Rule>().is_err());
+    }
+
+    #[test]
+    fn it_uses_the_first_matching_rule() {
+        let rules = RuleSet::new(vec![
+            "when external_display && on_ac then desktop"
+                .parse()
+                .unwrap(),
+            "when true then laptop".parse().unwrap(),
+        ]);
+        assert_eq!(
+            rules.evaluate(&facts(&[("external_display", true), ("on_ac", true)])),
+            Some(Mode::Desktop)
+        );
+        assert_eq!(
+            rules.evaluate(&facts(&[("external_display", true)])),
+            Some(Mode::Laptop)
+        );
+    }
+
+    #[test]
+    fn it_has_no_outcome_without_a_match() {
+        let rules = RuleSet::new(vec!["when on_ac then desktop".parse().unwrap()]);
+        assert_eq!(rules.evaluate(&facts(&[])), None);
+    }
+
+    #[test]
+    fn it_lists_referenced_facts() {
+        let rules = RuleSet::new(vec![
+            "when external_display && !on_ac then desktop"
+                .parse()
+                .unwrap(),
+            "when on_ac || true then laptop".parse().unwrap(),
+        ]);
+        assert_eq!(
+            rules.facts().into_iter().collect::<Vec<_>>(),
+            vec!["external_display", "on_ac"]
+        );
+    }
+}
@@ -0,0 +1,111 @@
+use std::{
+    env,
+    fs::{self, DirBuilder},
+    io,
+    os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+/// Directory for files which only matter while lod is running, eg the children it has spawned
+///
+/// This is `$XDG_RUNTIME_DIR/lod` where set, otherwise `lod-<uid>` in the temporary directory,
+/// which on macOS is already private to the user.
+///
+/// # Errors
+///
+/// If the directory could not be created, or is not a directory of the user's own which is
+/// inaccessible to other users
+pub fn dir() -> io::Result<PathBuf> {
+    // SAFETY: `getuid` has no memory safety requirements and cannot fail
+    let uid = unsafe { libc::getuid() };
+    let dir = env::var_os("XDG_RUNTIME_DIR").map_or_else(
+        || env::temp_dir().join(format!("lod-{uid}")),
+        |runtime_dir| PathBuf::from(runtime_dir).join("lod"),
+    );
+
+    DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+    check_private(&dir, uid)?;
+
+    Ok(dir)
+}
+
+/// Directory for what should outlast a logout or reboot, unlike `dir`, eg the mode lod was in
+///
+/// This is `$XDG_STATE_HOME/lod` where set, otherwise `~/.local/state/lod`.
+///
+/// # Errors
+///
+/// If neither `$XDG_STATE_HOME` nor `$HOME` is set, or the directory could not be created
+pub fn state_dir() -> io::Result<PathBuf> {
+    let dir = match (env::var_os("XDG_STATE_HOME"), env::var_os("HOME")) {
+        (Some(state_home), _) => PathBuf::from(state_home).join("lod"),
+        (None, Some(home)) => PathBuf::from(home).join(".local/state/lod"),
+        (None, None) => {
+            return Err(io::Error::other(
+                "Neither XDG_STATE_HOME nor HOME is set to keep state in",
+            ));
+        }
+    };
+
+    DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+    Ok(dir)
+}
+
+/// Check `dir` is a real directory, rather than a symlink, owned by `uid` and private to them, as
+/// another user could have created it first in the shared temporary directory
+fn check_private(dir: &Path, uid: u32) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() {
+        return Err(io::Error::other(format!(
+            "{} should be a directory, rather than a symlink or file",
+            dir.display()
+        )));
+    }
+    if metadata.uid() != uid {
+        return Err(io::Error::other(format!(
+            "{} should be owned by you, but is owned by uid {}",
+            dir.display(),
+            metadata.uid()
+        )));
+    }
+    let mode = metadata.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(io::Error::other(format!(
+            "{} should only be accessible by you, but has mode {mode:o}",
+            dir.display()
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn uid() -> u32 {
+        // SAFETY: `getuid` has no memory safety requirements and cannot fail
+        unsafe { libc::getuid() }
+    }
+
+    #[test]
+    fn it_accepts_a_private_directory_of_your_own() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o700)).unwrap();
+        assert!(check_private(dir.path(), uid()).is_ok());
+    }
+
+    #[test]
+    fn it_rejects_directories_others_could_have_planted() {
+        let dir = tempfile::tempdir().unwrap();
+        let private = dir.path().join("private");
+        DirBuilder::new().mode(0o700).create(&private).unwrap();
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink(&private, &link).unwrap();
+
+        assert!(check_private(&link, uid()).is_err());
+        assert!(check_private(&private, uid() + 1).is_err());
+        fs::set_permissions(&private, fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(check_private(&private, uid()).is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use super::Mode;
+use serde_json::{Value, json};
+use std::{
+    fs, io,
+    path::PathBuf,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// What lod was doing when it last changed, so that the next instance can carry on
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SavedState {
+    /// When timed caffeination stops, `None` if not caffeinating for a limited time
+    pub caffeinate_expires_at: Option<SystemTime>,
+
+    /// The mode lod was in, to start in where it cannot be worked out from the Dock
+    pub mode: Option<Mode>,
+}
+
+impl SavedState {
+    fn to_json(&self) -> Value {
+        let expires_at = self.caffeinate_expires_at.map(|expires_at| {
+            expires_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        });
+        json!({
+            "caffeinate_expires_at": expires_at,
+            "mode": self.mode.map(|mode| mode.name()),
+        })
+    }
+
+    fn from_json(json: &Value) -> Self {
+        Self {
+            caffeinate_expires_at: json["caffeinate_expires_at"]
+                .as_u64()
+                .map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+            mode: json["mode"].as_str().and_then(|mode| mode.parse().ok()),
+        }
+    }
+}
+
+/// The file in the state directory the `SavedState` is kept in, so it outlasts a reboot
+#[derive(Clone, Debug)]
+pub struct StateFile {
+    path: PathBuf,
+    /// What was last saved, so the file is only written when that changes
+    saved: Arc<Mutex<Option<SavedState>>>,
+}
+
+impl StateFile {
+    #[must_use]
+    pub fn open(path: PathBuf) -> Self {
+        Self {
+            path,
+            saved: Arc::default(),
+        }
+    }
+
+    /// The state saved by this or a previous instance, which is the default if there is none
+    #[must_use]
+    pub fn load(&self) -> SavedState {
+        match fs::read_to_string(&self.path) {
+            Ok(contents) => serde_json::from_str(&contents).map_or_else(
+                |error| {
+                    eprintln!("Ignoring malformed {}: {error}", self.path.display());
+                    SavedState::default()
+                },
+                |json| SavedState::from_json(&json),
+            ),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => SavedState::default(),
+            Err(error) => {
+                eprintln!("Failed to read {}: {error:?}", self.path.display());
+                SavedState::default()
+            }
+        }
+    }
+
+    /// Replace the saved state, should it have changed since last saved
+    ///
+    /// The new state is written alongside, then renamed over the file, so it is never left half
+    /// written.
+    pub fn save(&self, state: &SavedState) {
+        let mut saved = self.saved.lock().unwrap_or_else(PoisonError::into_inner);
+        if saved.as_ref() == Some(state) {
+            return;
+        }
+        let temporary = self.path.with_extension("json.tmp");
+        let written = fs::write(&temporary, format!("{}\n", state.to_json()))
+            .and_then(|()| fs::rename(&temporary, &self.path));
+        match written {
+            Ok(()) => *saved = Some(state.clone()),
+            Err(error) => eprintln!("Failed to write {}: {error:?}", self.path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn it_saves_and_loads_the_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = StateFile::open(dir.path().join("state.json"));
+        assert_eq!(file.load(), SavedState::default());
+
+        let state = SavedState {
+            caffeinate_expires_at: Some(UNIX_EPOCH + Duration::from_secs(1_760_000_000)),
+            mode: Some(Mode::Desktop),
+        };
+        file.save(&state);
+        assert_eq!(file.load(), state);
+    }
+
+    #[test]
+    fn it_only_writes_the_state_when_it_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let file = StateFile::open(path.clone());
+        let state = SavedState {
+            caffeinate_expires_at: Some(SystemTime::now()),
+            mode: None,
+        };
+
+        file.save(&state);
+        fs::remove_file(&path).unwrap();
+        file.save(&state);
+        assert!(!path.exists());
+        file.save(&SavedState::default());
+        assert_eq!(file.load(), SavedState::default());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn it_ignores_a_malformed_file() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("state.json"), "not json").unwrap();
+        let file = StateFile::open(dir.path().join("state.json"));
+        assert_eq!(file.load(), SavedState::default());
+    }
+}
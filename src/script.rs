@@ -0,0 +1,489 @@
+use super::{
+    Mode,
+    detector::{Fact, Facts},
+    program::{self, Command, Program, ProgramImpl},
+};
+use rhai::{
+    AST, Array, Dynamic, Engine, EvalAltResult, Map, ParseErrorType, Scope,
+    module_resolvers::DummyModuleResolver,
+};
+use std::{
+    fmt::{Debug, Formatter},
+    sync::{Arc, LazyLock},
+};
+
+/// Operations a script can perform before it is stopped, so one which loops forever cannot hang
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// Most facts a script can refer to, in case the names keep coming
+const MAX_FACTS: usize = 64;
+
+/// Makes the commands run by scripts, so they can be faked in tests
+pub trait Commands: Send + Sync {
+    fn command(&self, argv: &[String]) -> Box<dyn Command + Send>;
+}
+
+/// Runs commands for real
+pub struct SystemCommands;
+
+impl SystemCommands {
+    /// The one instance, shared by every script run for real
+    #[must_use]
+    pub fn shared() -> Arc<dyn Commands> {
+        static SHARED: LazyLock<Arc<dyn Commands>> = LazyLock::new(|| Arc::new(SystemCommands));
+        SHARED.clone()
+    }
+}
+
+impl Commands for SystemCommands {
+    fn command(&self, argv: &[String]) -> Box<dyn Command + Send> {
+        let mut command = std::process::Command::new(&argv[0]);
+        command.args(&argv[1..]);
+        Box::new(command)
+    }
+}
+
+/// What a script can see of the app
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScriptContext {
+    /// Current mode, or the one being switched to, if known
+    pub mode: Option<Mode>,
+    pub previous_mode: Option<Mode>,
+    pub caffeinating: bool,
+    pub facts: Facts,
+}
+
+/// A Rhai script, with access to a small API for acting on the Mac
+///
+/// The API is `run(argv)`, `defaults_read(domain, key)`, `defaults_write(domain, key, value)`,
+/// `notify(title, message)`, `mode()`, `previous_mode()` and `caffeinating()`. Facts are
+/// variables, eg `on_ac`, and are false when they could not be detected. Scripts cannot load
+/// modules, and are stopped should they run for too long.
+#[derive(Clone)]
+pub struct Script {
+    source: String,
+    ast: Arc<AST>,
+    facts: Vec<String>,
+}
+
+impl Script {
+    /// Compile `source`, finding the facts it refers to
+    ///
+    /// # Errors
+    ///
+    /// If the script does not parse
+    pub fn compile(source: &str) -> Result<Self, String> {
+        let engine = sandbox();
+        // Variables the script does not define itself are facts, found one at a time
+        let mut scope = Scope::new();
+        let mut facts = vec![];
+        loop {
+            match engine.compile_with_scope(&scope, source) {
+                Ok(ast) => {
+                    return Ok(Self {
+                        source: source.into(),
+                        ast: Arc::new(ast),
+                        facts,
+                    });
+                }
+                Err(error) => match error.err_type() {
+                    ParseErrorType::VariableUndefined(name) if facts.len() < MAX_FACTS => {
+                        scope.push_dynamic(name.as_str(), Dynamic::UNIT);
+                        facts.push(name.clone());
+                    }
+                    _ => return Err(format!("Invalid script: {error}")),
+                },
+            }
+        }
+    }
+
+    /// Names of the facts the script refers to
+    #[must_use]
+    pub fn facts(&self) -> &[String] {
+        &self.facts
+    }
+
+    /// Run the script, giving what it evaluates to
+    ///
+    /// # Errors
+    ///
+    /// If the script threw an error, or ran for too long
+    pub fn run(
+        &self,
+        context: &ScriptContext,
+        commands: &Arc<dyn Commands>,
+    ) -> Result<Dynamic, String> {
+        let engine = engine(context, commands);
+        let mut scope = Scope::new();
+        for name in &self.facts {
+            let value = match context.facts.get(name) {
+                Some(Fact::Bool(value)) => (*value).into(),
+                Some(Fact::Text(text)) => text.clone().into(),
+                None => false.into(),
+            };
+            scope.push_constant_dynamic(name.as_str(), value);
+        }
+
+        engine
+            .eval_ast_with_scope::<Dynamic>(&mut scope, &self.ast)
+            .map_err(|error| format!("Script failed: {error}"))
+    }
+
+    /// Run the script as a condition, which should evaluate to true or false
+    ///
+    /// # Errors
+    ///
+    /// If the script failed, or evaluated to something else
+    pub fn is_true(
+        &self,
+        context: &ScriptContext,
+        commands: &Arc<dyn Commands>,
+    ) -> Result<bool, String> {
+        self.run(context, commands)?.as_bool().map_err(|type_name| {
+            format!("Script should give true or false, rather than {type_name}")
+        })
+    }
+}
+
+impl Debug for Script {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Script({:?})", self.source)
+    }
+}
+
+impl PartialEq for Script {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Eq for Script {}
+
+/// An engine without the API, which can only compute
+fn sandbox() -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_module_resolver(DummyModuleResolver::new())
+        .set_max_operations(MAX_OPERATIONS)
+        .set_max_call_levels(32)
+        .set_max_string_size(1024 * 1024)
+        .set_max_array_size(10_000)
+        .set_max_map_size(10_000)
+        .set_strict_variables(true)
+        .on_print(|text| println!("Script: {text}"))
+        .on_debug(|text, _, _| println!("Script: {text}"));
+    engine
+}
+
+fn engine(context: &ScriptContext, commands: &Arc<dyn Commands>) -> Engine {
+    let mut engine = sandbox();
+
+    let mode = context.mode.map(|mode| mode.name());
+    engine.register_fn("mode", move || mode.map_or(Dynamic::UNIT, Dynamic::from));
+    let previous_mode = context.previous_mode.map(|mode| mode.name());
+    engine.register_fn("previous_mode", move || {
+        previous_mode.map_or(Dynamic::UNIT, Dynamic::from)
+    });
+    let caffeinating = context.caffeinating;
+    engine.register_fn("caffeinating", move || caffeinating);
+
+    let run_commands = commands.clone();
+    engine.register_fn(
+        "run",
+        move |argv: Array| -> Result<Map, Box<EvalAltResult>> {
+            let argv = argv
+                .into_iter()
+                .map(|arg| {
+                    arg.into_string()
+                        .map_err(|_| "run() takes an array of strings")
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let output = execute(run_commands.as_ref(), &argv)?;
+            Ok(Map::from([
+                ("status".into(), i64::from(*output.status_code()).into()),
+                ("stdout".into(), text(output.stdout()).into()),
+                ("stderr".into(), text(output.stderr()).into()),
+            ]))
+        },
+    );
+
+    let read_commands = commands.clone();
+    engine.register_fn(
+        "defaults_read",
+        move |domain: &str, key: &str| -> Result<String, Box<EvalAltResult>> {
+            let argv = ["defaults", "read", domain, key].map(String::from);
+            let output = succeed(read_commands.as_ref(), &argv)?;
+            Ok(text(output.stdout()).trim_end().into())
+        },
+    );
+
+    let write_commands = commands.clone();
+    engine.register_fn(
+        "defaults_write",
+        move |domain: &str, key: &str, value: Dynamic| -> Result<(), Box<EvalAltResult>> {
+            let (kind, value) = if let Ok(value) = value.as_bool() {
+                ("-bool", value.to_string())
+            } else if let Ok(value) = value.as_int() {
+                ("-int", value.to_string())
+            } else if let Ok(value) = value.as_float() {
+                ("-float", value.to_string())
+            } else if value.is_string() {
+                ("-string", value.to_string())
+            } else {
+                return Err(format!(
+                    "defaults_write() cannot write a {}, only a bool, number or string",
+                    value.type_name()
+                )
+                .into());
+            };
+            let argv = ["defaults", "write", domain, key, kind, &value].map(String::from);
+            succeed(write_commands.as_ref(), &argv)?;
+            Ok(())
+        },
+    );
+
+    let notify_commands = commands.clone();
+    engine.register_fn(
+        "notify",
+        move |title: &str, message: &str| -> Result<(), Box<EvalAltResult>> {
+            let script = format!(
+                "display notification {} with title {}",
+                apple_script_string(message),
+                apple_script_string(title)
+            );
+            let argv = [String::from("osascript"), String::from("-e"), script];
+            succeed(notify_commands.as_ref(), &argv)?;
+            Ok(())
+        },
+    );
+
+    engine
+}
+
+/// Run `argv`, whatever its exit code
+fn execute(commands: &dyn Commands, argv: &[String]) -> Result<program::Output, String> {
+    if argv.is_empty() {
+        return Err("run() needs a program to run".into());
+    }
+    match ProgramImpl::new(commands.command(argv), 0).execute() {
+        Ok(output) | Err(program::Error::UnexpectedStatusCode(output)) => Ok(output),
+        Err(error) => Err(format!("Failed to run `{}`: {error}", argv.join(" "))),
+    }
+}
+
+/// Run `argv`, which should exit successfully
+fn succeed(commands: &dyn Commands, argv: &[String]) -> Result<program::Output, String> {
+    let output = execute(commands, argv)?;
+    if *output.status_code() != 0 {
+        return Err(format!(
+            "`{}` exited with {}: {}",
+            argv.join(" "),
+            output.status_code(),
+            text(output.stderr()).trim_end()
+        ));
+    }
+    Ok(output)
+}
+
+fn text(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+/// Quote `s` as a string in `AppleScript`
+fn apple_script_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::program::MockCommand;
+    use std::{os::unix::process::ExitStatusExt, process::ExitStatus, sync::Mutex};
+
+    /// Records the commands run, replying with the exit code and stdout given by `reply`
+    struct FakeCommands {
+        run: Mutex<Vec<Vec<String>>>,
+        reply: fn(&[String]) -> (i32, &'static str),
+    }
+
+    impl Commands for FakeCommands {
+        fn command(&self, argv: &[String]) -> Box<dyn Command + Send> {
+            self.run.lock().unwrap().push(argv.to_vec());
+            let (code, stdout) = (self.reply)(argv);
+            let mut command = MockCommand::new();
+            command.expect_output().times(1).returning(move || {
+                Ok(std::process::Output {
+                    status: ExitStatus::from_raw(code << 8),
+                    stdout: stdout.into(),
+                    stderr: vec![],
+                })
+            });
+            Box::new(command)
+        }
+    }
+
+    fn fake(reply: fn(&[String]) -> (i32, &'static str)) -> (Arc<FakeCommands>, Arc<dyn Commands>) {
+        let commands = Arc::new(FakeCommands {
+            run: Mutex::new(vec![]),
+            reply,
+        });
+        (commands.clone(), commands)
+    }
+
+    fn context() -> ScriptContext {
+        ScriptContext {
+            mode: Some(Mode::Desktop),
+            previous_mode: Some(Mode::Laptop),
+            caffeinating: true,
+            facts: Facts::from([
+                ("on_ac".into(), true.into()),
+                ("wifi_ssid".into(), String::from("Office").into()),
+            ]),
+        }
+    }
+
+    fn run(source: &str, commands: &Arc<dyn Commands>) -> Result<Dynamic, String> {
+        Script::compile(source).unwrap().run(&context(), commands)
+    }
+
+    #[test]
+    fn it_gives_scripts_the_state_of_the_app() {
+        let (_, commands) = fake(|_| (0, ""));
+        let result = run(
+            "`${mode()} ${previous_mode()} ${caffeinating()}`",
+            &commands,
+        );
+        assert_eq!(
+            result.unwrap().into_string().unwrap(),
+            "desktop laptop true"
+        );
+    }
+
+    #[test]
+    fn it_finds_the_facts_scripts_refer_to() {
+        let sut = Script::compile(
+            r#"let docked = external_display && on_ac; docked || wifi_ssid == "Office""#,
+        )
+        .unwrap();
+        assert_eq!(sut.facts(), ["external_display", "on_ac", "wifi_ssid"]);
+
+        let (_, commands) = fake(|_| (0, ""));
+        // `external_display` was not detected, so is false
+        assert_eq!(sut.is_true(&context(), &commands), Ok(true));
+    }
+
+    #[test]
+    fn it_runs_commands() {
+        let (recorded, commands) = fake(|_| (1, "not running\n"));
+        let result = run(
+            r#"let zoom = run(["pgrep", "-q", "zoom.us"]); `${zoom.status} ${zoom.stdout}`"#,
+            &commands,
+        );
+        assert_eq!(result.unwrap().into_string().unwrap(), "1 not running\n");
+        assert_eq!(*recorded.run.lock().unwrap(), [["pgrep", "-q", "zoom.us"]]);
+    }
+
+    #[test]
+    fn it_reads_defaults() {
+        let (recorded, commands) = fake(|_| (0, "1\n"));
+        let result = run(
+            r#"defaults_read("com.apple.dock", "autohide") == "1""#,
+            &commands,
+        );
+        assert!(result.unwrap().as_bool().unwrap());
+        assert_eq!(
+            *recorded.run.lock().unwrap(),
+            [["defaults", "read", "com.apple.dock", "autohide"]]
+        );
+
+        let (_, commands) = fake(|_| (1, ""));
+        let error = run(r#"defaults_read("com.apple.dock", "nope")"#, &commands).unwrap_err();
+        assert!(error.contains("exited with 1"), "{error}");
+    }
+
+    #[test]
+    fn it_writes_defaults_with_their_type() {
+        let (recorded, commands) = fake(|_| (0, ""));
+        let result = run(
+            r#"
+                defaults_write("com.apple.dock", "autohide", true);
+                defaults_write("com.apple.dock", "tilesize", 48);
+                defaults_write("com.apple.dock", "orientation", "left");
+            "#,
+            &commands,
+        );
+        assert!(result.unwrap().is_unit());
+        assert_eq!(
+            *recorded.run.lock().unwrap(),
+            [
+                [
+                    "defaults",
+                    "write",
+                    "com.apple.dock",
+                    "autohide",
+                    "-bool",
+                    "true"
+                ],
+                [
+                    "defaults",
+                    "write",
+                    "com.apple.dock",
+                    "tilesize",
+                    "-int",
+                    "48"
+                ],
+                [
+                    "defaults",
+                    "write",
+                    "com.apple.dock",
+                    "orientation",
+                    "-string",
+                    "left"
+                ],
+            ]
+        );
+        assert!(run(r#"defaults_write("a", "b", [1])"#, &commands).is_err());
+    }
+
+    #[test]
+    fn it_notifies() {
+        let (recorded, commands) = fake(|_| (0, ""));
+        let result = run(r#"notify("lod", "Said \"hi\"")"#, &commands);
+        assert!(result.unwrap().is_unit());
+        assert_eq!(
+            *recorded.run.lock().unwrap(),
+            [[
+                "osascript",
+                "-e",
+                r#"display notification "Said \"hi\"" with title "lod""#
+            ]]
+        );
+    }
+
+    #[test]
+    fn it_reports_errors_thrown_by_scripts() {
+        let (_, commands) = fake(|_| (0, ""));
+        let error = run(r#"throw "not while presenting""#, &commands).unwrap_err();
+        assert!(error.contains("not while presenting"), "{error}");
+        assert!(Script::compile("on_ac &&").is_err());
+    }
+
+    #[test]
+    fn it_stops_scripts_which_run_forever() {
+        let (_, commands) = fake(|_| (0, ""));
+        assert!(run("loop {}", &commands).is_err());
+    }
+
+    #[test]
+    fn it_does_not_load_modules() {
+        let (_, commands) = fake(|_| (0, ""));
+        assert!(run(r#"import "/etc/hosts" as hosts; 1"#, &commands).is_err());
+    }
+
+    #[test]
+    fn it_expects_conditions_to_be_true_or_false() {
+        let (_, commands) = fake(|_| (0, ""));
+        let sut = Script::compile("wifi_ssid").unwrap();
+        assert!(sut.is_true(&context(), &commands).is_err());
+    }
+}
@@ -0,0 +1,80 @@
+use super::StateChangeMessage;
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM},
+    iterator::{Handle, Signals},
+};
+use std::{io, sync::mpsc::Sender, thread};
+
+/// Turns signals sent to lod into messages, so they are handled on the main thread like any
+/// other request: SIGINT and SIGTERM quit cleanly and SIGHUP reloads the configuration
+pub struct SignalBridge {
+    handle: Handle,
+}
+
+impl SignalBridge {
+    /// # Errors
+    ///
+    /// If the signal handlers could not be registered
+    pub fn spawn(sender: Sender<StateChangeMessage>) -> io::Result<Self> {
+        let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP])?;
+        let handle = signals.handle();
+
+        thread::spawn(move || {
+            for signal in &mut signals {
+                let Some(message) = message_for(signal) else {
+                    continue;
+                };
+                println!("Received signal {signal}, sending {message:?}");
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Self { handle })
+    }
+}
+
+impl Drop for SignalBridge {
+    fn drop(&mut self) {
+        self.handle.close();
+    }
+}
+
+const fn message_for(signal: libc::c_int) -> Option<StateChangeMessage> {
+    match signal {
+        SIGINT | SIGTERM => Some(StateChangeMessage::Quit),
+        SIGHUP => Some(StateChangeMessage::ReloadConfig),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{sync::mpsc, time::Duration};
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    #[test]
+    fn it_maps_signals_to_messages() {
+        assert_eq!(message_for(SIGINT), Some(StateChangeMessage::Quit));
+        assert_eq!(message_for(SIGTERM), Some(StateChangeMessage::Quit));
+        assert_eq!(message_for(SIGHUP), Some(StateChangeMessage::ReloadConfig));
+        assert_eq!(message_for(libc::SIGUSR1), None);
+    }
+
+    #[test]
+    fn it_sends_messages_for_signals_received() {
+        let (sender, receiver) = mpsc::channel();
+        let _sut = SignalBridge::spawn(sender).unwrap();
+
+        signal_hook::low_level::raise(SIGHUP).unwrap();
+        assert_eq!(
+            receiver.recv_timeout(TIMEOUT),
+            Ok(StateChangeMessage::ReloadConfig)
+        );
+        signal_hook::low_level::raise(SIGTERM).unwrap();
+        assert_eq!(receiver.recv_timeout(TIMEOUT), Ok(StateChangeMessage::Quit));
+    }
+}
@@ -0,0 +1,141 @@
+use super::{Mode, ServiceStatus};
+use serde_json::{Value, json};
+use std::{
+    sync::{Arc, Mutex, PoisonError},
+    time::SystemTime,
+};
+
+/// Snapshot of the app's state, published for the control socket whenever it changes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Status {
+    pub mode: Mode,
+    pub automatic_available: bool,
+    pub automatic_enabled: bool,
+    pub caffeinating: bool,
+    pub caffeinate_expires_at: Option<SystemTime>,
+    /// Whether caffeination is as the mode's keep awake policy says, `None` if it has none
+    pub caffeinate_by_mode: Option<bool>,
+    /// Name of what keeps the computer awake, eg `systemd-inhibit`
+    pub keep_awake: &'static str,
+    /// What is being kept from happening while caffeinating, eg `idle` and `sleep`
+    pub inhibits: Vec<String>,
+    /// Processes being kept awake for, by pid and name
+    pub holds: Vec<(u32, String)>,
+    /// Services by name, status and number of restarts
+    pub services: Vec<(String, ServiceStatus, u32)>,
+}
+
+impl Status {
+    #[must_use]
+    pub const fn new(mode: Mode) -> Self {
+        Self {
+            mode,
+            automatic_available: false,
+            automatic_enabled: false,
+            caffeinating: false,
+            caffeinate_expires_at: None,
+            caffeinate_by_mode: None,
+            keep_awake: "",
+            inhibits: vec![],
+            holds: vec![],
+            services: vec![],
+        }
+    }
+
+    #[must_use]
+    pub fn to_json(&self) -> Value {
+        let expires_in = self.caffeinate_expires_at.map(|expires_at| {
+            expires_at
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+                .as_secs()
+        });
+        let controlled_by = self
+            .caffeinate_by_mode
+            .map(|by_mode| if by_mode { "mode" } else { "manual" });
+        let holds: Vec<_> = self
+            .holds
+            .iter()
+            .map(|(pid, name)| json!({ "pid": pid, "name": name }))
+            .collect();
+        let services: Vec<_> = self
+            .services
+            .iter()
+            .map(|(name, status, restarts)| {
+                json!({ "name": name, "status": status.to_string(), "restarts": restarts })
+            })
+            .collect();
+
+        json!({
+            "mode": self.mode.name(),
+            "automatic": {
+                "available": self.automatic_available,
+                "enabled": self.automatic_enabled,
+            },
+            "caffeinate": {
+                "active": self.caffeinating,
+                "expires_in_secs": expires_in,
+                "controlled_by": controlled_by,
+                "backend": self.keep_awake,
+                "inhibits": self.inhibits,
+            },
+            "holds": holds,
+            "services": services,
+        })
+    }
+}
+
+/// The latest `Status`, shared between the main thread and the control socket
+#[derive(Clone, Debug)]
+pub struct SharedStatus(Arc<Mutex<Status>>);
+
+impl SharedStatus {
+    #[must_use]
+    pub fn new(status: Status) -> Self {
+        Self(Arc::new(Mutex::new(status)))
+    }
+
+    pub fn set(&self, status: Status) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = status;
+    }
+
+    #[must_use]
+    pub fn get(&self) -> Status {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn it_serialises_to_json() {
+        let mut status = Status::new(Mode::Desktop);
+        status.automatic_available = true;
+        status.caffeinating = true;
+        status.caffeinate_expires_at = Some(SystemTime::now() + Duration::from_secs(90));
+        status.caffeinate_by_mode = Some(false);
+        status.keep_awake = "systemd-inhibit";
+        status.inhibits = vec!["idle".into(), "sleep".into()];
+        status.holds.push((42, "rsync".into()));
+
+        let json = status.to_json();
+        assert_eq!(json["mode"], "desktop");
+        assert_eq!(
+            json["automatic"],
+            json!({ "available": true, "enabled": false })
+        );
+        assert_eq!(json["caffeinate"]["active"], true);
+        assert!(json["caffeinate"]["expires_in_secs"].as_u64().unwrap() <= 90);
+        assert_eq!(json["caffeinate"]["controlled_by"], "manual");
+        assert_eq!(json["caffeinate"]["backend"], "systemd-inhibit");
+        assert_eq!(json["caffeinate"]["inhibits"], json!(["idle", "sleep"]));
+        assert_eq!(json["holds"], json!([{ "pid": 42, "name": "rsync" }]));
+        assert_eq!(json["services"], json!([]));
+    }
+}
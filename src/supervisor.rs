@@ -0,0 +1,507 @@
+use super::{Registry, StateChangeMessage, WaitingChild};
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Formatter},
+    process::{Command, ExitStatus, Stdio},
+    str::FromStr,
+    sync::mpsc::Sender,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Wait before the first restart, doubling for each failure in a row
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// A service which has run for this long is considered healthy, so the backoff starts over
+const HEALTHY_AFTER: Duration = Duration::from_secs(60);
+
+/// When to restart a service once it has exited
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum RestartPolicy {
+    Never,
+    #[default]
+    OnFailure,
+    Always,
+}
+
+impl RestartPolicy {
+    const fn should_restart(self, success: bool) -> bool {
+        match self {
+            Self::Never => false,
+            Self::OnFailure => !success,
+            Self::Always => true,
+        }
+    }
+}
+
+impl FromStr for RestartPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "never" => Ok(Self::Never),
+            "on-failure" => Ok(Self::OnFailure),
+            "always" => Ok(Self::Always),
+            _ => Err(format!(
+                "Unknown restart policy `{s}`, expected `never`, `on-failure` or `always`"
+            )),
+        }
+    }
+}
+
+/// Signal used to ask a service to stop, eg `"SIGINT"` or `"INT"`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct StopSignal(libc::c_int);
+
+impl Default for StopSignal {
+    fn default() -> Self {
+        Self(libc::SIGTERM)
+    }
+}
+
+impl FromStr for StopSignal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.trim().to_ascii_uppercase();
+        let signal = match name.strip_prefix("SIG").unwrap_or(&name) {
+            "TERM" => libc::SIGTERM,
+            "INT" => libc::SIGINT,
+            "HUP" => libc::SIGHUP,
+            "QUIT" => libc::SIGQUIT,
+            "KILL" => libc::SIGKILL,
+            "USR1" => libc::SIGUSR1,
+            "USR2" => libc::SIGUSR2,
+            _ => {
+                return Err(format!(
+                    "Unknown stop signal `{s}`, eg `SIGTERM` or `SIGINT`"
+                ));
+            }
+        };
+        Ok(Self(signal))
+    }
+}
+
+/// A program to keep running while in a mode, from `desktop_services` or `laptop_services`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServiceConfig {
+    name: String,
+    argv: Vec<String>,
+    env: BTreeMap<String, String>,
+    restart: RestartPolicy,
+    stop_signal: StopSignal,
+}
+
+impl ServiceConfig {
+    /// # Errors
+    ///
+    /// If `argv` is empty
+    pub fn new(
+        name: Option<String>,
+        argv: Vec<String>,
+        env: BTreeMap<String, String>,
+        restart: RestartPolicy,
+        stop_signal: StopSignal,
+    ) -> Result<Self, String> {
+        let program = argv.first().ok_or("A service needs a command to run")?;
+        let name = name.unwrap_or_else(|| {
+            program
+                .rsplit('/')
+                .next()
+                .unwrap_or(program.as_str())
+                .into()
+        });
+
+        Ok(Self {
+            name,
+            argv,
+            env,
+            restart,
+            stop_signal,
+        })
+    }
+
+    #[must_use]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.argv[0]);
+        command
+            .args(&self.argv[1..])
+            .envs(&self.env)
+            .stdin(Stdio::null());
+        command
+    }
+}
+
+/// What a service is doing, as shown in the menu
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ServiceStatus {
+    Running(u32),
+    Restarting,
+    Exited(ExitStatus),
+    FailedToStart,
+}
+
+impl Display for ServiceStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Running(pid) => write!(f, "running ({pid})"),
+            Self::Restarting => write!(f, "restarting"),
+            Self::Exited(status) => match status.code() {
+                Some(code) => write!(f, "exited ({code})"),
+                None => write!(f, "killed"),
+            },
+            Self::FailedToStart => write!(f, "failed to start"),
+        }
+    }
+}
+
+struct Service {
+    config: ServiceConfig,
+    child: Option<WaitingChild>,
+    status: ServiceStatus,
+    started_at: Instant,
+    restart_at: Option<Instant>,
+    restarts: u32,
+    failures_in_a_row: u32,
+}
+
+/// Starts and stops the services for the current mode, restarting them as their policy says
+///
+/// Services are waited on with `WaitingChild`, which sends `StateChangeMessage::ServiceExited`,
+/// and restarts are scheduled by sending `StateChangeMessage::RestartServices` after a backoff.
+pub struct Supervisor {
+    services: Vec<Service>,
+    initial_backoff: Duration,
+    registry: Option<Registry>,
+    sender: Sender<StateChangeMessage>,
+}
+
+impl Supervisor {
+    /// Services are recorded in `registry` while running, so they are not lost should lod crash
+    #[must_use]
+    pub const fn new(registry: Option<Registry>, sender: Sender<StateChangeMessage>) -> Self {
+        Self {
+            services: vec![],
+            initial_backoff: INITIAL_BACKOFF,
+            registry,
+            sender,
+        }
+    }
+
+    #[cfg(test)]
+    const fn with_backoff(initial_backoff: Duration, sender: Sender<StateChangeMessage>) -> Self {
+        Self {
+            services: vec![],
+            initial_backoff,
+            registry: None,
+            sender,
+        }
+    }
+
+    /// Run exactly the given services, leaving those already running alone
+    pub fn set_services(&mut self, configs: &[ServiceConfig]) {
+        let (keep, stop): (Vec<_>, Vec<_>) = self
+            .services
+            .drain(..)
+            .partition(|service| configs.contains(&service.config));
+        for service in stop {
+            println!("Stopping service {}", service.config.name);
+            if let Some(child) = service.child {
+                if let Err(error) = child.stop(service.config.stop_signal.0) {
+                    eprintln!("Failed to stop service {}: {error:?}", service.config.name);
+                }
+            }
+        }
+        self.services = keep;
+
+        for config in configs {
+            if self
+                .services
+                .iter()
+                .any(|service| service.config == *config)
+            {
+                continue;
+            }
+            self.services.push(Service {
+                config: config.clone(),
+                child: None,
+                status: ServiceStatus::FailedToStart,
+                started_at: Instant::now(),
+                restart_at: None,
+                restarts: 0,
+                failures_in_a_row: 0,
+            });
+            self.start(self.services.len() - 1);
+        }
+    }
+
+    /// Stop all services, eg on quitting
+    pub fn stop_all(&mut self) {
+        self.set_services(&[]);
+    }
+
+    /// Handle `StateChangeMessage::ServiceExited`, scheduling restarts as needed
+    pub fn child_exited(&mut self) {
+        for index in 0..self.services.len() {
+            let Some(status) = self.services[index]
+                .child
+                .as_ref()
+                .and_then(WaitingChild::exit_status)
+            else {
+                continue;
+            };
+            let service = &mut self.services[index];
+            service.child = None;
+            service.status = ServiceStatus::Exited(status);
+            println!("Service {} {}", service.config.name, service.status);
+
+            if service.config.restart.should_restart(status.success()) {
+                let ran_for = service.started_at.elapsed();
+                self.schedule_restart(index, ran_for);
+            }
+        }
+    }
+
+    /// Handle `StateChangeMessage::RestartServices`, starting those whose backoff has passed
+    pub fn restart_due(&mut self) {
+        let now = Instant::now();
+        for index in 0..self.services.len() {
+            if self.services[index]
+                .restart_at
+                .is_some_and(|restart_at| restart_at <= now)
+            {
+                let service = &mut self.services[index];
+                service.restart_at = None;
+                service.restarts += 1;
+                self.start(index);
+            }
+        }
+    }
+
+    /// Name, status and number of restarts of each service
+    pub fn statuses(&self) -> impl Iterator<Item = (&str, ServiceStatus, u32)> {
+        self.services
+            .iter()
+            .map(|service| (service.config.name(), service.status, service.restarts))
+    }
+
+    fn start(&mut self, index: usize) {
+        let service = &mut self.services[index];
+        service.started_at = Instant::now();
+        match service.config.command().spawn() {
+            Ok(child) => {
+                service.status = ServiceStatus::Running(child.id());
+                let sender = self.sender.clone();
+                let on_exit = StateChangeMessage::ServiceExited;
+                service.child = Some(match &self.registry {
+                    Some(registry) => WaitingChild::recorded(child, sender, on_exit, registry),
+                    None => WaitingChild::new(child, sender, on_exit),
+                });
+                println!("Service {} {}", service.config.name, service.status);
+            }
+            Err(error) => {
+                eprintln!("Failed to start service {}: {error:?}", service.config.name);
+                service.status = ServiceStatus::FailedToStart;
+                // There is no exit to wait for, so treat this as failing straight away
+                if service.config.restart.should_restart(false) {
+                    self.schedule_restart(index, Duration::ZERO);
+                }
+            }
+        }
+    }
+
+    fn schedule_restart(&mut self, index: usize, ran_for: Duration) {
+        let service = &mut self.services[index];
+        if ran_for >= HEALTHY_AFTER {
+            service.failures_in_a_row = 0;
+        }
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2_u32.saturating_pow(service.failures_in_a_row))
+            .min(MAX_BACKOFF);
+        service.failures_in_a_row += 1;
+        service.status = ServiceStatus::Restarting;
+        service.restart_at = Some(Instant::now() + backoff);
+        println!("Restarting service {} in {backoff:?}", service.config.name);
+
+        let sender = self.sender.clone();
+        thread::spawn(move || {
+            thread::sleep(backoff);
+            let _ = sender.send(StateChangeMessage::RestartServices);
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc::{self, Receiver};
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn service(script: &str, restart: RestartPolicy) -> ServiceConfig {
+        let argv = ["sh", "-c", script].map(String::from).to_vec();
+        ServiceConfig::new(None, argv, BTreeMap::new(), restart, StopSignal::default()).unwrap()
+    }
+
+    fn supervisor() -> (Supervisor, Receiver<StateChangeMessage>) {
+        let (sender, receiver) = mpsc::channel();
+        (
+            Supervisor::with_backoff(Duration::from_millis(10), sender),
+            receiver,
+        )
+    }
+
+    /// Handle messages as the app would, until `done` or timing out
+    fn run_until(
+        sut: &mut Supervisor,
+        receiver: &Receiver<StateChangeMessage>,
+        done: impl Fn(&Supervisor) -> bool,
+    ) {
+        let deadline = Instant::now() + TIMEOUT;
+        while !done(sut) {
+            let message = receiver
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .expect("Timed out waiting for services");
+            match message {
+                StateChangeMessage::ServiceExited => sut.child_exited(),
+                StateChangeMessage::RestartServices => sut.restart_due(),
+                _ => (),
+            }
+        }
+    }
+
+    fn status(sut: &Supervisor) -> (ServiceStatus, u32) {
+        let (_, status, restarts) = sut.statuses().next().unwrap();
+        (status, restarts)
+    }
+
+    #[test]
+    fn it_parses_restart_policies_and_signals() {
+        assert_eq!("on-failure".parse(), Ok(RestartPolicy::OnFailure));
+        assert!("sometimes".parse::<RestartPolicy>().is_err());
+        assert_eq!("SIGINT".parse(), Ok(StopSignal(libc::SIGINT)));
+        assert_eq!("hup".parse(), Ok(StopSignal(libc::SIGHUP)));
+        assert!("SIGNOPE".parse::<StopSignal>().is_err());
+    }
+
+    #[test]
+    fn it_names_services_after_their_program() {
+        let argv = vec!["/usr/local/bin/synergyc".into(), "-f".into()];
+        let config = ServiceConfig::new(
+            None,
+            argv,
+            BTreeMap::new(),
+            RestartPolicy::Never,
+            StopSignal::default(),
+        )
+        .unwrap();
+        assert_eq!(config.name(), "synergyc");
+        assert!(
+            ServiceConfig::new(
+                None,
+                vec![],
+                BTreeMap::new(),
+                RestartPolicy::Never,
+                StopSignal::default()
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn it_does_not_restart_when_told_never_to() {
+        let (mut sut, receiver) = supervisor();
+        sut.set_services(&[service("exit 1", RestartPolicy::Never)]);
+        run_until(&mut sut, &receiver, |sut| {
+            matches!(status(sut).0, ServiceStatus::Exited(_))
+        });
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_millis(100)),
+            Err(mpsc::RecvTimeoutError::Timeout)
+        );
+        assert_eq!(status(&sut).1, 0);
+    }
+
+    #[test]
+    fn it_restarts_on_failure() {
+        let (mut sut, receiver) = supervisor();
+        sut.set_services(&[service("exit 1", RestartPolicy::OnFailure)]);
+        run_until(&mut sut, &receiver, |sut| status(sut).1 >= 2);
+    }
+
+    #[test]
+    fn it_does_not_restart_on_success_unless_always() {
+        let (mut sut, receiver) = supervisor();
+        sut.set_services(&[service("exit 0", RestartPolicy::OnFailure)]);
+        run_until(
+            &mut sut,
+            &receiver,
+            |sut| matches!(status(sut).0, ServiceStatus::Exited(status) if status.success()),
+        );
+
+        sut.set_services(&[service("exit 0", RestartPolicy::Always)]);
+        run_until(&mut sut, &receiver, |sut| status(sut).1 >= 1);
+    }
+
+    #[test]
+    fn it_passes_the_environment() {
+        let (mut sut, receiver) = supervisor();
+        let argv = ["sh", "-c", "exit $CODE"].map(String::from).to_vec();
+        let env = BTreeMap::from([("CODE".into(), "7".into())]);
+        let config =
+            ServiceConfig::new(None, argv, env, RestartPolicy::Never, StopSignal::default())
+                .unwrap();
+        sut.set_services(&[config]);
+        run_until(
+            &mut sut,
+            &receiver,
+            |sut| matches!(status(sut).0, ServiceStatus::Exited(status) if status.code() == Some(7)),
+        );
+    }
+
+    #[test]
+    fn it_stops_services_no_longer_wanted() {
+        let (mut sut, receiver) = supervisor();
+        let config = service("sleep 60", RestartPolicy::Always);
+        sut.set_services(std::slice::from_ref(&config));
+        let (ServiceStatus::Running(pid), _) = status(&sut) else {
+            panic!("Service should be running");
+        };
+
+        // Switching to a mode with the same service leaves it running
+        sut.set_services(std::slice::from_ref(&config));
+        assert_eq!(status(&sut).0, ServiceStatus::Running(pid));
+
+        sut.stop_all();
+        assert_eq!(sut.statuses().count(), 0);
+        assert_eq!(
+            receiver.recv_timeout(TIMEOUT),
+            Ok(StateChangeMessage::ServiceExited)
+        );
+        // SAFETY: signal 0 only checks whether the process exists
+        assert_eq!(unsafe { libc::kill(pid.try_into().unwrap(), 0) }, -1);
+    }
+
+    #[test]
+    fn it_retries_services_which_fail_to_start() {
+        let (mut sut, receiver) = supervisor();
+        let argv = vec!["/nonexistent/service".into()];
+        let config = ServiceConfig::new(
+            None,
+            argv,
+            BTreeMap::new(),
+            RestartPolicy::OnFailure,
+            StopSignal::default(),
+        )
+        .unwrap();
+        sut.set_services(&[config]);
+        assert_eq!(status(&sut).0, ServiceStatus::Restarting);
+        run_until(&mut sut, &receiver, |sut| status(sut).1 >= 1);
+    }
+}
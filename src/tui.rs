@@ -0,0 +1,435 @@
+use super::{
+    AppState, Event, Mode, StateChangeMessage, Status, cli,
+    detector::{Fact, Facts},
+    ui::StatusUi,
+};
+use ratatui::{
+    Frame, Terminal,
+    backend::CrosstermBackend,
+    crossterm::{
+        event::{self, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+        execute,
+        terminal::{EnterAlternateScreen, LeaveAlternateScreen, disable_raw_mode, enable_raw_mode},
+    },
+    layout::{Constraint, Layout, Rect},
+    widgets::{Block, Paragraph, Wrap},
+};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd},
+    sync::{
+        Arc, Mutex, PoisonError,
+        mpsc::{Receiver, TryRecvError},
+    },
+    thread,
+    time::Duration,
+};
+
+/// How long to wait for a key press before checking for messages, and redrawing
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How many lines of output are kept for the log view
+const MAX_LOG_LINES: usize = 1000;
+
+/// How many of the latest events are shown
+const MAX_EVENTS: usize = 50;
+
+const HELP: &str = "m switch mode  d desktop  l laptop  c caffeinate  a automatic  r reload config  \
+    v view logs  q quit";
+
+/// Run `app_state` in a full screen terminal interface until asked to quit, eg with `q` or
+/// `lod quit`
+///
+/// What lod prints while running is shown in the log view, rather than over the interface.
+///
+/// # Errors
+///
+/// If the terminal could not be set up or drawn on
+pub fn run<U: StatusUi>(
+    app_state: &mut AppState<U>,
+    receiver: &Receiver<StateChangeMessage>,
+) -> io::Result<()> {
+    let logs = Logs::default();
+    let capture = Capture::start(&logs)?;
+    let _screen = AlternateScreen::enter(capture.terminal()?)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(capture.terminal()?))?;
+    terminal.clear()?;
+
+    let events = app_state.events().subscribe();
+    let mut view = View::default();
+    loop {
+        for event in events.try_iter() {
+            view.push_event(&event);
+        }
+        let status = app_state.status().get();
+        let facts = app_state.facts();
+        terminal.draw(|frame| view.draw(frame, &status, &facts, &logs.lines()))?;
+
+        let mut messages = vec![];
+        loop {
+            match receiver.try_recv() {
+                Ok(message) => messages.push(message),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        }
+        if event::poll(POLL_INTERVAL)? {
+            if let event::Event::Key(key) = event::read()? {
+                match Action::for_key(key) {
+                    Some(Action::Send(message)) => messages.push(message),
+                    Some(Action::ToggleLogs) => view.show_logs = !view.show_logs,
+                    None => (),
+                }
+            }
+        }
+        for message in messages {
+            if message == StateChangeMessage::Quit {
+                return Ok(());
+            }
+            app_state.handle(message);
+        }
+    }
+}
+
+/// What a key press asks for
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Action {
+    Send(StateChangeMessage),
+    ToggleLogs,
+}
+
+impl Action {
+    fn for_key(key: KeyEvent) -> Option<Self> {
+        if key.kind != KeyEventKind::Press {
+            return None;
+        }
+        let message = match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                StateChangeMessage::Quit
+            }
+            KeyCode::Char('m') => StateChangeMessage::ToggleMode,
+            KeyCode::Char('d') => StateChangeMessage::SetMode(Mode::Desktop),
+            KeyCode::Char('l') => StateChangeMessage::SetMode(Mode::Laptop),
+            KeyCode::Char('c') => StateChangeMessage::ToggleCaffeination,
+            KeyCode::Char('a') => StateChangeMessage::ToggleAutomatic,
+            KeyCode::Char('r') => StateChangeMessage::ReloadConfig,
+            KeyCode::Char('q') | KeyCode::Esc => StateChangeMessage::Quit,
+            KeyCode::Char('v') => return Some(Self::ToggleLogs),
+            _ => return None,
+        };
+        Some(Self::Send(message))
+    }
+}
+
+/// What is shown besides the status, and how
+#[derive(Default)]
+struct View {
+    /// Described for people, latest first
+    events: VecDeque<String>,
+    show_logs: bool,
+}
+
+impl View {
+    fn push_event(&mut self, event: &Event) {
+        self.events.push_front(cli::format_event(&event.to_json()));
+        self.events.truncate(MAX_EVENTS);
+    }
+
+    fn draw(&self, frame: &mut Frame, status: &Status, facts: &Facts, logs: &[String]) {
+        let status = cli::format_status(&status.to_json());
+        let status_height = u16::try_from(status.lines().count()).unwrap_or(u16::MAX);
+        let [status_area, main_area, help_area] = Layout::vertical([
+            Constraint::Length(status_height.saturating_add(2)),
+            Constraint::Min(0),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        frame.render_widget(
+            Paragraph::new(status).block(Block::bordered().title(" lod ")),
+            status_area,
+        );
+        if self.show_logs {
+            Self::draw_logs(frame, main_area, logs);
+        } else {
+            let [facts_area, events_area] =
+                Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)])
+                    .areas(main_area);
+            frame.render_widget(
+                Paragraph::new(describe_facts(facts))
+                    .wrap(Wrap { trim: false })
+                    .block(Block::bordered().title(" Facts ")),
+                facts_area,
+            );
+            frame.render_widget(
+                Paragraph::new(
+                    self.events
+                        .iter()
+                        .map(String::as_str)
+                        .collect::<Vec<_>>()
+                        .join("\n"),
+                )
+                .wrap(Wrap { trim: false })
+                .block(Block::bordered().title(" Last Actions ")),
+                events_area,
+            );
+        }
+        frame.render_widget(Paragraph::new(HELP), help_area);
+    }
+
+    /// The latest lines which fit
+    fn draw_logs(frame: &mut Frame, area: Rect, logs: &[String]) {
+        let height = usize::from(area.height.saturating_sub(2));
+        let shown = &logs[logs.len().saturating_sub(height)..];
+        frame.render_widget(
+            Paragraph::new(shown.join("\n")).block(Block::bordered().title(" Logs ")),
+            area,
+        );
+    }
+}
+
+fn describe_facts(facts: &Facts) -> String {
+    if facts.is_empty() {
+        return String::from("None detected yet, or there are no rules for automatic switching");
+    }
+    facts
+        .iter()
+        .map(|(name, fact)| match fact {
+            Fact::Bool(value) => format!("{name}: {value}"),
+            Fact::Text(text) => format!("{name}: {text:?}"),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Lines written to stdout and stderr, the latest last
+#[derive(Clone, Default)]
+struct Logs(Arc<Mutex<VecDeque<String>>>);
+
+impl Logs {
+    fn push(&self, line: String) {
+        let mut lines = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if lines.len() == MAX_LOG_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    fn lines(&self) -> Vec<String> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
+
+/// Sends stdout and stderr to `Logs` until dropped, so that printing does not garble the screen
+struct Capture {
+    stdout: OwnedFd,
+    stderr: OwnedFd,
+}
+
+impl Capture {
+    fn start(logs: &Logs) -> io::Result<Self> {
+        io::stdout().flush()?;
+        let stdout = io::stdout().as_fd().try_clone_to_owned()?;
+        let stderr = io::stderr().as_fd().try_clone_to_owned()?;
+
+        let mut fds = [0; 2];
+        // SAFETY: `fds` has room for the two file descriptors `pipe` writes
+        if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `pipe` succeeded, so these are open and owned by nothing else
+        let (reader, writer) =
+            unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            // SAFETY: both file descriptors are open, and `dup2` closes `fd` before replacing it
+            if unsafe { libc::dup2(writer.as_raw_fd(), fd) } == -1 {
+                let error = io::Error::last_os_error();
+                drop(Self { stdout, stderr });
+                return Err(error);
+            }
+        }
+
+        let logs = logs.clone();
+        thread::spawn(move || {
+            // Ends once the pipe is no longer stdout or stderr, when the capture is dropped
+            for line in BufReader::new(File::from(reader)).lines() {
+                match line {
+                    Ok(line) => logs.push(line),
+                    Err(_) => break,
+                }
+            }
+        });
+
+        Ok(Self { stdout, stderr })
+    }
+
+    /// Where stdout went before it was captured, ie the terminal
+    fn terminal(&self) -> io::Result<File> {
+        Ok(File::from(self.stdout.try_clone()?))
+    }
+}
+
+impl Drop for Capture {
+    fn drop(&mut self) {
+        let _ = io::stdout().flush();
+        for (original, fd) in [
+            (&self.stdout, libc::STDOUT_FILENO),
+            (&self.stderr, libc::STDERR_FILENO),
+        ] {
+            // SAFETY: `original` is open for as long as `self` is
+            unsafe { libc::dup2(original.as_raw_fd(), fd) };
+        }
+    }
+}
+
+/// Raw mode on the alternate screen of `terminal`, left when dropped, including on panic
+struct AlternateScreen(File);
+
+impl AlternateScreen {
+    fn enter(mut terminal: File) -> io::Result<Self> {
+        enable_raw_mode()?;
+        execute!(terminal, EnterAlternateScreen)?;
+        Ok(Self(terminal))
+    }
+}
+
+impl Drop for AlternateScreen {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(self.0, LeaveAlternateScreen);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ratatui::backend::TestBackend;
+
+    fn render(view: &View, status: &Status, facts: &Facts, logs: &[String]) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(100, 20)).unwrap();
+        terminal
+            .draw(|frame| view.draw(frame, status, facts, logs))
+            .unwrap();
+        let buffer = terminal.backend().buffer();
+        buffer
+            .content()
+            .chunks(usize::from(buffer.area.width))
+            .map(|row| {
+                row.iter()
+                    .map(ratatui::buffer::Cell::symbol)
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    #[test]
+    fn it_shows_the_status_facts_and_latest_events() {
+        let mut view = View::default();
+        view.push_event(&Event::ModeChanged {
+            mode: Mode::Desktop,
+            previous: Mode::Laptop,
+        });
+        view.push_event(&Event::ActionFailed {
+            action: "run_applescript",
+            error: String::from("Exited with 1"),
+        });
+        let mut status = Status::new(Mode::Desktop);
+        status.caffeinating = true;
+        status.caffeinate_by_mode = Some(false);
+        let facts = Facts::from([
+            ("on_ac".into(), true.into()),
+            ("wifi_ssid".into(), String::from("Office").into()),
+        ]);
+
+        let screen = render(&view, &status, &facts, &[String::from("Hidden")]);
+        assert!(screen.contains("Mode: desktop"), "{screen}");
+        assert!(screen.contains("Caffeinate: on (manual)"), "{screen}");
+        assert!(screen.contains("on_ac: true"), "{screen}");
+        assert!(screen.contains("wifi_ssid: \"Office\""), "{screen}");
+        let failed = screen
+            .find("Failed to run applescript: Exited with 1")
+            .unwrap();
+        let changed = screen.find("Mode changed to desktop").unwrap();
+        assert!(failed < changed, "{screen}");
+        assert!(!screen.contains("Hidden"), "{screen}");
+        assert!(screen.contains("q quit"), "{screen}");
+    }
+
+    #[test]
+    fn it_says_when_there_are_no_facts() {
+        let screen = render(
+            &View::default(),
+            &Status::new(Mode::Laptop),
+            &Facts::new(),
+            &[],
+        );
+        assert!(screen.contains("Mode: laptop"), "{screen}");
+        assert!(screen.contains("None detected"), "{screen}");
+    }
+
+    #[test]
+    fn it_shows_the_latest_logs() {
+        let view = View {
+            show_logs: true,
+            ..View::default()
+        };
+        let logs: Vec<String> = (0..100).map(|line| format!("Line {line}")).collect();
+
+        let screen = render(&view, &Status::new(Mode::Laptop), &Facts::new(), &logs);
+        assert!(screen.contains("Line 99"), "{screen}");
+        assert!(!screen.contains("Line 0 "), "{screen}");
+        assert!(!screen.contains(" Facts "), "{screen}");
+    }
+
+    #[test]
+    fn it_keeps_the_latest_log_lines() {
+        let logs = Logs::default();
+        for line in 0..=MAX_LOG_LINES {
+            logs.push(line.to_string());
+        }
+        let lines = logs.lines();
+        assert_eq!(lines.len(), MAX_LOG_LINES);
+        assert_eq!(lines.first().map(String::as_str), Some("1"));
+    }
+
+    #[test]
+    fn it_maps_keys_to_messages() {
+        assert_eq!(
+            Action::for_key(key(KeyCode::Char('m'))),
+            Some(Action::Send(StateChangeMessage::ToggleMode))
+        );
+        assert_eq!(
+            Action::for_key(key(KeyCode::Char('d'))),
+            Some(Action::Send(StateChangeMessage::SetMode(Mode::Desktop)))
+        );
+        assert_eq!(
+            Action::for_key(key(KeyCode::Char('c'))),
+            Some(Action::Send(StateChangeMessage::ToggleCaffeination))
+        );
+        assert_eq!(
+            Action::for_key(KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL)),
+            Some(Action::Send(StateChangeMessage::Quit))
+        );
+        assert_eq!(
+            Action::for_key(key(KeyCode::Char('r'))),
+            Some(Action::Send(StateChangeMessage::ReloadConfig))
+        );
+        assert_eq!(
+            Action::for_key(key(KeyCode::Char('v'))),
+            Some(Action::ToggleLogs)
+        );
+        assert_eq!(Action::for_key(key(KeyCode::Char('x'))), None);
+    }
+}
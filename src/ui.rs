@@ -0,0 +1,168 @@
+use super::StateChangeMessage;
+
+/// Where the state is shown, eg the status item in the macOS menu bar
+pub trait StatusUi {
+    /// Show the icon for the current mode
+    fn set_icon(&mut self, icon: Icon);
+
+    /// Replace the menu, whose items send their message when clicked
+    fn set_menu(&mut self, menu: Vec<MenuEntry>);
+}
+
+/// No UI at all, for running as a daemon where the state is only seen through the control socket
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Headless;
+
+impl StatusUi for Headless {
+    fn set_icon(&mut self, _icon: Icon) {}
+
+    fn set_menu(&mut self, _menu: Vec<MenuEntry>) {}
+}
+
+/// An SF Symbol, by name, with its description for accessibility
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Icon {
+    pub symbol: &'static str,
+    pub description: &'static str,
+}
+
+impl Icon {
+    #[must_use]
+    pub const fn new(symbol: &'static str, description: &'static str) -> Self {
+        Self {
+            symbol,
+            description,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MenuEntry {
+    Item(MenuItem),
+    Separator,
+}
+
+impl From<MenuItem> for MenuEntry {
+    fn from(item: MenuItem) -> Self {
+        Self::Item(item)
+    }
+}
+
+/// A menu item, independent of how it is shown
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MenuItem {
+    pub title: String,
+    pub icon: Option<Icon>,
+    /// Whether the item is shown as on or off, where `None` shows neither
+    pub checked: Option<bool>,
+    /// Sent when the item is clicked
+    pub message: Option<StateChangeMessage>,
+    pub submenu: Option<Vec<MenuEntry>>,
+}
+
+impl MenuItem {
+    #[must_use]
+    pub fn new(title: impl Into<String>) -> Self {
+        Self {
+            title: title.into(),
+            icon: None,
+            checked: None,
+            message: None,
+            submenu: None,
+        }
+    }
+
+    #[must_use]
+    pub fn with_icon(self, symbol: &'static str, description: &'static str) -> Self {
+        Self {
+            icon: Some(Icon::new(symbol, description)),
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn with_checked(self, checked: bool) -> Self {
+        Self {
+            checked: Some(checked),
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn with_message(self, message: StateChangeMessage) -> Self {
+        Self {
+            message: Some(message),
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn with_submenu(self, submenu: Vec<MenuEntry>) -> Self {
+        Self {
+            submenu: Some(submenu),
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+pub use fake::FakeUi;
+
+#[cfg(test)]
+mod fake {
+    use super::{Icon, MenuEntry, MenuItem, StatusUi};
+    use std::sync::{Arc, Mutex};
+
+    /// Records what would have been shown, clones share the same record
+    #[derive(Clone, Default)]
+    pub struct FakeUi {
+        icons: Arc<Mutex<Vec<Icon>>>,
+        menus: Arc<Mutex<Vec<Vec<MenuEntry>>>>,
+    }
+
+    impl FakeUi {
+        /// Every icon shown so far, in order
+        ///
+        /// # Panics
+        ///
+        /// If another thread panicked while holding the lock
+        #[must_use]
+        pub fn icons(&self) -> Vec<Icon> {
+            self.icons.lock().unwrap().clone()
+        }
+
+        /// The latest menu
+        ///
+        /// # Panics
+        ///
+        /// If another thread panicked while holding the lock
+        #[must_use]
+        pub fn menu(&self) -> Vec<MenuEntry> {
+            self.menus
+                .lock()
+                .unwrap()
+                .last()
+                .cloned()
+                .unwrap_or_default()
+        }
+
+        /// The top level item of the latest menu whose title starts with `prefix`
+        #[must_use]
+        pub fn item(&self, prefix: &str) -> Option<MenuItem> {
+            self.menu().into_iter().find_map(|entry| match entry {
+                MenuEntry::Item(item) if item.title.starts_with(prefix) => Some(item),
+                MenuEntry::Item(_) | MenuEntry::Separator => None,
+            })
+        }
+    }
+
+    impl StatusUi for FakeUi {
+        fn set_icon(&mut self, icon: Icon) {
+            self.icons.lock().unwrap().push(icon);
+        }
+
+        fn set_menu(&mut self, menu: Vec<MenuEntry>) {
+            self.menus.lock().unwrap().push(menu);
+        }
+    }
+}
@@ -1,28 +1,91 @@
-use super::app_state::StateChangeMessage;
-use crate::program::{Program, ProgramImpl};
+use super::{Registry, StateChangeMessage};
 use std::{
     error::Error,
-    process::{Child, Command},
-    sync::mpsc::Sender,
+    io,
+    process::{Child, ExitStatus},
+    sync::{Arc, Condvar, Mutex, PoisonError, mpsc::Sender},
     thread,
+    time::Duration,
 };
 
+/// How long a child has to clean up after `SIGTERM` before it is sent `SIGKILL`
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// A child process which is waited on in the background, sending the given message once it exits
 pub struct WaitingChild {
     id: u32,
+    exited: Arc<(Mutex<Exit>, Condvar)>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Exit {
+    Running,
+    Exited,
+    Reaped(ExitStatus),
 }
 
 impl WaitingChild {
-    pub fn new(mut child: Child, sender: Sender<StateChangeMessage>) -> Self {
-        let result = Self { id: child.id() };
+    #[must_use]
+    pub fn new(
+        child: Child,
+        sender: Sender<StateChangeMessage>,
+        on_exit: StateChangeMessage,
+    ) -> Self {
+        Self::spawn(child, sender, on_exit, None)
+    }
+
+    /// As `new`, but recording the child in `registry` until it exits, so that it can be found
+    /// should lod not get the chance to stop it
+    ///
+    /// Recording runs `ps` and writes the registry, so is done on the waiting thread.
+    #[must_use]
+    pub fn recorded(
+        child: Child,
+        sender: Sender<StateChangeMessage>,
+        on_exit: StateChangeMessage,
+        registry: &Registry,
+    ) -> Self {
+        Self::spawn(child, sender, on_exit, Some(registry.clone()))
+    }
+
+    fn spawn(
+        mut child: Child,
+        sender: Sender<StateChangeMessage>,
+        on_exit: StateChangeMessage,
+        registry: Option<Registry>,
+    ) -> Self {
+        let exited = Arc::new((Mutex::new(Exit::Running), Condvar::new()));
+        let result = Self {
+            id: child.id(),
+            exited: exited.clone(),
+        };
 
         thread::spawn(move || {
+            if let Some(registry) = &registry {
+                registry.record(child.id());
+            }
+            // Wait for the child without reaping it, so its pid cannot be reused while a signal
+            // is being sent, then mark it as exited before reaping
+            if let Err(error) = wait_without_reaping(child.id()) {
+                eprintln!("Failed to wait for child {}: {error:?}", child.id());
+            }
+            let (lock, condvar) = &*exited;
+            *lock.lock().unwrap_or_else(PoisonError::into_inner) = Exit::Exited;
+            condvar.notify_all();
+
             // We need to `wait` on the child process, otherwise it hangs around on macOS as a
             // zombie. See https://doc.rust-lang.org/std/process/struct.Child.html#warning
-            let _ = child.wait();
+            if let Ok(status) = child.wait() {
+                *lock.lock().unwrap_or_else(PoisonError::into_inner) = Exit::Reaped(status);
+            }
+            if let Some(registry) = registry {
+                registry.forget(child.id());
+            }
 
-            if let Err(error) = sender.send(StateChangeMessage::ClearCaffeination) {
+            if let Err(error) = sender.send(on_exit) {
                 eprintln!(
-                    "Failed to send StateChangeMessage::ClearCaffeination message. Error: {error}"
+                    "Failed to send StateChangeMessage::{:?} message. Error: {error}",
+                    error.0
                 );
             }
         });
@@ -30,10 +93,219 @@ impl WaitingChild {
         result
     }
 
+    /// Whether the child has exited, as it is possible a message saying so was sent for a
+    /// previous child which has since been replaced
+    pub fn has_exited(&self) -> bool {
+        *self.exited.0.lock().unwrap_or_else(PoisonError::into_inner) != Exit::Running
+    }
+
+    /// How the child exited, once it has been reaped
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        let exited = *self.exited.0.lock().unwrap_or_else(PoisonError::into_inner);
+        match exited {
+            Exit::Reaped(status) => Some(status),
+            Exit::Running | Exit::Exited => None,
+        }
+    }
+
+    #[must_use]
+    pub const fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Ask the child to exit with `SIGTERM`, following up with `SIGKILL` in the background
+    /// should it still be running after a grace period
+    ///
+    /// # Errors
+    ///
+    /// If the child has already exited, or could not be signalled
     pub fn kill(&self) -> Result<(), Box<dyn Error>> {
-        let mut command = Command::new("kill");
-        command.args(["-9", &self.id.to_string()]);
-        let mut program = ProgramImpl::new(command, 0);
-        program.execute().map(|_| Ok(()))?
+        self.stop(libc::SIGTERM)
+    }
+
+    /// As `kill`, but asking the child to exit with the given `signal`
+    ///
+    /// # Errors
+    ///
+    /// If the child has already exited, or could not be signalled
+    pub fn stop(&self, signal: libc::c_int) -> Result<(), Box<dyn Error>> {
+        self.terminate(signal, KILL_GRACE_PERIOD)
+    }
+
+    fn terminate(&self, signal: libc::c_int, grace_period: Duration) -> Result<(), Box<dyn Error>> {
+        self.signal(signal)?;
+
+        let id = self.id;
+        let exited = self.exited.clone();
+        thread::spawn(move || {
+            let (lock, condvar) = &*exited;
+            let exited = condvar
+                .wait_timeout_while(
+                    lock.lock().unwrap_or_else(PoisonError::into_inner),
+                    grace_period,
+                    |exited| *exited == Exit::Running,
+                )
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+            if *exited == Exit::Running {
+                eprintln!("Child {id} still running after {grace_period:?}, sending SIGKILL");
+                if let Err(error) = send_signal(id, libc::SIGKILL) {
+                    eprintln!("Failed to kill child {id}: {error:?}");
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    fn signal(&self, signal: libc::c_int) -> Result<(), Box<dyn Error>> {
+        // Holding the lock stops the waiting thread reaping the child, and so the pid being
+        // reused, while it is being signalled
+        let exited = self.exited.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if *exited != Exit::Running {
+            return Err(format!("Child {} has already exited", self.id).into());
+        }
+
+        let result = send_signal(self.id, signal);
+        drop(exited);
+
+        Ok(result?)
+    }
+}
+
+/// Signal the child, along with the rest of its process group should it lead one, as keep awake
+/// backends do so that what they run is not left behind
+pub fn send_signal(id: u32, signal: libc::c_int) -> io::Result<()> {
+    let pid = libc::pid_t::try_from(id).map_err(io::Error::other)?;
+    // SAFETY: `getpgid` and `kill` have no memory safety requirements
+    let target = if unsafe { libc::getpgid(pid) } == pid {
+        -pid
+    } else {
+        pid
+    };
+    // SAFETY: as above
+    if unsafe { libc::kill(target, signal) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Block until the child exits, leaving it as a zombie to be reaped by `Child::wait`
+fn wait_without_reaping(id: u32) -> io::Result<()> {
+    loop {
+        // SAFETY: `waitid` only writes to the `siginfo_t` given
+        let result = unsafe {
+            let mut info = std::mem::zeroed::<libc::siginfo_t>();
+            libc::waitid(
+                libc::P_PID,
+                id,
+                &raw mut info,
+                libc::WEXITED | libc::WNOWAIT,
+            )
+        };
+        if result == 0 {
+            return Ok(());
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{
+        process::Command,
+        sync::mpsc::{self, Receiver},
+        time::Instant,
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn spawn(program: &str, args: &[&str]) -> (WaitingChild, Receiver<StateChangeMessage>) {
+        let (sender, receiver) = mpsc::channel();
+        let child = Command::new(program).args(args).spawn().unwrap();
+        (
+            WaitingChild::new(child, sender, StateChangeMessage::ClearCaffeination),
+            receiver,
+        )
+    }
+
+    #[test]
+    fn it_clears_once_the_child_exits() {
+        let (sut, receiver) = spawn("true", &[]);
+        assert_eq!(
+            receiver.recv_timeout(TIMEOUT),
+            Ok(StateChangeMessage::ClearCaffeination)
+        );
+        assert!(sut.has_exited());
+        assert!(sut.exit_status().unwrap().success());
+    }
+
+    #[test]
+    fn it_terminates_the_child() {
+        let (sut, receiver) = spawn("sleep", &["60"]);
+        assert!(!sut.has_exited());
+
+        sut.kill().unwrap();
+        assert_eq!(
+            receiver.recv_timeout(TIMEOUT),
+            Ok(StateChangeMessage::ClearCaffeination)
+        );
+        assert!(sut.has_exited());
+    }
+
+    #[test]
+    fn it_escalates_when_the_child_ignores_sigterm() {
+        let (sut, receiver) = spawn("sh", &["-c", "trap '' TERM; while :; do sleep 0.1; done"]);
+        // Give the shell time to install its trap
+        thread::sleep(Duration::from_millis(200));
+
+        sut.terminate(libc::SIGTERM, Duration::from_millis(200))
+            .unwrap();
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_millis(100)),
+            Err(mpsc::RecvTimeoutError::Timeout)
+        );
+        assert_eq!(
+            receiver.recv_timeout(TIMEOUT),
+            Ok(StateChangeMessage::ClearCaffeination)
+        );
+    }
+
+    #[test]
+    fn it_records_the_child_in_the_background_until_it_exits() {
+        let dir = tempfile::tempdir().unwrap();
+        let registry = Registry::open(dir.path().join("children"));
+        let (sender, receiver) = mpsc::channel();
+        let child = Command::new("sleep").arg("60").spawn().unwrap();
+        let sut = WaitingChild::recorded(
+            child,
+            sender,
+            StateChangeMessage::ClearCaffeination,
+            &registry,
+        );
+
+        let deadline = Instant::now() + TIMEOUT;
+        while !registry
+            .leftovers()
+            .iter()
+            .any(|record| record.pid() == sut.id())
+        {
+            assert!(Instant::now() < deadline, "Never recorded");
+            thread::sleep(Duration::from_millis(20));
+        }
+        sut.kill().unwrap();
+        receiver.recv_timeout(TIMEOUT).unwrap();
+        assert!(registry.leftovers().is_empty());
+    }
+
+    #[test]
+    fn it_refuses_to_signal_once_exited() {
+        let (sut, receiver) = spawn("true", &[]);
+        receiver.recv_timeout(TIMEOUT).unwrap();
+        assert!(sut.kill().is_err());
     }
 }
@@ -0,0 +1,523 @@
+use super::{
+    Event, Events, SharedStatus, Status,
+    program::{Program, ProgramImpl},
+};
+use hmac::{Hmac, Mac};
+use serde_json::{Value, json};
+use sha2::Sha256;
+use std::{
+    error::Error,
+    fmt::Write,
+    process::Command,
+    sync::{
+        Arc, Mutex, PoisonError,
+        mpsc::{Receiver, RecvTimeoutError},
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// How long to wait for a webhook to respond, unless configured otherwise
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many times to try again should delivery fail, unless configured otherwise
+pub const DEFAULT_RETRIES: u32 = 2;
+
+/// Delay before the first retry, doubling for each one after
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// How often the dispatching thread checks whether it should stop
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A URL to POST events to, from `[[webhooks]]` in config.toml
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WebhookConfig {
+    url: String,
+    events: Vec<String>,
+    timeout: Duration,
+    retries: u32,
+    secret: Option<String>,
+}
+
+impl WebhookConfig {
+    /// Events are sent only if named in `events`, or all of them if it is empty
+    ///
+    /// # Errors
+    ///
+    /// If the URL is not http(s), an event is unknown or the timeout is zero
+    pub fn new(
+        url: String,
+        events: Vec<String>,
+        timeout: Duration,
+        retries: u32,
+        secret: Option<String>,
+    ) -> Result<Self, String> {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            return Err(format!(
+                "Webhook URL `{url}` should start with http:// or https://"
+            ));
+        }
+        if let Some(event) = events
+            .iter()
+            .find(|event| !Event::NAMES.contains(&event.as_str()))
+        {
+            return Err(format!(
+                "Unknown event `{event}`, expected one of {}",
+                Event::NAMES.join(", ")
+            ));
+        }
+        if timeout.is_zero() {
+            return Err(String::from("Webhook timeout should be more than zero"));
+        }
+
+        Ok(Self {
+            url,
+            events,
+            timeout,
+            retries,
+            secret,
+        })
+    }
+
+    fn wants(&self, event: &Event) -> bool {
+        self.events.is_empty() || self.events.iter().any(|name| name == event.name())
+    }
+}
+
+/// Sends events to the configured webhooks in the background, so a slow or unreachable one
+/// never holds up the main thread
+pub struct Webhooks {
+    webhooks: Arc<Mutex<Vec<WebhookConfig>>>,
+}
+
+impl Webhooks {
+    #[must_use]
+    pub fn spawn(events: &Events, status: SharedStatus) -> Self {
+        let webhooks = Arc::new(Mutex::new(vec![]));
+        let receiver = events.subscribe();
+        let thread_webhooks = webhooks.clone();
+        thread::spawn(move || dispatch(&receiver, &thread_webhooks, &status));
+
+        Self { webhooks }
+    }
+
+    /// Replace the webhooks, eg when config.toml is reloaded
+    pub fn set(&self, webhooks: &[WebhookConfig]) {
+        *self.webhooks.lock().unwrap_or_else(PoisonError::into_inner) = webhooks.to_vec();
+    }
+}
+
+fn dispatch(
+    receiver: &Receiver<Event>,
+    webhooks: &Arc<Mutex<Vec<WebhookConfig>>>,
+    status: &SharedStatus,
+) {
+    let hostname = hostname();
+    let mut last_action = Value::Null;
+    // Stop once the `Webhooks` has been dropped
+    while Arc::strong_count(webhooks) > 1 {
+        let event = match receiver.recv_timeout(POLL_INTERVAL) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        // Kept track of whether or not the event is wanted, so later payloads have it
+        if let Some(action) = action_result(&event) {
+            last_action = action;
+        }
+        let wanted: Vec<_> = webhooks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter(|webhook| webhook.wants(&event))
+            .cloned()
+            .collect();
+        if wanted.is_empty() {
+            continue;
+        }
+
+        let body = payload(
+            &event,
+            &status.get(),
+            &last_action,
+            &hostname,
+            SystemTime::now(),
+        )
+        .to_string();
+        for webhook in wanted {
+            let body = body.clone();
+            let name = event.name();
+            // Each webhook is delivered to separately, so one which is slow does not delay others
+            thread::spawn(move || {
+                if let Err(error) = deliver_with_retries(&webhook, name, &body, RETRY_DELAY) {
+                    eprintln!("Failed to send {name} to {}: {error:?}", webhook.url);
+                }
+            });
+        }
+    }
+}
+
+/// What lod did which led to the event, eg `{"name": "start_caffeinate", "ok": true}`, or `None`
+/// for events not caused by lod
+fn action_result(event: &Event) -> Option<Value> {
+    let name = match event {
+        Event::ModeChanged { .. } => "switch_mode",
+        Event::CaffeinateStarted(_) => "start_caffeinate",
+        Event::CaffeinateStopped => "stop_caffeinate",
+        Event::ActionFailed { action, error } => {
+            return Some(json!({ "name": action, "ok": false, "error": error }));
+        }
+        Event::ConfigReloaded => "reload_config",
+        Event::PluginEvent { .. } => return None,
+    };
+    Some(json!({ "name": name, "ok": true, "error": null }))
+}
+
+/// The JSON sent for an event, along with the state of the app, the result of the last action
+/// and which Mac it is from
+fn payload(
+    event: &Event,
+    status: &Status,
+    last_action: &Value,
+    hostname: &str,
+    now: SystemTime,
+) -> Value {
+    let status_json = status.to_json();
+    // The status is published once the menu has been updated, which can be after a caffeinate
+    // event is emitted, so the event has the final say on caffeination
+    let caffeinate = match event {
+        Event::CaffeinateStarted(_) => json!({
+            "active": true,
+            "expires_in_secs": event.to_json()["expires_in_secs"],
+        }),
+        Event::CaffeinateStopped => json!({ "active": false, "expires_in_secs": null }),
+        _ => json!({
+            "active": status_json["caffeinate"]["active"],
+            "expires_in_secs": status_json["caffeinate"]["expires_in_secs"],
+        }),
+    };
+    let (mode, previous_mode) = match event {
+        Event::ModeChanged { mode, previous } => (mode.name(), Some(previous.name())),
+        _ => (status.mode.name(), None),
+    };
+    let plugin = match event {
+        Event::PluginEvent { plugin, name, data } => {
+            json!({ "name": plugin, "event": name, "data": data })
+        }
+        _ => Value::Null,
+    };
+
+    json!({
+        "event": event.name(),
+        "mode": mode,
+        "previous_mode": previous_mode,
+        "caffeinate": caffeinate,
+        "action": last_action,
+        "plugin": plugin,
+        "hostname": hostname,
+        "timestamp": now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+    })
+}
+
+fn deliver_with_retries(
+    webhook: &WebhookConfig,
+    event: &str,
+    body: &str,
+    retry_delay: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let mut delay = retry_delay;
+    let mut attempt = 0;
+    loop {
+        match deliver(webhook, event, body) {
+            Ok(()) => return Ok(()),
+            Err(error) if attempt >= webhook.retries => return Err(error),
+            Err(error) => {
+                eprintln!(
+                    "Failed to send {event} to {}, trying again in {delay:?}: {error:?}",
+                    webhook.url
+                );
+                thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// POST the body with `curl`, which is always on macOS and handles https
+fn deliver(webhook: &WebhookConfig, event: &str, body: &str) -> Result<(), Box<dyn Error>> {
+    let mut curl = Command::new("curl");
+    curl.args([
+        "--silent",
+        "--show-error",
+        "--fail",
+        "--max-time",
+        &format!("{:.3}", webhook.timeout.as_secs_f64()),
+        "--header",
+        "Content-Type: application/json",
+        "--header",
+        &format!("X-Lod-Event: {event}"),
+    ]);
+    if let Some(secret) = &webhook.secret {
+        curl.args([
+            "--header",
+            &format!("X-Lod-Signature: sha256={}", sign(secret, body)),
+        ]);
+    }
+    // The body is always a JSON object, so never starts with the `@` which would read a file
+    curl.args(["--data-binary", body, "--url", &webhook.url]);
+    ProgramImpl::new(curl, 0).execute()?;
+    Ok(())
+}
+
+/// HMAC-SHA256 of the body, in hex, so the receiver can check it came from lod
+fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    // SAFETY: `gethostname` writes at most `buffer.len()` bytes into the buffer
+    if unsafe { libc::gethostname(buffer.as_mut_ptr().cast(), buffer.len()) } != 0 {
+        return String::new();
+    }
+    let length = buffer
+        .iter()
+        .position(|byte| *byte == 0)
+        .unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..length]).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Mode;
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// A request received by the stand-in, as its headers in lower case and body
+    struct Received {
+        headers: Vec<String>,
+        body: String,
+    }
+
+    /// Stands in for a webhook, replying to each request with the next of `statuses`
+    fn stand_in(statuses: &[u16]) -> (String, mpsc::Receiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        let statuses = statuses.to_vec();
+        thread::spawn(move || {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut headers = vec![];
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    headers.push(line.trim().to_ascii_lowercase());
+                }
+                let length: usize = headers
+                    .iter()
+                    .find_map(|header| header.strip_prefix("content-length: "))
+                    .map_or(0, |length| length.parse().unwrap());
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                write!(
+                    stream,
+                    "HTTP/1.1 {status} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                )
+                .unwrap();
+                let _ = sender.send(Received {
+                    headers,
+                    body: String::from_utf8(body).unwrap(),
+                });
+            }
+        });
+        (url, receiver)
+    }
+
+    fn webhook(url: String, events: &[&str], retries: u32, secret: Option<&str>) -> WebhookConfig {
+        WebhookConfig::new(
+            url,
+            events.iter().map(ToString::to_string).collect(),
+            TIMEOUT,
+            retries,
+            secret.map(String::from),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn it_validates_webhooks() {
+        let new = |url: &str, event: &str, timeout| {
+            WebhookConfig::new(url.into(), vec![event.into()], timeout, 0, None)
+        };
+        assert!(new("https://example.com", "mode_changed", TIMEOUT).is_ok());
+        assert!(new("ftp://example.com", "mode_changed", TIMEOUT).is_err());
+        assert!(new("https://example.com", "mode_switched", TIMEOUT).is_err());
+        assert!(new("https://example.com", "mode_changed", Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn it_filters_events() {
+        let all = webhook("http://localhost".into(), &[], 0, None);
+        let some = webhook("http://localhost".into(), &["config_reloaded"], 0, None);
+        assert!(all.wants(&Event::CaffeinateStopped));
+        assert!(!some.wants(&Event::CaffeinateStopped));
+        assert!(some.wants(&Event::ConfigReloaded));
+    }
+
+    #[test]
+    fn it_signs_with_hmac_sha256() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", "what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn it_builds_the_payload() {
+        let mut status = Status::new(Mode::Desktop);
+        status.caffeinating = true;
+        let now = UNIX_EPOCH + Duration::from_secs(1_760_000_000);
+
+        let event = Event::ModeChanged {
+            mode: Mode::Desktop,
+            previous: Mode::Laptop,
+        };
+        let action = action_result(&event).unwrap();
+        assert_eq!(
+            payload(&event, &status, &action, "mac", now),
+            json!({
+                "event": "mode_changed",
+                "mode": "desktop",
+                "previous_mode": "laptop",
+                "caffeinate": { "active": true, "expires_in_secs": null },
+                "action": { "name": "switch_mode", "ok": true, "error": null },
+                "plugin": null,
+                "hostname": "mac",
+                "timestamp": 1_760_000_000,
+            })
+        );
+
+        let payload = payload(&Event::CaffeinateStopped, &status, &action, "mac", now);
+        assert_eq!(payload["caffeinate"]["active"], false);
+        assert_eq!(payload["previous_mode"], Value::Null);
+
+        let event = Event::ActionFailed {
+            action: "run_applescript",
+            error: "not found".into(),
+        };
+        let action = action_result(&event).unwrap();
+        assert_eq!(
+            action,
+            json!({ "name": "run_applescript", "ok": false, "error": "not found" })
+        );
+
+        // Events not caused by lod carry the result of the last action
+        let event = Event::PluginEvent {
+            plugin: "vpn".into(),
+            name: "connected".into(),
+            data: Value::Null,
+        };
+        assert_eq!(action_result(&event), None);
+        assert_eq!(
+            super::payload(&event, &status, &action, "mac", now)["action"],
+            action
+        );
+    }
+
+    #[test]
+    fn it_delivers_signed_payloads() {
+        let (url, received) = stand_in(&[200]);
+        let webhook = webhook(url, &[], 0, Some("secret"));
+        deliver_with_retries(&webhook, "config_reloaded", "{\"a\":1}", Duration::ZERO).unwrap();
+
+        let received = received.recv_timeout(TIMEOUT).unwrap();
+        assert!(received.headers[0].starts_with("post /hook "));
+        assert!(
+            received
+                .headers
+                .contains(&String::from("content-type: application/json"))
+        );
+        assert!(
+            received
+                .headers
+                .contains(&String::from("x-lod-event: config_reloaded"))
+        );
+        assert!(received.headers.contains(&format!(
+            "x-lod-signature: sha256={}",
+            sign("secret", "{\"a\":1}")
+        )));
+        assert_eq!(received.body, "{\"a\":1}");
+    }
+
+    #[test]
+    fn it_retries_failed_deliveries() {
+        let (url, received) = stand_in(&[500, 503, 200]);
+        let webhook = webhook(url, &[], 2, None);
+        deliver_with_retries(&webhook, "config_reloaded", "{}", Duration::from_millis(10)).unwrap();
+        assert_eq!(received.iter().count(), 3);
+    }
+
+    #[test]
+    fn it_gives_up_after_the_retries() {
+        let (url, received) = stand_in(&[500, 500]);
+        let webhook = webhook(url, &[], 1, None);
+        assert!(
+            deliver_with_retries(&webhook, "config_reloaded", "{}", Duration::from_millis(10))
+                .is_err()
+        );
+        assert_eq!(received.iter().count(), 2);
+    }
+
+    #[test]
+    fn it_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let webhook = WebhookConfig::new(url, vec![], Duration::from_millis(200), 0, None).unwrap();
+
+        let started = SystemTime::now();
+        assert!(deliver(&webhook, "config_reloaded", "{}").is_err());
+        assert!(started.elapsed().unwrap() < TIMEOUT);
+        drop(listener);
+    }
+
+    #[test]
+    fn it_sends_events_in_the_background() {
+        let (url, received) = stand_in(&[200]);
+        let events = Events::default();
+        let sut = Webhooks::spawn(&events, SharedStatus::new(Status::new(Mode::Laptop)));
+        sut.set(&[webhook(url, &["mode_changed"], 0, None)]);
+
+        events.emit(&Event::ConfigReloaded);
+        events.emit(&Event::ModeChanged {
+            mode: Mode::Desktop,
+            previous: Mode::Laptop,
+        });
+        let received = received.recv_timeout(TIMEOUT).unwrap();
+        let payload: Value = serde_json::from_str(&received.body).unwrap();
+        assert_eq!(payload["event"], "mode_changed");
+        assert_eq!(payload["mode"], "desktop");
+        assert_eq!(payload["hostname"], hostname());
+    }
+}
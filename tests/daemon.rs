@@ -0,0 +1,137 @@
+use serde_json::{Value, json};
+use std::{
+    fs,
+    path::Path,
+    process::{Child, Command, Output, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+use tempfile::TempDir;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `lod daemon` with its own config.toml, runtime and state directories, killed if the test fails
+struct Daemon {
+    child: Child,
+    dir: TempDir,
+}
+
+impl Daemon {
+    fn spawn(config: &str) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("config.toml"), config).unwrap();
+        let child = lod(dir.path())
+            .arg("daemon")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let daemon = Self { child, dir };
+        daemon.wait_for(|| daemon.run(&["status"]).status.success());
+        daemon
+    }
+
+    fn run(&self, args: &[&str]) -> Output {
+        lod(self.dir.path()).args(args).output().unwrap()
+    }
+
+    fn status(&self) -> Value {
+        let output = self.run(&["--json", "status"]);
+        assert!(output.status.success(), "{output:?}");
+        serde_json::from_slice::<Value>(&output.stdout).unwrap()["status"].clone()
+    }
+
+    fn wait_for(&self, mut condition: impl FnMut() -> bool) {
+        let started = Instant::now();
+        while !condition() {
+            assert!(started.elapsed() < TIMEOUT, "Timed out waiting for lod");
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+impl Drop for Daemon {
+    /// Quitting first, so lod stops the `sleep` it started
+    fn drop(&mut self) {
+        let _ = self.run(&["quit"]);
+        let started = Instant::now();
+        while matches!(self.child.try_wait(), Ok(None)) && started.elapsed() < TIMEOUT {
+            thread::sleep(Duration::from_millis(50));
+        }
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn lod(dir: &Path) -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_lod"));
+    command
+        .env("LOD_CONFIG", dir.join("config.toml"))
+        .env("XDG_RUNTIME_DIR", dir)
+        .env("XDG_STATE_HOME", dir);
+    command
+}
+
+/// `sleep` stands in for `caffeinate`, and the `after_switch` hook records the mode
+fn config(dir: &str) -> String {
+    format!(
+        r#"
+        desktop_applescript = ""
+        laptop_applescript = ""
+        caffeinate_app = "sleep"
+
+        [caffeinate_options]
+        args = ["60"]
+
+        [hooks]
+        after_switch = ["echo $LOD_MODE > {dir}/switched"]
+        "#
+    )
+}
+
+#[test]
+fn it_switches_mode_and_caffeinates_when_asked() {
+    let hooks_dir = tempfile::tempdir().unwrap();
+    let daemon = Daemon::spawn(&config(&hooks_dir.path().display().to_string()));
+
+    assert!(daemon.run(&["switch", "desktop"]).status.success());
+    daemon.wait_for(|| daemon.status()["mode"] == "desktop");
+    let switched = hooks_dir.path().join("switched");
+    daemon.wait_for(|| fs::read_to_string(&switched).is_ok_and(|mode| mode == "desktop\n"));
+
+    assert!(daemon.run(&["caffeinate", "on"]).status.success());
+    daemon.wait_for(|| daemon.status()["caffeinate"]["active"] == true);
+    assert_eq!(daemon.status()["caffeinate"]["backend"], "caffeinate");
+    assert_eq!(daemon.status()["caffeinate"]["inhibits"], json!(["idle"]));
+    assert!(daemon.run(&["caffeinate", "off"]).status.success());
+    daemon.wait_for(|| daemon.status()["caffeinate"]["active"] == false);
+}
+
+#[test]
+fn it_refuses_to_run_twice() {
+    let daemon = Daemon::spawn(&config("/nonexistent"));
+
+    let output = daemon.run(&["daemon"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("already running"));
+}
+
+#[test]
+fn it_quits_when_asked() {
+    let mut daemon = Daemon::spawn(&config("/nonexistent"));
+
+    assert!(daemon.run(&["quit"]).status.success());
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = daemon.child.try_wait().unwrap() {
+            break status;
+        }
+        assert!(
+            started.elapsed() < TIMEOUT,
+            "Timed out waiting for lod to quit"
+        );
+        thread::sleep(Duration::from_millis(50));
+    };
+    assert!(status.success());
+    assert_eq!(daemon.run(&["status"]).status.code(), Some(3));
+}
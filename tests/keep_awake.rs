@@ -0,0 +1,192 @@
+use lod::{KeepAwake, StateChangeMessage, WaitingChild};
+use std::{
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Stands in for `systemd-inhibit`, which needs a system bus, recording its options to
+/// `inhibit.log` before running the command it was given as a child, as the real one does
+fn fake_systemd_inhibit(dir: &Path) -> PathBuf {
+    let path = dir.join("systemd-inhibit");
+    fs::write(
+        &path,
+        format!(
+            r#"#!/bin/sh
+echo "$@" >> {}/inhibit.log
+while [ "${{1#--}}" != "$1" ]; do shift; done
+"$@" &
+wait $!
+"#,
+            dir.display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
+
+fn wait_for(mut condition: impl FnMut() -> bool) {
+    let started = Instant::now();
+    while !condition() {
+        assert!(started.elapsed() < TIMEOUT, "Timed out");
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn has_exited(child: &mut Child) -> bool {
+    child.try_wait().unwrap().is_some()
+}
+
+fn log(dir: &Path) -> String {
+    fs::read_to_string(dir.join("inhibit.log")).unwrap_or_default()
+}
+
+/// Processes still running in the process group `pgid`, other than zombies waiting to be reaped
+fn running_in_group(pgid: u32) -> Vec<String> {
+    let output = Command::new("ps")
+        .args(["-A", "-o", "pgid=,stat=,comm="])
+        .output()
+        .unwrap();
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let in_group = fields.next()?.parse::<u32>().ok()? == pgid;
+            let zombie = fields.next()?.starts_with('Z');
+            (in_group && !zombie).then(|| fields.collect::<Vec<_>>().join(" "))
+        })
+        .collect()
+}
+
+/// Stop the child as lod does, waiting until nothing it started is left running
+fn stop(child: Child) {
+    let pgid = child.id();
+    let (sender, receiver) = mpsc::channel();
+    let child = WaitingChild::new(child, sender, StateChangeMessage::ClearCaffeination);
+    child.kill().unwrap();
+    receiver.recv_timeout(TIMEOUT).unwrap();
+    wait_for(|| running_in_group(pgid).is_empty());
+}
+
+#[test]
+fn it_inhibits_idle_and_sleep_until_killed() {
+    let dir = tempfile::tempdir().unwrap();
+    let keep_awake = KeepAwake::systemd_inhibit(fake_systemd_inhibit(dir.path()).to_str().unwrap());
+
+    let mut child = keep_awake.command(None).spawn().unwrap();
+    wait_for(|| !log(dir.path()).is_empty());
+    assert_eq!(
+        log(dir.path()),
+        "--what=idle:sleep --who=lod --why=Caffeinating --mode=block sleep infinity\n"
+    );
+    thread::sleep(Duration::from_millis(200));
+    assert!(!has_exited(&mut child));
+    assert!(
+        running_in_group(child.id())
+            .iter()
+            .any(|command| command == "sleep")
+    );
+
+    stop(child);
+}
+
+#[test]
+fn it_stops_what_it_runs_while_a_process_runs() {
+    let dir = tempfile::tempdir().unwrap();
+    let keep_awake = KeepAwake::systemd_inhibit(fake_systemd_inhibit(dir.path()).to_str().unwrap());
+
+    let mut process = Command::new("sleep").arg("60").spawn().unwrap();
+    let child = keep_awake.command_while(process.id()).spawn().unwrap();
+    wait_for(|| running_in_group(child.id()).len() > 1);
+
+    stop(child);
+    process.kill().unwrap();
+    process.wait().unwrap();
+}
+
+#[test]
+fn it_stops_inhibiting_once_the_time_is_up() {
+    let dir = tempfile::tempdir().unwrap();
+    let keep_awake = KeepAwake::systemd_inhibit(fake_systemd_inhibit(dir.path()).to_str().unwrap());
+
+    let mut child = keep_awake
+        .command(Some(Duration::from_secs(1)))
+        .spawn()
+        .unwrap();
+    wait_for(|| has_exited(&mut child));
+    assert!(log(dir.path()).ends_with("sleep 1\n"));
+}
+
+#[test]
+fn it_stops_inhibiting_once_the_process_exits() {
+    let dir = tempfile::tempdir().unwrap();
+    let keep_awake = KeepAwake::systemd_inhibit(fake_systemd_inhibit(dir.path()).to_str().unwrap());
+
+    let mut process = Command::new("sleep").arg("1").spawn().unwrap();
+    let mut child = keep_awake.command_while(process.id()).spawn().unwrap();
+    wait_for(|| !log(dir.path()).is_empty());
+    assert!(!has_exited(&mut child));
+
+    process.wait().unwrap();
+    wait_for(|| has_exited(&mut child));
+    assert!(log(dir.path()).ends_with(&format!("lod-hold {}\n", process.id())));
+}
+
+#[test]
+fn it_keeps_awake_while_running_a_command() {
+    let dir = tempfile::tempdir().unwrap();
+    let program = fake_systemd_inhibit(dir.path());
+    fs::write(
+        dir.path().join("config.toml"),
+        format!(
+            "desktop_applescript = \"\"\nlaptop_applescript = \"\"\n\
+             [keep_awake]\nbackend = \"systemd-inhibit\"\nprogram = \"{}\"\n",
+            program.display()
+        ),
+    )
+    .unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_lod"))
+        .args(["caffeinate", "--", "sh", "-c", "sleep 0.5; exit 3"])
+        .env("LOD_CONFIG", dir.path().join("config.toml"))
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(3));
+    assert!(log(dir.path()).starts_with("--what=idle:sleep --who=lod"));
+    assert!(log(dir.path()).contains("lod-hold"));
+}
+
+/// Only where there is a system bus for `systemd-inhibit` to take its lock through
+#[test]
+fn it_takes_a_lock_with_systemd_inhibit() {
+    let available = Command::new("systemd-inhibit")
+        .arg("--list")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success());
+    if !available {
+        eprintln!("Skipping as systemd-inhibit is not available");
+        return;
+    }
+
+    let child = KeepAwake::systemd_inhibit("systemd-inhibit")
+        .command(None)
+        .spawn()
+        .unwrap();
+    wait_for(|| {
+        let list = Command::new("systemd-inhibit")
+            .arg("--list")
+            .output()
+            .unwrap();
+        String::from_utf8_lossy(&list.stdout).contains("Caffeinating")
+    });
+    stop(child);
+}
@@ -6,8 +6,8 @@ fn it_works() -> Result<(), Error> {
     let r#true = Command::new("true");
     let output = ProgramImpl::new(r#true, 0).execute()?;
     assert_eq!(output.status_code(), &0);
-    assert_eq!(output.stdout(), []);
-    assert_eq!(output.stderr(), []);
+    assert_eq!(output.stdout(), b"");
+    assert_eq!(output.stderr(), b"");
 
     Ok(())
 }